@@ -0,0 +1,92 @@
+use std::hint::black_box;
+use std::io::{BufRead, BufReader};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use serde::Deserialize;
+
+// One second of traffic at 10 000 messages/s, so the time per iteration is
+// the share of a second one connection's reader is busy
+const MESSAGES: usize = 10_000;
+
+// The server's default --read-buffer-bytes
+const READ_BUFFER_BYTES: usize = 65536;
+
+// The fields `db_receiver bench` sends, deserialized as the server does
+#[allow(dead_code)]
+#[derive(Deserialize)]
+struct Record {
+    #[serde(rename = "sessionID")]
+    session_id: i64,
+    timestamp: String,
+    latitude: f64,
+    longitude: f64,
+    altitude: f64,
+    accel_x: f64,
+    accel_y: f64,
+    accel_z: f64,
+    gyro_x: f64,
+    gyro_y: f64,
+    gyro_z: f64,
+    dac_1: f64,
+    dac_2: f64,
+    dac_3: f64,
+    dac_4: f64,
+}
+
+fn input() -> Vec<u8> {
+    let mut input = Vec::new();
+    for i in 0..MESSAGES {
+        let t = i as f64 * 0.01;
+        let record = serde_json::json!({
+            "sessionID": 900000,
+            "timestamp": format!("2024-05-18T10:{:02}:{:02}.{:03}", i / 6000 % 60, i / 100 % 60, i % 100 * 10),
+            "latitude": 45.0 + t * 1e-5, "longitude": -122.0, "altitude": 100.0,
+            "accel_x": t.sin(), "accel_y": t.cos(), "accel_z": 9.81,
+            "gyro_x": 0.0, "gyro_y": 0.0, "gyro_z": 0.0,
+            "dac_1": 0.0, "dac_2": 0.0, "dac_3": 0.0, "dac_4": 0.0,
+        });
+        serde_json::to_writer(&mut input, &record).unwrap();
+        input.push(b'\n');
+    }
+    input
+}
+
+// The reader before: a new String for every line
+fn read_strings(input: &[u8]) -> usize {
+    let reader = BufReader::with_capacity(READ_BUFFER_BYTES, input);
+    let mut records = 0;
+    for line in reader.lines() {
+        let line = line.unwrap();
+        black_box(serde_json::from_str::<Record>(line.trim()).unwrap());
+        records += 1;
+    }
+    records
+}
+
+// The reader read_client uses: lines are read into one buffer and swapped
+// into another for parsing, so both keep their capacity
+fn read_reused_buffer(input: &[u8]) -> usize {
+    let mut reader = BufReader::with_capacity(READ_BUFFER_BYTES, input);
+    let mut buffer = Vec::new();
+    let mut line_buffer = Vec::new();
+    let mut records = 0;
+    while reader.read_until(b'\n', &mut buffer).unwrap() > 0 {
+        std::mem::swap(&mut buffer, &mut line_buffer);
+        buffer.clear();
+        let line = std::str::from_utf8(&line_buffer).unwrap().trim();
+        black_box(serde_json::from_str::<Record>(line).unwrap());
+        records += 1;
+    }
+    records
+}
+
+fn readers(c: &mut Criterion) {
+    let input = input();
+    let mut group = c.benchmark_group("read_lines");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    group.bench_function("string_per_line", |b| b.iter(|| assert_eq!(read_strings(&input), MESSAGES)));
+    group.bench_function("reused_buffer", |b| b.iter(|| assert_eq!(read_reused_buffer(&input), MESSAGES)));
+    group.finish();
+}
+
+criterion_group!(benches, readers);
+criterion_main!(benches);
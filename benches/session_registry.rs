@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use criterion::{criterion_group, criterion_main, Criterion};
+use dashmap::DashMap;
+
+// Simultaneous connections, each on its own thread
+const CONNECTIONS: usize = 100;
+
+// Sessions each connection starts and ends before it disconnects
+const SESSIONS_PER_CONNECTION: usize = 100;
+
+// What the registry keeps about an open session; the server also keeps
+// whether to close it on disconnect, which no operation here reads
+#[derive(Debug, Clone, Copy)]
+struct OpenSession {
+    addr: SocketAddr,
+}
+
+// The registry operations a connection performs: session_start inserts,
+// session_end removes, and the disconnect releases whatever is left
+trait Registry: Sync {
+    fn new() -> Self;
+    // Each returns how long it waited for and held the lock
+    fn start(&self, session_id: i64, session: OpenSession) -> Duration;
+    fn end(&self, session_id: i64) -> Duration;
+    fn release(&self, addr: SocketAddr) -> Duration;
+}
+
+// The registry before: one lock around the whole map
+impl Registry for Mutex<HashMap<i64, OpenSession>> {
+    fn new() -> Self {
+        Mutex::new(HashMap::new())
+    }
+
+    fn start(&self, session_id: i64, session: OpenSession) -> Duration {
+        let started = Instant::now();
+        self.lock().unwrap().insert(session_id, session);
+        started.elapsed()
+    }
+
+    fn end(&self, session_id: i64) -> Duration {
+        let started = Instant::now();
+        black_box(self.lock().unwrap().remove(&session_id));
+        started.elapsed()
+    }
+
+    fn release(&self, addr: SocketAddr) -> Duration {
+        let started = Instant::now();
+        self.lock().unwrap().retain(|_, session| session.addr != addr);
+        started.elapsed()
+    }
+}
+
+// The registry session.rs keeps: a lock per shard
+impl Registry for DashMap<i64, OpenSession> {
+    fn new() -> Self {
+        DashMap::new()
+    }
+
+    fn start(&self, session_id: i64, session: OpenSession) -> Duration {
+        let started = Instant::now();
+        self.insert(session_id, session);
+        started.elapsed()
+    }
+
+    fn end(&self, session_id: i64) -> Duration {
+        let started = Instant::now();
+        black_box(self.remove(&session_id));
+        started.elapsed()
+    }
+
+    fn release(&self, addr: SocketAddr) -> Duration {
+        let started = Instant::now();
+        self.retain(|_, session| session.addr != addr);
+        started.elapsed()
+    }
+}
+
+// Run every connection at once against a fresh registry, returning the time
+// they spent in it altogether. Each keeps one session open across the run so
+// the map isn't empty, and leaves it for its disconnect to release.
+fn lock_wait<R: Registry>() -> Duration {
+    let registry = R::new();
+    let barrier = Barrier::new(CONNECTIONS);
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..CONNECTIONS)
+            .map(|connection| {
+                let (registry, barrier) = (&registry, &barrier);
+                scope.spawn(move || {
+                    let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 10000 + connection as u16);
+                    let session = OpenSession { addr };
+                    let first = (connection * (SESSIONS_PER_CONNECTION + 1)) as i64;
+                    barrier.wait();
+                    let mut waited = registry.start(first, session);
+                    for i in 1..=SESSIONS_PER_CONNECTION as i64 {
+                        waited += registry.start(first + i, session);
+                        waited += registry.end(first + i);
+                    }
+                    waited + registry.release(addr)
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).sum()
+    })
+}
+
+// Reported as the lock-wait time of one run of every connection
+fn registries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("session_registry");
+    group.sample_size(20);
+    group.bench_function("mutex_hashmap", |b| {
+        b.iter_custom(|runs| (0..runs).map(|_| lock_wait::<Mutex<HashMap<i64, OpenSession>>>()).sum())
+    });
+    group.bench_function("dashmap", |b| {
+        b.iter_custom(|runs| (0..runs).map(|_| lock_wait::<DashMap<i64, OpenSession>>()).sum())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, registries);
+criterion_main!(benches);
@@ -0,0 +1,12 @@
+// Generates the gRPC service from proto/ingest.proto when the grpc feature is
+// enabled. protox compiles the proto in Rust, so no protoc is needed.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/ingest.proto");
+        let descriptors = protox::compile(["ingest.proto"], ["proto"])?;
+        tonic_build::configure().build_client(false).compile_fds(descriptors)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use dashmap::DashSet;
+use log::warn;
+
+use crate::peer::Peer;
+use crate::SensorData;
+
+// A threshold a session's readings can cross
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Alert {
+    LowBattery,
+    HighTemperature,
+    GeoFenceViolation,
+}
+
+// Battery and temperature thresholds from the command line. A session is
+// alerted about each threshold once; records without the field never alert.
+#[derive(Debug, Default)]
+pub struct TelemetryAlerts {
+    battery_below_v: Option<f64>,
+    temperature_above_c: Option<f64>,
+    // Sessions already alerted, records without a sessionID under None
+    raised: DashSet<(Option<i64>, Alert)>,
+}
+
+impl TelemetryAlerts {
+    pub fn new(battery_below_v: Option<f64>, temperature_above_c: Option<f64>) -> Self {
+        TelemetryAlerts { battery_below_v, temperature_above_c, raised: DashSet::new() }
+    }
+
+    // Log the first reading of a session past a threshold
+    pub fn check(&self, data: &SensorData, addr: Peer) {
+        let session = || match data.session_id {
+            Some(id) => format!("session {}", id),
+            None => "records without a session".to_string(),
+        };
+        if let (Some(threshold), Some(volts)) = (self.battery_below_v, data.battery_v) {
+            if volts < threshold && self.raised.insert((data.session_id, Alert::LowBattery)) {
+                warn!(
+                    "ALERT: battery low in {} from {}: {} V at {}, below {} V",
+                    session(), addr, volts, data.timestamp, threshold
+                );
+            }
+        }
+        if let (Some(threshold), Some(celsius)) = (self.temperature_above_c, data.temperature_c) {
+            if celsius > threshold && self.raised.insert((data.session_id, Alert::HighTemperature)) {
+                warn!(
+                    "ALERT: temperature high in {} from {}: {} °C at {}, above {} °C",
+                    session(), addr, celsius, data.timestamp, threshold
+                );
+            }
+        }
+    }
+
+    // Log the first reading of a session outside the geo fence
+    pub fn geo_fence_violation(&self, data: &SensorData, addr: Peer) {
+        if !self.raised.insert((data.session_id, Alert::GeoFenceViolation)) {
+            return;
+        }
+        warn!(
+            "ALERT: geo_fence_violation in {} from {}: position {}, {} at {} is outside the geo fence",
+            data.session_id.map_or("records without a session".to_string(), |id| format!("session {}", id)),
+            addr,
+            data.latitude.unwrap_or_default(),
+            data.longitude.unwrap_or_default(),
+            data.timestamp
+        );
+    }
+
+    // An ended session's alerts are no longer tracked
+    pub fn forget(&self, session_id: i64) {
+        self.raised.retain(|(session, _)| *session != Some(session_id));
+    }
+}
@@ -0,0 +1,81 @@
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+
+use crate::timestamp::ClientTimestamp;
+
+// A labelled stretch of a session, such as a braking test, added over HTTP
+// after the fact
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Annotation {
+    // Assigned when it is stored; one sent with it is ignored
+    #[serde(skip_deserializing)]
+    pub id: i64,
+    pub session_id: i64,
+    pub start_ts: String,
+    pub end_ts: String,
+    pub label: String,
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+
+// The labels of the annotations covering a sensor_data row, as a JSON array
+// in start order, for selecting next to its columns
+pub const LABELS_COLUMN: &str = "(SELECT json_group_array(label) FROM (
+        SELECT label FROM annotations
+        WHERE annotations.session_id = sensor_data.sessionID
+          AND julianday(sensor_data.timestamp) BETWEEN julianday(annotations.start_ts) AND julianday(annotations.end_ts)
+        ORDER BY julianday(annotations.start_ts), annotations.id
+    )) AS annotations";
+
+impl Annotation {
+    // Times must be ISO 8601, as record timestamps are, so they compare with
+    // them, and the range must not run backwards
+    pub fn check(&self) -> Result<(), String> {
+        let time = |name: &str, text: &str| {
+            ClientTimestamp::parse(text)
+                .map(|time| time.to_utc())
+                .ok_or_else(|| format!("{} '{}' is not an ISO 8601 time", name, text))
+        };
+        if time("start_ts", &self.start_ts)? > time("end_ts", &self.end_ts)? {
+            return Err("end_ts must not be before start_ts".to_string());
+        }
+        if self.label.trim().is_empty() {
+            return Err("label must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Store an annotation, filling in its ID
+pub fn insert(conn: &Connection, annotation: &mut Annotation) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO annotations (session_id, start_ts, end_ts, label, notes) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![annotation.session_id, annotation.start_ts, annotation.end_ts, annotation.label, annotation.notes],
+    )?;
+    annotation.id = conn.last_insert_rowid();
+    Ok(())
+}
+
+// A session's annotations in the order they start
+pub fn get_annotations(conn: &Connection, session_id: i64) -> Result<Vec<Annotation>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, session_id, start_ts, end_ts, label, notes FROM annotations
+         WHERE session_id = ? ORDER BY julianday(start_ts), id",
+    )?;
+    let rows = stmt.query_map([session_id], |row| {
+        Ok(Annotation {
+            id: row.get(0)?,
+            session_id: row.get(1)?,
+            start_ts: row.get(2)?,
+            end_ts: row.get(3)?,
+            label: row.get(4)?,
+            notes: row.get(5)?,
+        })
+    })?;
+    rows.collect()
+}
+
+// Whether there was an annotation with this ID to delete
+pub fn delete(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    Ok(conn.execute("DELETE FROM annotations WHERE id = ?", [id])? > 0)
+}
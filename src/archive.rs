@@ -0,0 +1,60 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use log::info;
+use serde::Serialize;
+
+use crate::rotation::RotatingWriter;
+use crate::SensorData;
+
+// Buffered archive writes reach the file at least this often
+pub const ARCHIVE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// One archived line: the record as accepted plus the connection's device
+#[derive(Serialize)]
+struct ArchivedRecord<'a> {
+    #[serde(flatten)]
+    data: &'a SensorData,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<&'a str>,
+}
+
+// Append-only JSONL copy of every accepted record, independent of the
+// database. The file is rotated at midnight UTC and when it grows past the
+// configured size; see `RotatingWriter` for naming and retention.
+pub struct Archive {
+    writer: RotatingWriter,
+    last_flush: Instant,
+}
+
+impl Archive {
+    pub fn open(path: PathBuf, max_bytes: Option<u64>, keep: usize) -> io::Result<Self> {
+        Ok(Archive {
+            writer: RotatingWriter::open(path, max_bytes, keep)?,
+            last_flush: Instant::now(),
+        })
+    }
+
+    pub fn append(&mut self, data: &SensorData, device_id: Option<&str>) -> io::Result<()> {
+        let mut line = serde_json::to_string(&ArchivedRecord { data, device_id })?;
+        line.push('\n');
+        let written = self.writer.write_all(line.as_bytes());
+        for event in self.writer.take_events() {
+            info!("Archive: {}", event);
+        }
+        written?;
+        self.flush_if_due()
+    }
+
+    pub fn flush_if_due(&mut self) -> io::Result<()> {
+        if self.last_flush.elapsed() >= ARCHIVE_FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.writer.flush()
+    }
+}
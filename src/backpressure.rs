@@ -0,0 +1,45 @@
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+// Shortest gap between two backpressure notices on one connection
+const NOTICE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Sent when records from this connection were dropped because the database
+// writer's queue was full, so the firmware can slow down
+#[derive(Serialize, Debug)]
+pub struct BackpressureNotice {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    // Records dropped since the previous notice
+    drop_count: u64,
+    queue_full: bool,
+}
+
+// Collects a connection's dropped records and lets a notice about them out
+// at most once per second
+#[derive(Debug, Default)]
+pub struct BackpressureNotifier {
+    dropped: u64,
+    last_notice: Option<Instant>,
+}
+
+impl BackpressureNotifier {
+    // Count dropped records, returning a notice if one may go out now
+    pub fn dropped(&mut self, count: u64) -> Option<BackpressureNotice> {
+        self.dropped += count;
+        self.due()
+    }
+
+    // A notice for drops held back by the rate limit, once it may be sent
+    pub fn due(&mut self) -> Option<BackpressureNotice> {
+        if self.dropped == 0 || self.last_notice.is_some_and(|last| last.elapsed() < NOTICE_INTERVAL) {
+            return None;
+        }
+        self.last_notice = Some(Instant::now());
+        Some(BackpressureNotice {
+            message_type: "backpressure",
+            drop_count: std::mem::take(&mut self.dropped),
+            queue_full: true,
+        })
+    }
+}
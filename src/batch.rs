@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::{debug, error, warn};
+use rusqlite::{params, Connection};
+
+use crate::breaker::{self, CircuitBreaker};
+use crate::database::Database;
+use crate::encryption::GpsCipher;
+use crate::events::{self, EventRule};
+use serde::{Deserialize, Serialize};
+
+use crate::fallback::FallbackStore;
+use crate::metrics::Metrics;
+use crate::stats::{self, LiveStats};
+use crate::subscribe::Subscribers;
+use crate::sqlite::{self, OPTIMIZE_INTERVAL_ROWS};
+use crate::wal::Wal;
+use crate::webhook::EventAlerts;
+use crate::{insert_sensor_data, SensorData, ServerState};
+
+// A parsed record waiting for the next commit, with the connection state it
+// must be stored with
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingRecord {
+    #[serde(flatten)]
+    pub data: SensorData,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    // Row of the connection it came in on, if it was recorded in `connections`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_id: Option<i64>,
+}
+
+// Records of one client connection committed to the database so far, per
+// session, for upload checks scoped to the connection, and how many of them
+// were duplicates of rows already stored
+#[derive(Debug, Default)]
+pub struct Tally {
+    pub sessions: HashMap<String, u64>,
+    pub duplicates: u64,
+}
+
+pub type SessionTally = Mutex<Tally>;
+
+impl PendingRecord {
+    // Name under which per-session files (WAL, fallback) store this record
+    pub fn session_key(&self) -> String {
+        match self.data.session_id {
+            Some(id) => id.to_string(),
+            None => "nosession".to_string(),
+        }
+    }
+}
+
+// Buffers the records of every client and inserts them in a single
+// transaction, either when the batch is full or when the flush interval has
+// passed. It runs on the writer thread; dropping it commits whatever is still
+// buffered. Records are logged to the WAL before they are queued and marked
+// there once they have been stored.
+//
+// With a fallback store, a batch the database can't take for a reason other
+// than a busy lock is written there instead of being retried.
+pub struct BatchWriter<'a> {
+    db: Database,
+    fallback: Option<&'a FallbackStore>,
+    wal: &'a Wal,
+    metrics: &'a Metrics,
+    subscribers: &'a Subscribers,
+    live_stats: &'a LiveStats,
+    event_rules: &'a [EventRule],
+    event_alerts: Option<&'a EventAlerts>,
+    gps_cipher: Option<&'a GpsCipher>,
+    breaker: &'a CircuitBreaker,
+    dedup_timestamps: bool,
+    pending: Vec<PendingRecord>,
+    // Tally of the connection each pending record came from
+    owners: Vec<Arc<SessionTally>>,
+    batch_size: usize,
+    // Records the buffer may hold before the writer stops taking more
+    capacity: usize,
+    // The last commit failed, so only the flush interval retries it until the
+    // buffer fills up, rather than every push
+    retrying: bool,
+    flush_interval: Duration,
+    last_flush: Instant,
+}
+
+impl<'a> BatchWriter<'a> {
+    pub fn new(db: Database, server: &'a ServerState) -> Self {
+        let batch_size = server.config.batch_size.max(1);
+        BatchWriter {
+            db,
+            fallback: server.fallback.as_ref(),
+            wal: &server.wal,
+            metrics: &server.metrics,
+            subscribers: &server.subscribers,
+            live_stats: &server.live_stats,
+            event_rules: &server.config.events.rules,
+            event_alerts: server.event_alerts.as_ref(),
+            gps_cipher: server.gps_cipher.as_ref(),
+            breaker: &server.breaker,
+            dedup_timestamps: server.config.dedup_timestamps,
+            pending: Vec::with_capacity(batch_size),
+            owners: Vec::with_capacity(batch_size),
+            batch_size,
+            capacity: server.config.max_pending_records.max(batch_size),
+            retrying: false,
+            flush_interval: Duration::from_millis(server.config.batch_flush_ms),
+            last_flush: Instant::now(),
+        }
+    }
+
+    // Buffer records that belong together, such as the rows expanded from one
+    // sample block, committing the batch if it is now full (unless a failed
+    // commit is waiting for the flush interval to retry). Rows pushed
+    // together are committed in the same transaction even when there are more
+    // of them than the batch size. Returns the number of records committed to
+    // the database (0 if they were only buffered or the batch went to the
+    // fallback store).
+    pub fn push_all(&mut self, records: Vec<PendingRecord>, owner: Arc<SessionTally>) -> rusqlite::Result<usize> {
+        for record in records {
+            self.pending.push(record);
+            self.owners.push(owner.clone());
+        }
+        if self.pending.len() >= self.batch_size && !self.retrying {
+            self.flush_all()
+        } else {
+            Ok(0)
+        }
+    }
+
+    // The connection, for database work other than inserting records. It
+    // may be a new one after each flush.
+    pub fn conn(&self) -> &Connection {
+        self.db.conn()
+    }
+
+    // Whether the buffer holds as many records as it may, so reading more
+    // should wait until a commit gets through
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= self.capacity
+    }
+
+    // How long until the buffered records are due to be committed
+    pub fn until_due(&self) -> Duration {
+        if self.pending.is_empty() {
+            self.flush_interval
+        } else {
+            self.flush_interval.saturating_sub(self.last_flush.elapsed())
+        }
+    }
+
+    // Commit the buffered records if the flush interval has elapsed
+    pub fn flush_if_due(&mut self) -> rusqlite::Result<usize> {
+        if !self.pending.is_empty() && self.last_flush.elapsed() >= self.flush_interval {
+            self.flush_all()
+        } else {
+            Ok(0)
+        }
+    }
+
+    // Insert every buffered record in one transaction. On failure nothing is
+    // committed, and the records either move to the fallback store or stay
+    // buffered for the next attempt.
+    pub fn flush_all(&mut self) -> rusqlite::Result<usize> {
+        let result = self.try_flush();
+        // Counted from the end of the attempt, so a commit that waited out the
+        // busy timeout isn't retried before any more lines are read
+        self.last_flush = Instant::now();
+        self.retrying = result.is_err();
+        result
+    }
+
+    // Try the database with a trivial write while the circuit breaker is
+    // open, closing the breaker if it gets through. Buffered records are
+    // left for the next flush.
+    pub fn probe(&mut self) {
+        self.db.check_file();
+        match breaker::probe(self.db.conn()) {
+            Ok(()) => {
+                self.db.write_succeeded();
+                self.breaker.succeeded();
+            }
+            Err(e) => {
+                self.db.write_failed(&e);
+                self.breaker.probe_failed(&e);
+            }
+        }
+    }
+}
+
+impl BatchWriter<'_> {
+    fn try_flush(&mut self) -> rusqlite::Result<usize> {
+        // Also run ahead of other database work, which flushes first
+        self.db.check_file();
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+
+        let e = match commit(self.db.conn(), &self.pending, self.gps_cipher, self.dedup_timestamps) {
+            Ok(row_ids) => {
+                self.db.write_succeeded();
+                self.breaker.succeeded();
+                let committed = self.pending.len();
+                let duplicates = row_ids.iter().filter(|id| id.is_none()).count();
+                if duplicates > 0 {
+                    let same_timestamp = self.count_timestamp_duplicates(&row_ids);
+                    let same_message_id = duplicates - same_timestamp;
+                    if same_message_id > 0 {
+                        debug!("Skipped {} record(s) whose message_id was already stored", same_message_id);
+                        self.metrics.duplicate_messages_skipped.fetch_add(same_message_id as u64, Ordering::Relaxed);
+                    }
+                    if same_timestamp > 0 {
+                        debug!("Skipped {} record(s) whose session already had a row at their timestamp", same_timestamp);
+                        self.metrics.timestamp_duplicates_skipped.fetch_add(same_timestamp as u64, Ordering::Relaxed);
+                    }
+                }
+                // Whichever connection carries the total past the next multiple optimizes
+                let inserted = (committed - duplicates) as u64;
+                let before = self.metrics.rows_inserted.fetch_add(inserted, Ordering::Relaxed);
+                if before / OPTIMIZE_INTERVAL_ROWS != (before + inserted) / OPTIMIZE_INTERVAL_ROWS {
+                    sqlite::optimize(self.db.conn());
+                }
+                self.subscribers.publish(&self.pending, &row_ids, self.metrics);
+                stats::update(self.db.conn(), self.live_stats, &self.pending, &row_ids, self.gps_cipher.is_some());
+                self.record_events(&row_ids);
+                self.mark_stored(Some(&row_ids));
+                return Ok(committed);
+            }
+            // Another writer holds the lock; the next flush will likely succeed
+            Err(e) if is_busy(&e) => return Err(e),
+            Err(e) => {
+                self.db.write_failed(&e);
+                e
+            }
+        };
+
+        // Records the fallback store takes aren't lost, so only a batch
+        // that stays buffered counts towards opening the breaker
+        let Some(fallback) = self.fallback else {
+            self.breaker.failed(&e);
+            return Err(e);
+        };
+        match fallback.write_records(&self.pending) {
+            Ok(()) => {
+                warn!(
+                    "Database unavailable ({}); wrote {} record(s) to {}",
+                    e,
+                    self.pending.len(),
+                    fallback.dir().display()
+                );
+                self.mark_stored(None);
+                Ok(0)
+            }
+            Err(fe) => {
+                error!("Failed to write records to fallback files: {}", fe);
+                self.breaker.failed(&e);
+                Err(e)
+            }
+        }
+    }
+
+    // Store the events the committed rows trigger and pass them on to the
+    // webhook. The rows are committed either way, so a failure is only logged.
+    fn record_events(&self, row_ids: &[Option<i64>]) {
+        match events::record(self.db.conn(), self.event_rules, &self.pending, row_ids, self.gps_cipher.is_some()) {
+            Ok(events) if events.is_empty() => {}
+            Ok(events) => {
+                debug!("Recorded {} event(s)", events.len());
+                self.metrics.events_recorded.fetch_add(events.len() as u64, Ordering::Relaxed);
+                if let Some(alerts) = self.event_alerts {
+                    alerts.record(events);
+                }
+            }
+            Err(e) => error!("Failed to store events: {}", e),
+        }
+    }
+
+    // Of the pending records the commit skipped, those that weren't skipped
+    // for their message_id, so for their session and timestamp. A record
+    // that repeats both counts as a message_id duplicate.
+    fn count_timestamp_duplicates(&self, row_ids: &[Option<i64>]) -> usize {
+        if !self.dedup_timestamps {
+            return 0;
+        }
+        self.pending
+            .iter()
+            .zip(row_ids)
+            .filter(|(record, row_id)| row_id.is_none() && !message_id_stored(self.db.conn(), &record.data))
+            .count()
+    }
+
+    // Clear the buffer after its records were stored, telling the WAL which
+    // sessions they belonged to and, if they went to the database, their rows
+    // (None for a duplicate that was skipped) and their connections' tallies
+    fn mark_stored(&mut self, row_ids: Option<&[Option<i64>]>) {
+        self.metrics.records_settled.fetch_add(self.pending.len() as u64, Ordering::Relaxed);
+        let mut sessions: HashMap<String, (usize, Option<i64>)> = HashMap::new();
+        for (i, (record, owner)) in self.pending.drain(..).zip(self.owners.drain(..)).enumerate() {
+            let key = record.session_key();
+            if let Some(ids) = row_ids {
+                let mut tally = owner.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                // Like count_stored, only what the client sent
+                if !record.data.is_interpolated {
+                    *tally.sessions.entry(key.clone()).or_default() += 1;
+                }
+                if ids[i].is_none() {
+                    tally.duplicates += 1;
+                }
+            }
+            let session = sessions.entry(key).or_default();
+            session.0 += 1;
+            if let Some(row_id) = row_ids.and_then(|ids| ids[i]) {
+                session.1 = Some(row_id);
+            }
+        }
+        for (session, (count, row_id)) in sessions {
+            if let Err(e) = self.wal.commit(&session, count, row_id) {
+                warn!("Failed to write WAL commit for session {}: {}", session, e);
+            }
+        }
+    }
+}
+
+// Insert the records in one transaction, returning their row IDs in order
+fn commit(
+    conn: &Connection,
+    records: &[PendingRecord],
+    gps_cipher: Option<&GpsCipher>,
+    dedup_timestamps: bool,
+) -> rusqlite::Result<Vec<Option<i64>>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut row_ids = Vec::with_capacity(records.len());
+    for record in records {
+        row_ids.push(insert_sensor_data(&tx, &record.data, record.device_id.as_deref(), record.connection_id, gps_cipher, dedup_timestamps)?);
+    }
+    tx.commit()?;
+    Ok(row_ids)
+}
+
+// Whether a row with the record's message_id is stored. If that can't be
+// told, it is assumed to be, as it was before timestamps could clash.
+fn message_id_stored(conn: &Connection, data: &SensorData) -> bool {
+    let Some(message_id) = &data.message_id else {
+        return false;
+    };
+    conn.query_row("SELECT EXISTS (SELECT 1 FROM sensor_data WHERE message_id = ?)", [message_id], |row| row.get(0))
+        .unwrap_or(true)
+}
+
+// Errors that clear up on their own once another connection's write finishes
+fn is_busy(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+    )
+}
+
+impl Drop for BatchWriter<'_> {
+    fn drop(&mut self) {
+        let Err(e) = self.flush_all() else {
+            return;
+        };
+        let conn = self.db.conn();
+        error!(
+            "Final flush of {} buffered record(s) failed: {}; moving them to dead_letters",
+            self.pending.len(),
+            e
+        );
+        // Dead-lettered records are settled; any others stay in the WAL
+        let mut settled: HashMap<String, usize> = HashMap::new();
+        self.owners.clear();
+        for record in self.pending.drain(..) {
+            let payload = serde_json::to_string(&record.data).unwrap_or_default();
+            match insert_dead_letter(conn, &payload, "flush_failed", &e.to_string()) {
+                Ok(()) => {
+                    self.metrics.records_settled.fetch_add(1, Ordering::Relaxed);
+                    *settled.entry(record.session_key()).or_default() += 1;
+                }
+                Err(dl) => error!("Could not write dead letter, record left in the WAL: {} ({})", payload, dl),
+            }
+        }
+        for (session, count) in settled {
+            if let Err(e) = self.wal.commit(&session, count, None) {
+                warn!("Failed to write WAL commit for session {}: {}", session, e);
+            }
+        }
+    }
+}
+
+// Keep a record that could not be stored normally, with the reason, so it
+// can be inspected and recovered later
+pub fn insert_dead_letter(
+    conn: &Connection,
+    payload: &str,
+    error_type: &str,
+    error: &str,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO dead_letters (received_at, error_type, error, payload) VALUES (?, ?, ?, ?)",
+        params![
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            error_type,
+            error,
+            payload
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::schema::ensure_schema;
+
+    // Records still buffered when the writer loop dies, here by panicking,
+    // are committed as the batch is dropped
+    #[test]
+    fn buffered_records_survive_the_loop_dying() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let server = ServerState::new(Config::for_test(dir.path(), &["--batch-size", "100"])).unwrap();
+        let conn = sqlite::open(&path, &server.config.sqlite).unwrap();
+        ensure_schema(&conn, false).unwrap();
+        let db = Database::new(conn, &path, server.config.sqlite.clone(), false, server.metrics.clone());
+        let tally = Arc::new(SessionTally::default());
+
+        let killed = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut batch = BatchWriter::new(db, &server);
+            for second in 0..3 {
+                let data = serde_json::from_value(json!({"sessionID": 1, "timestamp": format!("2024-01-01T00:00:0{}Z", second)}))
+                    .unwrap();
+                assert_eq!(batch.push_all(vec![PendingRecord { data, device_id: None, connection_id: None }], tally.clone()).unwrap(), 0);
+            }
+            assert_eq!(batch.pending.len(), 3);
+            panic!("writer loop killed");
+        }));
+        assert!(killed.is_err());
+
+        let conn = sqlite::open(&path, &server.config.sqlite).unwrap();
+        let rows: i64 = conn.query_row("SELECT COUNT(*) FROM sensor_data", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 3);
+    }
+}
@@ -0,0 +1,196 @@
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use clap::Args;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::SensorData;
+
+// How long a client waits for the server to answer its upload checks
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Options of the `bench` subcommand
+#[derive(Args, Debug, Clone)]
+pub struct BenchArgs {
+    /// Address of the running server to load
+    #[arg(long, default_value = "127.0.0.1:9000")]
+    pub address: String,
+
+    /// Total records to send, split evenly between the clients
+    #[arg(long, value_name = "N", default_value_t = 10000)]
+    pub records: usize,
+
+    /// Simulated clients, each on its own connection
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    pub clients: usize,
+
+    /// Sessions each client spreads its records across, round-robin
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub sessions: usize,
+
+    /// sessionID of the first synthetic session; keep it clear of real data
+    #[arg(long, value_name = "ID", default_value_t = 900000)]
+    pub first_session: i64,
+}
+
+// Totals across all clients
+#[derive(Debug, Default)]
+pub struct BenchReport {
+    pub sent: usize,
+    pub stored: i64,
+    pub rejected: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchReport {
+    pub fn rows_per_sec(&self) -> f64 {
+        self.stored as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+// Replies the benchmark cares about: rejections and upload checks
+#[derive(Deserialize)]
+struct Reply {
+    #[serde(rename = "type")]
+    message_type: String,
+    #[serde(default)]
+    suppressed: u64,
+    #[serde(default)]
+    stored: i64,
+}
+
+// Stream synthetic records to a running server from several connections at
+// once. Each client finishes with an upload_complete per session, so the
+// elapsed time covers storing the records, not just sending them.
+pub fn run(args: &BenchArgs) -> Result<BenchReport, Box<dyn Error>> {
+    let clients = args.clients.max(1);
+    let started = Instant::now();
+
+    let handles: Vec<_> = (0..clients)
+        .map(|client| {
+            let args = args.clone();
+            // The first clients take the remainder when records don't divide evenly
+            let count = args.records / clients + usize::from(client < args.records % clients);
+            thread::spawn(move || run_client(&args, client, count))
+        })
+        .collect();
+
+    let mut report = BenchReport::default();
+    for handle in handles {
+        let client = handle.join().map_err(|_| "bench client panicked")??;
+        report.sent += client.sent;
+        report.stored += client.stored;
+        report.rejected += client.rejected;
+    }
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+fn run_client(args: &BenchArgs, client: usize, count: usize) -> io::Result<BenchReport> {
+    let stream = TcpStream::connect(&args.address)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let sessions = args.sessions.max(1).min(count.max(1));
+
+    send_line(&mut writer, &json!({
+        "type": "hello",
+        "device_id": format!("bench-{}", client),
+        "error_replies": true,
+    }))?;
+
+    let mut per_session = vec![0i64; sessions];
+    let base = chrono::Utc::now();
+    for i in 0..count {
+        let session = i % sessions;
+        per_session[session] += 1;
+        send_line(&mut writer, &synthetic_record(args.first_session + session as i64, base, client, i))?;
+    }
+    for (session, expected) in per_session.iter().enumerate() {
+        send_line(&mut writer, &json!({
+            "type": "upload_complete",
+            "sessionID": args.first_session + session as i64,
+            "expected_count": expected,
+            "scope": "connection",
+        }))?;
+    }
+    writer.flush()?;
+
+    // Read until every upload check is answered, counting rejections on the way
+    let mut report = BenchReport { sent: count, ..BenchReport::default() };
+    let mut pending = sessions;
+    for line in BufReader::new(stream).lines() {
+        let Ok(reply) = serde_json::from_str::<Reply>(&line?) else {
+            continue;
+        };
+        match reply.message_type.as_str() {
+            "error" => report.rejected += 1 + reply.suppressed,
+            "upload_status" => {
+                report.stored += reply.stored;
+                pending -= 1;
+                if pending == 0 {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    if pending > 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed before answering upload checks"));
+    }
+    Ok(report)
+}
+
+fn send_line<T: serde::Serialize>(writer: &mut impl Write, message: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.write_all(b"\n")
+}
+
+// A plausible record that differs from its neighbours, so deduplicated counts
+// are not thrown off
+fn synthetic_record(session_id: i64, base: chrono::DateTime<chrono::Utc>, client: usize, i: usize) -> SensorData {
+    let t = i as f64 * 0.01;
+    SensorData {
+        session_id: Some(session_id),
+        timestamp: (base + chrono::Duration::milliseconds(i as i64 * 10))
+            .format("%Y-%m-%dT%H:%M:%S%.3f")
+            .to_string(),
+        latitude: Some(45.0 + t * 1e-5),
+        longitude: Some(-122.0 + client as f64 * 1e-3),
+        altitude: Some(100.0),
+        accel_x: Some(t.sin()),
+        accel_y: Some(t.cos()),
+        accel_z: Some(9.81),
+        gyro_x: Some(0.0),
+        gyro_y: Some(0.0),
+        gyro_z: Some(0.0),
+        dac_1: Some(0.0),
+        dac_2: Some(0.0),
+        dac_3: Some(0.0),
+        dac_4: Some(0.0),
+        mag_x: None,
+        mag_y: None,
+        mag_z: None,
+        fix_quality: None,
+        num_satellites: None,
+        hdop: None,
+        temperature_c: None,
+        battery_v: None,
+        dac: None,
+        gps_low_quality: false,
+        outside_fence: false,
+        is_outlier: false,
+        is_interpolated: false,
+        accel_filtered: None,
+        cumulative_angles: None,
+        cumulative_distance_m: None,
+        tilt: None,
+        imu_raw: None,
+        dac_raw: None,
+        position_raw: None,
+        message_id: None,
+        extras: serde_json::Map::new(),
+    }
+}
@@ -0,0 +1,173 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use rusqlite::{ffi, Connection};
+use serde::Serialize;
+
+use crate::metrics::Metrics;
+
+// Shortest gap between two backoff notices on one connection
+const NOTICE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Stops ingest while the database keeps failing, e.g. when its disk is full
+// or has gone read-only. After `threshold` consecutive commits that stored
+// nothing the breaker opens: new connections are refused and clients are
+// told to back off instead of having their records queued. The writer then
+// leaves the database alone apart from a trivial write every probe
+// interval, and closes the breaker once one succeeds.
+pub struct CircuitBreaker {
+    // Failed commits that open the breaker; 0 never opens it
+    threshold: u32,
+    probe_interval: Duration,
+    open: AtomicBool,
+    // Only the writer thread changes these
+    state: Mutex<BreakerState>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    opened_at: Option<Instant>,
+    next_probe: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, probe_interval: Duration, metrics: Arc<Metrics>) -> Self {
+        CircuitBreaker {
+            threshold,
+            probe_interval,
+            open: AtomicBool::new(false),
+            state: Mutex::new(BreakerState::default()),
+            metrics,
+        }
+    }
+
+    // Whether ingest is paused
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    // How long clients are told to wait before sending again
+    pub fn retry_after(&self) -> Duration {
+        self.probe_interval
+    }
+
+    fn state(&self) -> MutexGuard<'_, BreakerState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // A commit stored nothing; opens the breaker once enough have in a row
+    pub fn failed(&self, e: &rusqlite::Error) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state();
+        state.failures = state.failures.saturating_add(1);
+        if self.is_open() || state.failures < self.threshold {
+            return;
+        }
+        warn!(
+            "Circuit breaker opened after {} consecutive failed commits (last: {}); pausing ingest and probing the database every {}s",
+            state.failures,
+            e,
+            self.probe_interval.as_secs()
+        );
+        let now = Instant::now();
+        state.opened_at = Some(now);
+        state.next_probe = Some(now + self.probe_interval);
+        self.open.store(true, Ordering::Relaxed);
+        self.metrics.breaker_opens.fetch_add(1, Ordering::Relaxed);
+        self.metrics.breaker_open.store(1, Ordering::Relaxed);
+    }
+
+    // A commit or probe got through; closes the breaker if it was open
+    pub fn succeeded(&self) {
+        let mut state = self.state();
+        state.failures = 0;
+        if !self.is_open() {
+            return;
+        }
+        let paused = state.opened_at.take().map(|at| at.elapsed().as_secs()).unwrap_or_default();
+        state.next_probe = None;
+        self.open.store(false, Ordering::Relaxed);
+        self.metrics.breaker_open.store(0, Ordering::Relaxed);
+        info!("Circuit breaker closed: the database took a write again after {}s; resuming ingest", paused);
+    }
+
+    // While open, how long until the next probe is due (zero if it is)
+    pub fn until_probe(&self) -> Option<Duration> {
+        let state = self.state();
+        state.next_probe.map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    // The probe failed; try again after another interval
+    pub fn probe_failed(&self, e: &rusqlite::Error) {
+        debug!("Database probe failed: {}; circuit breaker stays open", e);
+        self.state().next_probe = Some(Instant::now() + self.probe_interval);
+    }
+}
+
+// A write that changes nothing but still has to reach the disk: setting the
+// database header's user version to what it already is
+pub fn probe(conn: &Connection) -> rusqlite::Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    conn.execute_batch(&format!("PRAGMA user_version = {}", version))
+}
+
+// Returned for a flush asked for while ingest is paused
+pub fn paused() -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(
+        ffi::Error::new(ffi::SQLITE_IOERR),
+        Some("database unavailable; ingest is paused until it takes writes again".to_string()),
+    )
+}
+
+// Sent instead of storing records while the breaker is open, and to a new
+// connection before it is closed, so the client waits before sending again
+#[derive(Serialize, Debug)]
+pub struct BackoffNotice {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    reason: &'static str,
+    retry_after_ms: u64,
+    // Records refused since the previous notice
+    drop_count: u64,
+}
+
+impl BackoffNotice {
+    pub fn new(retry_after: Duration, drop_count: u64) -> Self {
+        BackoffNotice {
+            message_type: "backoff",
+            reason: "database_unavailable",
+            retry_after_ms: retry_after.as_millis() as u64,
+            drop_count,
+        }
+    }
+}
+
+// Collects a connection's refused records and lets a notice about them out
+// at most once per second
+#[derive(Debug, Default)]
+pub struct BackoffNotifier {
+    refused: u64,
+    last_notice: Option<Instant>,
+}
+
+impl BackoffNotifier {
+    // Count refused records, returning a notice if one may go out now
+    pub fn refused(&mut self, count: u64, retry_after: Duration) -> Option<BackoffNotice> {
+        self.refused += count;
+        self.due(retry_after)
+    }
+
+    // A notice for refusals held back by the rate limit, once it may be sent
+    pub fn due(&mut self, retry_after: Duration) -> Option<BackoffNotice> {
+        if self.refused == 0 || self.last_notice.is_some_and(|last| last.elapsed() < NOTICE_INTERVAL) {
+            return None;
+        }
+        self.last_notice = Some(Instant::now());
+        Some(BackoffNotice::new(retry_after, std::mem::take(&mut self.refused)))
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt;
+use clap::Args;
+
+use crate::{SensorData, DAC_COLUMNS, MAX_DAC_CHANNELS};
+
+// Fields a calibration may correct
+const IMU_FIELDS: [&str; 6] = ["accel_x", "accel_y", "accel_z", "gyro_x", "gyro_y", "gyro_z"];
+const POSITION_FIELDS: [&str; 3] = ["latitude", "longitude", "altitude"];
+
+// Per-unit corrections of a logger's sensors, from its bench calibration.
+// None are set by default.
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "Calibration")]
+pub struct CalibrationConfig {
+    /// Correct a field as (received - OFFSET) * SCALE, e.g. accel_x=0.12:1.003; accel, gyro and dac stand for all their channels, and a later correction of a field replaces an earlier one. Repeatable
+    #[arg(long = "calibrate", value_name = "FIELD=OFFSET:SCALE", value_parser = parse_calibration)]
+    pub calibrations: Vec<Calibration>,
+
+    /// Apply the --calibrate corrections, keeping the values as received in *_raw columns
+    #[arg(long)]
+    pub enable_calibration: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    fields: Vec<&'static str>,
+    offset: f64,
+    scale: f64,
+}
+
+fn parse_calibration(value: &str) -> Result<Calibration, String> {
+    let (name, correction) = value.split_once('=').ok_or("expected FIELD=OFFSET:SCALE")?;
+    let fields = match name {
+        "accel" => IMU_FIELDS[..3].to_vec(),
+        "gyro" => IMU_FIELDS[3..].to_vec(),
+        "dac" => DAC_COLUMNS.to_vec(),
+        _ => vec![IMU_FIELDS
+            .iter()
+            .chain(&POSITION_FIELDS)
+            .chain(&DAC_COLUMNS)
+            .find(|field| **field == name)
+            .copied()
+            .ok_or_else(|| {
+                format!("can't calibrate '{}'; use an accel, gyro or dac channel, latitude, longitude or altitude", name)
+            })?],
+    };
+    let (offset, scale) = correction.split_once(':').ok_or("expected FIELD=OFFSET:SCALE")?;
+    let number = |text: &str| match text.trim().parse::<f64>() {
+        Ok(n) if n.is_finite() => Ok(n),
+        _ => Err(format!("'{}' is not a finite number", text)),
+    };
+    let (offset, scale) = (number(offset)?, number(scale)?);
+    // A zero scale would store the same value whatever was measured
+    if scale == 0.0 {
+        return Err("scale must not be 0".to_string());
+    }
+    Ok(Calibration { fields, offset, scale })
+}
+
+impl CalibrationConfig {
+    // The correction in effect for a field: the last one given for it
+    fn correction(&self, field: &str) -> Option<(f64, f64)> {
+        self.calibrations
+            .iter()
+            .rev()
+            .find(|calibration| calibration.fields.contains(&field))
+            .map(|calibration| (calibration.offset, calibration.scale))
+    }
+}
+
+// The calibrations as they appear in the effective configuration line
+impl fmt::Display for CalibrationConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.enable_calibration || self.calibrations.is_empty() {
+            return write!(f, "off");
+        }
+        let corrections: Vec<String> = IMU_FIELDS
+            .iter()
+            .chain(&POSITION_FIELDS)
+            .chain(&DAC_COLUMNS)
+            .filter_map(|field| self.correction(field).map(|(offset, scale)| format!("{}={}:{}", field, offset, scale)))
+            .collect();
+        write!(f, "{}", corrections.join(","))
+    }
+}
+
+// Replace each calibrated field with (value - offset) * scale. The values as
+// received are kept in `imu_raw`, `position_raw` and `dac_raw` for every
+// group with a calibrated field, so the filters after this don't overwrite
+// them with values they already changed.
+pub fn apply_calibration(data: &mut SensorData, cal: &CalibrationConfig) {
+    if !cal.enable_calibration || cal.calibrations.is_empty() {
+        return;
+    }
+    let correct = |field: &str, value: &mut Option<f64>| match (cal.correction(field), *value) {
+        (Some((offset, scale)), Some(v)) => {
+            *value = Some((v - offset) * scale);
+            true
+        }
+        _ => false,
+    };
+
+    let imu = [
+        &mut data.accel_x,
+        &mut data.accel_y,
+        &mut data.accel_z,
+        &mut data.gyro_x,
+        &mut data.gyro_y,
+        &mut data.gyro_z,
+    ];
+    let raw: [Option<f64>; 6] = std::array::from_fn(|i| *imu[i]);
+    let mut corrected = false;
+    for (field, value) in IMU_FIELDS.iter().zip(imu) {
+        corrected |= correct(field, value);
+    }
+    if corrected {
+        data.imu_raw = Some(raw);
+    }
+
+    let position = [&mut data.latitude, &mut data.longitude, &mut data.altitude];
+    let raw: [Option<f64>; 3] = std::array::from_fn(|i| *position[i]);
+    let mut corrected = false;
+    for (field, value) in POSITION_FIELDS.iter().zip(position) {
+        corrected |= correct(field, value);
+    }
+    if corrected {
+        data.position_raw = Some(raw);
+    }
+
+    let mut raw = [None; MAX_DAC_CHANNELS];
+    let mut corrected = false;
+    for (channel, value) in data.dac_channels_mut().into_iter().enumerate() {
+        raw[channel] = *value;
+        corrected |= correct(DAC_COLUMNS[channel], value);
+    }
+    if corrected {
+        data.dac_raw = Some(raw);
+    }
+}
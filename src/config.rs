@@ -0,0 +1,347 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+
+use crate::bench::BenchArgs;
+use crate::calibration::CalibrationConfig;
+use crate::events::EventConfig;
+use crate::dac::DacRanges;
+use crate::export::ExportArgs;
+use crate::geo_fence::GeoFence;
+use crate::integrity::IntegrityCheck;
+use crate::listener::{self, ListenerSpec};
+use crate::gps_quality::GpsQualityGate;
+use crate::influx::InfluxOptions;
+use crate::mqtt::MqttConfig;
+use crate::outlier;
+use crate::profile::{self, Profile};
+use crate::units::UnitsSystem;
+use crate::rotation::DEFAULT_KEEP;
+use crate::serial::SerialConfig;
+use crate::socket_options::TcpOptions;
+use crate::sqlite::SqliteConfig;
+use crate::timestamp::{self, TimestampFormat};
+use crate::vacuum::VacuumArgs;
+use crate::validation::{AltitudeBounds, FieldLimits};
+use crate::webhook::WebhookConfig;
+
+// Command line interface. Running without a subcommand starts the server.
+#[derive(Parser, Debug)]
+#[command(version, about = "Receives sensor data over TCP and stores it in SQLite")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub config: Config,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Re-attempt insertion of quarantined lines, e.g. after a schema fix
+    ReplayQuarantine,
+    /// Stream synthetic records to a running server and report the rows/sec it stores
+    Bench(BenchArgs),
+    /// Compact the database, reclaiming the space of deleted rows, and exit without starting the server
+    Vacuum(VacuumArgs),
+    /// Write stored rows, optionally of one session or time range, to a file and exit without starting the server
+    Export(ExportArgs),
+}
+
+// Settings shared by the server and the maintenance subcommands
+#[derive(Args, Debug, Clone)]
+pub struct Config {
+    /// Directory where lines that fail to parse are quarantined
+    #[arg(long, value_name = "PATH", default_value = "quarantine", global = true)]
+    pub quarantine_dir: PathBuf,
+
+    /// Seconds of silence after which a client that negotiated keepalives is disconnected
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    pub keepalive_timeout_secs: u64,
+
+    /// Records a TCP, Unix socket or WebSocket connection may send before the server tells it so and closes it, forcing a reconnect
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_records_per_connection: Option<u64>,
+
+    /// Records per connection committed together in one transaction
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub batch_size: usize,
+
+    /// Longest a buffered record waits before its batch is committed
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub batch_flush_ms: u64,
+
+    /// Uncommitted records the database writer may buffer (and lines it may queue) before clients stop being read
+    #[arg(long, value_name = "N", default_value_t = 10000)]
+    pub max_pending_records: usize,
+
+    /// How long a connection waits for room in the database writer's queue before dropping a record
+    #[arg(long, value_name = "MS", default_value_t = 5000)]
+    pub backpressure_timeout_ms: u64,
+
+    /// Consecutive failed commits after which ingest pauses until a probe write succeeds; 0 never pauses
+    #[arg(long, value_name = "N", default_value_t = 5)]
+    pub breaker_failures: u32,
+
+    /// How often the database is probed while ingest is paused; clients are told to wait this long
+    #[arg(long, value_name = "SECS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub breaker_probe_secs: u64,
+
+    /// Largest JSON of unknown fields kept per record in the extras column; records with more are rejected
+    #[arg(long, value_name = "BYTES", default_value_t = 4096)]
+    pub max_extras_bytes: usize,
+
+    /// Most rows one query message may return
+    #[arg(long, value_name = "N", default_value_t = 10000)]
+    pub max_query_rows: u64,
+
+    /// Most bytes of rows one query message may return
+    #[arg(long, value_name = "BYTES", default_value_t = 16 * 1024 * 1024)]
+    pub max_query_bytes: usize,
+
+    /// Fill gaps longer than this between a connection's consecutive records with interpolated rows
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_gap_ms: Option<u64>,
+
+    /// Keep at most this many records per second of each connection's session, discarding the rest on arrival
+    #[arg(long, value_name = "HZ", value_parser = parse_positive)]
+    pub decimate_to_hz: Option<f64>,
+
+    /// Keep only the first record stored for each session and timestamp, skipping later ones with the same timestamp
+    #[arg(long)]
+    pub dedup_timestamps: bool,
+
+    /// Accept negative dt_ms offsets in batch messages instead of rejecting the batch
+    #[arg(long)]
+    pub allow_negative_dt: bool,
+
+    /// A form text timestamps may take: iso8601, epoch (seconds or milliseconds), a strftime pattern such as %Y%m%d%H%M%S, or any text, stored as sent. Tried in the order given; repeatable, and replaces the default list. iso8601 is tried last when not listed. Records in none of the forms are rejected, and sample blocks and dt_ms batches count from the normalized time. Older versions stored any text as sent; add --timestamp-format any after the others to keep doing that
+    #[arg(long = "timestamp-format", value_name = "FORMAT", default_values = ["iso8601", "epoch"], value_parser = timestamp::parse_format)]
+    pub timestamp_formats: Vec<TimestampFormat>,
+
+    /// Sensor fields records must carry; the rest are stored as NULL when missing
+    #[arg(long, value_enum, default_value_t = Profile::Full)]
+    pub profile: Profile,
+
+    /// Units sensor values are stored in; clients always send SI
+    #[arg(long, value_enum, default_value_t = UnitsSystem::Si)]
+    pub output_units: UnitsSystem,
+
+    /// Sensor fields records must carry on top of those the profile requires
+    #[arg(long, value_name = "FIELD,...", value_delimiter = ',', value_parser = profile::parse_field)]
+    pub require_fields: Vec<&'static str>,
+
+    /// Log an alert the first time a session reports a battery voltage below this
+    #[arg(long, value_name = "VOLTS")]
+    pub alert_battery_below: Option<f64>,
+
+    /// Log an alert the first time a session reports a temperature above this
+    #[arg(long, value_name = "CELSIUS")]
+    pub alert_temperature_above: Option<f64>,
+
+    /// Reject records with sensor values beyond f64's exact integer range (2^53) instead of only logging them
+    #[arg(long)]
+    pub reject_imprecise_numbers: bool,
+
+    /// Store moving averages of the accelerometer and gyroscope axes, keeping the values as received in *_raw columns
+    #[arg(long)]
+    pub enable_smoothing: bool,
+
+    /// Records per moving average with --enable-smoothing
+    #[arg(long, value_name = "N", default_value_t = 5, value_parser = parse_nonzero)]
+    pub smoothing_window: usize,
+
+    /// Replace the accelerometer and gyroscope axes with their medians over a sliding window, keeping the values as received in *_raw columns
+    #[arg(long)]
+    pub enable_median_filter: bool,
+
+    /// Records per median with --enable-median-filter
+    #[arg(long, value_name = "N", default_value_t = 5, value_parser = parse_nonzero)]
+    pub median_window: usize,
+
+    /// Low-pass filter the accelerometer axes into accel_*_filtered with this cutoff frequency
+    #[arg(long, value_name = "HZ", value_parser = parse_positive)]
+    pub lowpass_cutoff_hz: Option<f64>,
+
+    /// Rate the loggers sample the accelerometer at, for --lowpass-cutoff-hz
+    #[arg(long, value_name = "HZ", default_value_t = 100.0, value_parser = parse_positive)]
+    pub lowpass_sample_rate_hz: f64,
+
+    /// Flag records with a value more than this many standard deviations from its session's running mean
+    #[arg(long, value_name = "SIGMA", default_value_t = outlier::DEFAULT_SIGMA, value_parser = parse_positive)]
+    pub outlier_sigma: f64,
+
+    /// Accept TCP clients here, e.g. 9001,format=msgpack or 127.0.0.1:9002,ingest_only; repeat for more ports (default 0.0.0.0:9000)
+    #[arg(long, value_name = "[ADDR:]PORT[,OPTION...]", value_parser = listener::parse_listener)]
+    pub listen: Vec<ListenerSpec>,
+
+    /// Start even if some --listen addresses can't be bound, as long as one can
+    #[arg(long)]
+    pub allow_partial_listen: bool,
+
+    /// Also receive records as UDP datagrams on this port; nothing is sent back
+    #[arg(long, value_name = "PORT")]
+    pub udp_port: Option<u16>,
+
+    /// Largest UDP datagram accepted; larger ones are dropped and counted
+    #[arg(long, value_name = "BYTES", default_value_t = 8192, value_parser = parse_nonzero)]
+    pub max_datagram_bytes: usize,
+
+    /// Also accept clients on a Unix domain socket at this path, for producers on the same machine
+    #[arg(long, value_name = "PATH")]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Permissions of the --unix-socket file, in octal
+    #[arg(long, value_name = "MODE", default_value = "600", value_parser = parse_mode)]
+    pub unix_socket_mode: u32,
+
+    /// Also accept clients over WebSocket on this port, one JSON record or batch per text frame
+    #[arg(long, value_name = "PORT")]
+    pub websocket_port: Option<u16>,
+
+    /// Also accept records as HTTP POST /ingest requests on this port
+    #[arg(long, value_name = "PORT")]
+    pub http_port: Option<u16>,
+
+    /// Largest HTTP request body accepted, before and after gzip decompression; larger ones get 413
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024, value_parser = parse_nonzero)]
+    pub max_http_body_bytes: usize,
+
+    /// Also accept readings over gRPC on this port (builds with the grpc feature only)
+    #[arg(long, value_name = "PORT")]
+    pub grpc_port: Option<u16>,
+
+    /// Store latitude and longitude encrypted with the AES-256 key in this file (64 hex digits, mode 600)
+    #[arg(long, value_name = "PATH")]
+    pub gps_key_file: Option<PathBuf>,
+
+    /// Use key and password files that group or others can read after a warning, instead of refusing them
+    #[arg(long)]
+    pub allow_open_secret_files: bool,
+
+    /// Longest line accepted from a client; longer lines are discarded
+    #[arg(long, value_name = "BYTES", default_value_t = 65536)]
+    pub max_line_bytes: usize,
+
+    /// Socket read buffer per connection. Larger buffers take fewer system calls
+    /// at high message rates but cost that much memory for every connected
+    /// client; 8192 suits a few slow loggers, 262144 many fast ones.
+    #[arg(long, value_name = "BYTES", default_value_t = 65536, value_parser = parse_nonzero)]
+    pub read_buffer_bytes: usize,
+
+    /// Also append every accepted record to a JSONL archive, rotated daily
+    #[arg(long, value_name = "PATH")]
+    pub archive: Option<PathBuf>,
+
+    /// Directory of the write-ahead log that lets records survive a crash before commit
+    #[arg(long, value_name = "PATH", default_value = "wal")]
+    pub wal_dir: PathBuf,
+
+    /// Write records here as JSONL when the database is unavailable, and replay them once it's back
+    #[arg(long, value_name = "PATH")]
+    pub fallback_dir: Option<PathBuf>,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Write the server log to this file instead of stderr
+    #[arg(long, value_name = "PATH", global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the archive and log file once they would grow past this size
+    #[arg(long, value_name = "BYTES", global = true)]
+    pub rotate_max_bytes: Option<u64>,
+
+    /// Rotated archive and log files kept before the oldest are deleted
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_KEEP, global = true)]
+    pub rotate_keep: usize,
+
+    #[command(flatten)]
+    pub tcp: TcpOptions,
+
+    #[command(flatten)]
+    pub gps_quality: GpsQualityGate,
+
+    #[command(flatten)]
+    pub geo_fence: GeoFence,
+
+    #[command(flatten)]
+    pub influx: InfluxOptions,
+
+    #[command(flatten)]
+    pub mqtt: MqttConfig,
+
+    #[command(flatten)]
+    pub serial: SerialConfig,
+
+    #[command(flatten)]
+    pub calibration: CalibrationConfig,
+
+    #[command(flatten)]
+    pub events: EventConfig,
+
+    #[command(flatten)]
+    pub altitude: AltitudeBounds,
+
+    #[command(flatten)]
+    pub field_limits: FieldLimits,
+
+    #[command(flatten)]
+    pub dac: DacRanges,
+
+    #[command(flatten)]
+    pub integrity: IntegrityCheck,
+
+    #[command(flatten)]
+    pub webhook: WebhookConfig,
+
+    #[command(flatten)]
+    pub sqlite: SqliteConfig,
+}
+
+// A zero-capacity reader can't read anything, which looks like end of stream
+fn parse_nonzero(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Octal file permissions, as chmod takes them
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        Ok(_) => Err("must be at most 777".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Frequencies must be finite and above zero for the filter to make sense
+fn parse_positive(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(n) if n.is_finite() && n > 0.0 => Ok(n),
+        Ok(_) => Err("must be a positive number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    // The defaults with `args` on top, keeping the WAL and quarantine under
+    // `dir` rather than the working directory
+    pub fn for_test(dir: &std::path::Path, args: &[&str]) -> Config {
+        let wal_dir = dir.join("wal");
+        let quarantine_dir = dir.join("quarantine");
+        let mut argv = vec![
+            "db_receiver",
+            "--wal-dir",
+            wal_dir.to_str().unwrap(),
+            "--quarantine-dir",
+            quarantine_dir.to_str().unwrap(),
+        ];
+        argv.extend_from_slice(args);
+        Cli::try_parse_from(argv).expect("valid test arguments").config
+    }
+}
@@ -0,0 +1,93 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use chrono::{SecondsFormat, Utc};
+use log::{error, info};
+use rusqlite::{params, Connection};
+
+use crate::batch::SessionTally;
+use crate::peer::Peer;
+use crate::writer::Writer;
+
+// Values of connections.status
+pub const STATUS_ACTIVE: &str = "active";
+pub const STATUS_CLOSED: &str = "closed";
+// The handler gave up on the connection with an error
+pub const STATUS_ERROR: &str = "error";
+pub const STATUS_PANIC: &str = "panic";
+// Still active when the server stopped without closing it, e.g. a crash
+pub const STATUS_INTERRUPTED: &str = "interrupted";
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+// Record a client connection as it is accepted. Returns the row's ID, or
+// None if it couldn't be stored, which doesn't stop the client being served.
+pub fn open(writer: &Writer, addr: Peer) -> Option<i64> {
+    let client_addr = addr.to_string();
+    let result = writer.call(move |conn| {
+        conn.execute(
+            "INSERT INTO connections (client_addr, start_time, status) VALUES (?1, ?2, ?3)",
+            params![client_addr, now(), STATUS_ACTIVE],
+        )?;
+        Ok(conn.last_insert_rowid())
+    });
+    match result {
+        Ok(id) => Some(id),
+        Err(e) => {
+            error!("Failed to record the connection from {}: {}", addr, e);
+            None
+        }
+    }
+}
+
+// Fill in how a connection ended. It goes through the writer after the
+// records the connection queued, so the row count covers all of them.
+pub fn close(writer: &Writer, id: i64, status: &'static str, notes: Option<String>, tally: &Arc<SessionTally>, bytes_received: u64) {
+    let tally = tally.clone();
+    let result = writer.call(move |conn| {
+        let rows: u64 = tally.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).sessions.values().sum();
+        conn.execute(
+            "UPDATE connections SET end_time = ?1, row_count = ?2, status = ?3, notes = ?4, bytes_received = ?5 WHERE id = ?6",
+            params![now(), rows as i64, status, notes, bytes_received as i64, id],
+        )
+    });
+    if let Err(e) = result {
+        error!("Failed to record the end of connection {}: {}", id, e);
+    }
+}
+
+// Connections a previous run left active never got closed; run at startup,
+// before any client connects
+pub fn mark_interrupted(conn: &Connection) {
+    let result = conn.execute(
+        "UPDATE connections SET status = ?1 WHERE status = ?2",
+        params![STATUS_INTERRUPTED, STATUS_ACTIVE],
+    );
+    match result {
+        Ok(0) => {}
+        Ok(n) => info!("Marked {} connection(s) left active by the previous run as {}", n, STATUS_INTERRUPTED),
+        Err(e) => error!("Failed to mark connections left active: {}", e),
+    }
+}
+
+// Counts the bytes read through it, as they came off the wire
+pub struct Counted<R> {
+    inner: R,
+    bytes: Arc<AtomicU64>,
+}
+
+impl<R> Counted<R> {
+    pub fn new(inner: R, bytes: Arc<AtomicU64>) -> Self {
+        Counted { inner, bytes }
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt;
+use clap::Args;
+
+use crate::{SensorData, DAC_COLUMNS, MAX_DAC_CHANNELS};
+
+// Expected ranges of the DAC channels, which carry raw ADC counts whose range
+// depends on the board, e.g. 0 to 4095 for a 12-bit converter. None are set by
+// default.
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "DAC Channels")]
+pub struct DacRanges {
+    /// Range of a DAC channel's readings, e.g. dac_1=0:4095, or dac=0:4095 for every channel; a later range for a channel replaces an earlier one. Repeatable
+    #[arg(long = "dac-range", value_name = "CHANNEL=MIN:MAX", value_parser = parse_dac_range)]
+    pub dac_ranges: Vec<DacRange>,
+
+    /// Store channels with a --dac-range scaled to 0..1 in dac_N, keeping the readings as received in dac_N_raw
+    #[arg(long)]
+    pub dac_scale: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DacRange {
+    // Index of the channel, or None for all of them
+    channel: Option<usize>,
+    min: f64,
+    max: f64,
+}
+
+fn parse_dac_range(value: &str) -> Result<DacRange, String> {
+    let (name, bounds) = value.split_once('=').ok_or("expected CHANNEL=MIN:MAX")?;
+    let channel = match name {
+        "dac" => None,
+        _ => Some(
+            DAC_COLUMNS
+                .iter()
+                .position(|column| *column == name)
+                .ok_or_else(|| format!("unknown DAC channel '{}'; use dac_1 to dac_{} or dac", name, MAX_DAC_CHANNELS))?,
+        ),
+    };
+    let (min, max) = bounds.split_once(':').ok_or("expected CHANNEL=MIN:MAX")?;
+    let bound = |text: &str| match text.trim().parse::<f64>() {
+        Ok(n) if n.is_finite() => Ok(n),
+        _ => Err(format!("'{}' is not a finite number", text)),
+    };
+    let (min, max) = (bound(min)?, bound(max)?);
+    // Scaling divides by the width of the range
+    if min >= max {
+        return Err(format!("minimum {} must be below maximum {}", min, max));
+    }
+    Ok(DacRange { channel, min, max })
+}
+
+// Map a reading onto [0, 1] across its channel's range. Readings outside the
+// range land outside [0, 1] rather than being clamped.
+pub fn scale_dac(raw: f64, min: f64, max: f64) -> f64 {
+    (raw - min) / (max - min)
+}
+
+// A reading outside its channel's range
+#[derive(Debug, Clone, Copy)]
+pub struct DacViolation {
+    pub channel: &'static str,
+    pub value: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl fmt::Display for DacViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {} is outside its --dac-range [{}, {}]", self.channel, self.value, self.min, self.max)
+    }
+}
+
+impl DacRanges {
+    // The range in effect for a channel: the last one given for it
+    fn range(&self, channel: usize) -> Option<(f64, f64)> {
+        self.dac_ranges
+            .iter()
+            .rev()
+            .find(|range| range.channel.is_none_or(|c| c == channel))
+            .map(|range| (range.min, range.max))
+    }
+
+    // Check each channel against its range and, with --dac-scale, replace the
+    // reading with its scaled value, keeping the original in `dac_raw`.
+    // Returns the readings that were out of range.
+    pub fn apply(&self, data: &mut SensorData) -> Vec<DacViolation> {
+        let mut violations = Vec::new();
+        if self.dac_ranges.is_empty() {
+            return violations;
+        }
+        let mut raw = [None; MAX_DAC_CHANNELS];
+        for (channel, reading) in data.dac_channels_mut().into_iter().enumerate() {
+            let (Some(value), Some((min, max))) = (*reading, self.range(channel)) else {
+                continue;
+            };
+            if value < min || value > max {
+                violations.push(DacViolation { channel: DAC_COLUMNS[channel], value, min, max });
+            }
+            if self.dac_scale {
+                raw[channel] = Some(value);
+                *reading = Some(scale_dac(value, min, max));
+            }
+        }
+        // Calibration may have kept the readings as received already
+        if data.dac_raw.is_none() && raw.iter().any(Option::is_some) {
+            data.dac_raw = Some(raw);
+        }
+        violations
+    }
+}
+
+// The ranges as they appear in the effective configuration line
+impl fmt::Display for DacRanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dac_ranges.is_empty() {
+            return write!(f, "off");
+        }
+        for (i, range) in self.dac_ranges.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            let channel = range.channel.map_or("dac", |c| DAC_COLUMNS[c]);
+            write!(f, "{}{}={}:{}", separator, channel, range.min, range.max)?;
+        }
+        if self.dac_scale {
+            write!(f, " scaled")?;
+        }
+        Ok(())
+    }
+}
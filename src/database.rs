@@ -0,0 +1,254 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
+use rusqlite::{Connection, ErrorCode};
+
+use crate::schema::ensure_schema;
+use crate::metrics::Metrics;
+use crate::sqlite::{self, SqliteConfig};
+
+// Consecutive I/O errors before the writer reopens the database
+const REOPEN_AFTER_FAILURES: u32 = 3;
+
+// Wait between reopen attempts, doubling after each failed one
+const REOPEN_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const REOPEN_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+// Identity of the file at a path, so a file deleted, moved or replaced under
+// an open connection can be told apart from the one it was opened on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileId {
+    dev: u64,
+    ino: u64,
+}
+
+impl FileId {
+    #[cfg(unix)]
+    pub fn of(path: impl AsRef<Path>) -> Option<FileId> {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(FileId { dev: metadata.dev(), ino: metadata.ino() })
+    }
+
+    // Without inodes, only a missing file can be noticed
+    #[cfg(not(unix))]
+    pub fn of(path: impl AsRef<Path>) -> Option<FileId> {
+        std::fs::metadata(path).ok().map(|_| FileId { dev: 0, ino: 0 })
+    }
+}
+
+// The writer's connection. It is reopened, recreating the file and schema,
+// when the file it was opened on is no longer at its path or writes keep
+// failing with I/O errors, so a database moved away by a log rotation
+// mishap doesn't leave every later insert failing (or, on Unix, silently
+// landing in a deleted file).
+pub struct Database {
+    conn: Connection,
+    path: PathBuf,
+    config: SqliteConfig,
+    dedup_timestamps: bool,
+    file: Option<FileId>,
+    failures: u32,
+    backoff: Duration,
+    next_attempt: Option<Instant>,
+    metrics: Arc<Metrics>,
+}
+
+impl Database {
+    // Wrap a connection already opened on `path` with the schema in place
+    pub fn new(
+        conn: Connection,
+        path: impl Into<PathBuf>,
+        config: SqliteConfig,
+        dedup_timestamps: bool,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let path = path.into();
+        Database {
+            conn,
+            file: FileId::of(&path),
+            path,
+            config,
+            dedup_timestamps,
+            failures: 0,
+            backoff: REOPEN_BACKOFF_MIN,
+            next_attempt: None,
+            metrics,
+        }
+    }
+
+    pub fn conn(&self) -> &Connection {
+        &self.conn
+    }
+
+    // Before a write: reopen if the path no longer holds the file the
+    // connection has open
+    pub fn check_file(&mut self) {
+        let current = FileId::of(&self.path);
+        if current.is_some() && current == self.file {
+            return;
+        }
+        match current {
+            None => warn!("Database file {} is gone, probably deleted or moved", self.path.display()),
+            Some(_) => warn!("Database file {} was replaced by another file", self.path.display()),
+        }
+        self.reopen(true);
+    }
+
+    // After a write: count I/O errors and reopen once they keep coming
+    pub fn write_failed(&mut self, e: &rusqlite::Error) {
+        if !is_io_error(e) {
+            return;
+        }
+        self.failures += 1;
+        if self.failures >= REOPEN_AFTER_FAILURES {
+            warn!("{} consecutive I/O errors writing to {}; reopening it", self.failures, self.path.display());
+            self.reopen(false);
+        }
+    }
+
+    pub fn write_succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    // Open the path afresh, creating the file and schema if needed. Attempts
+    // back off while they keep failing, so a missing directory doesn't turn
+    // every flush into another try.
+    fn reopen(&mut self, moved: bool) {
+        if self.next_attempt.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+        if moved {
+            self.release_journal();
+        }
+        let result = sqlite::open(&self.path, &self.config).and_then(|conn| {
+            ensure_schema(&conn, self.dedup_timestamps)?;
+            Ok(conn)
+        });
+        match result {
+            Ok(conn) => {
+                self.conn = conn;
+                self.file = FileId::of(&self.path);
+                self.failures = 0;
+                self.backoff = REOPEN_BACKOFF_MIN;
+                self.next_attempt = None;
+                self.metrics.database_reopens.fetch_add(1, Ordering::Relaxed);
+                info!("Reopened database {}; writes resume", self.path.display());
+            }
+            Err(e) => {
+                error!("Failed to reopen database {}: {}; retrying in {}s", self.path.display(), e, self.backoff.as_secs());
+                self.next_attempt = Some(Instant::now() + self.backoff);
+                self.backoff = (self.backoff * 2).min(REOPEN_BACKOFF_MAX);
+            }
+        }
+    }
+
+    // The -wal and -shm files at the path still belong to the file that
+    // moved away; a new database there would read the old one's pages from
+    // them. Commits still in the WAL are copied into the moved file first.
+    // The -shm file is only an index and is removed, as is an emptied WAL; a
+    // WAL the checkpoint couldn't empty is renamed aside instead.
+    fn release_journal(&self) {
+        if let Err(e) = self.conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(())) {
+            debug!("Could not checkpoint the WAL into the moved database: {}", e);
+        }
+        for suffix in ["-wal", "-shm"] {
+            let leftover = with_suffix(&self.path, suffix);
+            let Ok(metadata) = std::fs::metadata(&leftover) else {
+                continue;
+            };
+            if suffix == "-shm" || metadata.len() == 0 {
+                if let Err(e) = std::fs::remove_file(&leftover) {
+                    error!("Failed to remove {} of the previous database file: {}", leftover.display(), e);
+                }
+                continue;
+            }
+            let aside = with_suffix(&leftover, &format!(".orphaned-{}", chrono::Utc::now().format("%Y%m%dT%H%M%SZ")));
+            match std::fs::rename(&leftover, &aside) {
+                Ok(()) => warn!("Moved {} of the previous database file to {}", leftover.display(), aside.display()),
+                Err(e) => error!("Failed to move {} aside: {}", leftover.display(), e),
+            }
+        }
+    }
+}
+
+// The path with `suffix` added to its file name, as SQLite names the -wal
+// and -shm files next to a database
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+// Errors of the file underneath rather than of the statement or a lock.
+// SQLite reports a database moved under a WAL-mode connection as read-only.
+fn is_io_error(e: &rusqlite::Error) -> bool {
+    matches!(
+        e.sqlite_error_code(),
+        Some(ErrorCode::SystemIoFailure | ErrorCode::CannotOpen | ErrorCode::ReadOnly | ErrorCode::NotADatabase)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn insert(db: &Database, timestamp: &str) -> rusqlite::Result<usize> {
+        db.conn().execute("INSERT INTO sensor_data (sessionID, timestamp) VALUES (1, ?1)", [timestamp])
+    }
+
+    fn count(db: &Database) -> i64 {
+        db.conn().query_row("SELECT COUNT(*) FROM sensor_data", [], |row| row.get(0)).unwrap()
+    }
+
+    // A database deleted under the writer is recreated at its path before
+    // the next write, which then lands in the new file
+    #[test]
+    fn writes_resume_after_the_file_is_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let config = Config::for_test(dir.path(), &[]).sqlite;
+        let conn = sqlite::open(&path, &config).unwrap();
+        ensure_schema(&conn, false).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let mut db = Database::new(conn, &path, config, false, metrics.clone());
+
+        db.check_file();
+        insert(&db, "2024-01-01T00:00:00Z").unwrap();
+        assert_eq!(count(&db), 1);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(with_suffix(&path, suffix));
+        }
+        db.check_file();
+        insert(&db, "2024-01-01T00:00:01Z").unwrap();
+
+        assert_eq!(metrics.database_reopens.load(Ordering::Relaxed), 1);
+        assert!(FileId::of(&path).is_some());
+        let reopened = sqlite::open(&path, &db.config).unwrap();
+        let rows: i64 = reopened.query_row("SELECT COUNT(*) FROM sensor_data", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 1);
+        assert_eq!(count(&db), 1);
+    }
+
+    // An unchanged file is left alone
+    #[test]
+    fn unchanged_file_is_not_reopened() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let config = Config::for_test(dir.path(), &[]).sqlite;
+        let conn = sqlite::open(&path, &config).unwrap();
+        ensure_schema(&conn, false).unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let mut db = Database::new(conn, &path, config, false, metrics.clone());
+
+        db.check_file();
+        insert(&db, "2024-01-01T00:00:00Z").unwrap();
+        db.check_file();
+        assert_eq!(metrics.database_reopens.load(Ordering::Relaxed), 0);
+        assert_eq!(count(&db), 1);
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+use chrono::{DateTime, Duration, Utc};
+use log::info;
+
+use crate::peer::Peer;
+use crate::timestamp::ClientTimestamp;
+use crate::SensorData;
+
+// Thins one connection's records down to --decimate-to-hz: a record is kept
+// only if at least the target interval has passed since the last one kept.
+// Starts over whenever the connection switches to another session.
+#[derive(Debug)]
+pub struct DecimationFilter {
+    target_interval_ms: u64,
+    last_accepted_ts: Option<DateTime<Utc>>,
+    session_id: Option<i64>,
+    // Records discarded per session, reported when the connection ends
+    discarded: BTreeMap<Option<i64>, u64>,
+}
+
+impl DecimationFilter {
+    pub fn new(target_hz: f64) -> Self {
+        DecimationFilter {
+            target_interval_ms: (1000.0 / target_hz).round() as u64,
+            last_accepted_ts: None,
+            session_id: None,
+            discarded: BTreeMap::new(),
+        }
+    }
+
+    // Whether to keep this record. One whose timestamp can't be read is
+    // always kept; one earlier than the last kept falls within the interval
+    // and is discarded.
+    pub fn should_accept(&mut self, data: &SensorData) -> bool {
+        if data.session_id != self.session_id {
+            self.session_id = data.session_id;
+            self.last_accepted_ts = None;
+        }
+        let Some(ts) = ClientTimestamp::parse(&data.timestamp).map(|ts| ts.to_utc()) else {
+            return true;
+        };
+        match self.last_accepted_ts {
+            Some(last) if ts - last < Duration::milliseconds(self.target_interval_ms as i64) => {
+                *self.discarded.entry(data.session_id).or_default() += 1;
+                false
+            }
+            _ => {
+                self.last_accepted_ts = Some(ts);
+                true
+            }
+        }
+    }
+
+    // Log how many records of each session were discarded
+    pub fn report(&self, addr: Peer) {
+        for (session_id, discarded) in &self.discarded {
+            info!(
+                "Decimation discarded {} record(s) of session {} from {}",
+                discarded,
+                session_id.map_or("none".to_string(), |id| id.to_string()),
+                addr
+            );
+        }
+    }
+}
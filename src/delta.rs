@@ -0,0 +1,63 @@
+use serde_json::Value;
+
+use crate::timestamp::{self, ClientTimestamp, TimestampFormat};
+
+// Fill in the timestamp of every batch element that gives `dt_ms` instead,
+// as the previous element's time plus that many milliseconds. Offsets are
+// summed as integers from the last full timestamp, so long runs don't drift.
+// A full timestamp in one of `formats` counts as its stored form.
+pub fn resolve_deltas(elements: &mut [Value], allow_negative: bool, formats: &[TimestampFormat]) -> Result<(), String> {
+    // Last full timestamp and the milliseconds accumulated since it
+    let mut base: Option<(ClientTimestamp, i64)> = None;
+
+    for (i, element) in elements.iter_mut().enumerate() {
+        let Some(fields) = element.as_object_mut() else {
+            return Err(format!("batch element {} is not an object", i));
+        };
+        // Later elements count from the stored form of the timestamp; one in
+        // none of the formats is left for validation to reject
+        let stored = match fields.get("timestamp") {
+            Some(Value::Number(epoch)) => epoch.as_f64().and_then(timestamp::from_epoch),
+            Some(Value::String(text)) => {
+                let mut text = text.clone();
+                timestamp::normalize(&mut text, formats).ok().map(|()| text)
+            }
+            _ => None,
+        };
+        if let Some(stored) = stored {
+            fields.insert("timestamp".to_string(), Value::String(stored));
+        }
+        let Some(dt) = fields.remove("dt_ms") else {
+            // A full timestamp starts a new base for the elements after it
+            base = fields
+                .get("timestamp")
+                .and_then(Value::as_str)
+                .and_then(ClientTimestamp::parse)
+                .map(|t| (t, 0));
+            continue;
+        };
+
+        if fields.contains_key("timestamp") {
+            return Err(format!("batch element {} has both timestamp and dt_ms", i));
+        }
+        let dt = dt
+            .as_i64()
+            .ok_or_else(|| format!("dt_ms of batch element {} must be an integer", i))?;
+        if dt < 0 && !allow_negative {
+            return Err(format!("dt_ms of batch element {} is negative ({})", i, dt));
+        }
+        let (timestamp, elapsed) = match base.as_mut() {
+            Some(base) => base,
+            None if i == 0 => return Err("the first batch element needs a full timestamp, not dt_ms".to_string()),
+            None => {
+                return Err(format!(
+                    "batch element {} uses dt_ms but the element before it has no date and time to count from",
+                    i
+                ))
+            }
+        };
+        *elapsed += dt;
+        fields.insert("timestamp".to_string(), Value::String(timestamp.plus_millis(*elapsed)));
+    }
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use crate::SensorData;
+
+// Mean radius of the Earth, in metres
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+// Steps shorter than this are taken for GPS jitter while standing still
+const MIN_STEP_M: f64 = 0.1;
+
+// Great-circle distance between two positions given in degrees, in metres
+pub fn haversine_distance_m(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let d_phi = (lat2 - lat1).to_radians();
+    let d_lambda = (lon2 - lon1).to_radians();
+    let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_M * a.sqrt().min(1.0).asin()
+}
+
+// Adds up the distance one connection's positions cover, starting over at 0
+// when the session changes. A position is measured from the last one that
+// counted; one without a fix (fix_quality 0), or within MIN_STEP_M of the
+// last, adds nothing and doesn't take its place, so jitter can't pile up.
+#[derive(Debug, Default)]
+pub struct TrackDistance {
+    total_m: f64,
+    last_fix: Option<(f64, f64)>,
+    session_id: Option<i64>,
+}
+
+impl TrackDistance {
+    // Fill `cumulative_distance_m` with the distance covered in the session
+    // up to and including this record. Runs before the GPS cipher seals the
+    // position, so it sees plain coordinates.
+    pub fn apply(&mut self, data: &mut SensorData) {
+        if data.session_id != self.session_id {
+            *self = TrackDistance { session_id: data.session_id, ..Default::default() };
+        }
+        if let (Some(lat), Some(lon)) = (data.latitude, data.longitude) {
+            if data.fix_quality != Some(0) {
+                match self.last_fix {
+                    Some((last_lat, last_lon)) => {
+                        let step = haversine_distance_m(last_lat, last_lon, lat, lon);
+                        if step.is_finite() && step >= MIN_STEP_M {
+                            self.total_m += step;
+                            self.last_fix = Some((lat, lon));
+                        }
+                    }
+                    None => self.last_fix = Some((lat, lon)),
+                }
+            }
+        }
+        data.cumulative_distance_m = Some(self.total_m);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() <= tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn known_distances() {
+        // One degree along a meridian is 1/360 of the circumference
+        assert_close(haversine_distance_m(0.0, 0.0, 1.0, 0.0), 111_195.0, 1.0);
+        assert_close(haversine_distance_m(45.0, 10.0, 46.0, 10.0), 111_195.0, 1.0);
+        // Along the equator too, but a degree of longitude shrinks with cos(latitude)
+        assert_close(haversine_distance_m(0.0, 0.0, 0.0, 1.0), 111_195.0, 1.0);
+        assert_close(haversine_distance_m(60.0, 0.0, 60.0, 1.0), 55_597.0, 1.0);
+        // Half the circumference between antipodes
+        assert_close(haversine_distance_m(0.0, 0.0, 0.0, 180.0), 20_015_087.0, 1.0);
+        assert_eq!(haversine_distance_m(52.5, 13.4, 52.5, 13.4), 0.0);
+    }
+
+    // The short way round, not 359.8 degrees the other way
+    #[test]
+    fn antimeridian_crossing() {
+        assert_close(haversine_distance_m(0.0, 179.9, 0.0, -179.9), 22_239.0, 1.0);
+        assert_close(haversine_distance_m(0.0, -179.9, 0.0, 179.9), 22_239.0, 1.0);
+    }
+
+    fn fix(session_id: i64, lat: f64, lon: f64) -> SensorData {
+        serde_json::from_value(serde_json::json!({
+            "sessionID": session_id, "timestamp": "2024-05-18T10:00:00Z", "latitude": lat, "longitude": lon,
+        }))
+        .unwrap()
+    }
+
+    fn cumulative(track: &mut TrackDistance, mut data: SensorData) -> f64 {
+        track.apply(&mut data);
+        data.cumulative_distance_m.unwrap()
+    }
+
+    // 5e-7 degrees of latitude is about 0.056 m, 1e-6 about 0.111 m
+    #[test]
+    fn jitter_below_cutoff_adds_nothing() {
+        let mut track = TrackDistance::default();
+        assert_eq!(cumulative(&mut track, fix(1, 0.0, 0.0)), 0.0);
+        assert_eq!(cumulative(&mut track, fix(1, 5e-7, 0.0)), 0.0);
+        assert_eq!(cumulative(&mut track, fix(1, 0.0, 0.0)), 0.0);
+        // Measured from the last position that counted, so slow drift still adds up
+        assert_close(cumulative(&mut track, fix(1, 1e-6, 0.0)), 0.111, 0.001);
+    }
+
+    #[test]
+    fn new_session_and_lost_fix() {
+        let mut track = TrackDistance::default();
+        cumulative(&mut track, fix(1, 0.0, 0.0));
+        assert_close(cumulative(&mut track, fix(1, 1.0, 0.0)), 111_195.0, 1.0);
+
+        let mut lost = fix(1, 10.0, 0.0);
+        lost.fix_quality = Some(0);
+        assert_close(cumulative(&mut track, lost), 111_195.0, 1.0);
+
+        assert_eq!(cumulative(&mut track, fix(2, 1.0, 0.0)), 0.0);
+    }
+}
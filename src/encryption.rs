@@ -0,0 +1,78 @@
+use std::fmt;
+use std::path::Path;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::secret::load_secret;
+
+// Columns stored encrypted when a GPS key is configured
+pub const ENCRYPTED_COLUMNS: &[&str] = &["latitude", "longitude", "latitude_raw", "longitude_raw"];
+
+// Bytes of the random nonce in front of each ciphertext
+const NONCE_BYTES: usize = 12;
+
+// AES-256-GCM over the latitude and longitude columns, and their raw copies. Each value is stored
+// as base64 of a fresh random nonce followed by the ciphertext and tag of its
+// little-endian f64 bytes. The column name is authenticated along with it, so
+// a latitude copied into the longitude column fails to decrypt.
+pub struct GpsCipher {
+    cipher: Aes256Gcm,
+}
+
+// The key never shows up in logs or panics
+impl fmt::Debug for GpsCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("GpsCipher(..)")
+    }
+}
+
+impl GpsCipher {
+    // The key file holds 32 bytes as 64 hex digits, e.g. from
+    // `openssl rand -hex 32`
+    pub fn load(path: &Path, allow_open: bool) -> Result<Self, String> {
+        let secret = load_secret(path, allow_open).map_err(|e| format!("can't read GPS key file {}: {}", path.display(), e))?;
+        let key = decode_hex(secret.trim())
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| format!("GPS key file {} must hold 64 hex digits (a 256-bit key)", path.display()))?;
+        Ok(GpsCipher { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)) })
+    }
+
+    pub fn encrypt(&self, column: &str, value: f64) -> String {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload { msg: &value.to_le_bytes(), aad: column.as_bytes() };
+        // Only fails for messages beyond GCM's length limit
+        let ciphertext = self.cipher.encrypt(&nonce, payload).expect("an f64 is within AES-GCM's message limit");
+        let mut stored = nonce.to_vec();
+        stored.extend_from_slice(&ciphertext);
+        BASE64.encode(stored)
+    }
+
+    pub fn decrypt(&self, column: &str, stored: &str) -> Result<f64, String> {
+        let bytes = BASE64.decode(stored).map_err(|_| format!("{} is not an encrypted value", column))?;
+        if bytes.len() < NONCE_BYTES {
+            return Err(format!("{} is not an encrypted value", column));
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_BYTES);
+        let payload = Payload { msg: ciphertext, aad: column.as_bytes() };
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| format!("can't decrypt {}; was it stored with another key?", column))?;
+        let plain: [u8; 8] = plain.try_into().map_err(|_| format!("encrypted {} is not a number", column))?;
+        Ok(f64::from_le_bytes(plain))
+    }
+
+    // A coordinate as stored: ciphertext for the encrypted columns
+    pub fn seal(&self, column: &str, value: Option<f64>) -> Option<String> {
+        value.map(|value| self.encrypt(column, value))
+    }
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
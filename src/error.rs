@@ -0,0 +1,52 @@
+use std::fmt;
+use std::io;
+
+// Errors from operations that touch both files and the database
+#[derive(Debug)]
+pub enum ReceiverError {
+    Io(io::Error),
+    Database(rusqlite::Error),
+    Json(serde_json::Error),
+    // A stored value the configured key can't decrypt
+    Decrypt(String),
+}
+
+impl fmt::Display for ReceiverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiverError::Io(e) => write!(f, "I/O error: {}", e),
+            ReceiverError::Database(e) => write!(f, "database error: {}", e),
+            ReceiverError::Json(e) => write!(f, "JSON error: {}", e),
+            ReceiverError::Decrypt(e) => write!(f, "decryption error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ReceiverError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReceiverError::Io(e) => Some(e),
+            ReceiverError::Database(e) => Some(e),
+            ReceiverError::Json(e) => Some(e),
+            ReceiverError::Decrypt(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReceiverError {
+    fn from(e: io::Error) -> Self {
+        ReceiverError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for ReceiverError {
+    fn from(e: rusqlite::Error) -> Self {
+        ReceiverError::Database(e)
+    }
+}
+
+impl From<serde_json::Error> for ReceiverError {
+    fn from(e: serde_json::Error) -> Self {
+        ReceiverError::Json(e)
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+use serde::Serialize;
+
+// Longest echo of the offending input included in an error reply
+const MAX_ECHO_CHARS: usize = 120;
+
+// Error replies allowed per connection in each window; the rest are counted
+// and reported with the next reply that gets through
+const MAX_REPLIES_PER_WINDOW: u32 = 5;
+const REPLY_WINDOW: Duration = Duration::from_secs(1);
+
+// Machine-readable reason a line was rejected
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    ParseError,
+    ValidationError,
+    OversizedLine,
+    SessionError,
+    Overloaded,
+    QueryError,
+    FlushError,
+    Unsupported,
+    DatabaseUnavailable,
+}
+
+// One-line JSON reply sent to clients that opted in to error feedback
+#[derive(Serialize, Debug)]
+pub struct ErrorReply {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    code: ErrorCode,
+    error: String,
+    input: String,
+    // Errors not replied to since the last reply because of rate limiting
+    #[serde(skip_serializing_if = "is_zero")]
+    suppressed: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+impl ErrorReply {
+    pub fn new(code: ErrorCode, error: &str, input: &str) -> Self {
+        ErrorReply {
+            message_type: "error",
+            code,
+            error: error.to_string(),
+            input: truncate_chars(input, MAX_ECHO_CHARS),
+            suppressed: 0,
+        }
+    }
+}
+
+pub fn truncate_chars(input: &str, max: usize) -> String {
+    match input.char_indices().nth(max) {
+        Some((end, _)) => format!("{}...", &input[..end]),
+        None => input.to_string(),
+    }
+}
+
+// Caps error replies per connection so a client streaming garbage doesn't
+// get back as many bytes as it sends
+#[derive(Debug)]
+pub struct ErrorReplyLimiter {
+    window_start: Instant,
+    sent_in_window: u32,
+    suppressed: u64,
+}
+
+impl Default for ErrorReplyLimiter {
+    fn default() -> Self {
+        ErrorReplyLimiter {
+            window_start: Instant::now(),
+            sent_in_window: 0,
+            suppressed: 0,
+        }
+    }
+}
+
+impl ErrorReplyLimiter {
+    // Returns the reply to send, or None if this one is suppressed
+    pub fn admit(&mut self, mut reply: ErrorReply) -> Option<ErrorReply> {
+        if self.window_start.elapsed() >= REPLY_WINDOW {
+            self.window_start = Instant::now();
+            self.sent_in_window = 0;
+        }
+        if self.sent_in_window >= MAX_REPLIES_PER_WINDOW {
+            self.suppressed += 1;
+            return None;
+        }
+        self.sent_in_window += 1;
+        reply.suppressed = std::mem::take(&mut self.suppressed);
+        Some(reply)
+    }
+}
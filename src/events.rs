@@ -0,0 +1,197 @@
+use std::fmt;
+use clap::Args;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::batch::PendingRecord;
+use crate::encryption::ENCRYPTED_COLUMNS;
+use crate::profile;
+use crate::SensorData;
+
+// Conditions on single values that are worth recording as they arrive, such
+// as a hard landing. None are set by default.
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "Events")]
+pub struct EventConfig {
+    /// Record an event when a stored value passes a threshold, e.g. accel_z:gt:19.6:high_g_event; OP is gt, ge, lt or le. Repeatable
+    #[arg(long = "event-rule", value_name = "FIELD:OP:THRESHOLD:LABEL", value_parser = parse_rule)]
+    pub rules: Vec<EventRule>,
+}
+
+#[derive(Debug, Clone)]
+pub struct EventRule {
+    field: &'static str,
+    operator: Operator,
+    threshold: f64,
+    label: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Operator {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Operator::Gt => value > threshold,
+            Operator::Ge => value >= threshold,
+            Operator::Lt => value < threshold,
+            Operator::Le => value <= threshold,
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operator::Gt => write!(f, "gt"),
+            Operator::Ge => write!(f, "ge"),
+            Operator::Lt => write!(f, "lt"),
+            Operator::Le => write!(f, "le"),
+        }
+    }
+}
+
+// The label is everything after the third colon, so it may contain colons
+fn parse_rule(value: &str) -> Result<EventRule, String> {
+    let parts: Vec<&str> = value.splitn(4, ':').collect();
+    let [field, operator, threshold, label] = parts[..] else {
+        return Err("expected FIELD:OP:THRESHOLD:LABEL".to_string());
+    };
+    let field = profile::parse_field(field)?;
+    let operator = match operator {
+        "gt" => Operator::Gt,
+        "ge" => Operator::Ge,
+        "lt" => Operator::Lt,
+        "le" => Operator::Le,
+        _ => return Err(format!("unknown operator '{}'; use gt, ge, lt or le", operator)),
+    };
+    let threshold = match threshold.trim().parse::<f64>() {
+        Ok(n) if n.is_finite() => n,
+        _ => return Err(format!("'{}' is not a finite number", threshold)),
+    };
+    if label.is_empty() {
+        return Err("the label must not be empty".to_string());
+    }
+    Ok(EventRule { field, operator, threshold, label: label.to_string() })
+}
+
+// The rules as they appear in the effective configuration line
+impl fmt::Display for EventConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.rules.is_empty() {
+            return write!(f, "none");
+        }
+        let rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|rule| format!("{}:{}:{}:{}", rule.field, rule.operator, rule.threshold, rule.label))
+            .collect();
+        write!(f, "{}", rules.join(","))
+    }
+}
+
+// A rule a stored value met, as kept in the events table
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub session_id: Option<i64>,
+    pub timestamp: String,
+    pub label: String,
+    pub field: String,
+    pub value: f64,
+    pub threshold: f64,
+}
+
+// The events a record triggers, one per rule it meets, in rule order
+pub fn evaluate_rules(data: &SensorData, rules: &[EventRule]) -> Vec<Event> {
+    if rules.is_empty() {
+        return Vec::new();
+    }
+    let fields = data.fields();
+    rules
+        .iter()
+        .filter_map(|rule| {
+            let (_, value) = fields.iter().find(|(name, _)| *name == rule.field)?;
+            let value = value.filter(|&value| rule.operator.holds(value, rule.threshold))?;
+            Some(Event {
+                id: None,
+                session_id: data.session_id,
+                timestamp: data.timestamp.clone(),
+                label: rule.label.clone(),
+                field: rule.field.to_string(),
+                value,
+                threshold: rule.threshold,
+            })
+        })
+        .collect()
+}
+
+// Evaluate the rules against the rows of a commit and store what they
+// trigger. Like statistics, duplicates that weren't stored and interpolated
+// rows are left out, and so are coordinates stored encrypted, which would
+// otherwise appear in the events table in the clear.
+pub fn record(
+    conn: &Connection,
+    rules: &[EventRule],
+    records: &[PendingRecord],
+    row_ids: &[Option<i64>],
+    gps_encrypted: bool,
+) -> rusqlite::Result<Vec<Event>> {
+    let mut events = Vec::new();
+    if rules.is_empty() {
+        return Ok(events);
+    }
+    for (record, row_id) in records.iter().zip(row_ids) {
+        if row_id.is_none() || record.data.is_interpolated {
+            continue;
+        }
+        events.extend(
+            evaluate_rules(&record.data, rules)
+                .into_iter()
+                .filter(|event| !(gps_encrypted && ENCRYPTED_COLUMNS.contains(&event.field.as_str()))),
+        );
+    }
+    if events.is_empty() {
+        return Ok(events);
+    }
+    let tx = conn.unchecked_transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(
+            "INSERT INTO events (session_id, timestamp, label, field, value, threshold) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        for event in &mut events {
+            stmt.execute(params![event.session_id, event.timestamp, event.label, event.field, event.value, event.threshold])?;
+            event.id = Some(tx.last_insert_rowid());
+        }
+    }
+    tx.commit()?;
+    Ok(events)
+}
+
+// Stored events, oldest first, optionally only those of one session or with
+// one label, and at most `limit` of them
+pub fn list(conn: &Connection, session_id: Option<i64>, label: Option<&str>, limit: u64) -> rusqlite::Result<Vec<Event>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, session_id, timestamp, label, field, value, threshold FROM events
+         WHERE (?1 IS NULL OR session_id = ?1) AND (?2 IS NULL OR label = ?2)
+         ORDER BY id LIMIT ?3",
+    )?;
+    let rows = stmt.query_map(params![session_id, label, limit as i64], |row| {
+        Ok(Event {
+            id: row.get(0)?,
+            session_id: row.get(1)?,
+            timestamp: row.get(2)?,
+            label: row.get(3)?,
+            field: row.get(4)?,
+            value: row.get(5)?,
+            threshold: row.get(6)?,
+        })
+    })?;
+    rows.collect()
+}
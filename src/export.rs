@@ -0,0 +1,406 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use clap::{Args, ValueEnum};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, Row};
+use serde::Serialize;
+
+use crate::annotations;
+use crate::encryption::{GpsCipher, ENCRYPTED_COLUMNS};
+use crate::query;
+use crate::tilt::Tilt;
+use crate::timestamp::ClientTimestamp;
+use crate::track;
+use crate::{SensorData, DAC_COLUMNS, MAX_DAC_CHANNELS, RAW_DAC_COLUMNS};
+
+// Options of the `export` subcommand
+#[derive(Args, Debug, Clone)]
+pub struct ExportArgs {
+    /// Format to write
+    #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+    pub format: ExportFormat,
+
+    /// Export only the rows of this session
+    #[arg(long, value_name = "ID")]
+    pub session: Option<i64>,
+
+    /// Export only the rows of this device, as named in its handshake
+    #[arg(long, value_name = "DEVICE_ID")]
+    pub device: Option<String>,
+
+    /// Export rows from this time on, e.g. 2024-05-18T10:00; without an offset the time is UTC
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    pub from: Option<DateTime<Utc>>,
+
+    /// Export rows before this time
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    pub to: Option<DateTime<Utc>>,
+
+    /// File to write; standard output when not given or -
+    #[arg(long, value_name = "PATH")]
+    pub out: Option<PathBuf>,
+
+    /// With --format geojson, add a Point feature per position with its time, altitude, speed and acceleration
+    #[arg(long)]
+    pub points: bool,
+
+    /// With csv or jsonl, add the labels of the annotations covering each row
+    #[arg(long)]
+    pub annotations: bool,
+
+    /// With a track format, drop positions that repeat the one before them
+    #[arg(long)]
+    pub collapse_duplicates: bool,
+
+    /// With --format gpx, start a new track segment after a gap of more than this between positions
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub segment_gap_secs: u64,
+
+    /// With --format kml, whether tracks follow the terrain or are drawn at their stored altitude
+    #[arg(long, value_enum, default_value_t = AltitudeMode::ClampToGround)]
+    pub altitude_mode: AltitudeMode,
+
+    /// With --format kml, colour each track by this value, split into four equal ranges
+    #[arg(long, value_enum)]
+    pub color_by: Option<ColorBy>,
+
+    /// With --format kml, thin a session's track evenly down to this many positions
+    #[arg(long, value_name = "N", default_value_t = 5000, value_parser = clap::value_parser!(u64).range(2..))]
+    pub max_points: u64,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Comma-separated values with a header row
+    Csv,
+    /// One JSON record per line, in the form the server accepts, for replaying into another instance
+    Jsonl,
+    /// A session's GPS track as a GeoJSON FeatureCollection, for web maps; needs --session
+    Geojson,
+    /// GPS tracks as a GPX 1.1 document, one track per session, for GPS tools
+    Gpx,
+    /// GPS tracks as a KML document, one styled placemark per session, for Google Earth
+    Kml,
+}
+
+impl ExportFormat {
+    // Whether the format draws GPS tracks rather than listing rows
+    fn is_track(self) -> bool {
+        matches!(self, ExportFormat::Geojson | ExportFormat::Gpx | ExportFormat::Kml)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AltitudeMode {
+    /// Drape the track over the terrain, ignoring altitude
+    #[value(alias = "clampToGround")]
+    ClampToGround,
+    /// Draw the track at its stored altitude above sea level; positions without one are left out
+    Absolute,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorBy {
+    /// The magnitude of the acceleration
+    #[value(name = "accel_mag")]
+    AccelMag,
+    /// The speed from the position before
+    Speed,
+}
+
+// One exported line: the record as stored plus the device it came from, as
+// the archive writes it
+#[derive(Serialize)]
+struct ExportedRecord {
+    #[serde(flatten)]
+    data: SensorData,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    // With --annotations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<Vec<String>>,
+}
+
+// A timestamp as the server accepts it, or one cut short at the minute or
+// the day, which is how people type a range
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Some(time) = ClientTimestamp::parse(value) {
+        return Ok(time.to_utc());
+    }
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_hms_opt(0, 0, 0).expect("midnight exists")))
+        .map(|time| time.and_utc())
+        .map_err(|_| format!("'{}' is not an ISO 8601 time such as 2024-05-18T10:00", value))
+}
+
+// Write the rows matching `args` to its output, oldest first, returning how
+// many there were. Rows are written as they are read, so an export never
+// holds more than one in memory. Nothing is written, and the output file not
+// created, when no row matches.
+pub fn run(conn: &Connection, args: &ExportArgs, gps_cipher: Option<&GpsCipher>) -> Result<u64, Box<dyn Error>> {
+    if let (Some(from), Some(to)) = (args.from, args.to) {
+        if to <= from {
+            return Err("--to must be later than --from".into());
+        }
+    }
+    if args.points && args.format != ExportFormat::Geojson {
+        return Err("--points only applies to --format geojson".into());
+    }
+    if args.collapse_duplicates && !args.format.is_track() {
+        return Err("--collapse-duplicates only applies to track formats".into());
+    }
+    if args.color_by.is_some() && args.format != ExportFormat::Kml {
+        return Err("--color-by only applies to --format kml".into());
+    }
+    if args.annotations && args.format.is_track() {
+        return Err("--annotations only applies to --format csv and jsonl".into());
+    }
+    if args.format.is_track() {
+        return track::export(conn, args, gps_cipher);
+    }
+    let columns = query::all_columns();
+    let mut selected = columns.join(", ");
+    if args.annotations {
+        selected = format!("{}, {}", selected, annotations::LABELS_COLUMN);
+    }
+    let mut stmt = conn.prepare(&select(&selected, "id"))?;
+    let mut rows = stmt.query(params![args.session, args.device, bound(args.from), bound(args.to)])?;
+
+    let Some(first) = rows.next()? else {
+        return Err(nothing_matched(args).into());
+    };
+    let render = |row: &Row| match args.format {
+        ExportFormat::Csv => csv_row(&columns, row, gps_cipher, args.annotations),
+        ExportFormat::Jsonl => jsonl_line(row, gps_cipher, args.annotations),
+        ExportFormat::Geojson | ExportFormat::Gpx | ExportFormat::Kml => unreachable!("tracks are exported by track::export"),
+    };
+    // Rendered before the output is opened, so a row that can't be decrypted
+    // leaves nothing behind
+    let first = render(first)?;
+    let mut out = open_output(args)?;
+    if args.format == ExportFormat::Csv {
+        let mut header: Vec<String> = columns.iter().map(|column| csv_text(column)).collect();
+        if args.annotations {
+            header.push("annotations".to_string());
+        }
+        writeln!(out, "{}", header.join(","))?;
+    }
+    out.write_all(first.as_bytes())?;
+    let mut exported = 1;
+    while let Some(row) = rows.next()? {
+        out.write_all(render(row)?.as_bytes())?;
+        exported += 1;
+    }
+    out.flush()?;
+    Ok(exported)
+}
+
+// The query for `columns` of the rows the filters of `args` select, bound
+// with `params![args.session, args.device, bound(args.from), bound(args.to)]`
+pub fn select(columns: &str, order_by: &str) -> String {
+    format!(
+        "SELECT {} FROM sensor_data
+         WHERE (?1 IS NULL OR sessionID = ?1)
+           AND (?2 IS NULL OR device_id = ?2)
+           AND (?3 IS NULL OR julianday(timestamp) >= julianday(?3))
+           AND (?4 IS NULL OR julianday(timestamp) < julianday(?4))
+         ORDER BY {}",
+        columns, order_by
+    )
+}
+
+// A --from or --to time as the query compares it
+pub fn bound(time: Option<DateTime<Utc>>) -> Option<String> {
+    time.map(|time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+// The file to write, or None for standard output
+pub fn output_path(args: &ExportArgs) -> Option<&Path> {
+    args.out.as_deref().filter(|path| *path != Path::new("-"))
+}
+
+pub fn open_output(args: &ExportArgs) -> Result<Box<dyn Write>, Box<dyn Error>> {
+    Ok(match output_path(args) {
+        Some(path) => Box::new(BufWriter::new(
+            File::create(path).map_err(|e| format!("can't create {}: {}", path.display(), e))?,
+        )),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    })
+}
+
+// Why an export came out empty, naming the filters that were given
+pub fn nothing_matched(args: &ExportArgs) -> String {
+    let mut filters = Vec::new();
+    if let Some(session) = args.session {
+        filters.push(format!("session {}", session));
+    }
+    if let Some(device) = &args.device {
+        filters.push(format!("device '{}'", device));
+    }
+    if let Some(from) = args.from {
+        filters.push(format!("from {}", from.to_rfc3339_opts(SecondsFormat::AutoSi, true)));
+    }
+    if let Some(to) = args.to {
+        filters.push(format!("before {}", to.to_rfc3339_opts(SecondsFormat::AutoSi, true)));
+    }
+    if filters.is_empty() {
+        "nothing to export: sensor_data has no rows".to_string()
+    } else {
+        format!("nothing to export: no rows match {}", filters.join(", "))
+    }
+}
+
+// The labels of the annotations covering the row, when they were selected
+fn annotation_labels(row: &Row) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(serde_json::from_str(&row.get::<_, String>("annotations")?)?)
+}
+
+// One line of cells. NULL is an empty cell, numbers are written in full
+// with as many digits as they need, and timestamps in RFC 3339 UTC. The
+// annotations cell lists labels separated by semicolons.
+fn csv_row(columns: &[&str], row: &Row, gps_cipher: Option<&GpsCipher>, annotations: bool) -> Result<String, Box<dyn Error>> {
+    let mut cells = Vec::with_capacity(columns.len());
+    for (i, column) in columns.iter().enumerate() {
+        let cell = match row.get_ref(i)? {
+            ValueRef::Null | ValueRef::Blob(_) => String::new(),
+            ValueRef::Integer(n) => n.to_string(),
+            ValueRef::Real(f) => f.to_string(),
+            ValueRef::Text(text) => {
+                let text = String::from_utf8_lossy(text);
+                if ENCRYPTED_COLUMNS.contains(column) {
+                    let cipher = gps_cipher
+                        .ok_or_else(|| format!("{} is stored encrypted; give its key with --gps-key-file", column))?;
+                    cipher.decrypt(column, &text)?.to_string()
+                } else if *column == "timestamp" {
+                    match ClientTimestamp::parse(&text) {
+                        Some(time) => time.to_utc().to_rfc3339_opts(SecondsFormat::AutoSi, true),
+                        None => csv_text(&text),
+                    }
+                } else {
+                    csv_text(&text)
+                }
+            }
+        };
+        cells.push(cell);
+    }
+    if annotations {
+        cells.push(csv_text(&annotation_labels(row)?.join(";")));
+    }
+    let mut line = cells.join(",");
+    line.push('\n');
+    Ok(line)
+}
+
+// The row as the record a client would send, with the columns the server
+// added under the keys the archive uses for them. The server clears those
+// when it takes such a line in, and computes them afresh.
+fn jsonl_line(row: &Row, gps_cipher: Option<&GpsCipher>, annotations: bool) -> Result<String, Box<dyn Error>> {
+    let real = |column: &str| -> Result<Option<f64>, Box<dyn Error>> {
+        match row.get_ref(column)? {
+            ValueRef::Text(text) if ENCRYPTED_COLUMNS.contains(&column) => {
+                let cipher = gps_cipher
+                    .ok_or_else(|| format!("{} is stored encrypted; give its key with --gps-key-file", column))?;
+                Ok(Some(cipher.decrypt(column, &String::from_utf8_lossy(text))?))
+            }
+            value => Ok(value.as_f64_or_null()?),
+        }
+    };
+    let flag = |column: &str| -> rusqlite::Result<bool> { Ok(row.get::<_, Option<bool>>(column)?.unwrap_or(false)) };
+
+    let mut dac = [None; MAX_DAC_CHANNELS];
+    for (channel, column) in dac.iter_mut().zip(DAC_COLUMNS) {
+        *channel = real(column)?;
+    }
+    // Channels past the fourth only fit the array form, which runs up to the
+    // last channel with a reading
+    let dac_array = dac[4..].iter().any(Option::is_some).then(|| {
+        let channels = dac.iter().rposition(Option::is_some).map_or(0, |last| last + 1);
+        dac[..channels].to_vec()
+    });
+    let named_dac = |channel: usize| if dac_array.is_some() { None } else { dac[channel] };
+
+    let extras = match row.get::<_, Option<String>>("extras")? {
+        Some(json) => serde_json::from_str(&json)?,
+        None => serde_json::Map::new(),
+    };
+    let tilt = match (real("pitch_rad")?, real("roll_rad")?) {
+        (Some(pitch_rad), Some(roll_rad)) => Some(Tilt { pitch_rad, roll_rad, valid: flag("pitch_roll_valid")? }),
+        _ => None,
+    };
+    let cumulative_angles = match (real("cumulative_pitch")?, real("cumulative_roll")?, real("cumulative_yaw")?) {
+        (Some(pitch), Some(roll), Some(yaw)) => Some([pitch, roll, yaw]),
+        _ => None,
+    };
+
+    let data = SensorData {
+        session_id: row.get("sessionID")?,
+        timestamp: row.get("timestamp")?,
+        latitude: real("latitude")?,
+        longitude: real("longitude")?,
+        altitude: real("altitude")?,
+        accel_x: real("accel_x")?,
+        accel_y: real("accel_y")?,
+        accel_z: real("accel_z")?,
+        gyro_x: real("gyro_x")?,
+        gyro_y: real("gyro_y")?,
+        gyro_z: real("gyro_z")?,
+        mag_x: real("mag_x")?,
+        mag_y: real("mag_y")?,
+        mag_z: real("mag_z")?,
+        fix_quality: row.get("fix_quality")?,
+        num_satellites: row.get("num_satellites")?,
+        hdop: real("hdop")?,
+        temperature_c: real("temperature_c")?,
+        battery_v: real("battery_v")?,
+        dac_1: named_dac(0),
+        dac_2: named_dac(1),
+        dac_3: named_dac(2),
+        dac_4: named_dac(3),
+        dac: dac_array,
+        gps_low_quality: flag("gps_low_quality")?,
+        outside_fence: flag("outside_fence")?,
+        is_outlier: flag("is_outlier")?,
+        is_interpolated: flag("is_interpolated")?,
+        accel_filtered: group(&real, ["accel_x_filtered", "accel_y_filtered", "accel_z_filtered"])?,
+        cumulative_angles,
+        cumulative_distance_m: real("cumulative_distance_m")?,
+        tilt,
+        imu_raw: group(&real, ["accel_x_raw", "accel_y_raw", "accel_z_raw", "gyro_x_raw", "gyro_y_raw", "gyro_z_raw"])?,
+        dac_raw: group(&real, RAW_DAC_COLUMNS)?,
+        position_raw: group(&real, ["latitude_raw", "longitude_raw", "altitude_raw"])?,
+        message_id: row.get("message_id")?,
+        extras,
+    };
+    let annotations = if annotations { Some(annotation_labels(row)?) } else { None };
+    let mut line = serde_json::to_string(&ExportedRecord { data, device_id: row.get("device_id")?, annotations })?;
+    line.push('\n');
+    Ok(line)
+}
+
+// Reads a numeric column of the current row, decrypting it if need be
+type ReadReal<'a> = dyn Fn(&str) -> Result<Option<f64>, Box<dyn Error>> + 'a;
+
+// A group of columns the server fills together, or None if it left them all NULL
+fn group<const N: usize>(
+    real: &ReadReal,
+    columns: [&str; N],
+) -> Result<Option<[Option<f64>; N]>, Box<dyn Error>> {
+    let mut values = [None; N];
+    for (value, column) in values.iter_mut().zip(columns) {
+        *value = real(column)?;
+    }
+    Ok(values.iter().any(Option::is_some).then_some(values))
+}
+
+// Quoted as RFC 4180 has it when the text holds a separator, a quote or a
+// line break, as extras do
+fn csv_text(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
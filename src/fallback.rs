@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::warn;
+use rusqlite::Connection;
+
+use crate::batch::PendingRecord;
+use crate::encryption::GpsCipher;
+use crate::error::ReceiverError;
+use crate::insert_sensor_data;
+
+// How often the background replayer checks whether the database is back
+pub const FALLBACK_REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+
+type SessionWriter = Arc<Mutex<BufWriter<File>>>;
+
+// JSONL files that hold records while the database can't take them, one
+// file per session in `<session_id>_<timestamp>.jsonl`. Each line is a
+// `PendingRecord`, so replaying a file stores exactly what would have been
+// inserted.
+pub struct FallbackStore {
+    dir: PathBuf,
+    // Open file per session, shared by every connection writing that session.
+    // A writer's own lock is always taken while holding this one, so emptying
+    // the map and then locking each writer waits out any write in progress.
+    writers: Mutex<HashMap<String, SessionWriter>>,
+}
+
+// Counts reported by one replay round
+#[derive(Debug, Default)]
+pub struct FallbackReplay {
+    pub files: usize,
+    pub inserted: usize,
+}
+
+impl FallbackStore {
+    pub fn new(dir: PathBuf) -> Self {
+        FallbackStore {
+            dir,
+            writers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Append records to their sessions' files and flush them to disk
+    pub fn write_records(&self, records: &[PendingRecord]) -> io::Result<()> {
+        let mut by_session: HashMap<String, Vec<&PendingRecord>> = HashMap::new();
+        for record in records {
+            by_session.entry(record.session_key()).or_default().push(record);
+        }
+
+        for (session, records) in by_session {
+            let mut writers = self.writers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let writer = match writers.get(&session) {
+                Some(writer) => writer.clone(),
+                None => {
+                    let writer = Arc::new(Mutex::new(BufWriter::new(self.create_file(&session)?)));
+                    writers.insert(session, writer.clone());
+                    writer
+                }
+            };
+            let mut file = writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            drop(writers);
+
+            for record in records {
+                let mut line = serde_json::to_string(record)?;
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+            }
+            // These records exist nowhere else, so they go to disk right away
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn create_file(&self, session: &str) -> io::Result<File> {
+        fs::create_dir_all(&self.dir)?;
+        let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+        let path = self.dir.join(format!("{}_{}.jsonl", session, stamp));
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    // True if any fallback file is waiting to be replayed
+    pub fn has_pending(&self) -> bool {
+        list_files(&self.dir).is_ok_and(|files| !files.is_empty())
+    }
+
+    // Insert every fallback file into the database, one transaction per file.
+    // Open files are closed first so they can be replayed too; records that
+    // arrive meanwhile start new files. A file is deleted once all of its
+    // lines are stored; unreadable lines are kept in it for inspection.
+    pub fn replay(
+        &self,
+        conn: &Connection,
+        gps_cipher: Option<&GpsCipher>,
+        dedup_timestamps: bool,
+    ) -> Result<FallbackReplay, ReceiverError> {
+        let files = {
+            let mut writers = self.writers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            for (session, writer) in writers.drain() {
+                let mut file = writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                if let Err(e) = file.flush() {
+                    warn!("Failed to flush fallback file for session {}: {}", session, e);
+                }
+            }
+            // Listed while no new file can be created
+            list_files(&self.dir)?
+        };
+
+        let mut summary = FallbackReplay::default();
+        for path in files {
+            let contents = fs::read_to_string(&path)?;
+            let mut remaining = Vec::new();
+            let mut inserted = 0;
+
+            let tx = conn.unchecked_transaction()?;
+            for line in contents.lines() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<PendingRecord>(line) {
+                    Ok(record) => {
+                        insert_sensor_data(&tx, &record.data, record.device_id.as_deref(), record.connection_id, gps_cipher, dedup_timestamps)?;
+                        inserted += 1;
+                    }
+                    Err(e) => {
+                        warn!("Unreadable fallback entry in {}: {}", path.display(), e);
+                        remaining.push(line);
+                    }
+                }
+            }
+            tx.commit()?;
+
+            if remaining.is_empty() {
+                fs::remove_file(&path)?;
+            } else {
+                let mut contents = remaining.join("\n");
+                contents.push('\n');
+                fs::write(&path, contents)?;
+            }
+            summary.files += 1;
+            summary.inserted += inserted;
+        }
+        Ok(summary)
+    }
+}
+
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
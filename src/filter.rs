@@ -0,0 +1,323 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::SensorData;
+
+// Moving average over the last `capacity` values
+#[derive(Debug)]
+pub struct SlidingWindow {
+    values: VecDeque<f64>,
+    capacity: usize,
+    sum: f64,
+}
+
+impl SlidingWindow {
+    pub fn new(capacity: usize) -> Self {
+        SlidingWindow {
+            values: VecDeque::with_capacity(capacity),
+            capacity,
+            sum: 0.0,
+        }
+    }
+
+    // Add a value, returning the mean of the window including it
+    pub fn push(&mut self, value: f64) -> f64 {
+        if self.values.len() == self.capacity {
+            if let Some(oldest) = self.values.pop_front() {
+                self.sum -= oldest;
+            }
+        }
+        self.values.push_back(value);
+        self.sum += value;
+        self.sum / self.values.len() as f64
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.sum = 0.0;
+    }
+}
+
+// Smooths the accelerometer and gyroscope axes of one connection's records.
+// Each axis has its own window; a missing value leaves its window alone. The
+// windows start over when the session changes, so one run's readings don't
+// bleed into the next.
+#[derive(Debug)]
+pub struct SensorFilter {
+    axes: [SlidingWindow; 6],
+    session_id: Option<i64>,
+}
+
+impl SensorFilter {
+    pub fn new(window: usize) -> Self {
+        SensorFilter {
+            axes: std::array::from_fn(|_| SlidingWindow::new(window)),
+            session_id: None,
+        }
+    }
+
+    // Replace the IMU values with their moving averages, keeping the
+    // originals in `imu_raw`
+    pub fn apply(&mut self, data: &mut SensorData) {
+        if data.session_id != self.session_id {
+            self.axes.iter_mut().for_each(SlidingWindow::clear);
+            self.session_id = data.session_id;
+        }
+        let axes = [
+            &mut data.accel_x,
+            &mut data.accel_y,
+            &mut data.accel_z,
+            &mut data.gyro_x,
+            &mut data.gyro_y,
+            &mut data.gyro_z,
+        ];
+        let mut raw = [None; 6];
+        for ((value, window), raw) in axes.into_iter().zip(&mut self.axes).zip(&mut raw) {
+            *raw = *value;
+            *value = value.map(|v| window.push(v));
+        }
+        // Calibration or the median filter may have kept the values as
+        // received already
+        data.imu_raw.get_or_insert(raw);
+    }
+}
+
+// Median of the last `size` values, which passes steps through but drops
+// spikes shorter than half the window
+#[derive(Debug)]
+pub struct MedianFilter {
+    window: VecDeque<f64>,
+    size: usize,
+}
+
+impl MedianFilter {
+    pub fn new(size: usize) -> Self {
+        MedianFilter { window: VecDeque::with_capacity(size), size }
+    }
+
+    // Add a value, returning the median of the window including it; the
+    // mean of the middle two while the window holds an even number
+    pub fn filter(&mut self, value: f64) -> f64 {
+        if self.window.len() == self.size {
+            self.window.pop_front();
+        }
+        self.window.push_back(value);
+        let mut sorted: Vec<f64> = self.window.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        let middle = sorted.len() / 2;
+        if sorted.len().is_multiple_of(2) {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        } else {
+            sorted[middle]
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.window.clear();
+    }
+}
+
+// Runs the accelerometer and gyroscope axes of one connection's records
+// through median filters, one per axis, keeping the values as received in
+// `imu_raw`. Like the moving averages, a missing value leaves its axis alone
+// and the filters start over when the session changes.
+#[derive(Debug)]
+pub struct ImuMedianFilter {
+    axes: [MedianFilter; 6],
+    session_id: Option<i64>,
+}
+
+impl ImuMedianFilter {
+    pub fn new(size: usize) -> Self {
+        ImuMedianFilter {
+            axes: std::array::from_fn(|_| MedianFilter::new(size)),
+            session_id: None,
+        }
+    }
+
+    pub fn apply(&mut self, data: &mut SensorData) {
+        if data.session_id != self.session_id {
+            self.axes.iter_mut().for_each(MedianFilter::clear);
+            self.session_id = data.session_id;
+        }
+        let axes = [
+            &mut data.accel_x,
+            &mut data.accel_y,
+            &mut data.accel_z,
+            &mut data.gyro_x,
+            &mut data.gyro_y,
+            &mut data.gyro_z,
+        ];
+        let mut raw = [None; 6];
+        for ((value, filter), raw) in axes.into_iter().zip(&mut self.axes).zip(&mut raw) {
+            *raw = *value;
+            *value = value.map(|v| filter.filter(v));
+        }
+        // Calibration may have kept the values as received already
+        data.imu_raw.get_or_insert(raw);
+    }
+}
+
+// First-order IIR low-pass filter: y[n] = alpha * x[n] + (1 - alpha) * y[n-1].
+// The first value passes through unchanged, so the output doesn't ramp up
+// from zero.
+#[derive(Debug, Clone, Copy)]
+pub struct LowPassFilter {
+    alpha: f64,
+    prev: Option<f64>,
+}
+
+impl LowPassFilter {
+    pub fn new(alpha: f64) -> Self {
+        LowPassFilter { alpha, prev: None }
+    }
+
+    // The smoothing factor for a cutoff frequency at a sample rate:
+    // alpha = dt / (RC + dt) with RC = 1 / (2π fc)
+    pub fn alpha(cutoff_hz: f64, sample_rate_hz: f64) -> f64 {
+        let dt = 1.0 / sample_rate_hz;
+        let rc = 1.0 / (2.0 * std::f64::consts::PI * cutoff_hz);
+        dt / (rc + dt)
+    }
+
+    pub fn push(&mut self, value: f64) -> f64 {
+        let filtered = match self.prev {
+            Some(prev) => self.alpha * value + (1.0 - self.alpha) * prev,
+            None => value,
+        };
+        self.prev = Some(filtered);
+        filtered
+    }
+}
+
+// Low-pass filters the accelerometer axes of one connection's records into
+// `accel_filtered`. Like the moving averages, the filters start over when the
+// session changes. A session_config message may set another alpha for one
+// session; without either, records are left unfiltered.
+#[derive(Debug, Default)]
+pub struct AccelLowPass {
+    default_alpha: Option<f64>,
+    overrides: HashMap<i64, f64>,
+    axes: Option<[LowPassFilter; 3]>,
+    session_id: Option<i64>,
+}
+
+impl AccelLowPass {
+    pub fn new(default_alpha: Option<f64>) -> Self {
+        AccelLowPass { default_alpha, ..Default::default() }
+    }
+
+    // Use `alpha` for the session's records from now on, or go back to the
+    // default with None
+    pub fn set_alpha(&mut self, session_id: i64, alpha: Option<f64>) {
+        match alpha {
+            Some(alpha) => self.overrides.insert(session_id, alpha),
+            None => self.overrides.remove(&session_id),
+        };
+        if self.session_id == Some(session_id) {
+            self.axes = None;
+        }
+    }
+
+    // Fill `accel_filtered` from the accelerometer values as received
+    pub fn apply(&mut self, data: &mut SensorData) {
+        if data.session_id != self.session_id {
+            self.axes = None;
+            self.session_id = data.session_id;
+        }
+        let alpha = data.session_id.and_then(|id| self.overrides.get(&id).copied()).or(self.default_alpha);
+        let Some(alpha) = alpha else {
+            return;
+        };
+        let axes = self.axes.get_or_insert_with(|| [LowPassFilter::new(alpha); 3]);
+        let mut filtered = [None; 3];
+        for ((value, filter), filtered) in [data.accel_x, data.accel_y, data.accel_z].into_iter().zip(axes).zip(&mut filtered) {
+            *filtered = value.map(|v| filter.push(v));
+        }
+        data.accel_filtered = Some(filtered);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowpass_alpha_from_cutoff() {
+        // RC = 1 / 2π ≈ 0.159 s against dt = 0.01 s
+        assert!((LowPassFilter::alpha(1.0, 100.0) - 0.059117).abs() < 1e-6);
+        // A cutoff far above the sample rate barely filters at all
+        assert!(LowPassFilter::alpha(1e6, 100.0) > 0.9999);
+        assert!(LowPassFilter::alpha(0.01, 100.0) < 0.001);
+    }
+
+    #[test]
+    fn lowpass_step_response() {
+        let mut filter = LowPassFilter::new(0.25);
+        // The first value passes through rather than ramping up from zero
+        assert_eq!(filter.push(4.0), 4.0);
+        assert_eq!(filter.push(4.0), 4.0);
+        // Each step closes a quarter of the remaining gap
+        assert_eq!(filter.push(8.0), 5.0);
+        assert_eq!(filter.push(8.0), 5.75);
+        assert_eq!(filter.push(8.0), 6.3125);
+    }
+
+    fn accel(session_id: i64, x: f64) -> SensorData {
+        serde_json::from_value(serde_json::json!({
+            "sessionID": session_id, "timestamp": "2024-05-18T10:00:00Z", "accel_x": x, "accel_y": 0.0, "accel_z": 9.81,
+        }))
+        .unwrap()
+    }
+
+    fn filtered_x(lowpass: &mut AccelLowPass, mut data: SensorData) -> Option<f64> {
+        lowpass.apply(&mut data);
+        data.accel_filtered.map(|[x, _, _]| x.unwrap())
+    }
+
+    // A session's own alpha replaces the default, and a new session starts
+    // its filters over
+    #[test]
+    fn lowpass_per_session_alpha() {
+        let mut lowpass = AccelLowPass::new(None);
+        assert_eq!(filtered_x(&mut lowpass, accel(1, 1.0)), None);
+
+        lowpass.set_alpha(1, Some(0.5));
+        assert_eq!(filtered_x(&mut lowpass, accel(1, 0.0)), Some(0.0));
+        assert_eq!(filtered_x(&mut lowpass, accel(1, 4.0)), Some(2.0));
+        assert_eq!(filtered_x(&mut lowpass, accel(2, 4.0)), None);
+
+        lowpass.set_alpha(1, None);
+        assert_eq!(filtered_x(&mut lowpass, accel(1, 4.0)), None);
+    }
+
+    #[test]
+    fn median_drops_a_spike() {
+        let mut median = MedianFilter::new(3);
+        let filtered: Vec<f64> = [1.0, 2.0, 50.0, 3.0, 4.0].into_iter().map(|v| median.filter(v)).collect();
+        // The mean of the middle two while the window fills up
+        assert_eq!(filtered, [1.0, 1.5, 2.0, 3.0, 4.0]);
+    }
+
+    // A lasting change comes through once it fills half the window
+    #[test]
+    fn median_passes_a_step() {
+        let mut median = MedianFilter::new(5);
+        for _ in 0..5 {
+            median.filter(0.0);
+        }
+        let filtered: Vec<f64> = [10.0, 10.0, 10.0, 10.0].into_iter().map(|v| median.filter(v)).collect();
+        assert_eq!(filtered, [0.0, 0.0, 10.0, 10.0]);
+    }
+
+    #[test]
+    fn median_starts_over_for_a_new_session() {
+        let mut median = ImuMedianFilter::new(3);
+        for x in [1.0, 1.0, 1.0] {
+            median.apply(&mut accel(1, x));
+        }
+        let mut data = accel(2, 7.0);
+        median.apply(&mut data);
+        assert_eq!(data.accel_x, Some(7.0));
+        assert_eq!(data.imu_raw.unwrap()[0], Some(7.0));
+    }
+}
@@ -0,0 +1,128 @@
+use chrono::{DateTime, Duration, Utc};
+use log::debug;
+
+use crate::timestamp::ClientTimestamp;
+use crate::{SensorData, MAX_DAC_CHANNELS};
+
+// Most rows made up for one gap. A longer gap is an outage rather than a few
+// lost samples, and is left as it is.
+const MAX_INTERPOLATED_ROWS: usize = 1000;
+
+// Fills gaps longer than --max-gap-ms between one connection's consecutive
+// records of a session with evenly spaced synthetic rows, so the series stays
+// close to uniform. Records are compared as received, before the filters.
+#[derive(Debug, Default)]
+pub struct GapFiller {
+    // The latest record of the session so far, and its time
+    last: Option<(SensorData, DateTime<Utc>)>,
+}
+
+impl GapFiller {
+    // `rows` with synthetic rows put in front of each record that follows
+    // the previous one by more than `max_gap_ms`. A record whose timestamp
+    // can't be read, or that goes back in time, gets none.
+    pub fn fill(&mut self, rows: Vec<SensorData>, max_gap_ms: u64) -> Vec<SensorData> {
+        let max_gap = Duration::milliseconds(max_gap_ms as i64);
+        let mut filled = Vec::with_capacity(rows.len());
+        for data in rows {
+            if self.last.as_ref().is_some_and(|(last, _)| last.session_id != data.session_id) {
+                self.last = None;
+            }
+            let Some(ts) = ClientTimestamp::parse(&data.timestamp).map(|ts| ts.to_utc()) else {
+                filled.push(data);
+                continue;
+            };
+            match &self.last {
+                // Keep the later record, so an out-of-order one doesn't open
+                // the same gap twice
+                Some((_, last_ts)) if ts < *last_ts => {
+                    filled.push(data);
+                    continue;
+                }
+                Some((last, last_ts)) if ts - *last_ts > max_gap => {
+                    let gap_ms = (ts - *last_ts).num_milliseconds().max(1) as u64;
+                    // Enough rows that none of the steps exceeds the threshold
+                    let n = gap_ms.div_ceil(max_gap_ms.max(1)).saturating_sub(1) as usize;
+                    if n <= MAX_INTERPOLATED_ROWS {
+                        filled.extend(interpolate_sensor_data(last, &data, n));
+                    } else {
+                        debug!("Not filling the {}ms gap before {}: it would take {} rows", gap_ms, data.timestamp, n);
+                    }
+                }
+                _ => {}
+            }
+            self.last = Some((data.clone(), ts));
+            filled.push(data);
+        }
+        filled
+    }
+}
+
+// `n` rows evenly spaced in time strictly between `a` and `b`, flagged as
+// interpolated. Sensor values are interpolated linearly where both records
+// have them, longitude the short way round the antimeridian; the GPS fix
+// quality and satellite count are the lower of the two. The rows carry no
+// message_id or extras.
+pub fn interpolate_sensor_data(a: &SensorData, b: &SensorData, n: usize) -> Vec<SensorData> {
+    let (Some(start), Some(end)) = (ClientTimestamp::parse(&a.timestamp), ClientTimestamp::parse(&b.timestamp)) else {
+        return Vec::new();
+    };
+    let span = end.to_utc() - start.to_utc();
+    let (dac_a, dac_b) = (a.dac_channels(), b.dac_channels());
+    (1..=n)
+        .map(|i| {
+            let t = i as f64 / (n + 1) as f64;
+            let lerp = |from: Option<f64>, to: Option<f64>| Some(from? + (to? - from?) * t);
+            let dac: [Option<f64>; MAX_DAC_CHANNELS] = std::array::from_fn(|channel| lerp(dac_a[channel], dac_b[channel]));
+            let offset = Duration::nanoseconds((span.num_nanoseconds().unwrap_or(i64::MAX) as f64 * t).round() as i64);
+            SensorData {
+                session_id: a.session_id,
+                timestamp: start.plus(offset),
+                latitude: lerp(a.latitude, b.latitude),
+                longitude: lerp_longitude(a.longitude, b.longitude, t),
+                altitude: lerp(a.altitude, b.altitude),
+                accel_x: lerp(a.accel_x, b.accel_x),
+                accel_y: lerp(a.accel_y, b.accel_y),
+                accel_z: lerp(a.accel_z, b.accel_z),
+                gyro_x: lerp(a.gyro_x, b.gyro_x),
+                gyro_y: lerp(a.gyro_y, b.gyro_y),
+                gyro_z: lerp(a.gyro_z, b.gyro_z),
+                mag_x: lerp(a.mag_x, b.mag_x),
+                mag_y: lerp(a.mag_y, b.mag_y),
+                mag_z: lerp(a.mag_z, b.mag_z),
+                fix_quality: a.fix_quality.zip(b.fix_quality).map(|(a, b)| a.min(b)),
+                num_satellites: a.num_satellites.zip(b.num_satellites).map(|(a, b)| a.min(b)),
+                hdop: lerp(a.hdop, b.hdop),
+                temperature_c: lerp(a.temperature_c, b.temperature_c),
+                battery_v: lerp(a.battery_v, b.battery_v),
+                // In the form the earlier record used
+                dac_1: a.dac.is_none().then_some(dac[0]).flatten(),
+                dac_2: a.dac.is_none().then_some(dac[1]).flatten(),
+                dac_3: a.dac.is_none().then_some(dac[2]).flatten(),
+                dac_4: a.dac.is_none().then_some(dac[3]).flatten(),
+                dac: a.dac.as_ref().map(|values| dac[..values.len()].to_vec()),
+                gps_low_quality: false,
+                outside_fence: false,
+                is_outlier: false,
+                is_interpolated: true,
+                accel_filtered: None,
+                cumulative_angles: None,
+                cumulative_distance_m: None,
+                tilt: None,
+                imu_raw: None,
+                dac_raw: None,
+                position_raw: None,
+                message_id: None,
+                extras: serde_json::Map::new(),
+            }
+        })
+        .collect()
+}
+
+// Across the antimeridian, 179 to -179 is 2 degrees east, not 358 west
+fn lerp_longitude(from: Option<f64>, to: Option<f64>, t: f64) -> Option<f64> {
+    let (from, to) = (from?, to?);
+    let delta = (to - from + 540.0).rem_euclid(360.0) - 180.0;
+    let lon = from + delta * t;
+    Some(if lon > 180.0 { lon - 360.0 } else if lon < -180.0 { lon + 360.0 } else { lon })
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use clap::Args;
+
+use crate::SensorData;
+
+// Bounding box the loggers are expected to stay in. Each bound is optional,
+// so a fence can be open on any side; records outside it are stored with
+// outside_fence set, not rejected. A record without a position is never
+// outside.
+#[derive(Args, Debug, Clone, Copy)]
+#[command(next_help_heading = "Geo fence")]
+pub struct GeoFence {
+    /// Southern edge of the geo fence, in degrees
+    #[arg(long, value_name = "DEGREES", allow_negative_numbers = true)]
+    pub geo_fence_min_lat: Option<f64>,
+
+    /// Northern edge of the geo fence, in degrees
+    #[arg(long, value_name = "DEGREES", allow_negative_numbers = true)]
+    pub geo_fence_max_lat: Option<f64>,
+
+    /// Western edge of the geo fence, in degrees; above the eastern edge, the fence spans the antimeridian
+    #[arg(long, value_name = "DEGREES", allow_negative_numbers = true)]
+    pub geo_fence_min_lon: Option<f64>,
+
+    /// Eastern edge of the geo fence, in degrees
+    #[arg(long, value_name = "DEGREES", allow_negative_numbers = true)]
+    pub geo_fence_max_lon: Option<f64>,
+}
+
+impl GeoFence {
+    // Set outside_fence on a record whose position lies beyond the fence,
+    // returning whether it did
+    pub fn apply(&self, data: &mut SensorData) -> bool {
+        let (Some(lat), Some(lon)) = (data.latitude, data.longitude) else {
+            return false;
+        };
+        data.outside_fence = !self.contains(lat, lon);
+        data.outside_fence
+    }
+
+    fn contains(&self, lat: f64, lon: f64) -> bool {
+        let lat_inside = self.geo_fence_min_lat.is_none_or(|min| lat >= min) && self.geo_fence_max_lat.is_none_or(|max| lat <= max);
+        let lon_inside = match (self.geo_fence_min_lon, self.geo_fence_max_lon) {
+            (Some(min), Some(max)) if min > max => lon >= min || lon <= max,
+            (min, max) => min.is_none_or(|min| lon >= min) && max.is_none_or(|max| lon <= max),
+        };
+        lat_inside && lon_inside
+    }
+}
+
+// The bounds as they appear in the effective configuration line
+impl fmt::Display for GeoFence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bounds = [self.geo_fence_min_lat, self.geo_fence_max_lat, self.geo_fence_min_lon, self.geo_fence_max_lon];
+        if bounds.iter().all(Option::is_none) {
+            return write!(f, "off");
+        }
+        let bound = |bound: Option<f64>| bound.map_or_else(|| "open".to_string(), |bound| bound.to_string());
+        write!(
+            f,
+            "lat[{},{}],lon[{},{}]",
+            bound(self.geo_fence_min_lat),
+            bound(self.geo_fence_max_lat),
+            bound(self.geo_fence_min_lon),
+            bound(self.geo_fence_max_lon),
+        )
+    }
+}
@@ -0,0 +1,95 @@
+use std::fmt;
+use clap::{Args, ValueEnum};
+
+use crate::SensorData;
+
+// Thresholds a record's position must meet to be trusted. A record that
+// doesn't report a quality field passes that check, as quality unknown.
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "GPS quality")]
+pub struct GpsQualityGate {
+    /// Lowest fix_quality a position may have (as in NMEA GGA: 1 GPS, 2 DGPS, 4 RTK fixed, 5 RTK float)
+    #[arg(long, value_name = "N")]
+    pub min_fix_quality: Option<u8>,
+
+    /// Fewest satellites a position may be computed from
+    #[arg(long, value_name = "N")]
+    pub min_satellites: Option<u32>,
+
+    /// Highest HDOP a position may have
+    #[arg(long, value_name = "HDOP")]
+    pub max_hdop: Option<f64>,
+
+    /// What happens to the position of a record below the thresholds
+    #[arg(long, value_enum, default_value_t = GpsQualityAction::Flag)]
+    pub gps_quality_action: GpsQualityAction,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpsQualityAction {
+    /// Store the position and set gps_low_quality
+    Flag,
+    /// Store NULL for latitude, longitude and altitude and set gps_low_quality; the other fields are kept
+    Drop,
+}
+
+impl fmt::Display for GpsQualityAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GpsQualityAction::Flag => write!(f, "flag"),
+            GpsQualityAction::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+// The thresholds as they appear in the effective configuration line
+impl fmt::Display for GpsQualityGate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.min_fix_quality.is_none() && self.min_satellites.is_none() && self.max_hdop.is_none() {
+            return write!(f, "off");
+        }
+        let unset = || "any".to_string();
+        write!(
+            f,
+            "{}(min_fix_quality={},min_satellites={},max_hdop={})",
+            self.gps_quality_action,
+            self.min_fix_quality.map_or_else(unset, |min| min.to_string()),
+            self.min_satellites.map_or_else(unset, |min| min.to_string()),
+            self.max_hdop.map_or_else(unset, |max| max.to_string()),
+        )
+    }
+}
+
+impl GpsQualityGate {
+    // Flag, and with `drop` clear, the position of a record below the
+    // thresholds. Returns why it fell short, if it did.
+    pub fn apply(&self, data: &mut SensorData) -> Option<String> {
+        let reason = self.shortfall(data)?;
+        data.gps_low_quality = true;
+        if self.gps_quality_action == GpsQualityAction::Drop {
+            data.latitude = None;
+            data.longitude = None;
+            data.altitude = None;
+        }
+        Some(reason)
+    }
+
+    fn shortfall(&self, data: &SensorData) -> Option<String> {
+        if let (Some(min), Some(fix)) = (self.min_fix_quality, data.fix_quality) {
+            if fix < min {
+                return Some(format!("fix_quality {} is below {}", fix, min));
+            }
+        }
+        if let (Some(min), Some(satellites)) = (self.min_satellites, data.num_satellites) {
+            if satellites < min {
+                return Some(format!("{} satellites are fewer than {}", satellites, min));
+            }
+        }
+        if let (Some(max), Some(hdop)) = (self.max_hdop, data.hdop) {
+            if hdop > max {
+                return Some(format!("hdop {} is above {}", hdop, max));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,201 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use log::{debug, error, info, warn};
+use serde_json::{Map, Value};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::batch::SessionTally;
+use crate::peer::Peer;
+use crate::{
+    claim_device, handle_records, parse_message, reject_unparsed, release_device, ConnectionState, Message,
+    ParseOptions, ServerState, BIND_ADDRESS,
+};
+
+// Code generated from proto/ingest.proto by build.rs
+pub mod proto {
+    tonic::include_proto!("db_receiver");
+}
+
+use proto::ingest_server::{Ingest, IngestServer};
+use proto::{PingReply, PingRequest, SensorReading, UploadSummary};
+
+// Metadata key naming the device behind an upload, as a TCP client's hello does
+const DEVICE_ID_METADATA: &str = "device-id";
+
+struct IngestService {
+    server: Arc<ServerState>,
+}
+
+// Serve the Ingest service on `port` from a thread of its own until the
+// server shuts down or starts draining. Uploads in progress are finished
+// before the returned thread exits.
+pub fn spawn(
+    server: Arc<ServerState>,
+    port: u16,
+    running: Arc<AtomicBool>,
+    accepting: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    let addr: SocketAddr = format!("{}:{}", BIND_ADDRESS, port).parse()?;
+    // Bind here so a port in use fails startup, as the other listeners do
+    let listener = runtime.block_on(tokio::net::TcpListener::bind(addr))?;
+    let incoming = TcpIncoming::from_listener(listener, true, None).map_err(|e| e.to_string())?;
+    info!("Accepting gRPC uploads on port {}...", port);
+    Ok(thread::spawn(move || {
+        let stopped = async move {
+            while running.load(Ordering::SeqCst) && accepting.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        let result = runtime.block_on(
+            Server::builder()
+                .add_service(IngestServer::new(IngestService { server }))
+                .serve_with_incoming_shutdown(incoming, stopped),
+        );
+        match result {
+            Ok(()) => info!("gRPC listener on port {} closed", port),
+            Err(e) => error!("gRPC server on port {} failed: {}", port, e),
+        }
+    }))
+}
+
+#[tonic::async_trait]
+impl Ingest for IngestService {
+    // Each reading is validated and queued as a one-record line from a TCP
+    // client would be. The summary waits for the accepted ones to be
+    // committed, so it can tell which were duplicates.
+    async fn upload(&self, request: Request<Streaming<SensorReading>>) -> Result<Response<UploadSummary>, Status> {
+        let server = &self.server;
+        let addr = Peer::from(request.remote_addr().unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0))));
+        let device_id = request
+            .metadata()
+            .get(DEVICE_ID_METADATA)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let mut stream = request.into_inner();
+        let mut state = ConnectionState::new(&server.config);
+        if let Some(device_id) = &device_id {
+            info!("gRPC upload from {} identified as device '{}'", addr, device_id);
+            claim_device(&server.devices, device_id, addr);
+        }
+        state.device_id = device_id;
+        let tally: Arc<SessionTally> = Arc::default();
+
+        let mut summary = UploadSummary::default();
+        let result = loop {
+            let reading = match stream.message().await {
+                Ok(Some(reading)) => reading,
+                Ok(None) => break Ok(()),
+                Err(status) => break Err(status),
+            };
+            // Validation and queueing block, on backpressure among others
+            let accepted = tokio::task::block_in_place(|| ingest(server, addr, &mut state, reading, &tally));
+            if accepted {
+                summary.accepted += 1;
+            } else {
+                summary.rejected += 1;
+            }
+        };
+        if let Some(device_id) = &state.device_id {
+            release_device(&server.devices, device_id, addr);
+        }
+        if let Err(status) = result {
+            warn!("gRPC upload from {} ended with an error: {}", addr, status);
+            return Err(status);
+        }
+
+        if let Err(e) = tokio::task::block_in_place(|| server.writer.flush()) {
+            // The records stay in the WAL or fallback files; only the count
+            // of duplicates among them is unknown
+            warn!("Failed to commit gRPC upload from {}: {}", addr, e);
+        }
+        summary.duplicates = tally.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).duplicates;
+        info!(
+            "gRPC upload from {}: {} reading(s) accepted ({} duplicate), {} rejected",
+            addr, summary.accepted, summary.duplicates, summary.rejected
+        );
+        Ok(Response::new(summary))
+    }
+
+    async fn ping(&self, _request: Request<PingRequest>) -> Result<Response<PingReply>, Status> {
+        Ok(Response::new(PingReply {
+            server_time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        }))
+    }
+}
+
+// Validate and queue one reading, returning whether it was accepted. It is
+// turned into the JSON record a TCP client would send, which is also what
+// dead_letters and the quarantine keep of it.
+fn ingest(
+    server: &ServerState,
+    addr: Peer,
+    state: &mut ConnectionState,
+    reading: SensorReading,
+    tally: &Arc<SessionTally>,
+) -> bool {
+    let config = &server.config;
+    let line = to_line(reading);
+    debug!("Received gRPC reading from {}: {}", addr, line);
+    let rows = match parse_message(&line, &ParseOptions::from(config)) {
+        Ok(Message::SensorData(rows)) => rows,
+        Ok(message) => {
+            // A reading has no type field, so this can't happen
+            warn!("gRPC reading from {} parsed as a control message: {:?}", addr, message);
+            return false;
+        }
+        Err(e) => {
+            reject_unparsed(None, addr, state, server, &line, &e.to_string());
+            return false;
+        }
+    };
+    match handle_records(server, None, addr, state, &line, rows, tally) {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            error!("Failed to queue records from {}: {}", addr, e);
+            false
+        }
+    }
+}
+
+// A reading as a JSON record, leaving out the fields it doesn't set. Extras
+// go in first so a known field of the same name wins.
+fn to_line(reading: SensorReading) -> String {
+    let mut object: Map<String, Value> = reading.extras.into_iter().map(|(key, value)| (key, Value::from(value))).collect();
+    let mut set = |key: &str, value: Option<Value>| {
+        if let Some(value) = value {
+            object.insert(key.to_string(), value);
+        }
+    };
+    set("sessionID", reading.session_id.map(Value::from));
+    set("timestamp", Some(Value::from(reading.timestamp)));
+    for (key, value) in [
+        ("latitude", reading.latitude),
+        ("longitude", reading.longitude),
+        ("altitude", reading.altitude),
+        ("accel_x", reading.accel_x),
+        ("accel_y", reading.accel_y),
+        ("accel_z", reading.accel_z),
+        ("gyro_x", reading.gyro_x),
+        ("gyro_y", reading.gyro_y),
+        ("gyro_z", reading.gyro_z),
+        ("mag_x", reading.mag_x),
+        ("mag_y", reading.mag_y),
+        ("mag_z", reading.mag_z),
+        ("hdop", reading.hdop),
+        ("temperature_c", reading.temperature_c),
+        ("battery_v", reading.battery_v),
+    ] {
+        set(key, value.map(Value::from));
+    }
+    set("fix_quality", reading.fix_quality.map(Value::from));
+    set("num_satellites", reading.num_satellites.map(Value::from));
+    set("dac", (!reading.dac.is_empty()).then(|| Value::from(reading.dac)));
+    set("message_id", reading.message_id.map(Value::from));
+    serde_json::to_string(&object).unwrap_or_default()
+}
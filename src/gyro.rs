@@ -0,0 +1,50 @@
+use chrono::{DateTime, Utc};
+
+use crate::timestamp::ClientTimestamp;
+use crate::SensorData;
+
+// Integrates one connection's gyroscope readings into cumulative angles:
+// pitch from gyro_x, roll from gyro_y and yaw from gyro_z, each the rate
+// times the time since the previous record. The angles start over at 0 when
+// the session changes. Drift grows with time, as with any plain integration,
+// so they suit relative motion over a run rather than absolute attitude.
+#[derive(Debug, Default)]
+pub struct GyroIntegrator {
+    pitch: f64,
+    roll: f64,
+    yaw: f64,
+    last_ts: Option<DateTime<Utc>>,
+    session_id: Option<i64>,
+}
+
+impl GyroIntegrator {
+    // Fill `cumulative_angles` from the gyroscope values as received. The
+    // first record of a session has no interval to integrate over, so its
+    // angles stay NULL, as do those of a record without all three rates or
+    // whose timestamp can't be read or goes backwards.
+    pub fn apply(&mut self, data: &mut SensorData) {
+        if data.session_id != self.session_id {
+            *self = GyroIntegrator { session_id: data.session_id, ..Default::default() };
+        }
+        let Some(ts) = ClientTimestamp::parse(&data.timestamp).map(|ts| ts.to_utc()) else {
+            return;
+        };
+        let Some(last_ts) = self.last_ts.filter(|&last_ts| last_ts <= ts) else {
+            // Keep the later time, so an out-of-order record isn't integrated twice
+            self.last_ts = self.last_ts.max(Some(ts));
+            return;
+        };
+        self.last_ts = Some(ts);
+        let (Some(gyro_x), Some(gyro_y), Some(gyro_z)) = (data.gyro_x, data.gyro_y, data.gyro_z) else {
+            return;
+        };
+        let dt = (ts - last_ts).num_nanoseconds().map_or(f64::INFINITY, |ns| ns as f64 / 1e9);
+        if !dt.is_finite() {
+            return;
+        }
+        self.pitch += gyro_x * dt;
+        self.roll += gyro_y * dt;
+        self.yaw += gyro_z * dt;
+        data.cumulative_angles = Some([self.pitch, self.roll, self.yaw]);
+    }
+}
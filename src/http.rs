@@ -0,0 +1,573 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use flate2::read::GzDecoder;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+
+use crate::annotations::{self, Annotation};
+use crate::batch::SessionTally;
+use crate::error_reply::{ErrorCode, ErrorReply};
+use crate::peer::Peer;
+use crate::events;
+use crate::stats;
+use crate::{handle_records, parse_message, reject_unparsed, save_session_stats, ConnectionState, Message, ParseOptions, ServerState, BIND_ADDRESS};
+
+// How long a client may take to send its whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Most header lines read before a request is turned away
+const MAX_HEADERS: usize = 100;
+
+// A client address not heard from for this long is forgotten, along with its
+// filters and outlier statistics
+const SOURCE_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// What the server keeps about a client address between requests, as it
+// would about a TCP connection. Loggers open a new connection per request, so
+// the address without its port stands for the logger.
+struct Source {
+    state: ConnectionState,
+    tally: Arc<SessionTally>,
+    last_seen: Instant,
+}
+
+type Sources = Mutex<HashMap<IpAddr, Arc<Mutex<Source>>>>;
+
+// Answer to a request whose records were parsed
+#[derive(Serialize, Debug)]
+struct IngestResult {
+    accepted: u64,
+    rejected: u64,
+}
+
+// A request as far as the handler cares: method, path and body
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+// Why a request couldn't be read, with the status to answer it with
+struct Refusal {
+    status: &'static str,
+    code: ErrorCode,
+    error: String,
+}
+
+impl Refusal {
+    fn new(status: &'static str, code: ErrorCode, error: &str) -> Self {
+        Refusal { status, code, error: error.to_string() }
+    }
+}
+
+// Reads from a client's stream, each read waiting only as long as is left of
+// the time the request may take, so a slow sender can't stretch it out
+struct Deadline {
+    stream: TcpStream,
+    until: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+// Accept `POST /ingest` requests on `port` until the server shuts down or
+// starts draining, each on a thread of its own. Requests in progress are
+// finished before the returned thread exits.
+pub fn spawn(
+    server: Arc<ServerState>,
+    port: u16,
+    running: Arc<AtomicBool>,
+    accepting: Arc<AtomicBool>,
+) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind((BIND_ADDRESS, port))?;
+    listener.set_nonblocking(true)?;
+    info!("Accepting HTTP POST /ingest and serving statistics, events and annotations on port {}...", port);
+    let sources: Arc<Sources> = Arc::default();
+    Ok(thread::spawn(move || {
+        let mut requests: Vec<JoinHandle<()>> = Vec::new();
+        while running.load(Ordering::SeqCst) && accepting.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    let (server, sources) = (server.clone(), sources.clone());
+                    requests.push(thread::spawn(move || {
+                        if let Err(e) = respond(stream, Peer::from(addr), &server, &sources) {
+                            warn!("HTTP request from {} failed: {}", addr, e);
+                        }
+                    }));
+                    requests.retain(|request| !request.is_finished());
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(100)),
+                Err(e) => error!("HTTP connection error: {}", e),
+            }
+        }
+        drop(listener);
+        for request in requests {
+            let _ = request.join();
+        }
+        info!("HTTP listener on port {} closed", port);
+    }))
+}
+
+fn respond(mut stream: TcpStream, addr: Peer, server: &ServerState, sources: &Sources) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let (status, body) = match read_request(&mut stream, server.config.max_line_bytes, server.config.max_http_body_bytes) {
+        Ok(request) => {
+            let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+            match (request.method.as_str(), path) {
+                ("POST", "/ingest") => ingest(server, addr, sources, &request.body),
+                (_, "/ingest") => ("405 Method Not Allowed", "use POST\n".to_string()),
+                ("GET", "/events") => list_events(server, query),
+                (_, "/events") => ("405 Method Not Allowed", "use GET\n".to_string()),
+                ("POST", "/annotations") => add_annotation(server, &request.body),
+                ("GET", "/annotations") => list_annotations(server, query),
+                (_, "/annotations") => ("405 Method Not Allowed", "use GET or POST\n".to_string()),
+                (method, path) if path.starts_with("/annotations/") => delete_annotation(server, method, path),
+                (method, path) if path.starts_with("/sessions/") => session_stats(server, method, path),
+                _ => ("404 Not Found", "not found\n".to_string()),
+            }
+        }
+        Err(refusal) => {
+            warn!("Refused HTTP request from {}: {}", addr, refusal.error);
+            (refusal.status, to_line(&ErrorReply::new(refusal.code, &refusal.error, "")))
+        }
+    };
+    let content_type = if body.starts_with(['{', '[']) { "application/json" } else { "text/plain" };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+// GET /sessions/<id>/stats/live answers the running mean and standard
+// deviation of each field of a session being stored; /stats/final those
+// saved when its connection closed or it ended
+fn session_stats(server: &ServerState, method: &str, path: &str) -> (&'static str, String) {
+    let parts: Vec<&str> = path.split('/').collect();
+    let (session_id, live) = match parts[..] {
+        ["", "sessions", id, "stats", which @ ("live" | "final")] => match id.parse::<i64>() {
+            Ok(id) => (id, which == "live"),
+            Err(_) => return ("404 Not Found", "not found\n".to_string()),
+        },
+        _ => return ("404 Not Found", "not found\n".to_string()),
+    };
+    if method != "GET" {
+        return ("405 Method Not Allowed", "use GET\n".to_string());
+    }
+    let snapshot = if live {
+        server.live_stats.get(&session_id).map(|stats| stats.snapshot())
+    } else {
+        match server.writer.call(move |conn| stats::final_snapshot(conn, session_id)) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("Failed to read the statistics of session {}: {}", session_id, e);
+                return ("500 Internal Server Error", format!("can't read statistics: {}\n", e));
+            }
+        }
+    };
+    match snapshot {
+        Some(snapshot) => ("200 OK", to_line(&snapshot)),
+        None if live => ("404 Not Found", format!("no records of session {} are being stored\n", session_id)),
+        None => ("404 Not Found", format!("no statistics saved for session {}\n", session_id)),
+    }
+}
+
+// GET /events answers the stored events as a JSON array, oldest first and
+// at most --max-query-rows of them. ?session=<id> and ?label=<label> narrow
+// them down.
+fn list_events(server: &ServerState, query: &str) -> (&'static str, String) {
+    let mut session_id = None;
+    let mut label = None;
+    let params = match query_params(query) {
+        Ok(params) => params,
+        Err(e) => return ("400 Bad Request", e),
+    };
+    for (name, value) in params {
+        match name {
+            "session" => match value.parse::<i64>() {
+                Ok(id) => session_id = Some(id),
+                Err(_) => return ("400 Bad Request", format!("session must be a number, not '{}'\n", value)),
+            },
+            "label" => label = Some(value),
+            _ => return ("400 Bad Request", format!("unknown parameter '{}'; use session or label\n", name)),
+        }
+    }
+    let limit = server.config.max_query_rows;
+    match server.writer.call(move |conn| events::list(conn, session_id, label.as_deref(), limit)) {
+        Ok(events) => ("200 OK", to_line(&events)),
+        Err(e) => {
+            error!("Failed to read events: {}", e);
+            ("500 Internal Server Error", format!("can't read events: {}\n", e))
+        }
+    }
+}
+
+// POST /annotations stores the annotation in the body and answers it with
+// its new ID
+fn add_annotation(server: &ServerState, body: &[u8]) -> (&'static str, String) {
+    let mut annotation: Annotation = match serde_json::from_slice(body) {
+        Ok(annotation) => annotation,
+        Err(e) => return ("400 Bad Request", format!("invalid annotation: {}\n", e)),
+    };
+    if let Err(e) = annotation.check() {
+        return ("400 Bad Request", format!("invalid annotation: {}\n", e));
+    }
+    match server.writer.call(move |conn| annotations::insert(conn, &mut annotation).map(|()| annotation)) {
+        Ok(annotation) => {
+            info!("Annotated session {} with '{}' (annotation {})", annotation.session_id, annotation.label, annotation.id);
+            ("201 Created", to_line(&annotation))
+        }
+        Err(e) => {
+            error!("Failed to store an annotation: {}", e);
+            ("500 Internal Server Error", format!("can't store the annotation: {}\n", e))
+        }
+    }
+}
+
+// GET /annotations?session=<id> answers a session's annotations as a JSON
+// array, in the order they start
+fn list_annotations(server: &ServerState, query: &str) -> (&'static str, String) {
+    let params = match query_params(query) {
+        Ok(params) => params,
+        Err(e) => return ("400 Bad Request", e),
+    };
+    let session_id = match params[..] {
+        [("session", ref value)] => match value.parse::<i64>() {
+            Ok(id) => id,
+            Err(_) => return ("400 Bad Request", format!("session must be a number, not '{}'\n", value)),
+        },
+        _ => return ("400 Bad Request", "give the session as ?session=<id>\n".to_string()),
+    };
+    match server.writer.call(move |conn| annotations::get_annotations(conn, session_id)) {
+        Ok(annotations) => ("200 OK", to_line(&annotations)),
+        Err(e) => {
+            error!("Failed to read the annotations of session {}: {}", session_id, e);
+            ("500 Internal Server Error", format!("can't read annotations: {}\n", e))
+        }
+    }
+}
+
+// DELETE /annotations/<id>
+fn delete_annotation(server: &ServerState, method: &str, path: &str) -> (&'static str, String) {
+    let Ok(id) = path["/annotations/".len()..].parse::<i64>() else {
+        return ("404 Not Found", "not found\n".to_string());
+    };
+    if method != "DELETE" {
+        return ("405 Method Not Allowed", "use DELETE\n".to_string());
+    }
+    match server.writer.call(move |conn| annotations::delete(conn, id)) {
+        Ok(true) => {
+            info!("Deleted annotation {}", id);
+            ("204 No Content", String::new())
+        }
+        Ok(false) => ("404 Not Found", format!("no annotation {}\n", id)),
+        Err(e) => {
+            error!("Failed to delete annotation {}: {}", id, e);
+            ("500 Internal Server Error", format!("can't delete the annotation: {}\n", e))
+        }
+    }
+}
+
+// The name and decoded value of each parameter of a query string, in order
+fn query_params(query: &str) -> Result<Vec<(&str, String)>, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            decode_query(value).map(|value| (name, value)).ok_or_else(|| format!("malformed value of '{}'\n", name))
+        })
+        .collect()
+}
+
+// A query string value with + and %XX escapes undone, or None if an escape
+// is malformed or the result isn't UTF-8
+fn decode_query(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = rest.get(..2).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+                bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+                rest = &rest[2..];
+            }
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+// Why reading a request failed: it ran out of time, or it broke off
+fn read_failed(e: io::Error) -> Refusal {
+    match e.kind() {
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            Refusal::new("408 Request Timeout", ErrorCode::ParseError, &format!("request not received within {}s", REQUEST_TIMEOUT.as_secs()))
+        }
+        _ => Refusal::new("400 Bad Request", ErrorCode::ParseError, &e.to_string()),
+    }
+}
+
+// Read one line of the request head into `line`, refusing it with `status`
+// once it runs past `max_line_bytes`
+fn read_head_line(reader: &mut impl BufRead, line: &mut String, max_line_bytes: usize, status: &'static str) -> Result<(), Refusal> {
+    line.clear();
+    // Read at most one byte past the limit, enough to tell the line is too long
+    reader.take(max_line_bytes as u64 + 1).read_line(line).map_err(read_failed)?;
+    if line.len() > max_line_bytes && !line.ends_with('\n') {
+        return Err(Refusal::new(status, ErrorCode::OversizedLine, &format!("line of the request head exceeds {} bytes", max_line_bytes)));
+    }
+    Ok(())
+}
+
+// Read the request line and the headers that matter, each line at most
+// `max_line_bytes`, and a body of at most `max_body_bytes`, inflated if it
+// was gzipped. All of it must arrive within REQUEST_TIMEOUT.
+fn read_request(stream: &mut TcpStream, max_line_bytes: usize, max_body_bytes: usize) -> Result<Request, Refusal> {
+    let bad_request = |error: String| Refusal::new("400 Bad Request", ErrorCode::ParseError, &error);
+    let clone = stream.try_clone().map_err(|e| bad_request(e.to_string()))?;
+    let mut reader = BufReader::new(Deadline { stream: clone, until: Instant::now() + REQUEST_TIMEOUT });
+    let mut line = String::new();
+    read_head_line(&mut reader, &mut line, max_line_bytes, "400 Bad Request")?;
+    let (method, path) = match line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        [method, path] => (method.to_string(), path.to_string()),
+        _ => return Err(bad_request("malformed request line".to_string())),
+    };
+
+    let mut content_length = None;
+    let mut gzip = false;
+    let mut expect_continue = false;
+    for headers in 0.. {
+        if headers == MAX_HEADERS {
+            return Err(bad_request(format!("more than {} headers", MAX_HEADERS)));
+        }
+        read_head_line(&mut reader, &mut line, max_line_bytes, "431 Request Header Fields Too Large")?;
+        let header = line.trim();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(bad_request(format!("malformed header '{}'", header)));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = Some(value.parse::<usize>().map_err(|_| bad_request(format!("bad Content-Length '{}'", value)))?)
+            }
+            "content-encoding" => match value.to_ascii_lowercase().as_str() {
+                "gzip" => gzip = true,
+                "identity" => {}
+                _ => {
+                    return Err(Refusal::new(
+                        "415 Unsupported Media Type",
+                        ErrorCode::Unsupported,
+                        &format!("Content-Encoding '{}' is not supported; use gzip or none", value),
+                    ))
+                }
+            },
+            "transfer-encoding" => {
+                return Err(Refusal::new("411 Length Required", ErrorCode::Unsupported, "send the body with a Content-Length"))
+            }
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+    if method != "POST" {
+        return Ok(Request { method, path, body: Vec::new() });
+    }
+
+    let too_large = || {
+        Refusal::new(
+            "413 Payload Too Large",
+            ErrorCode::OversizedLine,
+            &format!("body exceeds {} bytes", max_body_bytes),
+        )
+    };
+    let Some(length) = content_length else {
+        return Err(Refusal::new("411 Length Required", ErrorCode::Unsupported, "send the body with a Content-Length"));
+    };
+    if length > max_body_bytes {
+        return Err(too_large());
+    }
+    if expect_continue {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").map_err(|e| bad_request(e.to_string()))?;
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => bad_request(format!("incomplete body: {}", e)),
+        _ => read_failed(e),
+    })?;
+    if gzip {
+        // Read one byte past the limit, so a body that inflates beyond it is caught
+        let mut inflated = Vec::new();
+        GzDecoder::new(&body[..])
+            .take(max_body_bytes as u64 + 1)
+            .read_to_end(&mut inflated)
+            .map_err(|e| bad_request(format!("body is not valid gzip: {}", e)))?;
+        if inflated.len() > max_body_bytes {
+            return Err(too_large());
+        }
+        body = inflated;
+    }
+    Ok(Request { method, path, body })
+}
+
+// Parse a body as one line from a TCP client would be, then validate and
+// store each of its records separately so the answer can count them
+fn ingest(server: &ServerState, addr: Peer, sources: &Sources, body: &[u8]) -> (&'static str, String) {
+    let config = &server.config;
+    if server.breaker.is_open() {
+        server.metrics.breaker_refused_records.fetch_add(1, Ordering::Relaxed);
+        let error = format!(
+            "database unavailable; ingest is paused, retry in {}s",
+            server.breaker.retry_after().as_secs()
+        );
+        let body = String::from_utf8_lossy(body);
+        return ("503 Service Unavailable", to_line(&ErrorReply::new(ErrorCode::DatabaseUnavailable, &error, &body)));
+    }
+    let source = source(server, sources, addr);
+    let mut source = lock(&source);
+    let source = &mut *source;
+    let state = &mut source.state;
+
+    let text = match std::str::from_utf8(body) {
+        Ok(text) => text.trim(),
+        Err(e) => {
+            let text = String::from_utf8_lossy(body);
+            let error = format!("body is not valid UTF-8: {}", e);
+            reject_unparsed(None, addr, state, server, text.trim(), &error);
+            return ("400 Bad Request", to_line(&ErrorReply::new(ErrorCode::ParseError, &error, &text)));
+        }
+    };
+    debug!("Received HTTP body from {}: {}", addr, text);
+    let rows = match parse_message(text, &ParseOptions::from(config)) {
+        Ok(Message::SensorData(rows)) => rows,
+        Ok(message) => {
+            debug!("Refusing control message from {} over HTTP: {:?}", addr, message);
+            let error = "only records can be posted; control messages need a TCP connection";
+            return ("400 Bad Request", to_line(&ErrorReply::new(ErrorCode::Unsupported, error, text)));
+        }
+        Err(e) => {
+            reject_unparsed(None, addr, state, server, text, &e.to_string());
+            return ("400 Bad Request", to_line(&ErrorReply::new(ErrorCode::ParseError, &e.to_string(), text)));
+        }
+    };
+
+    let single = rows.len() == 1;
+    let mut result = IngestResult { accepted: 0, rejected: 0 };
+    for row in rows {
+        // A rejected record is kept in dead_letters as the client sent it,
+        // or as parsed when it was one of several
+        let line = if single { text.to_string() } else { serde_json::to_string(&row).unwrap_or_default() };
+        match handle_records(server, None, addr, state, &line, vec![row], &source.tally) {
+            Ok(true) => result.accepted += 1,
+            Ok(false) => result.rejected += 1,
+            Err(e) => {
+                error!("Failed to queue records from {}: {}", addr, e);
+                result.rejected += 1;
+            }
+        }
+    }
+    info!("HTTP POST from {}: {} record(s) accepted, {} rejected", addr, result.accepted, result.rejected);
+    ("200 OK", to_line(&result))
+}
+
+// The state kept for the client's address, forgetting addresses gone quiet
+fn source(server: &ServerState, sources: &Sources, addr: Peer) -> Arc<Mutex<Source>> {
+    let Peer::Ip(socket_addr) = addr else {
+        unreachable!("HTTP clients connect over TCP");
+    };
+    let mut sources = sources.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Sessions of the addresses forgotten, whose statistics are saved as
+    // when a connection closes
+    let mut forgotten = BTreeSet::new();
+    sources.retain(|_, source| match source.try_lock() {
+        Ok(source) if source.last_seen.elapsed() >= SOURCE_IDLE_TIMEOUT => {
+            forgotten.extend(&source.state.stats_sessions);
+            false
+        }
+        Ok(_) => true,
+        // Busy with a request, so not idle
+        Err(_) => true,
+    });
+    let source = sources.entry(socket_addr.ip()).or_insert_with(|| {
+        Arc::new(Mutex::new(Source {
+            state: ConnectionState::new(&server.config),
+            tally: Arc::default(),
+            last_seen: Instant::now(),
+        }))
+    });
+    lock(source).last_seen = Instant::now();
+    let source = source.clone();
+    drop(sources);
+    save_session_stats(server, &forgotten);
+    source
+}
+
+fn lock(source: &Mutex<Source>) -> MutexGuard<'_, Source> {
+    source.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn to_line<T: Serialize>(message: &T) -> String {
+    let mut line = serde_json::to_string(message).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What read_request makes of `request`, sent by a client that then waits
+    fn read(request: &[u8], max_line_bytes: usize) -> Result<Request, Refusal> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        read_request(&mut stream, max_line_bytes, 1024)
+    }
+
+    #[test]
+    fn oversized_header_is_refused() {
+        let mut request = b"POST /ingest HTTP/1.1\r\nX-Padding: ".to_vec();
+        // No newline: without the limit the server would keep reading
+        request.extend_from_slice(&[b'a'; 4096]);
+        let refusal = read(&request, 256).err().expect("header over the limit");
+        assert_eq!(refusal.status, "431 Request Header Fields Too Large");
+    }
+
+    #[test]
+    fn oversized_request_line_is_refused() {
+        let mut request = b"GET /".to_vec();
+        request.extend_from_slice(&[b'a'; 4096]);
+        let refusal = read(&request, 256).err().expect("request line over the limit");
+        assert_eq!(refusal.status, "400 Bad Request");
+    }
+
+    #[test]
+    fn headers_within_the_limit_are_read() {
+        let request = b"POST /ingest HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+        let request = read(request, 64).ok().expect("request within the limits");
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/ingest"));
+        assert_eq!(request.body, b"{}");
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt;
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::SensorData;
+
+// How lines in InfluxDB line protocol map onto records
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "InfluxDB line protocol")]
+pub struct InfluxOptions {
+    /// Measurement line protocol records must name; lines for any other are rejected
+    #[arg(long, value_name = "NAME", default_value = "sensors")]
+    pub influx_measurement: String,
+
+    /// Store a line protocol tag or field under another name, e.g. lat=latitude; repeatable
+    #[arg(long = "influx-field", value_name = "KEY=FIELD", value_parser = parse_mapping)]
+    pub influx_fields: Vec<(String, String)>,
+
+    /// Unit of line protocol timestamps
+    #[arg(long, value_enum, default_value_t = Precision::Ns)]
+    pub influx_precision: Precision,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    /// Nanoseconds, InfluxDB's default
+    Ns,
+    /// Microseconds
+    Us,
+    /// Milliseconds
+    Ms,
+    /// Seconds
+    S,
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Precision::Ns => write!(f, "ns"),
+            Precision::Us => write!(f, "us"),
+            Precision::Ms => write!(f, "ms"),
+            Precision::S => write!(f, "s"),
+        }
+    }
+}
+
+// The mapping as it appears in the effective configuration line
+impl fmt::Display for InfluxOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({}", self.influx_measurement, self.influx_precision)?;
+        for (key, field) in &self.influx_fields {
+            write!(f, ",{}={}", key, field)?;
+        }
+        write!(f, ")")
+    }
+}
+
+fn parse_mapping(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, field)) if !key.is_empty() && !field.is_empty() => Ok((key.to_string(), field.to_string())),
+        _ => Err("expected KEY=FIELD".to_string()),
+    }
+}
+
+impl InfluxOptions {
+    // The record field a tag or field key is stored under. The session tag
+    // is the sessionID unless mapped otherwise; anything that isn't a known
+    // field ends up among the extras.
+    fn field_name<'a>(&'a self, key: &'a str) -> &'a str {
+        match self.influx_fields.iter().find(|(from, _)| from == key) {
+            Some((_, field)) => field,
+            None if key == "session" => "sessionID",
+            None => key,
+        }
+    }
+}
+
+// Lines that don't start like JSON are taken to be line protocol
+pub fn is_line_protocol(line: &str) -> bool {
+    !matches!(line.trim_start().chars().next(), Some('{' | '['))
+}
+
+// Read one line such as
+// `sensors,session=12 latitude=44.5,longitude=-123.2,accel_x=0.1 1716026400123000000`
+// into a record. Without a timestamp the record is stamped with the time it
+// arrived, as InfluxDB does.
+pub fn parse(line: &str, options: &InfluxOptions) -> Result<SensorData, String> {
+    let line = line.trim();
+    let (series, rest) = split_unescaped(line, ' ');
+    let rest = rest.ok_or("line protocol needs a measurement followed by fields")?;
+    let (fields, timestamp) = split_fields(rest.trim_start());
+    let mut series = split_all(series, ',').into_iter();
+    let measurement = unescape(series.next().unwrap_or_default());
+    if measurement != options.influx_measurement {
+        return Err(format!("measurement '{}' is not '{}'", measurement, options.influx_measurement));
+    }
+
+    let mut record = Map::new();
+    for tag in series {
+        let (key, value) = key_value(tag, "tag")?;
+        insert(&mut record, options.field_name(&key), tag_value(&unescape(value)))?;
+    }
+    if fields.is_empty() {
+        return Err("line protocol needs at least one field".to_string());
+    }
+    for field in split_all(fields, ',') {
+        let (key, value) = key_value(field, "field")?;
+        let value = field_value(value).map_err(|e| format!("field '{}': {}", key, e))?;
+        insert(&mut record, options.field_name(&key), value)?;
+    }
+    if record.contains_key("timestamp") {
+        return Err("'timestamp' can't be a tag or field; it is the last element of the line".to_string());
+    }
+    let timestamp = match timestamp.map(str::trim).filter(|t| !t.is_empty()) {
+        Some(timestamp) => from_epoch(timestamp, options.influx_precision)?,
+        None => Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+    };
+    record.insert("timestamp".to_string(), Value::String(timestamp));
+
+    SensorData::deserialize(Value::Object(record)).map_err(|e| e.to_string())
+}
+
+fn insert(record: &mut Map<String, Value>, name: &str, value: Value) -> Result<(), String> {
+    match record.insert(name.to_string(), value) {
+        Some(_) => Err(format!("'{}' is given more than once", name)),
+        None => Ok(()),
+    }
+}
+
+fn key_value<'a>(pair: &'a str, kind: &str) -> Result<(String, &'a str), String> {
+    match split_unescaped(pair, '=') {
+        (key, Some(value)) if !key.is_empty() && !value.is_empty() => Ok((unescape(key), value)),
+        _ => Err(format!("{} '{}' is not key=value", kind, pair)),
+    }
+}
+
+// Tags are always strings in line protocol, but a session tag is a number
+// here, so numbers are stored as numbers
+fn tag_value(value: &str) -> Value {
+    if let Ok(n) = value.parse::<i64>() {
+        return Value::from(n);
+    }
+    match value.parse::<f64>() {
+        Ok(n) if n.is_finite() => Value::from(n),
+        _ => Value::String(value.to_string()),
+    }
+}
+
+// A field value: a float, an integer (`5i`) or unsigned integer (`5u`), a
+// quoted string or a boolean
+fn field_value(value: &str) -> Result<Value, String> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let inner = quoted.strip_suffix('"').ok_or("string value is missing its closing quote")?;
+        return Ok(Value::String(inner.replace("\\\"", "\"").replace("\\\\", "\\")));
+    }
+    if let Some(integer) = value.strip_suffix('i') {
+        return integer.parse::<i64>().map(Value::from).map_err(|_| format!("'{}' is not an integer", value));
+    }
+    if let Some(integer) = value.strip_suffix('u') {
+        return integer.parse::<u64>().map(Value::from).map_err(|_| format!("'{}' is not an unsigned integer", value));
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Ok(Value::Bool(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    match value.parse::<f64>() {
+        Ok(n) if n.is_finite() => Ok(Value::from(n)),
+        _ => Err(format!("'{}' is not a number, string or boolean", value)),
+    }
+}
+
+// The stored form of a line protocol timestamp, keeping as many fractional
+// digits as it has
+fn from_epoch(timestamp: &str, precision: Precision) -> Result<String, String> {
+    let value: i64 = timestamp.parse().map_err(|_| format!("timestamp '{}' is not an integer", timestamp))?;
+    let nanos = match precision {
+        Precision::Ns => Some(value),
+        Precision::Us => value.checked_mul(1_000),
+        Precision::Ms => value.checked_mul(1_000_000),
+        Precision::S => value.checked_mul(1_000_000_000),
+    };
+    let time = nanos
+        .map(DateTime::<Utc>::from_timestamp_nanos)
+        .ok_or_else(|| format!("timestamp {} is out of range", timestamp))?;
+    Ok(time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+// The field set and the optional timestamp after it. Spaces inside quoted
+// string values belong to the field set.
+fn split_fields(rest: &str) -> (&str, Option<&str>) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ' ' if !quoted => return (&rest[..i], Some(&rest[i + 1..])),
+            _ => {}
+        }
+    }
+    (rest, None)
+}
+
+// Split at the first separator that isn't escaped or inside a quoted string
+fn split_unescaped(text: &str, separator: char) -> (&str, Option<&str>) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if separator != ' ' => quoted = !quoted,
+            _ if c == separator && !quoted => return (&text[..i], Some(&text[i + c.len_utf8()..])),
+            _ => {}
+        }
+    }
+    (text, None)
+}
+
+fn split_all(mut text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    loop {
+        match split_unescaped(text, separator) {
+            (part, Some(rest)) => {
+                parts.push(part);
+                text = rest;
+            }
+            (part, None) => {
+                parts.push(part);
+                return parts;
+            }
+        }
+    }
+}
+
+// Measurement names, tag keys and values, and field keys escape commas,
+// spaces and equals signs with a backslash
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(',' | ' ' | '=' | '\\')) => out.extend(chars.next()),
+            _ => out.push(c),
+        }
+    }
+    out
+}
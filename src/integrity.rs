@@ -0,0 +1,151 @@
+use std::fmt;
+use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use clap::{Args, ValueEnum};
+use log::{error, info, warn};
+use rusqlite::{Connection, OpenFlags};
+
+use crate::metrics::Metrics;
+
+// How long the check waits for a lock before giving up on a run
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Problems SQLite reports are listed in the log and the command's environment
+// up to this many
+const MAX_REPORTED_PROBLEMS: usize = 10;
+
+// A background check of the database file for corruption
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "Integrity check")]
+pub struct IntegrityCheck {
+    /// Check the database for corruption every this many minutes; off by default since a check reads the whole file
+    #[arg(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub integrity_check_interval_mins: Option<u64>,
+
+    /// How thorough the check is
+    #[arg(long, value_enum, default_value_t = CheckMode::Quick)]
+    pub integrity_check_mode: CheckMode,
+
+    /// Shell command run when a check fails, with DB_RECEIVER_DATABASE and DB_RECEIVER_INTEGRITY_PROBLEMS set; use curl for a webhook
+    #[arg(long, value_name = "COMMAND")]
+    pub integrity_check_command: Option<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckMode {
+    /// PRAGMA quick_check: page structure and record formats, in time linear in the file size
+    Quick,
+    /// PRAGMA integrity_check: also that every index matches its table, which takes much longer
+    Full,
+}
+
+impl CheckMode {
+    fn pragma(self) -> &'static str {
+        match self {
+            CheckMode::Quick => "PRAGMA quick_check",
+            CheckMode::Full => "PRAGMA integrity_check",
+        }
+    }
+}
+
+impl fmt::Display for CheckMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckMode::Quick => write!(f, "quick"),
+            CheckMode::Full => write!(f, "full"),
+        }
+    }
+}
+
+impl fmt::Display for IntegrityCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(minutes) = self.integrity_check_interval_mins else {
+            return write!(f, "off");
+        };
+        write!(f, "{} every {}m", self.integrity_check_mode, minutes)?;
+        if self.integrity_check_command.is_some() {
+            write!(f, " with command")?;
+        }
+        Ok(())
+    }
+}
+
+impl IntegrityCheck {
+    // Check `path` on a thread of its own at the configured interval, if one
+    // is set. Each run opens its own read-only connection, which in WAL mode
+    // never holds up the writer.
+    pub fn spawn(&self, path: &'static str, metrics: Arc<Metrics>) {
+        let Some(minutes) = self.integrity_check_interval_mins else {
+            return;
+        };
+        let check = self.clone();
+        let interval = Duration::from_secs(minutes.saturating_mul(60));
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let started = Instant::now();
+            match check.run(path) {
+                Ok(()) => info!(
+                    "Database integrity check ({}) passed in {:.1}s",
+                    check.integrity_check_mode,
+                    started.elapsed().as_secs_f64()
+                ),
+                Err(problems) => {
+                    metrics.integrity_check_failures.fetch_add(1, Ordering::Relaxed);
+                    error!(
+                        "Database integrity check ({}) of {} FAILED: {}",
+                        check.integrity_check_mode,
+                        path,
+                        // SQLite lists several problems in one row at times
+                        problems.join("\n").replace('\n', "; ")
+                    );
+                    check.notify(path, &problems);
+                }
+            }
+        });
+    }
+
+    // The problems SQLite found, or why it couldn't look. A database too
+    // damaged to read fails the check as well.
+    fn run(&self, path: &str) -> Result<(), Vec<String>> {
+        let check = || -> rusqlite::Result<Vec<String>> {
+            let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            let mut stmt = conn.prepare(self.integrity_check_mode.pragma())?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            rows.take(MAX_REPORTED_PROBLEMS).collect()
+        };
+        match check() {
+            Ok(result) if result == ["ok"] => Ok(()),
+            Ok(problems) => Err(problems),
+            Err(e) => Err(vec![e.to_string()]),
+        }
+    }
+
+    // Run --integrity-check-command, waiting for it so runs never overlap
+    fn notify(&self, path: &str, problems: &[String]) {
+        let Some(command) = &self.integrity_check_command else {
+            return;
+        };
+        #[cfg(unix)]
+        let mut shell = Command::new("sh");
+        #[cfg(unix)]
+        shell.arg("-c");
+        #[cfg(not(unix))]
+        let mut shell = Command::new("cmd");
+        #[cfg(not(unix))]
+        shell.arg("/C");
+        let status = shell
+            .arg(command)
+            .env("DB_RECEIVER_DATABASE", path)
+            .env("DB_RECEIVER_INTEGRITY_PROBLEMS", problems.join("\n"))
+            .status();
+        match status {
+            Ok(status) if status.success() => info!("Ran --integrity-check-command"),
+            Ok(status) => warn!("--integrity-check-command exited with {}", status),
+            Err(e) => warn!("Could not run --integrity-check-command: {}", e),
+        }
+    }
+}
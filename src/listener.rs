@@ -0,0 +1,237 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use log::{error, info};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::wire::WireFormat;
+
+// Where clients connect when no --listen is given
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 9000;
+
+// Connections the kernel may queue before they are accepted
+const LISTEN_BACKLOG: i32 = 128;
+
+// A TCP port clients connect to, and what its connections may do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerSpec {
+    pub addr: SocketAddr,
+    pub options: ListenOptions,
+}
+
+// Settings every connection accepted on a listener is tagged with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ListenOptions {
+    // The format its clients speak, or None to tell from the first bytes
+    pub format: Option<WireFormat>,
+    // Store records but refuse queries and subscriptions, so clients of this
+    // port can't read back what others sent
+    pub ingest_only: bool,
+}
+
+impl Default for ListenerSpec {
+    fn default() -> Self {
+        ListenerSpec {
+            addr: SocketAddr::new(DEFAULT_BIND_ADDRESS.parse().expect("valid bind address"), DEFAULT_PORT),
+            options: ListenOptions::default(),
+        }
+    }
+}
+
+// Written the way --listen takes it, so a line of the effective configuration
+// can be pasted back
+impl fmt::Display for ListenerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        match self.options.format {
+            None => {}
+            Some(WireFormat::Lines) => write!(f, ",format=lines")?,
+            Some(WireFormat::Msgpack) => write!(f, ",format=msgpack")?,
+        }
+        if self.options.ingest_only {
+            write!(f, ",ingest_only")?;
+        }
+        Ok(())
+    }
+}
+
+// [ADDR:]PORT followed by comma-separated options: format=auto|lines|msgpack
+// and ingest_only. A port alone listens on every interface.
+pub fn parse_listener(value: &str) -> Result<ListenerSpec, String> {
+    let mut parts = value.split(',');
+    let address = parts.next().unwrap_or_default().trim();
+    let addr = match address.parse::<u16>() {
+        Ok(port) => SocketAddr::new(DEFAULT_BIND_ADDRESS.parse().expect("valid bind address"), port),
+        Err(_) => address
+            .to_socket_addrs()
+            .map_err(|e| format!("bad address '{}': {}", address, e))?
+            .next()
+            .ok_or_else(|| format!("'{}' resolves to no address", address))?,
+    };
+    let mut options = ListenOptions::default();
+    for option in parts.map(str::trim) {
+        match option.split_once('=') {
+            Some(("format", "auto")) => options.format = None,
+            Some(("format", "lines")) => options.format = Some(WireFormat::Lines),
+            Some(("format", "msgpack")) => options.format = Some(WireFormat::Msgpack),
+            Some(("format", other)) => return Err(format!("unknown format '{}'; expected auto, lines or msgpack", other)),
+            None if option == "ingest_only" => options.ingest_only = true,
+            _ => return Err(format!("unknown listener option '{}'; expected format=... or ingest_only", option)),
+        }
+    }
+    Ok(ListenerSpec { addr, options })
+}
+
+// The configured listeners, or the one on port 9000 when none are
+pub fn configured(specs: &[ListenerSpec]) -> Vec<ListenerSpec> {
+    if specs.is_empty() {
+        vec![ListenerSpec::default()]
+    } else {
+        specs.to_vec()
+    }
+}
+
+// The listeners as they appear in the effective configuration line
+pub fn describe(specs: &[ListenerSpec]) -> String {
+    configured(specs).iter().map(ListenerSpec::to_string).collect::<Vec<_>>().join(";")
+}
+
+// A listener that couldn't be bound, which stops the server from starting
+#[derive(Debug)]
+pub struct BindError {
+    pub addr: SocketAddr,
+    pub source: io::Error,
+}
+
+impl BindError {
+    // Another process, often an instance of the server that is still running,
+    // holds the port
+    pub fn is_addr_in_use(&self) -> bool {
+        self.source.kind() == io::ErrorKind::AddrInUse
+    }
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_addr_in_use() {
+            write!(
+                f,
+                "port {} is already in use on {}, perhaps by another db_receiver that is still running; \
+                 stop it, or listen on a free port with --listen <PORT>",
+                self.addr.port(),
+                self.addr.ip()
+            )
+        } else {
+            write!(f, "can't listen on {}: {}", self.addr, self.source)
+        }
+    }
+}
+
+impl Error for BindError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+// Bind every listener before serving any of them. One that can't be bound
+// fails startup with its address, rather than leaving the server up on the
+// rest, unless `allow_partial` is set; then it is logged and skipped, and
+// startup fails only if none could be bound.
+pub fn bind(specs: &[ListenerSpec], allow_partial: bool) -> Result<Vec<(TcpListener, ListenOptions)>, BindError> {
+    let specs = configured(specs);
+    let mut listeners = Vec::new();
+    let mut first_error = None;
+    for spec in &specs {
+        // An IPv6 listener takes IPv4 clients too, unless one of its own is
+        // configured on the same port
+        let v6_only = specs.iter().any(|other| other.addr.is_ipv4() && other.addr.port() == spec.addr.port());
+        match bind_one(spec.addr, v6_only) {
+            Ok(listener) => {
+                info!("Server listening on {}...", spec);
+                listeners.push((listener, spec.options));
+            }
+            Err(source) if allow_partial => {
+                let e = BindError { addr: spec.addr, source };
+                error!("Skipping a listener: {}", e);
+                first_error.get_or_insert(e);
+            }
+            Err(source) => return Err(BindError { addr: spec.addr, source }),
+        }
+    }
+    match first_error {
+        Some(e) if listeners.is_empty() => Err(e),
+        _ => Ok(listeners),
+    }
+}
+
+fn bind_one(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // As the standard library does, so a restarted server can bind while old
+    // connections sit in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpStream};
+
+    use super::*;
+    use crate::peer::Peer;
+
+    fn spec(addr: &str) -> ListenerSpec {
+        parse_listener(addr).expect("valid listener")
+    }
+
+    // Connect to `port` from `ip`, returning the peer the listener records
+    // for the connection and the one the client connected from
+    fn connect(listener: &TcpListener, ip: IpAddr, port: u16) -> (Peer, Peer) {
+        let client = TcpStream::connect((ip, port)).unwrap();
+        listener.set_nonblocking(false).unwrap();
+        let (_, addr) = listener.accept().unwrap();
+        (Peer::from(addr), Peer::Ip(client.local_addr().unwrap()))
+    }
+
+    // One IPv6 listener takes clients of both families; an IPv4 one shows
+    // up unmapped, as it would on an IPv4 listener
+    #[test]
+    fn dual_stack_listener_takes_both_families() {
+        let listeners = bind(&[spec("[::]:0")], false).unwrap();
+        let (listener, _) = &listeners[0];
+        let port = listener.local_addr().unwrap().port();
+
+        let (peer, client) = connect(listener, Ipv4Addr::LOCALHOST.into(), port);
+        assert_eq!(peer, client);
+        assert!(peer.to_string().starts_with("127.0.0.1:"));
+
+        let (peer, client) = connect(listener, Ipv6Addr::LOCALHOST.into(), port);
+        assert_eq!(peer, client);
+        assert!(peer.to_string().starts_with("[::1]:"));
+    }
+
+    // With an IPv4 listener of its own on the port, the IPv6 one is bound
+    // v6-only and each family arrives on its own listener
+    #[test]
+    fn separate_listeners_per_family_on_one_port() {
+        let port = TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)).unwrap().local_addr().unwrap().port();
+        let listeners = bind(&[spec(&format!("[::]:{}", port)), spec(&format!("0.0.0.0:{}", port))], false).unwrap();
+        let (v6_listener, v4_listener) = (&listeners[0].0, &listeners[1].0);
+
+        let (peer, client) = connect(v4_listener, Ipv4Addr::LOCALHOST.into(), port);
+        assert_eq!(peer, client);
+        assert!(peer.to_string().starts_with("127.0.0.1:"));
+
+        let (peer, client) = connect(v6_listener, Ipv6Addr::LOCALHOST.into(), port);
+        assert_eq!(peer, client);
+        assert!(peer.to_string().starts_with("[::1]:"));
+    }
+}
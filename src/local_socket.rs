@@ -0,0 +1,98 @@
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+
+use crate::peer::Peer;
+
+// A Unix domain socket for producers on the same machine, which spares them
+// the loopback TCP stack and the open port. The socket file gets the given
+// permissions and is removed when the listener is dropped.
+#[derive(Debug)]
+pub struct LocalListener {
+    listener: UnixListener,
+    path: PathBuf,
+    // Numbers the connections, which have no address to tell them apart
+    connections: u64,
+}
+
+impl LocalListener {
+    pub fn bind(path: &Path, mode: u32) -> io::Result<Self> {
+        remove_stale_socket(path)?;
+        let listener = LocalListener { listener: UnixListener::bind(path)?, path: path.to_path_buf(), connections: 0 };
+        // Until this, the socket has the permissions the umask leaves it
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+        listener.listener.set_nonblocking(true)?;
+        info!("Listening on Unix socket {} (mode {:o})...", path.display(), mode);
+        Ok(listener)
+    }
+
+    pub fn accept(&mut self) -> io::Result<(UnixStream, Peer)> {
+        let (stream, _) = self.listener.accept()?;
+        self.connections += 1;
+        let (uid, pid) = peer_credentials(&stream);
+        Ok((stream, Peer::Local { connection: self.connections, uid, pid }))
+    }
+}
+
+impl Drop for LocalListener {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => info!("Unix socket {} closed", self.path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove Unix socket {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+// A socket file left behind by a server that didn't shut down cleanly would
+// make bind fail. It is removed unless a server still answers on it; anything
+// other than a socket at the path is left for bind to refuse.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("another server is listening on {}", path.display()),
+                ));
+            }
+            warn!("Removing stale Unix socket {}", path.display());
+            fs::remove_file(path)
+        }
+        _ => Ok(()),
+    }
+}
+
+// The uid and pid of the process that connected, as the kernel saw it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(stream: &UnixStream) -> (Option<u32>, Option<i32>) {
+    use std::os::unix::io::AsRawFd;
+
+    let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: the descriptor is open for the life of `stream`, and the buffer
+    // and its length describe a ucred, which is what SO_PEERCRED fills in
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result == 0 {
+        (Some(credentials.uid), Some(credentials.pid))
+    } else {
+        warn!("Could not read the credentials of a Unix socket client: {}", io::Error::last_os_error());
+        (None, None)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_credentials(_stream: &UnixStream) -> (Option<u32>, Option<i32>) {
+    (None, None)
+}
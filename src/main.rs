@@ -1,215 +1,2230 @@
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::io::{self, BufRead, BufReader, ErrorKind};
+use std::net::{Shutdown, TcpListener};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
 use rusqlite::{Connection, params};
+use rusqlite::types::Value;
+use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
 use std::thread;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use ctrlc;
+use std::thread::JoinHandle;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use signal_hook::{consts::SIGUSR1, iterator::Signals};
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::time::SystemTime;
+use clap::Parser;
+use log::{debug, error, info, warn};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+
+mod alerts;
+mod annotations;
+mod archive;
+mod backpressure;
+mod breaker;
+mod bench;
+mod batch;
+mod calibration;
+mod config;
+mod connections;
+mod dac;
+mod decimate;
+mod database;
+mod delta;
+mod distance;
+mod encryption;
+mod error;
+mod error_reply;
+mod events;
+mod export;
+mod fallback;
+mod filter;
+mod gap;
+mod geo_fence;
+mod gps_quality;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod gyro;
+mod histogram;
+mod http;
+mod influx;
+mod integrity;
+mod listener;
+#[cfg(unix)]
+mod local_socket;
+mod metrics;
+mod mqtt;
+mod multiline;
+mod outlier;
+mod peer;
+mod positional;
+mod profile;
+mod query;
+mod quarantine;
+mod rotation;
+mod samples;
+mod schema;
+mod secret;
+mod session;
+mod session_id;
+mod socket_options;
+mod subscribe;
+mod serial;
+mod sqlite;
+mod stats;
+mod stream;
+mod tilt;
+mod track;
+mod timestamp;
+mod udp;
+mod units;
+mod vacuum;
+mod validation;
+mod wal;
+mod webhook;
+mod websocket;
+mod wire;
+mod writer;
+
+use alerts::TelemetryAlerts;
+use archive::Archive;
+use backpressure::{BackpressureNotice, BackpressureNotifier};
+use breaker::{BackoffNotice, BackoffNotifier, CircuitBreaker};
+use batch::{insert_dead_letter, PendingRecord, SessionTally};
+use config::{Cli, Command, Config};
+use connections::Counted;
+use database::FileId;
+use decimate::DecimationFilter;
+use distance::TrackDistance;
+use encryption::GpsCipher;
+use error_reply::{ErrorCode, ErrorReply, ErrorReplyLimiter};
+use fallback::{FallbackStore, FALLBACK_REPLAY_INTERVAL};
+use filter::{AccelLowPass, ImuMedianFilter, LowPassFilter, SensorFilter};
+use gap::GapFiller;
+use gyro::GyroIntegrator;
+use histogram::LatencyHistogram;
+use influx::InfluxOptions;
+use listener::{BindError, ListenOptions};
+#[cfg(unix)]
+use local_socket::LocalListener;
+use metrics::Metrics;
+use multiline::{MultiLine, Progress};
+use outlier::SessionOutliers;
+use peer::Peer;
+use profile::Profile;
+use query::{QueryLimits, QueryMessage};
+use rotation::{LogFile, RotatingWriter};
+use samples::SampleBlock;
+use session::{
+    OpenSession, OpenSessions, ResumeInfoMessage, SessionConfigMessage, SessionEndMessage, SessionStartMessage, SessionStarted, UploadCompleteMessage,
+    UploadScope, UploadStatus,
+};
+use sqlite::Durability;
+use stream::ClientStream;
+use stats::LiveStats;
+use subscribe::{ClientWriter, SubscribeMessage, Subscribed, Subscribers, Subscription};
+use tilt::Tilt;
+use validation::{FieldRangeAction, ValidationError};
+use wal::Wal;
+use webhook::{EventAlerts, RejectionAlerts};
+use wire::{WireFormat, WireReader};
+use writer::{Sent, Writer};
 
 // Define struct to match the expected JSON structure
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct SensorData {
-    sessionID: Option<i32>,
+    // Older firmware spells some fields differently (session_id, accelX,
+    // dac1, ...). A record that spells a field both ways is rejected as a
+    // duplicate field rather than one spelling winning.
+    #[serde(
+        rename = "sessionID",
+        alias = "session_id",
+        alias = "sessionId",
+        default,
+        deserialize_with = "session_id::deserialize"
+    )]
+    session_id: Option<i64>,
+    #[serde(deserialize_with = "timestamp::deserialize")]
     timestamp: String,
-    latitude: f64,
-    longitude: f64,
-    altitude: f64,
-    accel_x: f64,
-    accel_y: f64,
-    accel_z: f64,
-    gyro_x: f64,
-    gyro_y: f64,
-    gyro_z: f64,
-    dac_1: f64,
-    dac_2: f64,
-    dac_3: f64,
-    dac_4: f64,
-}
-
-// Struct for keepalive messages
-#[derive(Serialize, Deserialize, Debug)]
+    // Sensor fields; which of them may be missing depends on the profile
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    altitude: Option<f64>,
+    #[serde(alias = "accelX")]
+    accel_x: Option<f64>,
+    #[serde(alias = "accelY")]
+    accel_y: Option<f64>,
+    #[serde(alias = "accelZ")]
+    accel_z: Option<f64>,
+    #[serde(alias = "gyroX")]
+    gyro_x: Option<f64>,
+    #[serde(alias = "gyroY")]
+    gyro_y: Option<f64>,
+    #[serde(alias = "gyroZ")]
+    gyro_z: Option<f64>,
+    #[serde(alias = "magX")]
+    mag_x: Option<f64>,
+    #[serde(alias = "magY")]
+    mag_y: Option<f64>,
+    #[serde(alias = "magZ")]
+    mag_z: Option<f64>,
+    // Quality of the position, for receivers that report it: the NMEA GGA
+    // fix quality, satellites in use and horizontal dilution of precision
+    fix_quality: Option<u8>,
+    num_satellites: Option<u32>,
+    hdop: Option<f64>,
+    // Enclosure temperature and supply voltage, for loggers that report them
+    temperature_c: Option<f64>,
+    battery_v: Option<f64>,
+    #[serde(alias = "dac1")]
+    dac_1: Option<f64>,
+    #[serde(alias = "dac2")]
+    dac_2: Option<f64>,
+    #[serde(alias = "dac3")]
+    dac_3: Option<f64>,
+    #[serde(alias = "dac4")]
+    dac_4: Option<f64>,
+    // Boards with more or fewer than four DAC channels send them as an array
+    // instead, stored in dac_1 onwards; a record uses one form or the other
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dac: Option<Vec<Option<f64>>>,
+    // The position fell short of the GPS quality gate. Set by the server only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    gps_low_quality: bool,
+    // The position lay outside the --geo-fence-* bounding box. Set by the
+    // server only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    outside_fence: bool,
+    // A value lay beyond --outlier-sigma standard deviations of its field in
+    // the session so far. Set by the server only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    is_outlier: bool,
+    // Made up by --max-gap-ms to fill a gap between two records. Set by the
+    // server only.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    is_interpolated: bool,
+    // Low-pass filtered accel_x..accel_z. Set by the server only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accel_filtered: Option<[Option<f64>; 3]>,
+    // Pitch, roll and yaw integrated from the gyroscope since the start of
+    // the session. Set by the server only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cumulative_angles: Option<[f64; 3]>,
+    // Metres of GPS track covered since the start of the session. Set by the
+    // server only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cumulative_distance_m: Option<f64>,
+    // Pitch and roll from the accelerometer, and whether it was still enough
+    // to trust them. Set by the server only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tilt: Option<Tilt>,
+    // accel_x..gyro_z as received, when smoothing replaced them with moving
+    // averages. Set by the server only; what a client sends here is dropped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    imu_raw: Option<[Option<f64>; 6]>,
+    // DAC channels as received, when --dac-scale or calibration replaced
+    // them. Set by the server only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dac_raw: Option<[Option<f64>; MAX_DAC_CHANNELS]>,
+    // Latitude, longitude and altitude as received, when calibration
+    // corrected them. Set by the server only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    position_raw: Option<[Option<f64>; 3]>,
+    // Client-chosen ID that makes a retransmitted record a no-op
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message_id: Option<String>,
+    // Fields the server doesn't know, such as RSSI, kept
+    // in the extras column instead of being dropped
+    #[serde(flatten)]
+    extras: serde_json::Map<String, serde_json::Value>,
+}
+
+impl SensorData {
+    // The extras column: unknown fields as a JSON object, or NULL without any
+    fn extras_json(&self) -> Option<String> {
+        (!self.extras.is_empty()).then(|| serde_json::to_string(&self.extras).unwrap_or_default())
+    }
+
+    // Length of the acceleration vector, for the accel_magnitude column. hypot
+    // scales its arguments, so squaring huge values doesn't overflow.
+    fn accel_magnitude(&self) -> Option<f64> {
+        Some(self.accel_x?.hypot(self.accel_y?).hypot(self.accel_z?))
+    }
+
+    // The DAC channels in column order, from whichever form the record used
+    fn dac_channels(&self) -> [Option<f64>; MAX_DAC_CHANNELS] {
+        let mut channels = [None; MAX_DAC_CHANNELS];
+        match &self.dac {
+            Some(values) => {
+                for (channel, value) in channels.iter_mut().zip(values) {
+                    *channel = *value;
+                }
+            }
+            None => channels[..4].copy_from_slice(&[self.dac_1, self.dac_2, self.dac_3, self.dac_4]),
+        }
+        channels
+    }
+
+    // The DAC channels the record carries, in column order, for replacing
+    // their values in place
+    fn dac_channels_mut(&mut self) -> Vec<&mut Option<f64>> {
+        match &mut self.dac {
+            Some(values) => values.iter_mut().collect(),
+            None => vec![&mut self.dac_1, &mut self.dac_2, &mut self.dac_3, &mut self.dac_4],
+        }
+    }
+
+    // A `dac` array must fit the columns and can't be mixed with dac_1..dac_4
+    fn check_dac(&self) -> Result<(), String> {
+        let Some(values) = &self.dac else {
+            return Ok(());
+        };
+        if [self.dac_1, self.dac_2, self.dac_3, self.dac_4].iter().any(Option::is_some) {
+            return Err("`dac` can't be combined with the named fields dac_1 to dac_4".to_string());
+        }
+        if values.is_empty() || values.len() > MAX_DAC_CHANNELS {
+            return Err(format!("`dac` must have 1 to {} channels, not {}", MAX_DAC_CHANNELS, values.len()));
+        }
+        Ok(())
+    }
+
+    // The sensor fields by column name
+    fn fields(&self) -> [(&'static str, Option<f64>); SENSOR_FIELDS] {
+        let dac = self.dac_channels();
+        let mut fields = [("", None); SENSOR_FIELDS];
+        fields[..14].copy_from_slice(&[
+            ("latitude", self.latitude),
+            ("longitude", self.longitude),
+            ("altitude", self.altitude),
+            ("accel_x", self.accel_x),
+            ("accel_y", self.accel_y),
+            ("accel_z", self.accel_z),
+            ("gyro_x", self.gyro_x),
+            ("gyro_y", self.gyro_y),
+            ("gyro_z", self.gyro_z),
+            ("mag_x", self.mag_x),
+            ("mag_y", self.mag_y),
+            ("mag_z", self.mag_z),
+            ("temperature_c", self.temperature_c),
+            ("battery_v", self.battery_v),
+        ]);
+        for (i, field) in fields[14..].iter_mut().enumerate() {
+            *field = (DAC_COLUMNS[i], dac[i]);
+        }
+        fields
+    }
+}
+
+// Sensor fields of a record: GPS, IMU, magnetometer, temperature, battery
+// and the DAC channels
+const SENSOR_FIELDS: usize = 14 + MAX_DAC_CHANNELS;
+
+// DAC channels a record can carry, one sensor_data column each
+const MAX_DAC_CHANNELS: usize = 16;
+pub(crate) const DAC_COLUMNS: [&str; MAX_DAC_CHANNELS] = [
+    "dac_1", "dac_2", "dac_3", "dac_4", "dac_5", "dac_6", "dac_7", "dac_8",
+    "dac_9", "dac_10", "dac_11", "dac_12", "dac_13", "dac_14", "dac_15", "dac_16",
+];
+
+// Where the DAC readings as received go when --dac-scale is on
+pub(crate) const RAW_DAC_COLUMNS: [&str; MAX_DAC_CHANNELS] = [
+    "dac_1_raw", "dac_2_raw", "dac_3_raw", "dac_4_raw", "dac_5_raw", "dac_6_raw", "dac_7_raw", "dac_8_raw",
+    "dac_9_raw", "dac_10_raw", "dac_11_raw", "dac_12_raw", "dac_13_raw", "dac_14_raw", "dac_15_raw", "dac_16_raw",
+];
+
+// Struct for keepalive messages. Also used to read the "type" of any other
+// control message.
+#[derive(Deserialize, Debug)]
 struct KeepaliveMessage {
     #[serde(rename = "type")]
     message_type: String,
+    // Only checked for presence
+    timestamp: Option<serde::de::IgnoredAny>,
+}
+
+// Every "type" the server answers as a control message
+const CONTROL_TYPES: [&str; 11] = [
+    "keepalive", "hello", "session_start", "session_end", "session_config", "upload_complete", "query",
+    "subscribe", "unsubscribe", "flush", "resume_info",
+];
+
+impl KeepaliveMessage {
+    // A sensor record may carry a "type" of its own among its extras, so an
+    // unknown type is only a control message when there's no timestamp
+    fn is_control(&self) -> bool {
+        CONTROL_TYPES.contains(&self.message_type.as_str()) || self.timestamp.is_none()
+    }
+}
+
+// Handshake sent by a client before its data, identifying the logger hardware
+#[derive(Serialize, Deserialize, Debug)]
+struct HelloMessage {
+    #[serde(rename = "type")]
+    message_type: String,
+    device_id: Option<String>,
+    // Client promises to send keepalives and may be disconnected when they stop
+    #[serde(default)]
+    keepalive: bool,
+    // Client wants a JSON error reply for each line the server rejects
+    #[serde(default)]
+    error_replies: bool,
+}
+
+// Reply to a keepalive so clients can detect a half-open connection
+#[derive(Serialize, Deserialize, Debug)]
+struct PongMessage {
+    #[serde(rename = "type")]
+    message_type: String,
+    server_time: String,
+}
+
+// Sent before the server closes a connection that has sent
+// --max-records-per-connection records, so the client knows to reconnect
+#[derive(Serialize, Debug)]
+struct LimitReached {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    limit: u64,
+}
+
+// Reply to a flush once the records sent before it are committed
+#[derive(Serialize, Debug)]
+struct FlushAck {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    // Records the flush committed, from every connection's buffered records
+    committed: usize,
 }
 
 // Enum to handle different message types
 #[derive(Debug)]
 enum Message {
-    SensorData(SensorData),
+    // Rows from one line: a single record, or one per sample of a sample block
+    SensorData(Vec<SensorData>),
     Keepalive,
-    Unknown,
+    Hello(HelloMessage),
+    SessionStart(SessionStartMessage),
+    SessionEnd(SessionEndMessage),
+    SessionConfig(SessionConfigMessage),
+    UploadComplete(UploadCompleteMessage),
+    Query(QueryMessage),
+    Subscribe(SubscribeMessage),
+    Unsubscribe,
+    Flush,
+    ResumeInfo(ResumeInfoMessage),
+    Unknown(String),
 }
 
+// Per-connection state established by the handshake, plus timing of the two
+// phases of each record: waiting on the network and writing to the database
+#[derive(Debug, Default)]
+struct ConnectionState {
+    device_id: Option<String>,
+    // Row of the connection in the connections table, stored with its records
+    connection_id: Option<i64>,
+    keepalive_negotiated: bool,
+    // Present once the client opts in to error replies
+    error_replies: Option<ErrorReplyLimiter>,
+    backpressure: BackpressureNotifier,
+    backoff: BackoffNotifier,
+    // Moving averages of the IMU axes, with --enable-smoothing
+    filter: Option<SensorFilter>,
+    // Medians of the IMU axes, with --enable-median-filter
+    median: Option<ImuMedianFilter>,
+    lowpass: AccelLowPass,
+    gyro: GyroIntegrator,
+    distance: TrackDistance,
+    gaps: GapFiller,
+    // Drops records that come faster than --decimate-to-hz
+    decimation: Option<DecimationFilter>,
+    // Running statistics of each sensor field in the current session
+    outliers: SessionOutliers,
+    // Sessions this connection sent records of, whose statistics are saved
+    // when it goes away
+    stats_sessions: BTreeSet<i64>,
+    // Records pushed to this connection as they are stored, after subscribe
+    subscription: Option<Subscription>,
+    // Connected over WebSocket, which doesn't offer subscriptions
+    websocket: bool,
+    // Settings of the listener that accepted the connection
+    listen_options: ListenOptions,
+    // Records handed to the writer, for --max-records-per-connection
+    records_sent: u64,
+    // Records this connection got into the database, counted by the writer
+    tally: Arc<SessionTally>,
+    // Bytes read from the client, for the connections table
+    bytes_received: Arc<AtomicU64>,
+    read_latency: LatencyHistogram,
+    insert_latency: LatencyHistogram,
+}
+
+impl ConnectionState {
+    // A new client's state, with the filters the configuration asks for
+    fn new(config: &Config) -> Self {
+        ConnectionState {
+            filter: config.enable_smoothing.then(|| SensorFilter::new(config.smoothing_window)),
+            median: config.enable_median_filter.then(|| ImuMedianFilter::new(config.median_window)),
+            lowpass: AccelLowPass::new(
+                config.lowpass_cutoff_hz.map(|cutoff| LowPassFilter::alpha(cutoff, config.lowpass_sample_rate_hz)),
+            ),
+            outliers: SessionOutliers::new(config.outlier_sigma),
+            decimation: config.decimate_to_hz.map(DecimationFilter::new),
+            ..ConnectionState::default()
+        }
+    }
+}
+
+// Device IDs currently claimed by a live connection, used to spot two
+// connections pretending to be the same logger
+type ActiveDevices = Mutex<HashMap<String, Peer>>;
+
+// State shared by every client thread
+struct ServerState {
+    config: Config,
+    devices: ActiveDevices,
+    sessions: OpenSessions,
+    // JSONL copy of accepted records, when `--archive` is set
+    archive: Option<Mutex<Archive>>,
+    // Where records go while the database is unavailable, when `--fallback-dir` is set
+    fallback: Option<FallbackStore>,
+    wal: Wal,
+    metrics: Arc<Metrics>,
+    alerts: TelemetryAlerts,
+    subscribers: Arc<Subscribers>,
+    // Mean and spread of each field of the sessions being stored
+    live_stats: Arc<LiveStats>,
+    // Encrypts coordinates before they are stored, when `--gps-key-file` is set
+    gps_cipher: Option<GpsCipher>,
+    // The one thread that writes to the database
+    writer: Writer,
+    // Pauses ingest while the database keeps failing
+    breaker: CircuitBreaker,
+    // Reports bursts of rejected lines, when `--alert-webhook` is set
+    rejection_alerts: Option<RejectionAlerts>,
+    // Posts the events --event-rule triggers, when `--alert-webhook` is set too
+    event_alerts: Option<EventAlerts>,
+}
+
+impl ServerState {
+    // Everything the listeners share, set up from the configuration. The
+    // database is opened separately and handed to the writer once it has
+    // its schema.
+    fn new(config: Config) -> Result<Self, Box<dyn Error>> {
+        let archive = match &config.archive {
+            Some(path) => {
+                info!("Archiving accepted records to {}", path.display());
+                let archive = Archive::open(path.clone(), config.rotate_max_bytes, config.rotate_keep)?;
+                Some(Mutex::new(archive))
+            }
+            None => None,
+        };
+        let fallback = config.fallback_dir.clone().map(|dir| {
+            info!("Records the database can't take will be written to {}", dir.display());
+            FallbackStore::new(dir)
+        });
+        let wal = Wal::open(config.wal_dir.clone())?;
+        let metrics = Arc::new(Metrics::default());
+        let writer = Writer::new(
+            config.max_pending_records,
+            Duration::from_millis(config.backpressure_timeout_ms),
+            metrics.clone(),
+        );
+        let breaker = CircuitBreaker::new(config.breaker_failures, Duration::from_secs(config.breaker_probe_secs), metrics.clone());
+        let alerts = TelemetryAlerts::new(config.alert_battery_below, config.alert_temperature_above);
+        let gps_cipher = load_gps_cipher(&config)?;
+        let rejection_alerts = RejectionAlerts::start(&config.webhook);
+        let event_alerts = if config.events.rules.is_empty() { None } else { EventAlerts::start(&config.webhook) };
+        if gps_cipher.is_some() && config.archive.is_some() {
+            warn!("The archive keeps coordinates unencrypted; GPS encryption only covers the database");
+        }
+        Ok(ServerState {
+            config,
+            devices: Mutex::new(HashMap::new()),
+            sessions: OpenSessions::new(),
+            archive,
+            fallback,
+            wal,
+            metrics,
+            alerts,
+            subscribers: Arc::new(Subscribers::default()),
+            live_stats: Arc::new(LiveStats::new()),
+            gps_cipher,
+            writer,
+            breaker,
+            rejection_alerts,
+            event_alerts,
+        })
+    }
+}
+
+// Where the UDP, HTTP and gRPC listeners bind, and where records are stored
+const BIND_ADDRESS: &str = "0.0.0.0";
+const DATABASE_PATH: &str = "received_data.db";
+
+// Socket read timeout. Reads wake up at least this often so buffered records
+// can be flushed and an idle client noticed while no data is arriving.
+const READ_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often drain mode reports the number of connections still open
+const DRAIN_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Exit status when a listening port can't be bound, so scripts can tell a
+// port conflict from other failures; 1 is any other error and 2 bad usage
+const EXIT_BIND_FAILED: i32 = 3;
+
 fn main() -> Result<(), Box<dyn Error>> {
-    // 1. Start listening on port 9000
-    let listener = TcpListener::bind("0.0.0.0:9000")?;
-    listener.set_nonblocking(true)?;
-    println!("Server listening on port 9000...");
+    let cli = Cli::parse();
+    init_logging(&cli.config)?;
+
+    match cli.command {
+        Some(Command::ReplayQuarantine) => replay_quarantine(&cli.config),
+        Some(Command::Bench(args)) => bench(&args),
+        Some(Command::Vacuum(args)) => vacuum(&cli.config, &args),
+        Some(Command::Export(args)) => export(&cli.config, &args),
+        None => run_server(cli.config).inspect_err(|e| {
+            if let Some(e) = e.downcast_ref::<BindError>() {
+                error!("Can't start the server: {}", e);
+                process::exit(EXIT_BIND_FAILED);
+            }
+        }),
+    }
+}
+
+// Log to stderr, or to a rotating file when `--log-file` is given
+fn init_logging(config: &Config) -> io::Result<()> {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(path) = &config.log_file {
+        let file = RotatingWriter::open(path.clone(), config.rotate_max_bytes, config.rotate_keep)?;
+        builder.target(env_logger::Target::Pipe(Box::new(LogFile(file))));
+    }
+    builder.init();
+    Ok(())
+}
+
+// One line with every setting the server runs with, so a report from a
+// misbehaving deployment shows its configuration
+fn log_effective_config(config: &Config) {
+    let off = |value: Option<String>| value.unwrap_or_else(|| "off".to_string());
+    let path = |path: &Option<std::path::PathBuf>| path.as_ref().map(|path| path.display().to_string());
+    let settings: Vec<(&str, String)> = vec![
+        ("listen", listener::describe(&config.listen)),
+        ("allow_partial_listen", config.allow_partial_listen.to_string()),
+        ("tcp", config.tcp.to_string()),
+        ("db", DATABASE_PATH.to_string()),
+        ("influx", config.influx.to_string()),
+        ("udp", off(config.udp_port.map(|port| port.to_string()))),
+        ("max_datagram_bytes", config.max_datagram_bytes.to_string()),
+        (
+            "unix_socket",
+            off(config.unix_socket.as_ref().map(|path| format!("{} (mode {:o})", path.display(), config.unix_socket_mode))),
+        ),
+        ("websocket", off(config.websocket_port.map(|port| port.to_string()))),
+        ("http", off(config.http_port.map(|port| port.to_string()))),
+        ("max_http_body_bytes", config.max_http_body_bytes.to_string()),
+        ("grpc", off(config.grpc_port.map(|port| port.to_string()))),
+        ("mqtt", config.mqtt.to_string()),
+        ("serial", config.serial.to_string()),
+        ("alert_webhook", config.webhook.to_string()),
+        ("keepalive_timeout", format!("{}s", config.keepalive_timeout_secs)),
+        (
+            "max_records_per_connection",
+            config.max_records_per_connection.map_or("unlimited".to_string(), |max| max.to_string()),
+        ),
+        ("batch_size", config.batch_size.to_string()),
+        ("batch_flush", format!("{}ms", config.batch_flush_ms)),
+        ("max_line_bytes", config.max_line_bytes.to_string()),
+        ("read_buffer_bytes", config.read_buffer_bytes.to_string()),
+        ("max_pending_records", config.max_pending_records.to_string()),
+        ("backpressure_timeout", format!("{}ms", config.backpressure_timeout_ms)),
+        (
+            "breaker",
+            off((config.breaker_failures > 0)
+                .then(|| format!("{} failures, probe every {}s", config.breaker_failures, config.breaker_probe_secs))),
+        ),
+        ("max_extras_bytes", config.max_extras_bytes.to_string()),
+        ("max_query_rows", config.max_query_rows.to_string()),
+        ("max_query_bytes", config.max_query_bytes.to_string()),
+        ("dedup_timestamps", config.dedup_timestamps.to_string()),
+        ("decimate_to", off(config.decimate_to_hz.map(|hz| format!("{}Hz", hz)))),
+        ("max_gap", off(config.max_gap_ms.map(|ms| format!("{}ms", ms)))),
+        ("median", off(config.enable_median_filter.then(|| format!("{} samples", config.median_window)))),
+        ("smoothing", off(config.enable_smoothing.then(|| format!("{} samples", config.smoothing_window)))),
+        (
+            "lowpass",
+            off(config.lowpass_cutoff_hz.map(|cutoff| {
+                let alpha = LowPassFilter::alpha(cutoff, config.lowpass_sample_rate_hz);
+                format!("{}Hz at {}Hz (alpha {:.4})", cutoff, config.lowpass_sample_rate_hz, alpha)
+            })),
+        ),
+        ("outlier_sigma", config.outlier_sigma.to_string()),
+        ("altitude", config.altitude.to_string()),
+        ("field_ranges", config.field_limits.to_string()),
+        ("dac_ranges", config.dac.to_string()),
+        ("calibration", config.calibration.to_string()),
+        ("event_rules", config.events.to_string()),
+        ("gps_quality", config.gps_quality.to_string()),
+        ("geo_fence", config.geo_fence.to_string()),
+        ("gps_encryption", off(path(&config.gps_key_file))),
+        ("allow_open_secret_files", config.allow_open_secret_files.to_string()),
+        ("wal_dir", config.wal_dir.display().to_string()),
+        ("quarantine_dir", config.quarantine_dir.display().to_string()),
+        ("archive", off(path(&config.archive))),
+        ("fallback_dir", off(path(&config.fallback_dir))),
+        ("log_file", path(&config.log_file).unwrap_or_else(|| "stderr".to_string())),
+        ("rotate_max_bytes", config.rotate_max_bytes.map_or("unlimited".to_string(), |max| max.to_string())),
+        ("rotate_keep", config.rotate_keep.to_string()),
+        ("metrics", off(config.metrics_addr.map(|addr| addr.to_string()))),
+        ("allow_negative_dt", config.allow_negative_dt.to_string()),
+        (
+            "timestamp_formats",
+            config.timestamp_formats.iter().map(ToString::to_string).collect::<Vec<_>>().join(","),
+        ),
+        ("profile", config.profile.to_string()),
+        ("output_units", config.output_units.to_string()),
+        (
+            "require_fields",
+            if config.require_fields.is_empty() { "none".to_string() } else { config.require_fields.join(",") },
+        ),
+        ("alert_battery_below", off(config.alert_battery_below.map(|volts| format!("{}V", volts)))),
+        ("alert_temperature_above", off(config.alert_temperature_above.map(|celsius| format!("{}C", celsius)))),
+        ("reject_imprecise_numbers", config.reject_imprecise_numbers.to_string()),
+        ("durability", config.sqlite.durability.to_string()),
+        (
+            "sqlite_page_size",
+            config.sqlite.page_size_bytes.map_or("default".to_string(), |size| size.to_string()),
+        ),
+        ("sqlite_cache_size", config.sqlite.cache_size_kb.to_string()),
+        ("sqlite_mmap_size", format!("{}MB", config.sqlite.mmap_size_mb)),
+        ("sqlite_wal_autocheckpoint", config.sqlite.wal_autocheckpoint_pages.to_string()),
+        ("sqlite_temp_store", config.sqlite.temp_store.to_string()),
+        ("integrity_check", config.integrity.to_string()),
+    ];
+    let line: Vec<String> = settings.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+    info!("Effective configuration: {}", line.join(" "));
+}
+
+// The key for --gps-key-file, checked before anything is stored
+fn load_gps_cipher(config: &Config) -> Result<Option<GpsCipher>, Box<dyn Error>> {
+    Ok(config.gps_key_file.as_deref().map(|path| GpsCipher::load(path, config.allow_open_secret_files)).transpose()?)
+}
+
+fn replay_quarantine(config: &Config) -> Result<(), Box<dyn Error>> {
+    let gps_cipher = load_gps_cipher(config)?;
+    let conn = sqlite::open(DATABASE_PATH, &config.sqlite)?;
+    schema::ensure_schema(&conn, config.dedup_timestamps)?;
+
+    println!("Replaying quarantined lines from {}...", config.quarantine_dir.display());
+    let options = ParseOptions::from(config);
+    let summary = quarantine::replay_quarantine(&conn, &config.quarantine_dir, &options, gps_cipher.as_ref(), config.dedup_timestamps)?;
+    println!(
+        "Replay complete: {} inserted, {} skipped (control messages), {} still failing",
+        summary.inserted, summary.skipped, summary.still_failing
+    );
+    Ok(())
+}
+
+fn bench(args: &bench::BenchArgs) -> Result<(), Box<dyn Error>> {
+    println!(
+        "Sending {} records from {} client(s) across {} session(s) each to {}...",
+        args.records, args.clients, args.sessions, args.address
+    );
+    let report = bench::run(args)?;
+    println!(
+        "Bench complete: {} sent, {} stored, {} rejected in {:.2}s ({:.0} rows/sec)",
+        report.sent,
+        report.stored,
+        report.rejected,
+        report.elapsed.as_secs_f64(),
+        report.rows_per_sec()
+    );
+    Ok(())
+}
+
+fn vacuum(config: &Config, args: &vacuum::VacuumArgs) -> Result<(), Box<dyn Error>> {
+    let path = std::path::Path::new(DATABASE_PATH);
+    if !path.exists() {
+        return Err(format!("no database at {}", DATABASE_PATH).into());
+    }
+    let size = vacuum::database_size(path);
+    match &args.into {
+        Some(into) => println!("Writing a compacted copy of {} ({} bytes) to {}...", DATABASE_PATH, size, into.display()),
+        None => println!("Compacting {} ({} bytes)...", DATABASE_PATH, size),
+    }
+    println!("Warning: this needs up to {} bytes of free disk space while it runs", size);
+    let conn = sqlite::open(DATABASE_PATH, &config.sqlite)?;
+    let report = vacuum::run(&conn, path, args)?;
+    println!(
+        "Vacuum complete: {} bytes before, {} bytes after ({} bytes reclaimed)",
+        report.before,
+        report.after,
+        report.before.saturating_sub(report.after)
+    );
+    Ok(())
+}
+
+fn export(config: &Config, args: &export::ExportArgs) -> Result<(), Box<dyn Error>> {
+    if !std::path::Path::new(DATABASE_PATH).exists() {
+        return Err(format!("no database at {}", DATABASE_PATH).into());
+    }
+    let gps_cipher = load_gps_cipher(config)?;
+    let conn = sqlite::open(DATABASE_PATH, &config.sqlite)?;
+    schema::ensure_schema(&conn, config.dedup_timestamps)?;
+    let rows = export::run(&conn, args, gps_cipher.as_ref())?;
+    // On stderr, so it stays out of an export written to standard output
+    match export::output_path(args) {
+        Some(path) => eprintln!("Export complete: {} rows written to {}", rows, path.display()),
+        None => eprintln!("Export complete: {} rows written", rows),
+    }
+    Ok(())
+}
+
+fn run_server(config: Config) -> Result<(), Box<dyn Error>> {
+    log_effective_config(&config);
+    if config.sqlite.durability == Durability::Fast {
+        warn!("Durability is 'fast': SQLite doesn't wait for the disk, so an OS crash or power loss can lose committed records or corrupt the database");
+    }
+    let server = Arc::new(ServerState::new(config)?);
+    if let Some(addr) = server.config.metrics_addr {
+        metrics::serve(addr, server.metrics.clone())?;
+    }
+
+    // 1. Start listening on every configured port, port 9000 by default
+    let mut listeners = listener::bind(&server.config.listen, server.config.allow_partial_listen)?;
+    server.config.tcp.log();
+    #[cfg(unix)]
+    let mut local_listener = match &server.config.unix_socket {
+        Some(path) => Some(LocalListener::bind(path, server.config.unix_socket_mode)?),
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if server.config.unix_socket.is_some() {
+        return Err("--unix-socket needs a platform with Unix domain sockets".into());
+    }
+    #[cfg(not(feature = "grpc"))]
+    if server.config.grpc_port.is_some() {
+        return Err("--grpc-port needs a build with the grpc feature (cargo build --features grpc)".into());
+    }
+    let mut websocket_listener = match server.config.websocket_port {
+        Some(port) => {
+            let listener = TcpListener::bind((BIND_ADDRESS, port))?;
+            listener.set_nonblocking(true)?;
+            info!("Accepting WebSocket clients on port {}", port);
+            Some(listener)
+        }
+        None => None,
+    };
     
     // 2. Open or create a local database
-    let conn = Connection::open("received_data.db")?;
+    let conn = sqlite::open(DATABASE_PATH, &server.config.sqlite)?;
     
-    // Create table if it doesn't exist
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS sensor_data (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            sessionID INTEGER,
-            timestamp TEXT,
-            latitude REAL,
-            longitude REAL,
-            altitude REAL,
-            accel_x REAL,
-            accel_y REAL,
-            accel_z REAL,
-            gyro_x REAL,
-            gyro_y REAL,
-            gyro_z REAL,
-            dac_1 REAL,
-            dac_2 REAL,
-            dac_3 REAL,
-            dac_4 REAL
-        )",
-        [],
-    )?;
+    schema::ensure_schema(&conn, server.config.dedup_timestamps)?;
+
+    // Store records a previous run accepted but never committed
+    let recovered = wal::recover_wal(&conn, &server.config.wal_dir, server.gps_cipher.as_ref(), server.config.dedup_timestamps)?;
+    if recovered > 0 {
+        info!("Recovered {} record(s) from the WAL in {}", recovered, server.config.wal_dir.display());
+    }
+
+    connections::mark_interrupted(&conn);
+
+    // From here on every write goes through the writer thread
+    Writer::start(&server, conn);
+
+    if server.fallback.is_some() {
+        spawn_fallback_replayer(server.clone());
+    }
+    server.config.integrity.spawn(DATABASE_PATH, server.metrics.clone());
 
     // Create a shared flag for graceful shutdown
-    let running = Arc::new(Mutex::new(true));
+    let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
     
     // Set up ctrl-c handler for graceful shutdown
     ctrlc::set_handler(move || {
-        println!("Shutdown signal received, closing server gracefully...");
-        let mut running = r.lock().unwrap();
-        *running = false;
+        info!("Shutdown signal received, closing server gracefully...");
+        r.store(false, Ordering::SeqCst);
     })?;
 
+    // Cleared by SIGUSR1 to drain: stop accepting but keep serving existing clients
+    let accepting = Arc::new(AtomicBool::new(true));
+    #[cfg(unix)]
+    spawn_drain_signal_handler(accepting.clone(), running.clone())?;
+
+    let udp_listener = match server.config.udp_port {
+        Some(port) => Some(udp::spawn(server.clone(), port, running.clone(), accepting.clone())?),
+        None => None,
+    };
+    let http_listener = match server.config.http_port {
+        Some(port) => Some(http::spawn(server.clone(), port, running.clone(), accepting.clone())?),
+        None => None,
+    };
+    let mqtt_subscriber = match server.config.mqtt.mqtt_broker {
+        Some(_) => Some(mqtt::spawn(server.clone(), running.clone(), accepting.clone())?),
+        None => None,
+    };
+    #[cfg(feature = "grpc")]
+    let grpc_listener = match server.config.grpc_port {
+        Some(port) => Some(grpc::spawn(server.clone(), port, running.clone(), accepting.clone())?),
+        None => None,
+    };
+    let serial_readers = serial::spawn(server.clone(), running.clone(), accepting.clone());
+
     // Track client threads
     let mut client_threads = Vec::new();
+    let active_connections = Arc::new(AtomicUsize::new(0));
 
-    // 3. Accept incoming connections
-    while *running.lock().unwrap() {
-        match listener.accept() {
-            Ok((stream, addr)) => {
-                println!("Client connected: {:?}", addr);
-                
-                // Make the client stream blocking for reliable data transfer
-                stream.set_nonblocking(false).unwrap_or_else(|e| {
-                    eprintln!("Warning: Could not set client socket to blocking mode: {}", e);
-                });
-                
-                // Open a new database connection for this thread
-                let thread_conn = match Connection::open("received_data.db") {
-                    Ok(c) => c,
-                    Err(e) => {
-                        eprintln!("Failed to open database connection: {}", e);
-                        continue;
-                    }
-                };
-                
-                // Handle each client in a separate thread
-                let handle = thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, &thread_conn) {
-                        eprintln!("Error handling client {}: {}", addr, e);
-                    }
-                    println!("Connection from {} ended", addr);
-                });
-                
-                client_threads.push(handle);
-                
-                // Clean up completed threads
-                client_threads.retain(|h| !h.is_finished());
+    // 3. Accept incoming connections, polling every listener
+    while running.load(Ordering::SeqCst) && accepting.load(Ordering::SeqCst) {
+        let mut accepted = false;
+        for (listener, options) in &listeners {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    server.config.tcp.apply(&stream, addr.into());
+                    let connect = move || Ok(ClientStream::Tcp(stream));
+                    client_threads.push(spawn_client(&server, connect, addr.into(), *options, &active_connections));
+                    accepted = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => error!("Connection error: {}", e),
             }
-            Err(e) => {
-                if e.kind() == io::ErrorKind::WouldBlock {
-                    // No connection available, sleep briefly and check running flag
-                    thread::sleep(Duration::from_millis(100));
-                } else {
-                    eprintln!("Connection error: {}", e);
-                    thread::sleep(Duration::from_millis(100));
+        }
+        #[cfg(unix)]
+        if let Some(local) = &mut local_listener {
+            match local.accept() {
+                Ok((stream, addr)) => {
+                    client_threads.push(spawn_client(&server, move || Ok(ClientStream::Unix(stream)), addr, ListenOptions::default(), &active_connections));
+                    accepted = true;
                 }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => error!("Unix socket connection error: {}", e),
             }
         }
+        if let Some(websocket) = &websocket_listener {
+            match websocket.accept() {
+                Ok((stream, addr)) => {
+                    server.config.tcp.apply(&stream, addr.into());
+                    // The handshake runs on the client's thread so a slow one can't hold up the others
+                    let max_message_bytes = server.config.max_line_bytes;
+                    let connect = move || {
+                        stream.set_nonblocking(false)?;
+                        websocket::accept(stream, max_message_bytes).map(ClientStream::WebSocket)
+                    };
+                    client_threads.push(spawn_client(&server, connect, addr.into(), ListenOptions::default(), &active_connections));
+                    accepted = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => error!("WebSocket connection error: {}", e),
+            }
+        }
+
+        if accepted {
+            // Clean up completed threads
+            client_threads.retain(|h| !h.is_finished());
+        } else {
+            // No connection available, sleep briefly and check running flag
+            flush_archive(&server, false);
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    // Drain mode: close the listeners so a new instance can take over the
+    // port and socket, then wait for in-flight clients unless another signal
+    // forces shutdown
+    if running.load(Ordering::SeqCst) {
+        listeners.clear();
+        #[cfg(unix)]
+        drop(local_listener.take());
+        drop(websocket_listener.take());
+        info!(
+            "Entering drain mode: listeners closed, {} active connection(s) remaining",
+            active_connections.load(Ordering::SeqCst)
+        );
+
+        let mut last_report = Instant::now();
+        while running.load(Ordering::SeqCst) && active_connections.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(100));
+            flush_archive(&server, false);
+            if last_report.elapsed() >= DRAIN_REPORT_INTERVAL {
+                info!(
+                    "Draining: {} active connection(s) remaining",
+                    active_connections.load(Ordering::SeqCst)
+                );
+                last_report = Instant::now();
+            }
+        }
+
+        if !running.load(Ordering::SeqCst) {
+            warn!(
+                "Shutdown forced during drain with {} active connection(s)",
+                active_connections.load(Ordering::SeqCst)
+            );
+            server.writer.shutdown();
+            flush_archive(&server, true);
+            return Ok(());
+        }
+        info!("Drain complete: all clients disconnected");
     }
 
-    println!("Server shutting down... waiting for client connections to finish");
+    #[cfg(unix)]
+    drop(local_listener);
+    info!("Server shutting down... waiting for client connections to finish");
     
     // Wait for active client threads to complete (optional timeout could be added)
     for handle in client_threads {
         let _ = handle.join();
     }
+    if let Some(handle) = udp_listener {
+        let _ = handle.join();
+    }
+    if let Some(handle) = http_listener {
+        let _ = handle.join();
+    }
+    if let Some(handle) = mqtt_subscriber {
+        let _ = handle.join();
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(handle) = grpc_listener {
+        let _ = handle.join();
+    }
+    for handle in serial_readers {
+        let _ = handle.join();
+    }
+
+    // Commit whatever the writer still holds
+    server.writer.shutdown();
+    flush_archive(&server, true);
+    info!("Server shutdown complete");
+    Ok(())
+}
+
+// Handle a client in a thread of its own, with the settings of the listener
+// that accepted it. `connect` finishes setting up the connection there, so
+// any handshake it performs doesn't block accepting.
+fn spawn_client(
+    server: &Arc<ServerState>,
+    connect: impl FnOnce() -> io::Result<ClientStream> + Send + 'static,
+    addr: Peer,
+    options: ListenOptions,
+    active_connections: &Arc<AtomicUsize>,
+) -> JoinHandle<()> {
+    info!("Client connected: {}", addr);
+
+    let server = server.clone();
+    let guard = ConnectionGuard::new(active_connections);
+    thread::spawn(move || {
+        // Dropping the guard decrements the active count on every exit path, including a panic
+        let _guard = guard;
+        let stream = match connect() {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Could not set up connection from {}: {}", addr, e);
+                return;
+            }
+        };
+
+        // Make the client stream blocking for reliable data transfer
+        stream.set_nonblocking(false).unwrap_or_else(|e| {
+            warn!("Could not set client socket to blocking mode: {}", e);
+        });
+
+        // Refused while ingest is paused, with a notice saying when to retry
+        if server.breaker.is_open() {
+            refuse_client(&server, stream, addr);
+            return;
+        }
+
+        let connection_id = connections::open(&server.writer, addr);
+        // Outside the handler, so the connection's row is completed however
+        // the handler ends
+        let mut state = ConnectionState { connection_id, ..ConnectionState::new(&server.config) };
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+            handle_client(stream, addr, options, &server, &mut state)
+        }));
+        let (status, notes) = match outcome {
+            Ok(Ok(())) => (connections::STATUS_CLOSED, None),
+            Ok(Err(e)) => {
+                error!("Error handling client {}: {}", addr, e);
+                (connections::STATUS_ERROR, Some(e.to_string()))
+            }
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                error!("Client handler for {} panicked: {}", addr, message);
+                release_all_devices(&server.devices, addr);
+                session::release_sessions(&server.writer, &server.sessions, addr, true);
+                (connections::STATUS_PANIC, Some(message.to_string()))
+            }
+        };
+        if let Some(id) = connection_id {
+            let bytes_received = state.bytes_received.load(Ordering::Relaxed);
+            connections::close(&server.writer, id, status, notes, &state.tally, bytes_received);
+        }
+        info!("Connection from {} ended", addr);
+    })
+}
+
+// Push buffered archive lines to disk, either unconditionally or only once the
+// flush interval has passed
+fn flush_archive(server: &ServerState, force: bool) {
+    let Some(archive) = &server.archive else {
+        return;
+    };
+    let mut archive = archive.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let result = if force { archive.flush() } else { archive.flush_if_due() };
+    if let Err(e) = result {
+        warn!("Failed to flush archive: {}", e);
+    }
+}
+
+// Periodically move records from fallback files into the database once it
+// can be reached again. Runs for the life of the server.
+fn spawn_fallback_replayer(server: Arc<ServerState>) {
+    thread::spawn(move || loop {
+        thread::sleep(FALLBACK_REPLAY_INTERVAL);
+        let Some(fallback) = &server.fallback else {
+            return;
+        };
+        if !fallback.has_pending() {
+            continue;
+        }
+        // Replayed on the writer thread, between batches of live records
+        let state = server.clone();
+        let replayed = server.writer.call(move |conn| {
+            let fallback = state.fallback.as_ref().expect("replayer runs only with a fallback store");
+            Ok(fallback.replay(conn, state.gps_cipher.as_ref(), state.config.dedup_timestamps))
+        });
+        match replayed {
+            Ok(Ok(summary)) if summary.files > 0 => info!(
+                "Replayed {} record(s) from {} fallback file(s)",
+                summary.inserted, summary.files
+            ),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("Fallback replay stopped, will retry: {}", e),
+            Err(e) => {
+                warn!("Database writer unavailable, keeping fallback files: {}", e);
+                return;
+            }
+        }
+    });
+}
 
-    println!("Server shutdown complete");
+// SIGUSR1 starts draining; a second SIGUSR1 while draining forces shutdown
+#[cfg(unix)]
+fn spawn_drain_signal_handler(accepting: Arc<AtomicBool>, running: Arc<AtomicBool>) -> io::Result<()> {
+    let mut signals = Signals::new([SIGUSR1])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            if accepting.swap(false, Ordering::SeqCst) {
+                info!("Drain signal received, no longer accepting new connections...");
+            } else {
+                warn!("Second drain signal received, forcing shutdown...");
+                running.store(false, Ordering::SeqCst);
+            }
+        }
+    });
     Ok(())
 }
 
-fn handle_client(mut stream: TcpStream, conn: &Connection) -> Result<(), Box<dyn Error>> {
+// Counts a client thread as active for as long as it holds the guard, even if
+// the thread panics
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl ConnectionGuard {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(counter.clone())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Record the device claimed in a handshake, warning if another live
+// connection already claims the same device
+fn claim_device(devices: &ActiveDevices, device_id: &str, addr: Peer) {
+    let mut devices = devices.lock().unwrap();
+    if let Some(existing) = devices.get(device_id) {
+        if *existing != addr {
+            warn!(
+                "Suspicious: device '{}' claimed by {} while already connected from {}",
+                device_id, addr, existing
+            );
+        }
+    }
+    devices.insert(device_id.to_string(), addr);
+}
+
+fn release_device(devices: &ActiveDevices, device_id: &str, addr: Peer) {
+    let mut devices = devices.lock().unwrap();
+    if devices.get(device_id) == Some(&addr) {
+        devices.remove(device_id);
+    }
+}
+
+// Drop every device claim held by a connection whose handler died without
+// running its normal cleanup
+fn release_all_devices(devices: &ActiveDevices, addr: Peer) {
+    let mut devices = devices.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    devices.retain(|_, owner| *owner != addr);
+}
+
+// Best-effort text of a panic payload; panics raised with a message carry
+// either a String or a &str
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+fn handle_client(
+    stream: ClientStream,
+    addr: Peer,
+    options: ListenOptions,
+    server: &ServerState,
+    state: &mut ConnectionState,
+) -> Result<(), Box<dyn Error>> {
+    state.listen_options = options;
+    // WebSocket clients get error frames without asking for them
+    if matches!(stream, ClientStream::WebSocket(_)) {
+        state.websocket = true;
+        state.error_replies = Some(ErrorReplyLimiter::default());
+    }
+    let result = read_client(stream, addr, server, state);
+
+    // A slow client shows up as read latency, a database that can't keep up
+    // as insert latency
+    if state.read_latency.count() > 0 {
+        info!("Read latency for {}: {}", addr, state.read_latency);
+    }
+    if state.insert_latency.count() > 0 {
+        info!("Insert latency for {}: {}", addr, state.insert_latency);
+    }
+    if let Some(decimation) = &state.decimation {
+        decimation.report(addr);
+    }
+
+    if let Some(device_id) = &state.device_id {
+        release_device(&server.devices, device_id, addr);
+    }
+    session::release_sessions(&server.writer, &server.sessions, addr, false);
+    save_session_stats(server, &state.stats_sessions);
+    result
+}
+
+// Keep the statistics of the sessions a connection sent on their rows, once
+// the records it queued are stored
+fn save_session_stats(server: &ServerState, sessions: &BTreeSet<i64>) {
+    for &session_id in sessions {
+        let live = server.live_stats.clone();
+        if let Err(e) = server.writer.call(move |conn| stats::save_final(conn, &live, session_id)) {
+            error!("Failed to save the statistics of session {}: {}", session_id, e);
+        }
+    }
+}
+
+// Settings that change how lines are parsed
+#[derive(Debug, Clone, Copy)]
+struct ParseOptions<'a> {
+    // Accept a negative dt_ms in a batch instead of rejecting it
+    allow_negative_dt: bool,
+    // Sensor fields a record must carry
+    profile: Profile,
+    require_fields: &'a [&'static str],
+    // How line protocol maps onto records
+    influx: &'a InfluxOptions,
+    // What sample blocks and delta-encoded batches count from
+    timestamp_formats: &'a [timestamp::TimestampFormat],
+}
+
+impl<'a> From<&'a Config> for ParseOptions<'a> {
+    fn from(config: &'a Config) -> Self {
+        ParseOptions {
+            allow_negative_dt: config.allow_negative_dt,
+            profile: config.profile,
+            require_fields: &config.require_fields,
+            influx: &config.influx,
+            timestamp_formats: &config.timestamp_formats,
+        }
+    }
+}
+
+// Decide what kind of message a line holds. A JSON array is either one
+// record in the positional format or a batch of records. Control messages
+// are recognised by their "type" field first; anything else is parsed
+// as sensor data, either a plain record or a block of IMU samples. Lines that
+// aren't JSON at all are InfluxDB line protocol. A sample block that can't be expanded, a batch whose
+// delta timestamps can't be resolved, or a row lacking a field the profile
+// requires is reported as a parse error.
+fn parse_message(line: &str, options: &ParseOptions) -> Result<Message, serde_json::Error> {
+    let mut rows = if line.trim_start().starts_with('[') {
+        let mut elements: Vec<serde_json::Value> = serde_json::from_str(line)?;
+        if positional::is_positional(&elements) {
+            vec![positional::parse(&elements).map_err(<serde_json::Error as serde::de::Error>::custom)?]
+        } else {
+            parse_batch(&mut elements, options)?
+        }
+    } else if influx::is_line_protocol(line) {
+        vec![influx::parse(line, options.influx).map_err(<serde_json::Error as serde::de::Error>::custom)?]
+    } else if let Some(control) = serde_json::from_str::<KeepaliveMessage>(line).ok().filter(KeepaliveMessage::is_control) {
+        return Ok(match control.message_type.as_str() {
+            "keepalive" => Message::Keepalive,
+            "hello" => Message::Hello(serde_json::from_str(line)?),
+            "session_start" => Message::SessionStart(serde_json::from_str(line)?),
+            "session_end" => Message::SessionEnd(serde_json::from_str(line)?),
+            "session_config" => Message::SessionConfig(serde_json::from_str(line)?),
+            "upload_complete" => Message::UploadComplete(serde_json::from_str(line)?),
+            "query" => Message::Query(serde_json::from_str(line)?),
+            "subscribe" => Message::Subscribe(serde_json::from_str(line)?),
+            "unsubscribe" => Message::Unsubscribe,
+            "flush" => Message::Flush,
+            "resume_info" => Message::ResumeInfo(serde_json::from_str(line)?),
+            _ => Message::Unknown(control.message_type),
+        });
+    } else {
+        parse_record(line, options)?
+    };
+    // Dropped by handle_records; the profile would reject it as a record
+    if is_disguised_keepalive(line, &rows) {
+        return Ok(Message::SensorData(rows));
+    }
+    for data in &mut rows {
+        // The device comes from the handshake, as it always has; kept among
+        // the extras it would clash with the device_id stored next to them
+        data.extras.remove("device_id");
+        data.imu_raw = None;
+        data.accel_filtered = None;
+        data.cumulative_angles = None;
+        data.cumulative_distance_m = None;
+        data.tilt = None;
+        data.dac_raw = None;
+        data.position_raw = None;
+        data.is_outlier = false;
+        data.is_interpolated = false;
+        data.gps_low_quality = false;
+        data.outside_fence = false;
+        data.check_dac().map_err(<serde_json::Error as serde::de::Error>::custom)?;
+        options.profile.check(data, options.require_fields).map_err(<serde_json::Error as serde::de::Error>::custom)?;
+    }
+    Ok(Message::SensorData(rows))
+}
+
+// The rows of a single record line
+fn parse_record(line: &str, options: &ParseOptions) -> Result<Vec<SensorData>, serde_json::Error> {
+    match serde_json::from_str::<SensorData>(line) {
+        Ok(data) => Ok(vec![data]),
+        // Not a plain record, but it may carry arrays of IMU samples
+        Err(e) => match serde_json::from_str::<SampleBlock>(line) {
+            Ok(block) => block.expand(options.timestamp_formats).map_err(<serde_json::Error as serde::de::Error>::custom),
+            Err(_) => Err(e),
+        },
+    }
+}
+
+// Rows from every element of a batch line, in order
+fn parse_batch(elements: &mut [serde_json::Value], options: &ParseOptions) -> Result<Vec<SensorData>, serde_json::Error> {
+    delta::resolve_deltas(elements, options.allow_negative_dt, options.timestamp_formats).map_err(<serde_json::Error as serde::de::Error>::custom)?;
+
+    let mut rows = Vec::with_capacity(elements.len());
+    for element in elements.iter() {
+        match SensorData::deserialize(element) {
+            Ok(data) => rows.push(data),
+            Err(e) => match SampleBlock::deserialize(element) {
+                Ok(block) => rows.extend(block.expand(options.timestamp_formats).map_err(<serde_json::Error as serde::de::Error>::custom)?),
+                Err(_) => return Err(e),
+            },
+        }
+    }
+    Ok(rows)
+}
+
+fn send_pong(writer: &mut ClientWriter) -> io::Result<()> {
+    let pong = PongMessage {
+        message_type: "pong".to_string(),
+        server_time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+    };
+    send_json(writer, &pong)
+}
+
+// Write one reply as a single JSON line
+fn send_json<T: Serialize>(writer: &mut ClientWriter, message: &T) -> io::Result<()> {
+    let mut reply = serde_json::to_string(message)?;
+    reply.push('\n');
+    writer.write_all(reply.as_bytes())
+}
+
+fn send_backpressure_notice(writer: &mut ClientWriter, addr: Peer, notice: &BackpressureNotice) {
+    if let Err(e) = send_json(writer, notice) {
+        warn!("Failed to send backpressure notice to {}: {}", addr, e);
+    }
+}
+
+fn send_backoff_notice(writer: &mut ClientWriter, addr: Peer, notice: &BackoffNotice) {
+    if let Err(e) = send_json(writer, notice) {
+        warn!("Failed to send backoff notice to {}: {}", addr, e);
+    }
+}
+
+// Turn away a connection that arrived while ingest is paused
+fn refuse_client(server: &ServerState, stream: ClientStream, addr: Peer) {
+    info!("Refusing connection from {}: ingest is paused while the database is unavailable", addr);
+    server.metrics.breaker_refused_connections.fetch_add(1, Ordering::Relaxed);
+    match ClientWriter::new(stream) {
+        Ok(mut writer) => {
+            send_backoff_notice(&mut writer, addr, &BackoffNotice::new(server.breaker.retry_after(), 0));
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+        Err(e) => warn!("Failed to send backoff notice to {}: {}", addr, e),
+    }
+}
+
+// Tell the client why a line was rejected, if it asked to be told
+fn send_error_reply(
+    writer: &mut ClientWriter,
+    addr: Peer,
+    state: &mut ConnectionState,
+    code: ErrorCode,
+    error: &str,
+    input: &str,
+) {
+    let Some(limiter) = state.error_replies.as_mut() else {
+        return;
+    };
+    if let Some(reply) = limiter.admit(ErrorReply::new(code, error, input)) {
+        if let Err(e) = send_json(writer, &reply) {
+            warn!("Failed to send error reply to {}: {}", addr, e);
+        }
+    }
+}
+
+// Old firmware sends its keepalive as a plain object record whose timestamp
+// is the word "keepalive". Only that exact shape counts: a batch or a
+// positional array is real data even if its timestamp is garbage, and is
+// validated as such.
+fn is_disguised_keepalive(line: &str, rows: &[SensorData]) -> bool {
+    line.trim_start().starts_with('{') && matches!(rows, [data] if data.timestamp == "keepalive")
+}
+
+// Validate, filter and queue the rows of one line. Returns whether they were
+// accepted; a rejected line is answered through `writer` when the client can
+// be replied to and asked for error replies.
+fn handle_records(
+    server: &ServerState,
+    writer: Option<&mut ClientWriter>,
+    addr: Peer,
+    state: &mut ConnectionState,
+    line: &str,
+    mut rows: Vec<SensorData>,
+    tally: &Arc<SessionTally>,
+) -> rusqlite::Result<bool> {
+    let config = &server.config;
+    if is_disguised_keepalive(line, &rows) {
+        debug!("Detected keepalive disguised as sensor data");
+        return Ok(false);
+    }
+
+    // Everything after this reads timestamps in their stored form
+    if let Err(error) = rows.iter_mut().try_for_each(|data| timestamp::normalize(&mut data.timestamp, &config.timestamp_formats)) {
+        reject_line(server, writer, addr, state, line, "timestamp", &error);
+        return Ok(false);
+    }
+
+    // Records beyond the target rate are discarded before anything else
+    // looks at them, as if they had never been sent
+    if let Some(decimation) = state.decimation.as_mut() {
+        rows.retain(|data| {
+            let accept = decimation.should_accept(data);
+            if !accept {
+                debug!("Decimated record from {} at {}", addr, data.timestamp);
+                server.metrics.decimated_rows.fetch_add(1, Ordering::Relaxed);
+            }
+            accept
+        });
+        if rows.is_empty() {
+            return Ok(true);
+        }
+    }
+
+    // Validation and everything after it work on the corrected values
+    for data in &mut rows {
+        calibration::apply_calibration(data, &config.calibration);
+    }
+
+    // Rows expanded from one line are accepted or rejected together
+    if let Err(error) = rows.iter().try_for_each(|data| validate_sensor_data(data, config)) {
+        reject_line(server, writer, addr, state, line, "validation", &error);
+        return Ok(false);
+    }
+    let field_ranges_reject = config.field_limits.field_range_action == FieldRangeAction::Reject;
+    let checked = rows.iter().try_for_each(|data| {
+        validation::check_position(data)?;
+        if field_ranges_reject {
+            config.field_limits.check(data)?;
+        }
+        Ok(())
+    });
+    if let Err(error) = checked {
+        if matches!(error, ValidationError::BelowMinimum { .. } | ValidationError::AboveMaximum { .. }) {
+            server.metrics.field_range_violations.fetch_add(1, Ordering::Relaxed);
+        }
+        reject_line(server, writer, addr, state, line, error.error_type(), &error.to_string());
+        return Ok(false);
+    }
+
+    for data in &rows {
+        server.alerts.check(data, addr);
+    }
+
+    // Synthetic rows go through the filters below like received ones
+    if let Some(max_gap_ms) = config.max_gap_ms {
+        rows = state.gaps.fill(rows, max_gap_ms);
+    }
+
+    // Validation saw the values as received; the archive and
+    // database get the smoothed ones, with the originals alongside.
+    // The low-pass filter works on the values as received too, or on
+    // their medians with --enable-median-filter.
+    for data in &mut rows {
+        if let Some(reason) = config.gps_quality.apply(data) {
+            debug!("Low-quality position from {}: {}", addr, reason);
+            server.metrics.gps_low_quality.fetch_add(1, Ordering::Relaxed);
+        }
+        if config.geo_fence.apply(data) {
+            server.alerts.geo_fence_violation(data, addr);
+        }
+        state.distance.apply(data);
+        // Made-up values would only pull the statistics toward themselves
+        if !data.is_interpolated && state.outliers.apply(data) {
+            server.metrics.outlier_rows.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(altitude) = config.altitude.violation(data) {
+            warn!(
+                "Implausible altitude {} m in session {} from {} at {}; flagging the record as an outlier",
+                altitude,
+                data.session_id.map_or("none".to_string(), |id| id.to_string()),
+                addr,
+                data.timestamp
+            );
+            data.is_outlier = true;
+            server.metrics.altitude_range_violations.fetch_add(1, Ordering::Relaxed);
+        }
+        // With --field-range-action reject, such records were turned away above
+        if let Err(error) = config.field_limits.check(data) {
+            warn!("Record from {} at {}: {}; flagging it as an outlier", addr, data.timestamp, error);
+            data.is_outlier = true;
+            server.metrics.field_range_violations.fetch_add(1, Ordering::Relaxed);
+        }
+        for violation in config.dac.apply(data) {
+            warn!("Record from {} at {}: {}", addr, data.timestamp, violation);
+            server.metrics.dac_range_violations.fetch_add(1, Ordering::Relaxed);
+        }
+        // Spikes go before anything downstream integrates or averages them
+        if let Some(median) = state.median.as_mut() {
+            median.apply(data);
+        }
+        state.lowpass.apply(data);
+        state.gyro.apply(data);
+        if let Some(filter) = state.filter.as_mut() {
+            filter.apply(data);
+        }
+        tilt::apply(data);
+        config.output_units.apply(data);
+    }
+
+    // While ingest is paused the records are refused rather than queued
+    // behind a database that can't take them
+    if server.breaker.is_open() {
+        let count = rows.len() as u64;
+        server.metrics.breaker_refused_records.fetch_add(count, Ordering::Relaxed);
+        debug!("Refused {} record(s) from {}: ingest is paused while the database is unavailable", count, addr);
+        let notice = state.backoff.refused(count, server.breaker.retry_after());
+        if let (Some(writer), Some(notice)) = (writer, notice) {
+            send_backoff_notice(writer, addr, &notice);
+        }
+        return Ok(false);
+    }
+
+    // The archive is best-effort and never holds up the database path
+    if let Some(archive) = &server.archive {
+        let mut archive = archive.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for data in &rows {
+            if let Err(e) = archive.append(data, state.device_id.as_deref()) {
+                warn!("Failed to write record to archive: {}", e);
+            }
+        }
+    }
+
+    // Log to the WAL, then queue for the writer. Sending
+    // waits while the writer's queue is full, which stops
+    // reads so TCP pushes back on the client.
+    let insert_started = Instant::now();
+    state.stats_sessions.extend(rows.iter().filter_map(|data| data.session_id));
+    let records: Vec<PendingRecord> = rows
+        .into_iter()
+        .map(|data| PendingRecord { data, device_id: state.device_id.clone(), connection_id: state.connection_id })
+        .collect();
+    for record in &records {
+        if let Err(e) = server.wal.append(record) {
+            warn!("Failed to write record to WAL: {}", e);
+        }
+    }
+    let count = records.len() as u64;
+    let sent = server.writer.send(records, tally)?;
+    state.insert_latency.record(insert_started.elapsed());
+    let Sent::Dropped(records) = sent else {
+        state.records_sent += count;
+        return Ok(true);
+    };
+    // Dropped records must not come back when the WAL is recovered
+    for record in &records {
+        if let Err(e) = server.wal.commit(&record.session_key(), 1, None) {
+            warn!("Failed to write WAL commit for session {}: {}", record.session_key(), e);
+        }
+    }
+    let error = format!(
+        "database writer queue full for {}ms; {} record(s) dropped",
+        config.backpressure_timeout_ms,
+        records.len()
+    );
+    warn!("Dropped record from {}: {}", addr, error);
+    let notice = state.backpressure.dropped(records.len() as u64);
+    if let Some(writer) = writer {
+        send_error_reply(writer, addr, state, ErrorCode::Overloaded, &error, line);
+        if let Some(notice) = notice {
+            send_backpressure_notice(writer, addr, &notice);
+        }
+    }
+    Ok(false)
+}
+
+// A line of records that failed validation is kept in dead_letters instead
+// of being stored
+fn reject_line(
+    server: &ServerState,
+    writer: Option<&mut ClientWriter>,
+    addr: Peer,
+    state: &mut ConnectionState,
+    line: &str,
+    error_type: &'static str,
+    error: &str,
+) {
+    warn!("Rejected record from {}: {}", addr, error);
+    server.metrics.validation_errors.fetch_add(1, Ordering::Relaxed);
+    if let Some(alerts) = &server.rejection_alerts {
+        alerts.record(addr, error_type, error, line);
+    }
+    if let Some(writer) = writer {
+        send_error_reply(writer, addr, state, ErrorCode::ValidationError, error, line);
+    }
+    let (rejected, reason) = (line.to_string(), error.to_string());
+    let recorded = server.writer.call(move |conn| insert_dead_letter(conn, &rejected, error_type, &reason));
+    if let Err(e) = recorded {
+        error!("Failed to record rejected line: {}", e);
+    }
+}
+
+// Largest magnitude below which every integer has an exact f64
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_992.0; // 2^53
+
+// Whether a parsed value can be trusted to be the number the client sent.
+// From 2^53 on not every integer is representable, so a number that large
+// may have been rounded to its nearest neighbour while parsing.
+fn is_lossless_f64(value: f64) -> bool {
+    value.abs() < MAX_SAFE_INTEGER
+}
+
+// Checks a parsed record must pass before it is stored. A sensor value that
+// may have lost precision is logged, and rejects the record if configured to.
+fn validate_sensor_data(data: &SensorData, config: &Config) -> Result<(), String> {
+    if data.timestamp.trim().is_empty() {
+        return Err("timestamp must not be empty".to_string());
+    }
+    if let Some(extras) = data.extras_json().filter(|extras| extras.len() > config.max_extras_bytes) {
+        return Err(format!(
+            "unknown fields take {} bytes, more than the {} allowed",
+            extras.len(),
+            config.max_extras_bytes
+        ));
+    }
+    if data.hdop.is_some_and(|hdop| !(hdop.is_finite() && hdop >= 0.0)) {
+        return Err("hdop must be a finite number of at least 0".to_string());
+    }
+    for (name, value) in data.fields() {
+        if value.is_some_and(|value| !value.is_finite()) {
+            return Err(format!("{} must be a finite number", name));
+        }
+        let Some(value) = value.filter(|&value| !is_lossless_f64(value)) else {
+            continue;
+        };
+        let error = format!("{} = {} is beyond the exact integer range of f64 and may have lost precision", name, value);
+        if config.reject_imprecise_numbers {
+            return Err(error);
+        }
+        warn!("Record at {}: {}", data.timestamp, error);
+    }
+    Ok(())
+}
+
+// The INSERT for one record, with the given conflict handling
+macro_rules! insert_sensor_data_sql {
+    ($verb:literal) => {
+        concat!(
+            $verb,
+            " INTO sensor_data (
+                sessionID, timestamp, latitude, longitude, altitude,
+                accel_x, accel_y, accel_z,
+                gyro_x, gyro_y, gyro_z,
+                dac_1, dac_2, dac_3, dac_4, dac_5, dac_6, dac_7, dac_8,
+                dac_9, dac_10, dac_11, dac_12, dac_13, dac_14, dac_15, dac_16,
+                device_id, message_id, extras,
+                accel_x_raw, accel_y_raw, accel_z_raw, gyro_x_raw, gyro_y_raw, gyro_z_raw,
+                mag_x, mag_y, mag_z, temperature_c, battery_v,
+                accel_x_filtered, accel_y_filtered, accel_z_filtered, is_outlier,
+                fix_quality, num_satellites, hdop, gps_low_quality, accel_magnitude,
+                cumulative_pitch, cumulative_roll, cumulative_yaw,
+                dac_1_raw, dac_2_raw, dac_3_raw, dac_4_raw, dac_5_raw, dac_6_raw, dac_7_raw, dac_8_raw,
+                dac_9_raw, dac_10_raw, dac_11_raw, dac_12_raw, dac_13_raw, dac_14_raw, dac_15_raw, dac_16_raw,
+                pitch_rad, roll_rad, pitch_roll_valid, outside_fence, cumulative_distance_m,
+                is_interpolated, latitude_raw, longitude_raw, altitude_raw,
+                connection_id, timestamp_ms, after_session_end
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11,
+                ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27,
+                ?28, ?29, ?30,
+                ?31, ?32, ?33, ?34, ?35, ?36,
+                ?37, ?38, ?39, ?40, ?41,
+                ?42, ?43, ?44, ?45,
+                ?46, ?47, ?48, ?49, ?50,
+                ?51, ?52, ?53,
+                ?54, ?55, ?56, ?57, ?58, ?59, ?60, ?61, ?62, ?63, ?64, ?65, ?66, ?67, ?68, ?69,
+                ?70, ?71, ?72, ?73, ?74,
+                ?75, ?76, ?77, ?78,
+                (SELECT id FROM connections WHERE id = ?79), ?80,
+                EXISTS (SELECT 1 FROM sessions WHERE sessionID = ?1 AND ended_at IS NOT NULL))"
+        )
+    };
+}
+
+// Records for a session that has already ended are stored, but flagged. A
+// record keeps its connection only if that connection's row is in this file;
+// after a reopen, or when replayed into another file, it has none. A
+// record whose message_id is already stored is skipped, as is one whose
+// session has a row at its timestamp with `dedup_timestamps`. With a GPS cipher,
+// latitude and longitude are stored encrypted. Returns the new row's ID, or
+// None if the record was a duplicate.
+//
+// The statement comes from the connection's prepared statement cache, so the
+// SQL is compiled once per connection rather than once per record.
+fn insert_sensor_data(
+    conn: &Connection,
+    data: &SensorData,
+    device_id: Option<&str>,
+    connection_id: Option<i64>,
+    gps_cipher: Option<&GpsCipher>,
+    dedup_timestamps: bool,
+) -> rusqlite::Result<Option<i64>> {
+    // Otherwise only records with an ID can be duplicates; OR IGNORE would
+    // also hide other constraint failures, so plain records don't use it
+    let sql = if data.message_id.is_some() || dedup_timestamps {
+        insert_sensor_data_sql!("INSERT OR IGNORE")
+    } else {
+        insert_sensor_data_sql!("INSERT")
+    };
+    let mut stmt = conn.prepare_cached(sql)?;
+    let dac = data.dac_channels();
+    let raw = data.imu_raw.unwrap_or_default();
+    let filtered = data.accel_filtered.unwrap_or_default();
+    let angles = data.cumulative_angles.map_or([None; 3], |angles| angles.map(Some));
+    let dac_raw = data.dac_raw.unwrap_or_default();
+    let tilt = data.tilt;
+    let position_raw = data.position_raw.unwrap_or_default();
+    let seal = |column: &str, value: Option<f64>| match gps_cipher {
+        Some(cipher) => Value::from(cipher.seal(column, value)),
+        None => Value::from(value),
+    };
+    let (latitude, longitude) = (seal("latitude", data.latitude), seal("longitude", data.longitude));
+    let (latitude_raw, longitude_raw) = (seal("latitude_raw", position_raw[0]), seal("longitude_raw", position_raw[1]));
+    // The timestamp is in its normalized form by now; time ranges are
+    // queried on this, which unlike the text compares across offsets
+    let timestamp_ms = timestamp::ClientTimestamp::parse(&data.timestamp).map(|time| time.to_utc().timestamp_millis());
+    let inserted = stmt.execute(params![
+        data.session_id, data.timestamp, latitude, longitude, data.altitude,
+        data.accel_x, data.accel_y, data.accel_z,
+        data.gyro_x, data.gyro_y, data.gyro_z,
+        dac[0], dac[1], dac[2], dac[3], dac[4], dac[5], dac[6], dac[7],
+        dac[8], dac[9], dac[10], dac[11], dac[12], dac[13], dac[14], dac[15],
+        device_id, data.message_id, data.extras_json(),
+        raw[0], raw[1], raw[2], raw[3], raw[4], raw[5],
+        data.mag_x, data.mag_y, data.mag_z, data.temperature_c, data.battery_v,
+        filtered[0], filtered[1], filtered[2], data.is_outlier,
+        data.fix_quality, data.num_satellites, data.hdop, data.gps_low_quality, data.accel_magnitude(),
+        angles[0], angles[1], angles[2],
+        dac_raw[0], dac_raw[1], dac_raw[2], dac_raw[3], dac_raw[4], dac_raw[5], dac_raw[6], dac_raw[7],
+        dac_raw[8], dac_raw[9], dac_raw[10], dac_raw[11], dac_raw[12], dac_raw[13], dac_raw[14], dac_raw[15],
+        tilt.map(|tilt| tilt.pitch_rad), tilt.map(|tilt| tilt.roll_rad), tilt.map(|tilt| tilt.valid), data.outside_fence,
+        data.cumulative_distance_m,
+        data.is_interpolated, latitude_raw, longitude_raw, position_raw[2],
+        connection_id, timestamp_ms
+    ])?;
+    Ok((inserted > 0).then(|| conn.last_insert_rowid()))
+}
+
+// From now on the client is disconnected if it goes quiet for longer than the
+// keepalive timeout
+fn negotiate_keepalive(addr: Peer, config: &Config, state: &mut ConnectionState) {
+    state.keepalive_negotiated = true;
+    info!(
+        "Client {} uses keepalives; disconnecting after {}s of silence",
+        addr, config.keepalive_timeout_secs
+    );
+}
+
+// A read that gave up waiting reports TimedOut or, on Unix, WouldBlock
+fn is_read_timeout(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock
+}
+
+fn read_client(
+    stream: ClientStream,
+    addr: Peer,
+    server: &ServerState,
+    state: &mut ConnectionState,
+) -> Result<(), Box<dyn Error>> {
+    let config = &server.config;
+    let devices = &server.devices;
+
     // Set read timeout instead of using non-blocking mode
-    stream.set_read_timeout(Some(Duration::from_secs(300)))?; // 5 minutes
+    stream.set_read_timeout(Some(READ_POLL_INTERVAL))?;
     
-    // Use larger buffer size
-    let reader = BufReader::with_capacity(8192, stream);
-
-    // Process each line as one JSON record
-    for line in reader.lines() {
-        match line {
-            Ok(line) => {
-                let line = line.trim();
+    // Keep a handle for replies before the reader takes ownership
+    let mut writer = ClientWriter::new(stream.try_clone()?)?;
+
+    // The first bytes tell JSON lines from MessagePack frames, unless the
+    // listener fixes the format. WebSocket frames are always text.
+    let format = match stream {
+        ClientStream::WebSocket(_) => Some(WireFormat::Lines),
+        _ => state.listen_options.format,
+    };
+    let stream = WireReader::new(Counted::new(stream, state.bytes_received.clone()), addr, config.max_line_bytes, format);
+    let mut reader = BufReader::with_capacity(config.read_buffer_bytes, stream);
+
+    // Process each line as one JSON record. The buffer lives across reads so a
+    // timeout part-way through a line doesn't discard what has arrived so far.
+    // A complete line is swapped into `line_buffer` for processing, so both
+    // keep their capacity and reading a line doesn't allocate.
+    let mut buffer = Vec::new();
+    let mut line_buffer = Vec::new();
+    let mut read_started = Instant::now();
+    let mut last_message = Instant::now();
+    let mut discarding = false;
+    // A pretty-printed value whose closing bracket hasn't arrived yet, and
+    // whether the rest of one that grew too large is being skipped
+    let mut pending_value: Option<MultiLine> = None;
+    let mut skipping_value = false;
+
+    let tally = state.tally.clone();
+    // Opened on the first query, with the file it was opened on; reads don't
+    // go through the writer
+    let mut query_conn: Option<(Connection, Option<FileId>)> = None;
+
+    loop {
+        // Report drops the rate limit held back once it allows another notice
+        if let Some(notice) = state.backpressure.due() {
+            send_backpressure_notice(&mut writer, addr, &notice);
+        }
+        if let Some(notice) = state.backoff.due(server.breaker.retry_after()) {
+            send_backoff_notice(&mut writer, addr, &notice);
+        }
+
+        // Throw away the rest of a line that was too long to keep
+        if discarding {
+            match reader.skip_until(b'\n') {
+                Ok(0) => break,
+                Ok(_) => discarding = false,
+                Err(e) if is_read_timeout(&e) => {}
+                Err(e) => {
+                    info!("Client disconnected: {}", e);
+                    break;
+                }
+            }
+            continue;
+        }
+
+        if buffer.is_empty() {
+            read_started = Instant::now();
+        }
+        // Read at most one byte past the limit, enough to tell the line is too long
+        let limit = (config.max_line_bytes + 1).saturating_sub(buffer.len()) as u64;
+        match (&mut reader).take(limit).read_until(b'\n', &mut buffer) {
+            // End of stream
+            Ok(0) => break,
+            Ok(_) if buffer.len() > config.max_line_bytes && buffer.last() != Some(&b'\n') => {
+                let error = format!("line exceeds {} bytes", config.max_line_bytes);
+                warn!("Discarding oversized line from {}: {}", addr, error);
+                send_error_reply(&mut writer, addr, state, ErrorCode::OversizedLine, &error, &String::from_utf8_lossy(&buffer));
+                buffer.clear();
+                discarding = true;
+                // A value this line belonged to can't be completed either
+                skipping_value |= pending_value.take().is_some();
+            }
+            Ok(_) => {
+                state.read_latency.record(read_started.elapsed());
+                last_message = Instant::now();
+                std::mem::swap(&mut buffer, &mut line_buffer);
+                buffer.clear();
+                let line = match std::str::from_utf8(&line_buffer) {
+                    Ok(line) => line.trim(),
+                    Err(e) => {
+                        let line = String::from_utf8_lossy(line_buffer.trim_ascii());
+                        let error = format!("line is not valid UTF-8: {}", e);
+                        warn!("Rejected line from {}: {}", addr, error);
+                        send_error_reply(&mut writer, addr, state, ErrorCode::ParseError, &error, &line);
+                        if let Err(qe) = quarantine::quarantine_message(&config.quarantine_dir, &line, &error) {
+                            error!("Failed to quarantine line: {}", qe);
+                        }
+                        continue;
+                    }
+                };
                 // Skip empty lines
                 if line.is_empty() {
                     continue;
                 }
+
+                // What is left of a value too large to keep is dropped up to
+                // the start of the next one
+                if skipping_value {
+                    if !multiline::starts_value(&line_buffer) {
+                        continue;
+                    }
+                    skipping_value = false;
+                }
+                let assembled;
+                let line = match pending_value.take() {
+                    // A new value starting before the pending one closed means
+                    // the pending one was cut short or broken
+                    Some(value) if multiline::starts_value(&line_buffer) => {
+                        let error = "JSON value ended before its closing bracket";
+                        reject_unparsed(Some(&mut writer), addr, state, server, &value.into_text(), error);
+                        line
+                    }
+                    Some(mut value) => match value.push(line, config.max_line_bytes) {
+                        Progress::Incomplete => {
+                            pending_value = Some(value);
+                            continue;
+                        }
+                        Progress::Complete(text) => {
+                            assembled = text;
+                            assembled.as_str()
+                        }
+                        Progress::TooLarge(text) => {
+                            let error = format!("JSON value exceeds {} bytes", config.max_line_bytes);
+                            warn!("Discarding oversized value from {}: {}", addr, error);
+                            send_error_reply(&mut writer, addr, state, ErrorCode::OversizedLine, &error, &text);
+                            skipping_value = true;
+                            continue;
+                        }
+                    },
+                    None => line,
+                };
                 
                 // Debug output to see what's being received
-                println!("Received data: {}", line);
+                debug!("Received data: {}", line);
                 
-                // First check if the line contains "keepalive" before attempting to parse
-                if line.contains("\"type\":\"keepalive\"") {
-                    println!("Received keepalive message");
-                    continue; // Skip further processing for this line
+                let parsed = parse_message(line, &ParseOptions::from(config));
+                // A line that stops part-way through a value is the start of a
+                // pretty-printed one; the lines after it complete it
+                if parsed.as_ref().is_err_and(|e| e.is_eof()) && line.starts_with(['{', '[']) && line.len() < config.max_line_bytes {
+                    pending_value = Some(MultiLine::start(line));
+                    continue;
                 }
-                
-                // Try to parse as sensor data
-                match serde_json::from_str::<SensorData>(&line) {
-                        Ok(data) => {
-                            // Additional validation - skip if timestamp is "keepalive"
-                            if data.timestamp == "keepalive" || data.timestamp.contains("keepalive") {
-                                println!("Detected keepalive disguised as sensor data");
-                                continue;
-                            }
-                                                        
-                            // Insert into the database
-                            if let Err(e) = conn.execute(
-                                "INSERT INTO sensor_data (
-                                    sessionID, timestamp, latitude, longitude, altitude,
-                                    accel_x, accel_y, accel_z, 
-                                    gyro_x, gyro_y, gyro_z,
-                                    dac_1, dac_2, dac_3, dac_4
-                                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
-                                params![
-                                    data.sessionID, data.timestamp, data.latitude, data.longitude, data.altitude,
-                                    data.accel_x, data.accel_y, data.accel_z, 
-                                    data.gyro_x, data.gyro_y, data.gyro_z,
-                                    data.dac_1, data.dac_2, data.dac_3, data.dac_4
-                                ],
-                            ) {
-                                eprintln!("Database error: {}", e);
-                            } else {
-                                println!("Data successfully inserted into database");
-                            }
-                        },
-                    Err(e) => {
-                        eprintln!("JSON parsing error: {}", e);
-                        eprintln!("Invalid JSON data: {}", line);
+                match parsed {
+                    Ok(Message::Keepalive) => {
+                        debug!("Received keepalive message");
+                        // A client that sends keepalives is held to them
+                        if !state.keepalive_negotiated {
+                            negotiate_keepalive(addr, config, state);
+                        }
+                        if let Err(e) = send_pong(&mut writer) {
+                            warn!("Failed to send pong to {}: {}", addr, e);
+                        }
+                    }
+                    Ok(Message::Hello(hello)) => {
+                        // Handshake identifying the device behind this connection
+                        if let Some(device_id) = hello.device_id {
+                            info!("Client {} identified as device '{}'", addr, device_id);
+                            if let Some(previous) = state.device_id.take() {
+                                release_device(devices, &previous, addr);
+                            }
+                            claim_device(devices, &device_id, addr);
+                            state.device_id = Some(device_id);
+                        }
+                        if hello.error_replies && state.error_replies.is_none() {
+                            state.error_replies = Some(ErrorReplyLimiter::default());
+                        }
+                        if hello.keepalive && !state.keepalive_negotiated {
+                            negotiate_keepalive(addr, config, state);
+                        }
+                    }
+                    Ok(Message::SessionStart(start)) => {
+                        let device_id = start.device_id.clone().or_else(|| state.device_id.clone());
+                        let units = config.output_units;
+                        let started = server
+                            .writer
+                            .call(move |conn| session::start_session(conn, &start, device_id.as_deref(), units).map(|id| (id, start)));
+                        match started.map_err(|e| e.to_string()) {
+                            Ok((session_id, start)) => {
+                                info!(
+                                    "Client {} started session {} ({})",
+                                    addr,
+                                    session_id,
+                                    start.label.as_deref().unwrap_or("no label")
+                                );
+                                server.sessions.insert(
+                                    session_id,
+                                    OpenSession { addr, auto_close: start.auto_close },
+                                );
+                                if let Err(e) = send_json(&mut writer, &SessionStarted::new(session_id)) {
+                                    warn!("Failed to send session ID to {}: {}", addr, e);
+                                }
+                            }
+                            Err(error) => {
+                                error!("Failed to start session for {}: {}", addr, error);
+                                send_error_reply(&mut writer, addr, state, ErrorCode::SessionError, &error, line);
+                            }
+                        }
+                    }
+                    Ok(Message::SessionEnd(end)) => {
+                        // The writer commits records of this session queued so
+                        // far before summarising it
+                        server.sessions.remove(&end.session_id);
+                        server.alerts.forget(end.session_id);
+                        let session_id = end.session_id;
+                        let live = server.live_stats.clone();
+                        let ended = server
+                            .writer
+                            .call(move |conn| {
+                                let summary = session::end_session(conn, session_id, session::STATUS_ENDED)?;
+                                if summary.is_some() {
+                                    stats::save_final(conn, &live, session_id)?;
+                                }
+                                Ok(summary)
+                            })
+                            .map_err(|e| e.to_string())
+                            .and_then(|summary| summary.ok_or_else(|| format!("unknown session {}", end.session_id)));
+                        match ended {
+                            Ok(summary) => {
+                                info!(
+                                    "Client {} ended session {} with {} record(s), {} of them interpolated",
+                                    addr, summary.session_id, summary.record_count, summary.interpolated_count
+                                );
+                                if let Err(e) = send_json(&mut writer, &summary) {
+                                    warn!("Failed to send session summary to {}: {}", addr, e);
+                                }
+                            }
+                            Err(error) => {
+                                warn!("Failed to end session for {}: {}", addr, error);
+                                send_error_reply(&mut writer, addr, state, ErrorCode::SessionError, &error, line);
+                            }
+                        }
+                    }
+                    Ok(Message::SessionConfig(session_config)) => {
+                        match session_config.lowpass_alpha {
+                            Some(alpha) if !(alpha > 0.0 && alpha <= 1.0) => {
+                                let error = format!("lowpass_alpha must be above 0 and at most 1, not {}", alpha);
+                                warn!("Rejected session_config from {}: {}", addr, error);
+                                send_error_reply(&mut writer, addr, state, ErrorCode::SessionError, &error, line);
+                            }
+                            alpha => {
+                                info!(
+                                    "Client {} set lowpass_alpha={} for session {}",
+                                    addr,
+                                    alpha.map_or("default".to_string(), |alpha| alpha.to_string()),
+                                    session_config.session_id
+                                );
+                                state.lowpass.set_alpha(session_config.session_id, alpha);
+                            }
+                        }
+                    }
+                    Ok(Message::UploadComplete(upload)) => {
+                        // The writer commits everything queued so far before counting
+                        let session_id = upload.session_id;
+                        let stored = match upload.scope {
+                            UploadScope::Connection => {
+                                let tally = tally.clone();
+                                server.writer.call(move |_| {
+                                    let tally = tally.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                                    Ok(tally.sessions.get(&session_id.to_string()).copied().unwrap_or(0) as i64)
+                                })
+                            }
+                            UploadScope::Session => {
+                                let gps_encrypted = server.gps_cipher.is_some();
+                                server.writer.call(move |conn| session::count_stored(conn, session_id, gps_encrypted))
+                            }
+                        };
+                        let checked = stored
+                            .map(|stored| UploadStatus::new(upload.session_id, upload.expected_count, stored))
+                            .map_err(|e| e.to_string());
+                        match checked {
+                            Ok(status) => {
+                                if status.is_ok() {
+                                    info!("Upload of session {} from {} verified: {} record(s)", status.session_id, addr, status.stored);
+                                } else {
+                                    warn!(
+                                        "Upload of session {} from {} does not match: expected {} record(s), stored {}",
+                                        status.session_id, addr, status.expected, status.stored
+                                    );
+                                }
+                                let recorded = status.clone();
+                                if let Err(e) = server.writer.call(move |conn| session::record_upload_status(conn, &recorded)) {
+                                    error!("Failed to record upload status of session {}: {}", status.session_id, e);
+                                }
+                                if let Err(e) = send_json(&mut writer, &status) {
+                                    warn!("Failed to send upload status to {}: {}", addr, e);
+                                }
+                            }
+                            Err(error) => {
+                                error!("Failed to verify upload for {}: {}", addr, error);
+                                send_error_reply(&mut writer, addr, state, ErrorCode::SessionError, &error, line);
+                            }
+                        }
+                    }
+                    Ok(Message::Query(_) | Message::Subscribe(_)) if state.listen_options.ingest_only => {
+                        let reply = ErrorReply::new(ErrorCode::Unsupported, "this port only accepts records", line);
+                        if let Err(e) = send_json(&mut writer, &reply) {
+                            warn!("Failed to refuse request from {}: {}", addr, e);
+                        }
                     }
+                    Ok(Message::Query(query)) => {
+                        let limits = QueryLimits { max_rows: config.max_query_rows, max_bytes: config.max_query_bytes };
+                        let result = query.validate(server.gps_cipher.is_some()).and_then(|select| {
+                            // Reopened like the writer's if the file was moved away
+                            let file = FileId::of(DATABASE_PATH);
+                            let conn = match query_conn.take() {
+                                Some((conn, opened_on)) if opened_on == file => conn,
+                                _ => sqlite::open(DATABASE_PATH, &config.sqlite)
+                                    .and_then(|conn| {
+                                        schema::ensure_schema(&conn, config.dedup_timestamps)?;
+                                        Ok(conn)
+                                    })
+                                    .map_err(|e| e.to_string())?,
+                            };
+                            let result = query::run(&conn, &query, &select, limits, config.output_units, server.gps_cipher.as_ref(), &mut writer).map_err(|e| e.to_string());
+                            query_conn = Some((conn, file));
+                            result
+                        });
+                        let sent = match result {
+                            Ok(complete) => {
+                                debug!("Answered query from {} with {} row(s)", addr, complete.rows);
+                                send_json(&mut writer, &complete)
+                            }
+                            Err(error) => {
+                                warn!("Query from {} failed: {}", addr, error);
+                                // The client asked, so it is answered even without error replies
+                                send_json(&mut writer, &ErrorReply::new(ErrorCode::QueryError, &error, line))
+                            }
+                        };
+                        if let Err(e) = sent {
+                            warn!("Failed to answer query from {}: {}", addr, e);
+                        }
+                    }
+                    Ok(Message::Subscribe(_)) if state.websocket => {
+                        // Pushing records would contend with the reader for the socket
+                        let reply = ErrorReply::new(ErrorCode::Unsupported, "subscribe is not available over WebSocket", line);
+                        if let Err(e) = send_json(&mut writer, &reply) {
+                            warn!("Failed to refuse subscription from {}: {}", addr, e);
+                        }
+                    }
+                    Ok(Message::Subscribe(subscribe)) => {
+                        // A new subscription replaces the connection's previous one
+                        state.subscription = None;
+                        if let Err(e) = send_json(&mut writer, &Subscribed::new(subscribe.session_id)) {
+                            warn!("Failed to confirm subscription to {}: {}", addr, e);
+                        }
+                        state.subscription = Some(server.subscribers.subscribe(addr, subscribe.session_id, writer.clone()));
+                    }
+                    Ok(Message::Unsubscribe) => {
+                        state.subscription = None;
+                    }
+                    Ok(Message::Flush) => {
+                        // Queued behind this connection's records, so all of
+                        // them are committed once it returns
+                        let sent = match server.writer.flush() {
+                            Ok(committed) => {
+                                debug!("Flush from {} committed {} record(s)", addr, committed);
+                                send_json(&mut writer, &FlushAck { message_type: "flush_ack", committed })
+                            }
+                            Err(e) => {
+                                warn!("Flush from {} failed: {}", addr, e);
+                                // The client asked, so it is answered even without error replies
+                                send_json(&mut writer, &ErrorReply::new(ErrorCode::FlushError, &e.to_string(), line))
+                            }
+                        };
+                        if let Err(e) = sent {
+                            warn!("Failed to answer flush from {}: {}", addr, e);
+                        }
+                    }
+                    Ok(Message::ResumeInfo(resume)) => {
+                        // Behind this connection's records in the writer's
+                        // queue, so the answer covers all of them
+                        let session_id = resume.session_id;
+                        let sent = match server.writer.call(move |conn| session::resume_position(conn, session_id)) {
+                            Ok(position) => {
+                                debug!(
+                                    "Resume info for session {} to {}: max id {:?}, last timestamp {:?}",
+                                    session_id, addr, position.max_id, position.last_timestamp
+                                );
+                                send_json(&mut writer, &position)
+                            }
+                            Err(e) => {
+                                warn!("Resume info for session {} from {} failed: {}", session_id, addr, e);
+                                // The client asked, so it is answered even without error replies
+                                send_json(&mut writer, &ErrorReply::new(ErrorCode::SessionError, &e.to_string(), line))
+                            }
+                        };
+                        if let Err(e) = sent {
+                            warn!("Failed to answer resume info from {}: {}", addr, e);
+                        }
+                    }
+                    Ok(Message::Unknown(message_type)) => {
+                        warn!("Ignoring control message of unknown type '{}'", message_type);
+                    }
+                    Ok(Message::SensorData(rows)) => {
+                        handle_records(server, Some(&mut writer), addr, state, line, rows, &tally)?;
+                        // The line that reaches the limit is stored whole
+                        if let Some(limit) = config.max_records_per_connection.filter(|limit| state.records_sent >= *limit) {
+                            info!("Client {} sent {} records, reaching the limit of {}; closing connection", addr, state.records_sent, limit);
+                            server.metrics.connection_limits_reached.fetch_add(1, Ordering::Relaxed);
+                            if let Err(e) = send_json(&mut writer, &LimitReached { message_type: "limit_reached", limit }) {
+                                warn!("Failed to tell {} it reached the record limit: {}", addr, e);
+                            }
+                            let _ = writer.shutdown(Shutdown::Both);
+                            break;
+                        }
+                    }
+                    Err(e) => reject_unparsed(Some(&mut writer), addr, state, server, line, &e.to_string()),
                 }
             },
             Err(e) => {
                 // Handle connection errors
+                let timed_out = is_read_timeout(&e);
+                if timed_out && state.keepalive_negotiated {
+                    let idle = last_message.elapsed();
+                    if idle >= Duration::from_secs(config.keepalive_timeout_secs) {
+                        info!(
+                            "Client {} idle for {:.1}s without data or keepalive, closing connection",
+                            addr,
+                            idle.as_secs_f64()
+                        );
+                        let _ = writer.shutdown(Shutdown::Both);
+                        break;
+                    }
+                }
+
                 if e.kind() == ErrorKind::TimedOut {
                     continue; // Just a timeout, keep waiting
                 } else if e.kind() == ErrorKind::WouldBlock {
@@ -218,13 +2233,331 @@ fn handle_client(mut stream: TcpStream, conn: &Connection) -> Result<(), Box<dyn
                     continue;
                 } else {
                     // Client disconnected or other error
-                    println!("Client disconnected: {}", e);
+                    info!("Client disconnected: {}", e);
                     break;
                 }
             }
         }
     }
 
-    println!("Finished receiving data from client.");
+    if let Some(value) = pending_value {
+        let error = "connection closed before the JSON value was complete";
+        reject_unparsed(Some(&mut writer), addr, state, server, &value.into_text(), error);
+    }
+
+    info!("Finished receiving data from client.");
     Ok(())
-}
\ No newline at end of file
+}
+
+// A line that couldn't be parsed is quarantined so it can be replayed once
+// the cause is fixed
+fn reject_unparsed(
+    writer: Option<&mut ClientWriter>,
+    addr: Peer,
+    state: &mut ConnectionState,
+    server: &ServerState,
+    line: &str,
+    error: &str,
+) {
+    warn!("Parsing error: {}", error);
+    warn!("Invalid data: {}", line);
+    if let Some(writer) = writer {
+        send_error_reply(writer, addr, state, ErrorCode::ParseError, error, line);
+    }
+    if let Some(alerts) = &server.rejection_alerts {
+        alerts.record(addr, "parse_error", error, line);
+    }
+    if let Err(qe) = quarantine::quarantine_message(&server.config.quarantine_dir, line, error) {
+        error!("Failed to quarantine line: {}", qe);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::StatementStatus;
+
+    use super::*;
+
+    fn record(timestamp: &str) -> SensorData {
+        serde_json::from_value(serde_json::json!({"sessionID": 1, "timestamp": timestamp, "accel_x": 0.5}))
+            .expect("valid record")
+    }
+
+    // Times the insert statement in the connection's cache has run
+    fn cached_insert_runs(conn: &Connection) -> i32 {
+        conn.prepare_cached(insert_sensor_data_sql!("INSERT")).unwrap().get_status(StatementStatus::Run)
+    }
+
+    // The second insert runs the statement the first one compiled, rather
+    // than compiling its own
+    #[test]
+    fn insert_reuses_cached_statement() {
+        let conn = Connection::open_in_memory().unwrap();
+        schema::ensure_schema(&conn, false).unwrap();
+        // Nothing is cached yet, so a statement fetched now is compiled anew
+        assert_eq!(cached_insert_runs(&conn), 0);
+        conn.flush_prepared_statement_cache();
+
+        insert_sensor_data(&conn, &record("2024-01-01T00:00:00Z"), None, None, None, false).unwrap();
+        assert_eq!(cached_insert_runs(&conn), 1);
+        insert_sensor_data(&conn, &record("2024-01-01T00:00:01Z"), None, None, None, false).unwrap();
+        assert_eq!(cached_insert_runs(&conn), 2);
+
+        // Without the cache every insert would compile a statement of its own
+        conn.flush_prepared_statement_cache();
+        conn.set_prepared_statement_cache_capacity(0);
+        insert_sensor_data(&conn, &record("2024-01-01T00:00:02Z"), None, None, None, false).unwrap();
+        assert_eq!(cached_insert_runs(&conn), 0);
+        let rows: i64 = conn.query_row("SELECT COUNT(*) FROM sensor_data", [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 3);
+    }
+
+    // A record keeps the connection it came in on through the WAL's JSON form,
+    // and its row points at that connection's row
+    #[test]
+    fn stored_row_links_to_its_connection() {
+        let conn = Connection::open_in_memory().unwrap();
+        schema::ensure_schema(&conn, false).unwrap();
+        conn.execute("INSERT INTO connections (client_addr, status) VALUES ('127.0.0.1:5000', 'active')", []).unwrap();
+        let connection_id = conn.last_insert_rowid();
+
+        let pending = PendingRecord { data: record("2024-01-01T00:00:00Z"), device_id: None, connection_id: Some(connection_id) };
+        let logged: PendingRecord = serde_json::from_str(&serde_json::to_string(&pending).unwrap()).unwrap();
+        assert!(logged.data.extras.is_empty());
+        let row_id = insert_sensor_data(&conn, &logged.data, None, logged.connection_id, None, false).unwrap().unwrap();
+        insert_sensor_data(&conn, &record("2024-01-01T00:00:01Z"), None, None, None, false).unwrap();
+
+        let client_addr: String = conn
+            .query_row(
+                "SELECT client_addr FROM sensor_data JOIN connections ON connections.id = sensor_data.connection_id WHERE sensor_data.id = ?1",
+                [row_id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(client_addr, "127.0.0.1:5000");
+        let unlinked: i64 = conn.query_row("SELECT COUNT(*) FROM sensor_data WHERE connection_id IS NULL", [], |row| row.get(0)).unwrap();
+        assert_eq!(unlinked, 1);
+    }
+
+    // The foreign key refuses a row naming a connection that isn't stored;
+    // the insert leaves such a record unlinked rather than losing it
+    #[test]
+    fn unknown_connection_is_refused() {
+        let conn = Connection::open_in_memory().unwrap();
+        schema::ensure_schema(&conn, false).unwrap();
+        let error = conn
+            .execute("INSERT INTO sensor_data (timestamp, connection_id) VALUES ('2024-01-01T00:00:00Z', 42)", [])
+            .unwrap_err();
+        assert!(error.to_string().contains("FOREIGN KEY"), "{}", error);
+
+        let row_id = insert_sensor_data(&conn, &record("2024-01-01T00:00:00Z"), None, Some(42), None, false).unwrap().unwrap();
+        let connection_id: Option<i64> =
+            conn.query_row("SELECT connection_id FROM sensor_data WHERE id = ?1", [row_id], |row| row.get(0)).unwrap();
+        assert_eq!(connection_id, None);
+    }
+
+    fn parse(line: &str) -> Message {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::for_test(dir.path(), &["--profile", "none"]);
+        parse_message(line, &ParseOptions::from(&config)).expect("line parses")
+    }
+
+    #[test]
+    fn keepalive_message_from_new_clients() {
+        assert!(matches!(parse(r#"{"type":"keepalive"}"#), Message::Keepalive));
+        assert!(matches!(parse(r#"{"type": "keepalive", "seq": 7}"#), Message::Keepalive));
+    }
+
+    // Parsed under the default profile, which would reject it as a record
+    #[test]
+    fn keepalive_disguised_as_a_record_from_old_firmware() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::for_test(dir.path(), &[]);
+        let line = r#"{"sessionID":1,"timestamp":"keepalive"}"#;
+        let Ok(Message::SensorData(rows)) = parse_message(line, &ParseOptions::from(&config)) else {
+            panic!("expected sensor data");
+        };
+        assert!(is_disguised_keepalive(line, &rows));
+    }
+
+    // The same record wrapped in a one-element batch is data, so the default
+    // profile rejects it for lacking sensor fields
+    #[test]
+    fn keepalive_stamped_batch_is_validated_as_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::for_test(dir.path(), &[]);
+        let line = r#"[{"sessionID":1,"timestamp":"keepalive"}]"#;
+        assert!(parse_message(line, &ParseOptions::from(&config)).is_err());
+
+        let Message::SensorData(rows) = parse(line) else {
+            panic!("expected sensor data");
+        };
+        assert!(!is_disguised_keepalive(line, &rows));
+    }
+
+    // Timestamps of the rows of `line`, parsed with `--timestamp-format` set
+    // to each of `formats`
+    fn expanded_timestamps(line: &str, formats: &[&str]) -> Result<Vec<String>, serde_json::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let mut args = vec!["--profile", "none"];
+        for format in formats {
+            args.extend(["--timestamp-format", format]);
+        }
+        let config = Config::for_test(dir.path(), &args);
+        match parse_message(line, &ParseOptions::from(&config))? {
+            Message::SensorData(rows) => Ok(rows.into_iter().map(|data| data.timestamp).collect()),
+            _ => panic!("expected sensor data"),
+        }
+    }
+
+    // Sample blocks and dt_ms batches count from the normalized timestamp,
+    // whichever configured format it came in
+    #[test]
+    fn derived_timestamps_follow_the_configured_formats() {
+        let block = r#"{"sessionID":1,"timestamp":"20240518100000","accel_x":[1.0,2.0],"sample_interval_ms":500}"#;
+        assert_eq!(
+            expanded_timestamps(block, &["%Y%m%d%H%M%S"]).unwrap(),
+            ["2024-05-18T10:00:00Z", "2024-05-18T10:00:00.500Z"]
+        );
+        let batch = r#"[{"sessionID":1,"timestamp":"1716026400"},{"sessionID":1,"dt_ms":10}]"#;
+        assert_eq!(
+            expanded_timestamps(batch, &["epoch"]).unwrap(),
+            ["2024-05-18T10:00:00.000Z", "2024-05-18T10:00:00.010Z"]
+        );
+        // Text kept only through any has no time to count from
+        assert!(expanded_timestamps(block, &["iso8601", "any"]).is_err());
+    }
+
+    // A record's own "type" is one of its fields, not a control message
+    #[test]
+    fn record_type_field_is_kept_among_extras() {
+        let Message::SensorData(rows) = parse(r#"{"sessionID":1,"timestamp":"2024-01-01T00:00:00Z","type":"walk"}"#) else {
+            panic!("expected sensor data");
+        };
+        assert_eq!(rows[0].extras.get("type"), Some(&serde_json::json!("walk")));
+
+        assert!(matches!(parse(r#"{"type":"walk"}"#), Message::Unknown(message_type) if message_type == "walk"));
+        assert!(matches!(parse(r#"{"type":"flush","timestamp":"2024-01-01T00:00:00Z"}"#), Message::Flush));
+    }
+
+    // A record that merely mentions the word, or a batch holding a lone
+    // keepalive-stamped row, is data and goes on to validation
+    #[test]
+    fn records_mentioning_keepalive_are_not_dropped() {
+        let line = r#"{"sessionID":1,"timestamp":"keepalive-2024"}"#;
+        let Message::SensorData(rows) = parse(line) else {
+            panic!("expected sensor data");
+        };
+        assert!(!is_disguised_keepalive(line, &rows));
+
+        let rows = vec![record("2024-01-01T00:00:00Z"), record("keepalive")];
+        assert!(!is_disguised_keepalive("[]", &rows));
+
+        let line = r#"{"sessionID":1,"timestamp":"2024-01-01T00:00:00Z","notes":"{\"type\":\"keepalive\"}"}"#;
+        let Message::SensorData(rows) = parse(line) else {
+            panic!("expected sensor data");
+        };
+        assert!(!is_disguised_keepalive(line, &rows));
+    }
+
+    // Every spelling a client may use, next to the field it fills
+    const SPELLINGS: &[(&str, &str)] = &[
+        ("sessionID", "sessionID"),
+        ("session_id", "sessionID"),
+        ("sessionId", "sessionID"),
+        ("accel_x", "accel_x"),
+        ("accelX", "accel_x"),
+        ("accel_y", "accel_y"),
+        ("accelY", "accel_y"),
+        ("accel_z", "accel_z"),
+        ("accelZ", "accel_z"),
+        ("gyro_x", "gyro_x"),
+        ("gyroX", "gyro_x"),
+        ("gyro_y", "gyro_y"),
+        ("gyroY", "gyro_y"),
+        ("gyro_z", "gyro_z"),
+        ("gyroZ", "gyro_z"),
+        ("mag_x", "mag_x"),
+        ("magX", "mag_x"),
+        ("mag_y", "mag_y"),
+        ("magY", "mag_y"),
+        ("mag_z", "mag_z"),
+        ("magZ", "mag_z"),
+        ("dac_1", "dac_1"),
+        ("dac1", "dac_1"),
+        ("dac_2", "dac_2"),
+        ("dac2", "dac_2"),
+        ("dac_3", "dac_3"),
+        ("dac3", "dac_3"),
+        ("dac_4", "dac_4"),
+        ("dac4", "dac_4"),
+    ];
+
+    #[test]
+    fn every_spelling_fills_its_field() {
+        for &(spelling, field) in SPELLINGS {
+            let line = format!(r#"{{"timestamp":"2024-01-01T00:00:00Z","{}":7}}"#, spelling);
+            let data: SensorData = serde_json::from_str(&line).unwrap_or_else(|e| panic!("{}: {}", spelling, e));
+            assert!(data.extras.is_empty(), "{} kept among the extras", spelling);
+            let stored = serde_json::to_value(&data).unwrap();
+            assert_eq!(stored[field].as_f64(), Some(7.0), "{} did not fill {}", spelling, field);
+        }
+    }
+
+    // Two spellings of one field in a record is an error, not a choice
+    #[test]
+    fn two_spellings_of_a_field_are_rejected() {
+        for (i, &(first, field)) in SPELLINGS.iter().enumerate() {
+            for &(second, _) in SPELLINGS[i + 1..].iter().filter(|(_, other)| *other == field) {
+                let line = format!(r#"{{"timestamp":"2024-01-01T00:00:00Z","{}":1,"{}":2}}"#, first, second);
+                let error = serde_json::from_str::<SensorData>(&line).expect_err(&line).to_string();
+                assert!(error.contains("duplicate field"), "{}: {}", line, error);
+            }
+        }
+    }
+
+    // A record with everything the default profile requires
+    fn full_record() -> serde_json::Value {
+        serde_json::json!({
+            "sessionID": 1, "timestamp": "2024-01-01T00:00:00Z",
+            "latitude": 52.5, "longitude": 13.4, "altitude": 34.0,
+            "accel_x": 0.1, "accel_y": 0.2, "accel_z": 9.8,
+            "gyro_x": 0.0, "gyro_y": 0.0, "gyro_z": 0.0,
+            "dac_1": 1.0, "dac_2": 2.0, "dac_3": 3.0, "dac_4": 4.0,
+        })
+    }
+
+    fn parse_stored(record: &serde_json::Value) -> Option<[f64; 3]> {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::for_test(dir.path(), &[]);
+        let Ok(Message::SensorData(rows)) = parse_message(&record.to_string(), &ParseOptions::from(&config)) else {
+            panic!("expected sensor data");
+        };
+        let conn = Connection::open_in_memory().unwrap();
+        schema::ensure_schema(&conn, false).unwrap();
+        insert_sensor_data(&conn, &rows[0], None, None, None, false).unwrap();
+        conn.query_row("SELECT mag_x, mag_y, mag_z FROM sensor_data", [], |row| {
+            Ok(match (row.get(0)?, row.get(1)?, row.get(2)?) {
+                (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+                (None, None, None) => None,
+                stored => panic!("partly stored magnetometer reading {:?}", stored),
+            })
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn magnetometer_present() {
+        let mut record = full_record();
+        record["mag_x"] = 21.5.into();
+        record["mag_y"] = (-4.0).into();
+        record["mag_z"] = 40.25.into();
+        assert_eq!(parse_stored(&record), Some([21.5, -4.0, 40.25]));
+    }
+
+    // Loggers without a magnetometer still pass the default profile
+    #[test]
+    fn magnetometer_absent() {
+        assert_eq!(parse_stored(&full_record()), None);
+    }
+}
@@ -0,0 +1,259 @@
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use log::{info, warn};
+
+// Counters exported in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub duplicate_messages_skipped: AtomicU64,
+    pub timestamp_duplicates_skipped: AtomicU64,
+    pub rows_inserted: AtomicU64,
+    pub backpressure_stalls: AtomicU64,
+    pub dropped_records: AtomicU64,
+    pub database_reopens: AtomicU64,
+    pub outlier_rows: AtomicU64,
+    pub decimated_rows: AtomicU64,
+    pub connection_limits_reached: AtomicU64,
+    pub events_recorded: AtomicU64,
+    pub gps_low_quality: AtomicU64,
+    pub subscriber_dropped_records: AtomicU64,
+    pub validation_errors: AtomicU64,
+    pub altitude_range_violations: AtomicU64,
+    pub field_range_violations: AtomicU64,
+    pub dac_range_violations: AtomicU64,
+    pub udp_datagrams_received: AtomicU64,
+    pub udp_datagrams_rejected: AtomicU64,
+    pub udp_datagrams_oversized: AtomicU64,
+    pub integrity_check_failures: AtomicU64,
+    pub mqtt_messages_dropped: AtomicU64,
+    pub breaker_opens: AtomicU64,
+    pub breaker_refused_records: AtomicU64,
+    pub breaker_refused_connections: AtomicU64,
+    // 1 while the circuit breaker is open
+    pub breaker_open: AtomicU64,
+    // Records handed to the writer and records it has since stored or given
+    // up on; their difference is the queue depth
+    pub records_queued: AtomicU64,
+    pub records_settled: AtomicU64,
+}
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(
+            &mut out,
+            "duplicate_messages_skipped_total",
+            "Records not inserted because a row with the same message_id exists",
+            &self.duplicate_messages_skipped,
+        );
+        counter(
+            &mut out,
+            "timestamp_duplicates_skipped_total",
+            "Records not inserted because their session has a row at the same timestamp, with --dedup-timestamps",
+            &self.timestamp_duplicates_skipped,
+        );
+        counter(
+            &mut out,
+            "rows_inserted_total",
+            "Rows inserted into sensor_data by client connections",
+            &self.rows_inserted,
+        );
+        counter(
+            &mut out,
+            "backpressure_stalls_total",
+            "Times a connection stopped reading because the database writer's queue was full",
+            &self.backpressure_stalls,
+        );
+        counter(
+            &mut out,
+            "dropped_records_total",
+            "Records dropped because the database writer's queue stayed full for --backpressure-timeout-ms",
+            &self.dropped_records,
+        );
+        counter(
+            &mut out,
+            "database_reopens_total",
+            "Times the database writer reopened the database after its file was deleted, moved or kept failing",
+            &self.database_reopens,
+        );
+        counter(
+            &mut out,
+            "outlier_rows_total",
+            "Records flagged as outliers, with a value beyond --outlier-sigma standard deviations of its session",
+            &self.outlier_rows,
+        );
+        counter(
+            &mut out,
+            "decimated_rows_total",
+            "Records discarded on arrival to keep a session within --decimate-to-hz",
+            &self.decimated_rows,
+        );
+        counter(
+            &mut out,
+            "connection_limits_reached_total",
+            "Connections closed after sending --max-records-per-connection records",
+            &self.connection_limits_reached,
+        );
+        counter(
+            &mut out,
+            "events_recorded_total",
+            "Events stored for values that met an --event-rule",
+            &self.events_recorded,
+        );
+        counter(
+            &mut out,
+            "gps_low_quality_total",
+            "Records whose position fell short of the GPS quality gate",
+            &self.gps_low_quality,
+        );
+        counter(
+            &mut out,
+            "subscriber_dropped_records_total",
+            "Records not pushed to a subscriber because it had fallen too far behind",
+            &self.subscriber_dropped_records,
+        );
+        counter(
+            &mut out,
+            "validation_errors_total",
+            "Lines of records rejected by validation and moved to dead_letters",
+            &self.validation_errors,
+        );
+        counter(
+            &mut out,
+            "altitude_range_violations_total",
+            "Records flagged as outliers for an altitude outside --altitude-min..--altitude-max",
+            &self.altitude_range_violations,
+        );
+        counter(
+            &mut out,
+            "field_range_violations_total",
+            "Records with a sensor value outside its --field-range, rejected or flagged",
+            &self.field_range_violations,
+        );
+        counter(
+            &mut out,
+            "dac_range_violations_total",
+            "DAC readings outside their channel's --dac-range",
+            &self.dac_range_violations,
+        );
+        counter(
+            &mut out,
+            "udp_datagrams_received_total",
+            "UDP datagrams received, whatever became of them",
+            &self.udp_datagrams_received,
+        );
+        counter(
+            &mut out,
+            "udp_datagrams_rejected_total",
+            "UDP datagrams with a line that couldn't be parsed, failed validation or was a control message",
+            &self.udp_datagrams_rejected,
+        );
+        counter(
+            &mut out,
+            "udp_datagrams_oversized_total",
+            "UDP datagrams dropped for exceeding --max-datagram-bytes",
+            &self.udp_datagrams_oversized,
+        );
+        counter(
+            &mut out,
+            "integrity_check_failures_total",
+            "Periodic database integrity checks that found a problem or couldn't read the database",
+            &self.integrity_check_failures,
+        );
+        counter(
+            &mut out,
+            "mqtt_messages_dropped_total",
+            "MQTT messages dropped because their payload couldn't be parsed",
+            &self.mqtt_messages_dropped,
+        );
+        counter(
+            &mut out,
+            "breaker_opens_total",
+            "Times ingest was paused after --breaker-failures consecutive failed commits",
+            &self.breaker_opens,
+        );
+        counter(
+            &mut out,
+            "breaker_refused_records_total",
+            "Records refused with a backoff notice while ingest was paused",
+            &self.breaker_refused_records,
+        );
+        counter(
+            &mut out,
+            "breaker_refused_connections_total",
+            "Connections closed on arrival while ingest was paused",
+            &self.breaker_refused_connections,
+        );
+        gauge(
+            &mut out,
+            "breaker_open",
+            "1 while ingest is paused because the database keeps failing, 0 otherwise",
+            self.breaker_open.load(Ordering::Relaxed),
+        );
+        let queued = self.records_queued.load(Ordering::Relaxed);
+        let settled = self.records_settled.load(Ordering::Relaxed);
+        gauge(
+            &mut out,
+            "queue_depth",
+            "Records handed to the database writer and not yet committed",
+            queued.saturating_sub(settled),
+        );
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: &AtomicU64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Serve GET /metrics over plain HTTP on its own thread. Scrapes are rare and
+// tiny, so requests are handled one at a time.
+pub fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &metrics));
+            if let Err(e) = result {
+                warn!("Metrics request failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers; nothing in them matters here
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim() != "" {
+        header.clear();
+    }
+
+    let (status, content_type, body) = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
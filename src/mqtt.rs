@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use clap::Args;
+use log::{debug, error, info, warn};
+use rumqttc::{Client, Connection, Event, Outgoing, Packet, Publish, QoS, RecvTimeoutError, SubscribeFilter, SubscribeReasonCode};
+
+use crate::batch::SessionTally;
+use crate::peer::Peer;
+use crate::secret::load_secret;
+use crate::{handle_records, parse_message, reject_unparsed, ConnectionState, Message, ParseOptions, ServerState};
+
+// Longest wait between attempts to reach the broker
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// A topic not published to for this long is forgotten, along with its
+// filters and outlier statistics
+const TOPIC_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// How long shutdown waits for the broker connection to send what is left
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Room for the fixed header and topic of a publish on top of its payload
+const PACKET_OVERHEAD_BYTES: usize = 1024;
+
+// Broker to subscribe to and what to subscribe to there
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "MQTT")]
+pub struct MqttConfig {
+    /// Also receive records from this MQTT broker, e.g. mqtt://broker:1883
+    #[arg(long, value_name = "URL", value_parser = parse_broker, requires = "mqtt_topic")]
+    pub mqtt_broker: Option<Broker>,
+
+    /// Topic filters to subscribe to, e.g. vehicles/+/telemetry; the topic of a message is stored as its device_id
+    #[arg(long, value_name = "FILTER,...", value_delimiter = ',')]
+    pub mqtt_topic: Vec<String>,
+
+    /// Client ID to connect with; the broker keeps subscriptions and unacknowledged messages under it
+    #[arg(long, value_name = "ID", default_value = "db_receiver")]
+    pub mqtt_client_id: String,
+
+    /// User name to log in to the broker with
+    #[arg(long, value_name = "NAME")]
+    pub mqtt_username: Option<String>,
+
+    /// File holding the password for --mqtt-username
+    #[arg(long, value_name = "PATH", requires = "mqtt_username")]
+    pub mqtt_password_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Broker {
+    host: String,
+    port: u16,
+}
+
+impl fmt::Display for Broker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mqtt://{}:{}", self.host, self.port)
+    }
+}
+
+// mqtt://host[:port], or host[:port]; TLS brokers aren't supported
+fn parse_broker(value: &str) -> Result<Broker, String> {
+    let address = match value.split_once("://") {
+        Some(("mqtt" | "tcp", address)) => address,
+        Some((scheme, _)) => return Err(format!("unsupported scheme '{}'; use mqtt://", scheme)),
+        None => value,
+    };
+    let address = address.trim_end_matches('/');
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| format!("bad port '{}'", port))?),
+        None => (address, 1883),
+    };
+    if host.is_empty() {
+        return Err("missing host".to_string());
+    }
+    Ok(Broker { host: host.to_string(), port })
+}
+
+// The subscription as it appears in the effective configuration line
+impl fmt::Display for MqttConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.mqtt_broker {
+            Some(broker) => write!(f, "{}({})", broker, self.mqtt_topic.join(",")),
+            None => write!(f, "off"),
+        }
+    }
+}
+
+// What the server keeps about a topic between messages, as it would about a
+// TCP connection
+struct Topic {
+    state: ConnectionState,
+    tally: Arc<SessionTally>,
+    last_seen: Instant,
+}
+
+// Subscribe to the broker's topics until the server shuts down or starts
+// draining, storing each message's records through the same path as TCP
+// lines. Messages are acknowledged once the records they held are committed,
+// so the broker sends those it never got an acknowledgement for again.
+pub fn spawn(
+    server: Arc<ServerState>,
+    running: Arc<AtomicBool>,
+    accepting: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>, Box<dyn std::error::Error>> {
+    let config = &server.config.mqtt;
+    let Some(broker) = config.mqtt_broker.clone() else {
+        return Err("no --mqtt-broker to connect to".into());
+    };
+    let mut options = rumqttc::MqttOptions::new(&config.mqtt_client_id, &broker.host, broker.port);
+    // A persistent session keeps the subscriptions and unacknowledged
+    // messages while the server is away
+    options.set_clean_session(false);
+    options.set_manual_acks(true);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_max_packet_size(server.config.max_line_bytes + PACKET_OVERHEAD_BYTES, PACKET_OVERHEAD_BYTES);
+    if let Some(username) = &config.mqtt_username {
+        let password = match &config.mqtt_password_file {
+            Some(path) => load_secret(path, server.config.allow_open_secret_files)
+                .map_err(|e| format!("can't read --mqtt-password-file {}: {}", path.display(), e))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+            None => String::new(),
+        };
+        options.set_credentials(username, password);
+    }
+    let (client, connection) = Client::new(options, 64);
+    info!("Subscribing to {} on MQTT broker {}...", config.mqtt_topic.join(", "), broker);
+    Ok(thread::spawn(move || {
+        let active = || running.load(Ordering::SeqCst) && accepting.load(Ordering::SeqCst);
+        receive(&server, &client, connection, &broker, active);
+        info!("MQTT subscription to {} closed", broker);
+    }))
+}
+
+fn receive(server: &ServerState, client: &Client, mut connection: Connection, broker: &Broker, active: impl Fn() -> bool) {
+    let config = &server.config;
+    let flush_interval = Duration::from_millis(config.batch_flush_ms);
+    let mut topics: HashMap<String, Topic> = HashMap::new();
+    // Messages handled but not yet acknowledged, and when the oldest arrived
+    let mut unacked: Vec<Publish> = Vec::new();
+    let mut oldest = Instant::now();
+    let mut reconnect_delay = Duration::from_secs(1);
+
+    while active() {
+        match connection.recv_timeout(flush_interval) {
+            Ok(Ok(Event::Incoming(Packet::ConnAck(ack)))) => {
+                info!("Connected to MQTT broker {}", broker);
+                reconnect_delay = Duration::from_secs(1);
+                // The broker remembers the subscriptions of a session it kept
+                if !ack.session_present {
+                    let filters = config.mqtt.mqtt_topic.iter().map(|topic| SubscribeFilter::new(topic.clone(), QoS::AtLeastOnce));
+                    if let Err(e) = client.subscribe_many(filters) {
+                        error!("Failed to subscribe on MQTT broker {}: {}", broker, e);
+                    }
+                }
+            }
+            Ok(Ok(Event::Incoming(Packet::SubAck(ack)))) => {
+                if ack.return_codes.contains(&SubscribeReasonCode::Failure) {
+                    error!("MQTT broker {} refused to subscribe to some of {}", broker, config.mqtt.mqtt_topic.join(", "));
+                }
+            }
+            Ok(Ok(Event::Incoming(Packet::Publish(publish)))) => {
+                if unacked.is_empty() {
+                    oldest = Instant::now();
+                }
+                let topic = topics.entry(publish.topic.clone()).or_insert_with(|| {
+                    info!("First MQTT message on topic {}", publish.topic);
+                    let mut state = ConnectionState::new(config);
+                    state.device_id = Some(publish.topic.clone());
+                    Topic { state, tally: Arc::default(), last_seen: Instant::now() }
+                });
+                topic.last_seen = Instant::now();
+                handle_message(server, topic, &publish);
+                unacked.push(publish);
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                // Acknowledging for the old connection is pointless; the
+                // broker sends those messages again
+                unacked.clear();
+                warn!("MQTT broker {} unreachable: {}; retrying in {}s", broker, e, reconnect_delay.as_secs());
+                let retry_at = Instant::now() + reconnect_delay;
+                while active() && Instant::now() < retry_at {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        if !unacked.is_empty() && (unacked.len() >= config.batch_size || oldest.elapsed() >= flush_interval) {
+            acknowledge(server, client, &mut unacked);
+        }
+        topics.retain(|_, topic| topic.last_seen.elapsed() < TOPIC_IDLE_TIMEOUT);
+    }
+
+    // The last acknowledgements and the disconnect only go out while the
+    // connection is polled
+    acknowledge(server, client, &mut unacked);
+    if let Err(e) = client.disconnect() {
+        debug!("Failed to disconnect from MQTT broker {}: {}", broker, e);
+    }
+    while let Ok(Ok(event)) = connection.recv_timeout(DISCONNECT_TIMEOUT) {
+        if matches!(event, Event::Outgoing(Outgoing::Disconnect)) {
+            break;
+        }
+    }
+}
+
+// Parse a message's payload as one line from a TCP client would be and queue
+// its records. Whatever becomes of them, the message is acknowledged: one
+// that can't be stored would only be sent again.
+fn handle_message(server: &ServerState, topic: &mut Topic, publish: &Publish) {
+    let config = &server.config;
+    let addr = Peer::Mqtt;
+    let state = &mut topic.state;
+    let line = String::from_utf8_lossy(&publish.payload);
+    let line = line.trim();
+    debug!("Received MQTT message on {}: {}", publish.topic, line);
+    let rows = match parse_message(line, &ParseOptions::from(config)) {
+        Ok(Message::SensorData(rows)) => rows,
+        Ok(message) => {
+            warn!("Ignoring control message on MQTT topic {}: {:?}", publish.topic, message);
+            return;
+        }
+        Err(e) => {
+            server.metrics.mqtt_messages_dropped.fetch_add(1, Ordering::Relaxed);
+            reject_unparsed(None, addr, state, server, line, &format!("{} (MQTT topic {})", e, publish.topic));
+            return;
+        }
+    };
+    if let Err(e) = handle_records(server, None, addr, state, line, rows, &topic.tally) {
+        error!("Failed to queue records from MQTT topic {}: {}", publish.topic, e);
+    }
+}
+
+// Commit what the handled messages queued, then acknowledge them. If the
+// commit fails they stay unacknowledged, for the broker to send again.
+fn acknowledge(server: &ServerState, client: &Client, unacked: &mut Vec<Publish>) {
+    if unacked.is_empty() {
+        return;
+    }
+    if let Err(e) = server.writer.flush() {
+        warn!("Failed to commit {} MQTT message(s); leaving them unacknowledged: {}", unacked.len(), e);
+        unacked.clear();
+        return;
+    }
+    for publish in unacked.drain(..) {
+        if let Err(e) = client.ack(&publish) {
+            warn!("Failed to acknowledge MQTT message on {}: {}", publish.topic, e);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// A JSON object or array spread over several lines, as pretty-printing
+// clients send them. Lines are added until the brackets balance; strings
+// are tracked so brackets inside them don't count.
+pub struct MultiLine {
+    text: String,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+pub enum Progress {
+    Incomplete,
+    // The whole value, ready to be parsed like a single line
+    Complete(String),
+    // The value grew past the limit; what had arrived is returned
+    TooLarge(String),
+}
+
+// Whether a line as read, before trimming, opens a top-level JSON value. A
+// bracket in the first column is taken as the start of the next value,
+// since nested ones are indented by pretty-printers.
+pub fn starts_value(raw: &[u8]) -> bool {
+    matches!(raw.first(), Some(b'{' | b'['))
+}
+
+impl MultiLine {
+    // The first line of a value, which didn't parse on its own because it
+    // ended part-way through
+    pub fn start(line: &str) -> Self {
+        let mut multiline = MultiLine { text: String::new(), depth: 0, in_string: false, escaped: false };
+        multiline.scan(line);
+        multiline.text.push_str(line);
+        multiline
+    }
+
+    pub fn push(&mut self, line: &str, max_bytes: usize) -> Progress {
+        if self.text.len() + 1 + line.len() > max_bytes {
+            return Progress::TooLarge(std::mem::take(&mut self.text));
+        }
+        self.scan(line);
+        self.text.push('\n');
+        self.text.push_str(line);
+        if self.depth == 0 {
+            Progress::Complete(std::mem::take(&mut self.text))
+        } else {
+            Progress::Incomplete
+        }
+    }
+
+    // What has arrived of a value that will never be completed
+    pub fn into_text(self) -> String {
+        self.text
+    }
+
+    fn scan(&mut self, line: &str) {
+        for byte in line.bytes() {
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed the lines after the first until the value completes
+    fn assemble(lines: &[&str], max_bytes: usize) -> Option<String> {
+        let mut value = MultiLine::start(lines[0]);
+        for line in &lines[1..] {
+            match value.push(line, max_bytes) {
+                Progress::Incomplete => {}
+                Progress::Complete(text) => return Some(text),
+                Progress::TooLarge(_) => return None,
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn pretty_printed_object() {
+        let lines = ["{", r#"  "sessionID": 1,"#, r#"  "dac": [1.0, 2.0],"#, r#"  "nested": {"a": [{}]}"#, "}"];
+        let text = assemble(&lines, 1024).expect("value completes");
+        assert_eq!(text, lines.join("\n"));
+        assert!(serde_json::from_str::<serde_json::Value>(&text).is_ok());
+    }
+
+    // Brackets and escaped quotes inside strings don't count
+    #[test]
+    fn brackets_inside_strings() {
+        let lines = ["{", r#"  "note": "closing } and ] here","#, r#"  "quote": "a \"}\" and a \\","#, r#"  "done": true"#, "}"];
+        assert_eq!(assemble(&lines, 1024), Some(lines.join("\n")));
+        assert_eq!(assemble(&["[", r#"  "]""#], 1024), None);
+        assert_eq!(assemble(&["[", r#"  "]""#, "]"], 1024), Some("[\n  \"]\"\n]".to_string()));
+    }
+
+    #[test]
+    fn too_large_returns_what_arrived() {
+        let mut value = MultiLine::start("{");
+        assert!(matches!(value.push(r#"  "a": 1,"#, 16), Progress::Incomplete));
+        match value.push(r#"  "b": 2"#, 16) {
+            Progress::TooLarge(text) => assert_eq!(text, "{\n  \"a\": 1,"),
+            _ => panic!("expected the value to be too large"),
+        }
+    }
+
+    #[test]
+    fn value_starts_in_the_first_column() {
+        assert!(starts_value(b"{"));
+        assert!(starts_value(b"[1,"));
+        assert!(!starts_value(b"  {"));
+        assert!(!starts_value(b"\"a\": 1"));
+        assert!(!starts_value(b""));
+    }
+}
@@ -0,0 +1,108 @@
+use crate::stats::RunningStats;
+use crate::{SensorData, SENSOR_FIELDS};
+
+// Standard deviations from the mean past which a value is an outlier
+pub const DEFAULT_SIGMA: f64 = 3.0;
+
+// Values a field needs before its spread says anything about the next one
+const MIN_SAMPLES: u64 = 10;
+
+// Running statistics of one field, against which each new value is judged
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OutlierDetector {
+    stats: RunningStats,
+}
+
+impl OutlierDetector {
+    // Whether `value` lies more than `sigma` standard deviations from the
+    // mean of the values before it. The value is added either way.
+    pub fn check(&mut self, value: f64, sigma: f64) -> bool {
+        let outlier = self.stats.count() >= MIN_SAMPLES
+            && match (self.stats.mean(), self.stats.std_dev()) {
+                (Some(mean), Some(std_dev)) => (value - mean).abs() > sigma * std_dev,
+                _ => false,
+            };
+        self.stats.push(value);
+        outlier
+    }
+}
+
+// One detector per sensor field for the session a connection is sending.
+// Like the filters, the statistics start over when the session changes.
+#[derive(Debug)]
+pub struct SessionOutliers {
+    fields: [OutlierDetector; SENSOR_FIELDS],
+    session_id: Option<i64>,
+    sigma: f64,
+}
+
+impl Default for SessionOutliers {
+    fn default() -> Self {
+        SessionOutliers::new(DEFAULT_SIGMA)
+    }
+}
+
+impl SessionOutliers {
+    pub fn new(sigma: f64) -> Self {
+        SessionOutliers { fields: [OutlierDetector::default(); SENSOR_FIELDS], session_id: None, sigma }
+    }
+
+    // Flag the record if any of its values is an outlier for its field
+    pub fn apply(&mut self, data: &mut SensorData) -> bool {
+        if data.session_id != self.session_id {
+            self.fields = [OutlierDetector::default(); SENSOR_FIELDS];
+            self.session_id = data.session_id;
+        }
+        let mut outlier = false;
+        for ((_, value), detector) in data.fields().into_iter().zip(&mut self.fields) {
+            if let Some(value) = value {
+                outlier |= detector.check(value, self.sigma);
+            }
+        }
+        data.is_outlier = outlier;
+        outlier
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Ten values alternating 9 and 11: mean 10, standard deviation about 1.05
+    fn warmed_up() -> OutlierDetector {
+        let mut detector = OutlierDetector::default();
+        for i in 0..MIN_SAMPLES {
+            assert!(!detector.check(if i % 2 == 0 { 9.0 } else { 11.0 }, DEFAULT_SIGMA));
+        }
+        detector
+    }
+
+    #[test]
+    fn value_beyond_sigma_is_an_outlier() {
+        assert!(warmed_up().check(20.0, DEFAULT_SIGMA));
+        assert!(warmed_up().check(0.0, DEFAULT_SIGMA));
+        assert!(!warmed_up().check(12.0, DEFAULT_SIGMA));
+        assert!(warmed_up().check(12.0, 1.0));
+    }
+
+    // Too few values to judge by, however far off the next one is
+    #[test]
+    fn nothing_is_flagged_while_warming_up() {
+        let mut detector = OutlierDetector::default();
+        for _ in 1..MIN_SAMPLES {
+            detector.check(10.0, DEFAULT_SIGMA);
+        }
+        assert!(!detector.check(1e6, DEFAULT_SIGMA));
+    }
+
+    // With no spread at all, any other value is an outlier
+    #[test]
+    fn constant_field() {
+        let mut detector = OutlierDetector::default();
+        for _ in 0..MIN_SAMPLES {
+            detector.check(5.0, DEFAULT_SIGMA);
+        }
+        assert!(!detector.check(5.0, DEFAULT_SIGMA));
+        assert!(detector.check(5.001, DEFAULT_SIGMA));
+    }
+}
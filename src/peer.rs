@@ -0,0 +1,48 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+// Who is on the other end of a connection. TCP clients and UDP sources are
+// known by their address. A Unix socket client has none, so the server numbers
+// its connections and notes the peer's credentials where the platform gives
+// them. Records from the MQTT broker stand for themselves, as their topic
+// names the device. A serial port is known by its device path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Ip(SocketAddr),
+    Local { connection: u64, uid: Option<u32>, pid: Option<i32> },
+    Mqtt,
+    Serial(&'static str),
+}
+
+// An IPv4 client of a dual-stack listener shows up as ::ffff:a.b.c.d, and is
+// known by its IPv4 address like on an IPv4 listener
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+                Some(ip) => Peer::Ip(SocketAddr::new(ip.into(), v6.port())),
+                None => Peer::Ip(addr),
+            },
+            SocketAddr::V4(_) => Peer::Ip(addr),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Ip(addr) => write!(f, "{}", addr),
+            Peer::Local { connection, uid, pid } => {
+                write!(f, "unix#{}", connection)?;
+                match (uid, pid) {
+                    (Some(uid), Some(pid)) => write!(f, " (uid {}, pid {})", uid, pid),
+                    (Some(uid), None) => write!(f, " (uid {})", uid),
+                    (None, Some(pid)) => write!(f, " (pid {})", pid),
+                    (None, None) => Ok(()),
+                }
+            }
+            Peer::Mqtt => write!(f, "mqtt"),
+            Peer::Serial(path) => write!(f, "{}", path),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use serde_json::Value;
+
+use crate::timestamp;
+use crate::SensorData;
+
+// Field order of the compact positional format, matching the sensor_data columns
+const FIELDS: [&str; 15] = [
+    "sessionID", "timestamp", "latitude", "longitude", "altitude",
+    "accel_x", "accel_y", "accel_z", "gyro_x", "gyro_y", "gyro_z",
+    "dac_1", "dac_2", "dac_3", "dac_4",
+];
+
+// A positional record starts with its sessionID, where a batch starts with
+// an object
+pub fn is_positional(elements: &[Value]) -> bool {
+    matches!(elements.first(), Some(Value::Number(_) | Value::Null))
+}
+
+// Read a record sent as a bare array of values in column order, e.g.
+// `[1, "2024-05-18T10:00:00Z", 44.5, -123.2, 80.1, ...]`
+pub fn parse(elements: &[Value]) -> Result<SensorData, String> {
+    if elements.len() != FIELDS.len() {
+        return Err(format!(
+            "positional record needs {} elements, got {}",
+            FIELDS.len(),
+            elements.len()
+        ));
+    }
+    let session_id = match &elements[0] {
+        Value::Null => None,
+        value => Some(value.as_i64().ok_or_else(|| type_error(0, "an integer or null", value))?),
+    };
+    let timestamp = match &elements[1] {
+        Value::String(timestamp) => timestamp.clone(),
+        Value::Number(epoch) => epoch
+            .as_f64()
+            .and_then(timestamp::from_epoch)
+            .ok_or_else(|| format!("element 1 (timestamp) {} is out of range", epoch))?,
+        value => return Err(type_error(1, "a string or a number", value)),
+    };
+    let number = |i: usize| match &elements[i] {
+        Value::Null => Ok(None),
+        value => value.as_f64().map(Some).ok_or_else(|| type_error(i, "a number or null", value)),
+    };
+
+    Ok(SensorData {
+        session_id,
+        timestamp,
+        latitude: number(2)?,
+        longitude: number(3)?,
+        altitude: number(4)?,
+        accel_x: number(5)?,
+        accel_y: number(6)?,
+        accel_z: number(7)?,
+        gyro_x: number(8)?,
+        gyro_y: number(9)?,
+        gyro_z: number(10)?,
+        dac_1: number(11)?,
+        dac_2: number(12)?,
+        dac_3: number(13)?,
+        dac_4: number(14)?,
+        mag_x: None,
+        mag_y: None,
+        mag_z: None,
+        fix_quality: None,
+        num_satellites: None,
+        hdop: None,
+        temperature_c: None,
+        battery_v: None,
+        dac: None,
+        gps_low_quality: false,
+        outside_fence: false,
+        is_outlier: false,
+        is_interpolated: false,
+        accel_filtered: None,
+        cumulative_angles: None,
+        cumulative_distance_m: None,
+        tilt: None,
+        imu_raw: None,
+        dac_raw: None,
+        position_raw: None,
+        message_id: None,
+        extras: serde_json::Map::new(),
+    })
+}
+
+fn type_error(index: usize, expected: &str, value: &Value) -> String {
+    format!("element {} ({}) must be {}, got {}", index, FIELDS[index], expected, value)
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+use clap::ValueEnum;
+
+use crate::{SensorData, DAC_COLUMNS};
+
+const GPS_FIELDS: &[&str] = &["latitude", "longitude", "altitude"];
+const IMU_FIELDS: &[&str] = &["accel_x", "accel_y", "accel_z", "gyro_x", "gyro_y", "gyro_z"];
+const DAC_FIELDS: &[&str] = &["dac_1", "dac_2", "dac_3", "dac_4"];
+// Optional under every profile; --require-fields can ask for them
+const MAG_FIELDS: &[&str] = &["mag_x", "mag_y", "mag_z"];
+const POWER_FIELDS: &[&str] = &["temperature_c", "battery_v"];
+
+// Which sensor fields a deployment's records must carry. Fields a profile
+// leaves optional may be omitted or null and are stored as NULL.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// Every sensor field but the magnetometer, temperature and battery is required
+    #[default]
+    Full,
+    /// GPS trackers: latitude, longitude and altitude are required
+    Gps,
+    /// IMU loggers: the accelerometer and gyroscope axes are required
+    Imu,
+    /// No sensor field is required; pair with --require-fields to pick them
+    None,
+}
+
+impl Profile {
+    pub fn required_fields(self) -> Vec<&'static str> {
+        match self {
+            Profile::Full => [GPS_FIELDS, IMU_FIELDS, DAC_FIELDS].concat(),
+            Profile::Gps => GPS_FIELDS.to_vec(),
+            Profile::Imu => IMU_FIELDS.to_vec(),
+            Profile::None => Vec::new(),
+        }
+    }
+
+    // Fail on the first field the record lacks that either the profile or
+    // `--require-fields` asks for
+    pub fn check(self, data: &SensorData, also_required: &[&str]) -> Result<(), String> {
+        let required = self.required_fields();
+        for (name, value) in data.fields() {
+            if value.is_some() {
+                continue;
+            }
+            if required.contains(&name) {
+                return Err(format!("missing field `{}` required by the {} profile", name, self));
+            }
+            if also_required.contains(&name) {
+                return Err(format!("missing field `{}` required by --require-fields", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Name of a sensor field given on the command line
+pub fn parse_field(name: &str) -> Result<&'static str, String> {
+    [GPS_FIELDS, IMU_FIELDS, MAG_FIELDS, POWER_FIELDS, &DAC_COLUMNS]
+        .concat()
+        .into_iter()
+        .find(|field| *field == name)
+        .ok_or_else(|| format!("unknown sensor field '{}'", name))
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Profile::Full => write!(f, "full"),
+            Profile::Gps => write!(f, "gps"),
+            Profile::Imu => write!(f, "imu"),
+            Profile::None => write!(f, "none"),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use log::{error, warn};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::encryption::GpsCipher;
+use crate::{insert_sensor_data, parse_message, Message, ParseOptions, SensorData};
+
+// One quarantined line, written as a single JSON object per line
+#[derive(Serialize, Deserialize, Debug)]
+struct QuarantineEntry {
+    raw: String,
+    error: String,
+    received_at: String,
+}
+
+// Append a line that could not be parsed to today's quarantine file so it can
+// be inspected or replayed later instead of being lost
+pub fn quarantine_message(dir: &Path, line: &str, error: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let now = chrono::Utc::now();
+    let path = dir.join(format!("quarantine_{}.jsonl", now.format("%Y-%m-%d")));
+
+    let entry = QuarantineEntry {
+        raw: line.to_string(),
+        error: error.to_string(),
+        received_at: now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+    };
+    let mut record = serde_json::to_string(&entry)?;
+    record.push('\n');
+
+    // A single append of a whole line keeps concurrent writers from interleaving
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(record.as_bytes())
+}
+
+// Counts reported by a replay run
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub inserted: usize,
+    pub skipped: usize,
+    pub still_failing: usize,
+}
+
+// Re-attempt every quarantined line. Files whose entries all succeed are
+// removed; otherwise the file is rewritten with only the entries that still fail.
+pub fn replay_quarantine(
+    conn: &Connection,
+    dir: &Path,
+    options: &ParseOptions,
+    gps_cipher: Option<&GpsCipher>,
+    dedup_timestamps: bool,
+) -> io::Result<ReplaySummary> {
+    let mut summary = ReplaySummary::default();
+    if !dir.exists() {
+        return Ok(summary);
+    }
+
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("quarantine_") && name.ends_with(".jsonl"))
+        })
+        .collect();
+    files.sort();
+
+    for path in files {
+        let mut remaining = Vec::new();
+        for line in BufReader::new(fs::File::open(&path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: QuarantineEntry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Unreadable quarantine entry in {}: {}", path.display(), e);
+                    remaining.push(line);
+                    continue;
+                }
+            };
+
+            match parse_message(&entry.raw, options) {
+                Ok(Message::SensorData(rows)) => match insert_rows(conn, &rows, gps_cipher, dedup_timestamps) {
+                    Ok(()) => summary.inserted += rows.len(),
+                    Err(e) => {
+                        error!("Database error replaying quarantined line: {}", e);
+                        remaining.push(line);
+                    }
+                },
+                // Control messages carry no data to insert
+                Ok(_) => summary.skipped += 1,
+                Err(e) => {
+                    let entry = QuarantineEntry { error: e.to_string(), ..entry };
+                    remaining.push(serde_json::to_string(&entry)?);
+                }
+            }
+        }
+
+        summary.still_failing += remaining.len();
+        if remaining.is_empty() {
+            fs::remove_file(&path)?;
+        } else {
+            let mut contents = remaining.join("\n");
+            contents.push('\n');
+            fs::write(&path, contents)?;
+        }
+    }
+
+    Ok(summary)
+}
+
+// All rows from one line, or none of them
+fn insert_rows(
+    conn: &Connection,
+    rows: &[SensorData],
+    gps_cipher: Option<&GpsCipher>,
+    dedup_timestamps: bool,
+) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for data in rows {
+        insert_sensor_data(&tx, data, None, None, gps_cipher, dedup_timestamps)?;
+    }
+    tx.commit()
+}
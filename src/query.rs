@@ -0,0 +1,476 @@
+use std::io::Write;
+use rusqlite::types::{Value as SqlValue, ValueRef};
+use rusqlite::{params_from_iter, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::encryption::{GpsCipher, ENCRYPTED_COLUMNS};
+use crate::error::ReceiverError;
+use crate::timestamp::ClientTimestamp;
+use crate::units::UnitsSystem;
+use crate::{DAC_COLUMNS, RAW_DAC_COLUMNS};
+
+// sensor_data columns a query may return, in the order they are returned
+// when the query doesn't list any
+const COLUMNS: &[&str] = &[
+    "id", "sessionID", "timestamp", "timestamp_ms", "latitude", "longitude", "altitude",
+    "accel_x", "accel_y", "accel_z", "gyro_x", "gyro_y", "gyro_z", "mag_x", "mag_y", "mag_z",
+    "temperature_c", "battery_v", "device_id", "connection_id", "message_id", "extras", "after_session_end", "is_outlier", "is_interpolated",
+    "fix_quality", "num_satellites", "hdop", "gps_low_quality", "outside_fence",
+    "accel_x_raw", "accel_y_raw", "accel_z_raw", "gyro_x_raw", "gyro_y_raw", "gyro_z_raw",
+    "accel_x_filtered", "accel_y_filtered", "accel_z_filtered", "accel_magnitude",
+    "cumulative_pitch", "cumulative_roll", "cumulative_yaw", "pitch_rad", "roll_rad", "pitch_roll_valid",
+    "cumulative_distance_m", "latitude_raw", "longitude_raw", "altitude_raw",
+];
+
+// Every column of a sensor_data row, in the order a query without `fields`
+// and an export return them
+pub fn all_columns() -> Vec<&'static str> {
+    COLUMNS.iter().chain(&DAC_COLUMNS).chain(&RAW_DAC_COLUMNS).copied().collect()
+}
+
+// Columns holding sensor readings, the only ones an aggregate may summarise
+const NUMERIC_COLUMNS: &[&str] = &[
+    "latitude", "longitude", "altitude",
+    "accel_x", "accel_y", "accel_z", "gyro_x", "gyro_y", "gyro_z", "mag_x", "mag_y", "mag_z",
+    "temperature_c", "battery_v", "fix_quality", "num_satellites", "hdop",
+    "accel_x_raw", "accel_y_raw", "accel_z_raw", "gyro_x_raw", "gyro_y_raw", "gyro_z_raw",
+    "accel_x_filtered", "accel_y_filtered", "accel_z_filtered", "accel_magnitude",
+    "cumulative_pitch", "cumulative_roll", "cumulative_yaw", "pitch_rad", "roll_rad", "pitch_roll_valid",
+    "cumulative_distance_m", "latitude_raw", "longitude_raw", "altitude_raw",
+];
+
+// Summary a query may compute instead of returning rows
+#[derive(Debug, Clone, Copy)]
+enum Aggregate {
+    Count,
+    Avg,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn parse(name: &str) -> Option<Aggregate> {
+        match name {
+            "count" => Some(Aggregate::Count),
+            "avg" => Some(Aggregate::Avg),
+            "min" => Some(Aggregate::Min),
+            "max" => Some(Aggregate::Max),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Aggregate::Count => "count",
+            Aggregate::Avg => "avg",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+        }
+    }
+}
+
+// What an aggregate's rows are grouped by; time buckets are in UTC
+#[derive(Debug, Clone, Copy)]
+enum GroupBy {
+    Session,
+    Minute,
+    Hour,
+    Day,
+}
+
+impl GroupBy {
+    fn parse(name: &str) -> Option<GroupBy> {
+        match name {
+            "session" => Some(GroupBy::Session),
+            "minute" => Some(GroupBy::Minute),
+            "hour" => Some(GroupBy::Hour),
+            "day" => Some(GroupBy::Day),
+            _ => None,
+        }
+    }
+
+    // Output key and SQL expression of the group
+    fn key(self) -> (&'static str, &'static str) {
+        match self {
+            GroupBy::Session => ("sessionID", "sessionID"),
+            GroupBy::Minute => ("bucket", "strftime('%Y-%m-%dT%H:%M:00Z', timestamp)"),
+            GroupBy::Hour => ("bucket", "strftime('%Y-%m-%dT%H:00:00Z', timestamp)"),
+            GroupBy::Day => ("bucket", "strftime('%Y-%m-%dT00:00:00Z', timestamp)"),
+        }
+    }
+}
+
+// The SELECT a query turns into, built only from whitelisted names
+#[derive(Debug)]
+pub struct Select {
+    // Output key and SQL expression of each returned value
+    columns: Vec<(&'static str, String)>,
+    // Rows are ordered by id, groups by their key
+    group_by: Option<&'static str>,
+}
+
+// Read-back request for stored rows, answered with one JSON line per row and
+// a closing `query_complete`
+#[derive(Deserialize, Debug)]
+pub struct QueryMessage {
+    #[serde(rename = "session_id", alias = "sessionID")]
+    pub session_id: Option<i64>,
+    // Time range, inclusive of `from` and exclusive of `to`
+    pub from: Option<String>,
+    pub to: Option<String>,
+    // Columns to return; all of them when empty
+    #[serde(default)]
+    pub fields: Vec<String>,
+    pub limit: Option<u64>,
+    // Leave out rows whose fix_quality is lower; rows without one are kept
+    pub min_fix_quality: Option<u8>,
+    // Summarise `field` per `group_by` instead of returning rows. Checked
+    // in `validate` rather than by serde so a bad name gets a query error.
+    pub aggregate: Option<String>,
+    pub field: Option<String>,
+    pub group_by: Option<String>,
+}
+
+// Server-side caps on what one query may return
+#[derive(Debug, Clone, Copy)]
+pub struct QueryLimits {
+    pub max_rows: u64,
+    pub max_bytes: usize,
+}
+
+// Last line of a query's answer
+#[derive(Serialize, Debug)]
+pub struct QueryComplete {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    pub rows: u64,
+    // More rows matched than the server's caps let through
+    pub truncated: bool,
+    // Units the returned sensor values are in
+    units: UnitsSystem,
+}
+
+impl QueryMessage {
+    // Columns to select, checked against the known ones so a field name can
+    // never become SQL
+    fn columns(&self) -> Result<Vec<&'static str>, String> {
+        if self.fields.is_empty() {
+            return Ok(all_columns());
+        }
+        self.fields
+            .iter()
+            .map(|field| {
+                all_columns()
+                    .into_iter()
+                    .find(|column| column == field)
+                    .ok_or_else(|| format!("unknown field '{}'", field))
+            })
+            .collect()
+    }
+
+    // Value and grouping of an aggregate query. Encrypted columns can't be
+    // summarised, since SQLite only sees their ciphertext.
+    fn summary(&self, aggregate: Aggregate, gps_encrypted: bool) -> Result<Select, String> {
+        if !self.fields.is_empty() {
+            return Err("`fields` can't be combined with `aggregate`".to_string());
+        }
+        let field = match &self.field {
+            Some(field) => Some(
+                NUMERIC_COLUMNS
+                    .iter()
+                    .chain(&DAC_COLUMNS)
+                    .chain(&RAW_DAC_COLUMNS)
+                    .find(|column| *column == field)
+                    .copied()
+                    .ok_or_else(|| format!("'{}' is not a field that can be aggregated", field))?,
+            ),
+            // Counting rows needs no field
+            None if matches!(aggregate, Aggregate::Count) => None,
+            None => return Err(format!("`{}` needs a `field`", aggregate.name())),
+        };
+        if let Some(field) = field.filter(|field| gps_encrypted && ENCRYPTED_COLUMNS.contains(field)) {
+            return Err(format!("'{}' is stored encrypted and can't be aggregated", field));
+        }
+        let value = format!("{}({})", aggregate.name().to_uppercase(), field.unwrap_or("*"));
+        let mut columns = Vec::new();
+        let group_by = match &self.group_by {
+            Some(name) => {
+                let group = GroupBy::parse(name)
+                    .ok_or_else(|| format!("can't group by '{}'; use session, minute, hour or day", name))?;
+                let (key, expr) = group.key();
+                columns.push((key, expr.to_string()));
+                Some(expr)
+            }
+            None => None,
+        };
+        columns.push((aggregate.name(), value));
+        Ok(Select { columns, group_by })
+    }
+
+    // Check the request before touching the database, returning what to
+    // select
+    pub fn validate(&self, gps_encrypted: bool) -> Result<Select, String> {
+        let select = match &self.aggregate {
+            Some(name) => {
+                let aggregate = Aggregate::parse(name)
+                    .ok_or_else(|| format!("unknown aggregate '{}'; use count, avg, min or max", name))?;
+                self.summary(aggregate, gps_encrypted)?
+            }
+            None if self.field.is_some() || self.group_by.is_some() => {
+                return Err("`field` and `group_by` need an `aggregate`".to_string());
+            }
+            None => Select {
+                columns: self.columns()?.into_iter().map(|column| (column, column.to_string())).collect(),
+                group_by: None,
+            },
+        };
+        for (name, bound) in [("from", &self.from), ("to", &self.to)] {
+            if let Some(bound) = bound {
+                if ClientTimestamp::parse(bound).is_none() {
+                    return Err(format!("`{}` must be an ISO 8601 timestamp, not '{}'", name, bound));
+                }
+            }
+        }
+        Ok(select)
+    }
+}
+
+// The SELECT for the rows matching `query`, at most one past `limit`, and
+// the values to bind to it. Only the filters the query gives are included,
+// since one left in as `? IS NULL OR` would keep the planner off the indexes.
+// Times are compared as milliseconds since the epoch (timestamp_ms), so stored
+// and requested offsets don't have to match.
+fn statement(query: &QueryMessage, select: &Select, limit: u64) -> (String, Vec<SqlValue>) {
+    let millis = |bound: &Option<String>| bound.as_deref().and_then(ClientTimestamp::parse).map(|time| time.to_utc().timestamp_millis());
+    let mut filters = vec!["1"];
+    let mut values: Vec<SqlValue> = Vec::new();
+    if let Some(session_id) = query.session_id {
+        filters.push("sessionID = ?");
+        values.push(session_id.into());
+    }
+    if let Some(from) = millis(&query.from) {
+        filters.push("timestamp_ms >= ?");
+        values.push(from.into());
+    }
+    if let Some(to) = millis(&query.to) {
+        filters.push("timestamp_ms < ?");
+        values.push(to.into());
+    }
+    if let Some(min_fix_quality) = query.min_fix_quality {
+        filters.push("(fix_quality IS NULL OR fix_quality >= ?)");
+        values.push(min_fix_quality.into());
+    }
+    // One row past the limit tells whether the cap cut anything off
+    values.push((limit.saturating_add(1) as i64).into());
+    let sql = format!(
+        "SELECT {} FROM sensor_data
+         WHERE {}
+         {}
+         LIMIT ?",
+        select.columns.iter().map(|(_, expr)| expr.as_str()).collect::<Vec<_>>().join(", "),
+        filters.join(" AND "),
+        match (select.group_by, &query.aggregate) {
+            (Some(expr), _) => format!("GROUP BY {0} ORDER BY {0}", expr),
+            // A single aggregate value has nothing to order
+            (None, Some(_)) => String::new(),
+            (None, None) => "ORDER BY id".to_string(),
+        },
+    );
+    (sql, values)
+}
+
+// Stream what `select` picks from the rows matching `query` to `out` as JSON
+// lines, oldest row or first group first, stopping at the query's limit or
+// the server's caps. Encrypted coordinates are decrypted with `gps_cipher`.
+// `units` is the system values were stored in.
+pub fn run(
+    conn: &Connection,
+    query: &QueryMessage,
+    select: &Select,
+    limits: QueryLimits,
+    units: UnitsSystem,
+    gps_cipher: Option<&GpsCipher>,
+    out: &mut impl Write,
+) -> Result<QueryComplete, ReceiverError> {
+    let limit = query.limit.unwrap_or(limits.max_rows).min(limits.max_rows);
+    let capped = query.limit.is_none_or(|requested| requested > limits.max_rows);
+    let (sql, values) = statement(query, select, limit);
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params_from_iter(values))?;
+
+    let mut complete = QueryComplete { message_type: "query_complete", rows: 0, truncated: false, units };
+    let mut bytes = 0;
+    while let Some(row) = rows.next()? {
+        if complete.rows == limit {
+            complete.truncated = capped;
+            break;
+        }
+        let mut object = Map::new();
+        for (i, (column, _)) in select.columns.iter().enumerate() {
+            let value = match (gps_cipher, row.get_ref(i)?) {
+                (Some(cipher), ValueRef::Text(text)) if ENCRYPTED_COLUMNS.contains(column) => {
+                    Value::from(cipher.decrypt(column, &String::from_utf8_lossy(text)).map_err(ReceiverError::Decrypt)?)
+                }
+                (_, value) => to_json(column, value),
+            };
+            object.insert(column.to_string(), value);
+        }
+        let mut line = serde_json::to_string(&object)?;
+        line.push('\n');
+        if bytes + line.len() > limits.max_bytes {
+            complete.truncated = true;
+            break;
+        }
+        bytes += line.len();
+        out.write_all(line.as_bytes())?;
+        complete.rows += 1;
+    }
+    Ok(complete)
+}
+
+// A column value as JSON; extras go out as the object they hold
+fn to_json(column: &str, value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::from(i),
+        ValueRef::Real(f) => Value::from(f),
+        ValueRef::Text(text) => {
+            let text = String::from_utf8_lossy(text);
+            match column {
+                "extras" => serde_json::from_str(&text).unwrap_or(Value::String(text.into_owned())),
+                _ => Value::String(text.into_owned()),
+            }
+        }
+        ValueRef::Blob(_) => Value::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(json: serde_json::Value) -> QueryMessage {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn selected(json: serde_json::Value) -> Vec<(&'static str, String)> {
+        query(json).validate(false).expect("valid query").columns
+    }
+
+    fn rejected(json: serde_json::Value, gps_encrypted: bool) -> String {
+        query(json).validate(gps_encrypted).expect_err("invalid query")
+    }
+
+    #[test]
+    fn aggregates_by_name() {
+        assert_eq!(selected(serde_json::json!({"aggregate": "count"})), [("count", "COUNT(*)".to_string())]);
+        assert_eq!(
+            selected(serde_json::json!({"aggregate": "avg", "field": "accel_z", "group_by": "session"})),
+            [("sessionID", "sessionID".to_string()), ("avg", "AVG(accel_z)".to_string())]
+        );
+        assert_eq!(
+            selected(serde_json::json!({"aggregate": "max", "field": "dac_16", "group_by": "hour"})),
+            [("bucket", "strftime('%Y-%m-%dT%H:00:00Z', timestamp)".to_string()), ("max", "MAX(dac_16)".to_string())]
+        );
+    }
+
+    // Names are matched against the known ones, so nothing a client sends
+    // becomes SQL
+    #[test]
+    fn unknown_names_are_rejected() {
+        assert_eq!(
+            rejected(serde_json::json!({"aggregate": "sum", "field": "accel_z"}), false),
+            "unknown aggregate 'sum'; use count, avg, min or max"
+        );
+        assert_eq!(rejected(serde_json::json!({"aggregate": "COUNT"}), false), "unknown aggregate 'COUNT'; use count, avg, min or max");
+        assert_eq!(
+            rejected(serde_json::json!({"aggregate": "min", "field": "accel_z); DROP TABLE sensor_data; --"}), false),
+            "'accel_z); DROP TABLE sensor_data; --' is not a field that can be aggregated"
+        );
+        assert_eq!(
+            rejected(serde_json::json!({"aggregate": "max", "field": "device_id"}), false),
+            "'device_id' is not a field that can be aggregated"
+        );
+        assert_eq!(
+            rejected(serde_json::json!({"aggregate": "count", "group_by": "week"}), false),
+            "can't group by 'week'; use session, minute, hour or day"
+        );
+    }
+
+    #[test]
+    fn aggregate_combinations() {
+        assert_eq!(rejected(serde_json::json!({"aggregate": "avg"}), false), "`avg` needs a `field`");
+        assert_eq!(
+            rejected(serde_json::json!({"aggregate": "count", "fields": ["id"]}), false),
+            "`fields` can't be combined with `aggregate`"
+        );
+        assert_eq!(rejected(serde_json::json!({"field": "accel_z"}), false), "`field` and `group_by` need an `aggregate`");
+        assert_eq!(
+            rejected(serde_json::json!({"aggregate": "avg", "field": "latitude"}), true),
+            "'latitude' is stored encrypted and can't be aggregated"
+        );
+        assert!(query(serde_json::json!({"aggregate": "avg", "field": "latitude"})).validate(false).is_ok());
+    }
+
+    // Rows at 10:00Z, 10:30Z (sent as 12:30+02:00) and 11:00Z in session 1,
+    // and one in session 2
+    fn database() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        crate::schema::ensure_schema(&conn, false).unwrap();
+        for (session_id, timestamp) in [
+            (1, "2024-05-18T10:00:00Z"),
+            (1, "2024-05-18T12:30:00+02:00"),
+            (1, "2024-05-18T11:00:00.000Z"),
+            (2, "2024-05-18T10:30:00Z"),
+        ] {
+            let data = serde_json::from_value(serde_json::json!({"sessionID": session_id, "timestamp": timestamp})).unwrap();
+            crate::insert_sensor_data(&conn, &data, None, None, None, false).unwrap();
+        }
+        conn
+    }
+
+    fn timestamps(conn: &Connection, json: serde_json::Value) -> Vec<String> {
+        let query = query(json);
+        let select = query.validate(false).expect("valid query");
+        let limits = QueryLimits { max_rows: 100, max_bytes: 1 << 20 };
+        let mut out = Vec::new();
+        run(conn, &query, &select, limits, UnitsSystem::Si, None, &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).unwrap().get("timestamp").and_then(Value::as_str).map(str::to_string))
+            .collect()
+    }
+
+    // Bounds are points in time, whatever offset they and the rows were sent
+    // with; `from` is inclusive and `to` exclusive
+    #[test]
+    fn time_range_compares_points_in_time() {
+        let conn = database();
+        assert_eq!(
+            timestamps(&conn, serde_json::json!({"session_id": 1, "from": "2024-05-18T12:30:00+02:00", "to": "2024-05-18T11:00:00Z"})),
+            ["2024-05-18T12:30:00+02:00"]
+        );
+        assert_eq!(
+            timestamps(&conn, serde_json::json!({"from": "2024-05-18T10:15:00Z", "fields": ["timestamp"]})),
+            ["2024-05-18T12:30:00+02:00", "2024-05-18T11:00:00.000Z", "2024-05-18T10:30:00Z"]
+        );
+    }
+
+    // The range is looked up in the index rather than by scanning the table
+    #[test]
+    fn time_range_uses_the_index() {
+        let conn = database();
+        let query = query(serde_json::json!({"session_id": 1, "from": "2024-05-18T10:15:00Z", "to": "2024-05-18T11:00:00Z"}));
+        let select = query.validate(false).unwrap();
+        let (sql, values) = statement(&query, &select, 10);
+        let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql)).unwrap();
+        let plan: Vec<String> = stmt.query_map(params_from_iter(values), |row| row.get(3)).unwrap().map(Result::unwrap).collect();
+        assert!(
+            plan.iter().any(|step| step.contains("idx_sensor_data_session_time") && step.contains("timestamp_ms>?")),
+            "{:?}",
+            plan
+        );
+    }
+}
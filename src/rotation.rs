@@ -0,0 +1,207 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+
+// Default number of rotated files kept next to the active one
+pub const DEFAULT_KEEP: usize = 7;
+
+// A file writer that rotates on size and at midnight UTC. Writes go to the
+// active file at `path`; on rotation it is renamed to
+// `<stem>.<YYYYMMDDTHHMMSSZ>.<ext>` (the time it was opened) and a fresh
+// active file is started. At most `keep` rotated files are retained.
+//
+// Rotation only happens between `write` calls, so callers that write whole
+// lines at a time never have a line split across files.
+//
+// The writer doesn't log rotations and prunes itself, since it may be the
+// logger's own output; callers collect them with `take_events`.
+pub struct RotatingWriter {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    keep: usize,
+    file: Option<BufWriter<File>>,
+    written: u64,
+    opened_at: DateTime<Utc>,
+    events: Vec<String>,
+}
+
+impl RotatingWriter {
+    pub fn open(path: PathBuf, max_bytes: Option<u64>, keep: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = RotatingWriter {
+            path,
+            max_bytes,
+            keep,
+            file: None,
+            written: 0,
+            opened_at: Utc::now(),
+            events: Vec::new(),
+        };
+        writer.open_active()?;
+        Ok(writer)
+    }
+
+    fn open_active(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        // Appending to a file left by a previous run counts toward its size
+        self.written = file.metadata()?.len();
+        self.opened_at = match self.written {
+            0 => Utc::now(),
+            _ => file.metadata()?.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now()),
+        };
+        self.file = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    // Rotations and prunes since the last call, for the caller to log
+    pub fn take_events(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        if self.written == 0 {
+            return false;
+        }
+        let crossed_midnight = Utc::now().date_naive() != self.opened_at.date_naive();
+        let too_big = self
+            .max_bytes
+            .is_some_and(|max| self.written + incoming as u64 > max);
+        crossed_midnight || too_big
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        let rotated = self.rotated_path(&self.opened_at);
+        // Rename is atomic, so readers see either the whole old file or none of it
+        fs::rename(&self.path, &rotated)?;
+        self.events.push(format!("Rotated {} to {}", self.path.display(), rotated.display()));
+        self.open_active()?;
+        self.prune();
+        Ok(())
+    }
+
+    fn rotated_path(&self, opened_at: &DateTime<Utc>) -> PathBuf {
+        let (stem, ext) = stem_and_extension(&self.path);
+        let stamp = opened_at.format("%Y%m%dT%H%M%SZ");
+        let mut name = format!("{}.{}", stem, stamp);
+        // Two rotations within the same second must not overwrite each other
+        let mut candidate = self.path.with_file_name(with_extension(&name, &ext));
+        let mut n = 1;
+        while candidate.exists() {
+            name = format!("{}.{}-{}", stem, stamp, n);
+            candidate = self.path.with_file_name(with_extension(&name, &ext));
+            n += 1;
+        }
+        candidate
+    }
+
+    // Delete the oldest rotated files beyond the retention count
+    fn prune(&mut self) {
+        let (stem, ext) = stem_and_extension(&self.path);
+        let prefix = format!("{}.", stem);
+        let dir = match self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::from("."),
+        };
+        let active = self.path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+
+        let mut rotated: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| {
+                    path.file_name().and_then(|n| n.to_str()).is_some_and(|name| {
+                        name != active
+                            && name.starts_with(&prefix)
+                            && ext.as_ref().is_none_or(|ext| name.ends_with(&format!(".{}", ext)))
+                    })
+                })
+                .collect(),
+            Err(e) => {
+                self.events.push(format!("Could not list {} to prune rotated files: {}", dir.display(), e));
+                return;
+            }
+        };
+        // Oldest first; the name breaks ties between files finished in the same instant
+        rotated.sort_by_cached_key(|path| {
+            let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+            (modified, path.clone())
+        });
+
+        let excess = rotated.len().saturating_sub(self.keep);
+        for old in rotated.into_iter().take(excess) {
+            let event = match fs::remove_file(&old) {
+                Ok(()) => format!("Pruned rotated file {}", old.display()),
+                Err(e) => format!("Could not prune rotated file {}: {}", old.display(), e),
+            };
+            self.events.push(event);
+        }
+    }
+}
+
+fn stem_and_extension(path: &Path) -> (String, Option<String>) {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("output").to_string();
+    let ext = path.extension().and_then(|e| e.to_str()).map(str::to_string);
+    (stem, ext)
+}
+
+fn with_extension(name: &str, ext: &Option<String>) -> String {
+    match ext {
+        Some(ext) => format!("{}.{}", name, ext),
+        None => name.to_string(),
+    }
+}
+
+impl Write for RotatingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => {
+                self.open_active()?;
+                self.file.as_mut().expect("active file was just opened")
+            }
+        };
+        let n = file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Log file target for the logger. Rotation events are written into the log
+// itself, in the same format as other lines, since logging them through the
+// logger from inside its own writer would deadlock.
+pub struct LogFile(pub RotatingWriter);
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        for event in self.0.take_events() {
+            let line = format!(
+                "[{} INFO  {}] {}\n",
+                Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+                module_path!(),
+                event
+            );
+            self.0.write_all(line.as_bytes())?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
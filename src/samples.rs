@@ -0,0 +1,178 @@
+use chrono::Duration;
+use serde::Deserialize;
+
+use crate::timestamp::{self, ClientTimestamp, TimestampFormat};
+use crate::SensorData;
+
+// An IMU reading that is either a single value or a run of samples taken
+// `sample_interval_ms` apart
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Samples {
+    One(f64),
+    Many(Vec<f64>),
+}
+
+impl Samples {
+    fn len(&self) -> Option<usize> {
+        match self {
+            Samples::One(_) => None,
+            Samples::Many(values) => Some(values.len()),
+        }
+    }
+
+    fn get(&self, i: usize) -> f64 {
+        match self {
+            Samples::One(value) => *value,
+            Samples::Many(values) => values[i],
+        }
+    }
+}
+
+// A record whose IMU fields carry several samples, so a high-rate IMU doesn't
+// have to repeat the slower GPS and DAC fields in every line. It stands for
+// one row per sample; scalar fields are copied into each row, and missing
+// fields stay missing in every row.
+#[derive(Deserialize, Debug)]
+pub struct SampleBlock {
+    #[serde(
+        rename = "sessionID",
+        alias = "session_id",
+        alias = "sessionId",
+        default,
+        deserialize_with = "crate::session_id::deserialize"
+    )]
+    session_id: Option<i64>,
+    #[serde(deserialize_with = "crate::timestamp::deserialize")]
+    timestamp: String,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    altitude: Option<f64>,
+    #[serde(alias = "accelX")]
+    accel_x: Option<Samples>,
+    #[serde(alias = "accelY")]
+    accel_y: Option<Samples>,
+    #[serde(alias = "accelZ")]
+    accel_z: Option<Samples>,
+    #[serde(alias = "gyroX")]
+    gyro_x: Option<Samples>,
+    #[serde(alias = "gyroY")]
+    gyro_y: Option<Samples>,
+    #[serde(alias = "gyroZ")]
+    gyro_z: Option<Samples>,
+    #[serde(alias = "magX")]
+    mag_x: Option<Samples>,
+    #[serde(alias = "magY")]
+    mag_y: Option<Samples>,
+    #[serde(alias = "magZ")]
+    mag_z: Option<Samples>,
+    fix_quality: Option<u8>,
+    num_satellites: Option<u32>,
+    hdop: Option<f64>,
+    temperature_c: Option<f64>,
+    battery_v: Option<f64>,
+    #[serde(alias = "dac1")]
+    dac_1: Option<f64>,
+    #[serde(alias = "dac2")]
+    dac_2: Option<f64>,
+    #[serde(alias = "dac3")]
+    dac_3: Option<f64>,
+    #[serde(alias = "dac4")]
+    dac_4: Option<f64>,
+    #[serde(default)]
+    dac: Option<Vec<Option<f64>>>,
+    sample_interval_ms: f64,
+    message_id: Option<String>,
+    #[serde(flatten)]
+    extras: serde_json::Map<String, serde_json::Value>,
+}
+
+impl SampleBlock {
+    // One row per sample, timestamped `timestamp + i * sample_interval_ms`,
+    // counting from the stored form of a timestamp in one of `formats`
+    pub fn expand(self, formats: &[TimestampFormat]) -> Result<Vec<SensorData>, String> {
+        let fields = [
+            ("accel_x", self.accel_x.as_ref()),
+            ("accel_y", self.accel_y.as_ref()),
+            ("accel_z", self.accel_z.as_ref()),
+            ("gyro_x", self.gyro_x.as_ref()),
+            ("gyro_y", self.gyro_y.as_ref()),
+            ("gyro_z", self.gyro_z.as_ref()),
+            ("mag_x", self.mag_x.as_ref()),
+            ("mag_y", self.mag_y.as_ref()),
+            ("mag_z", self.mag_z.as_ref()),
+        ];
+        let mut count: Option<(&str, usize)> = None;
+        for (name, samples) in fields {
+            let Some(len) = samples.and_then(Samples::len) else {
+                continue;
+            };
+            match count {
+                None => count = Some((name, len)),
+                Some((first, expected)) if len != expected => {
+                    return Err(format!(
+                        "sample arrays differ in length: {} has {} samples but {} has {}",
+                        first, expected, name, len
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+        let count = match count {
+            Some((_, 0)) => return Err("sample arrays must not be empty".to_string()),
+            Some((_, len)) => len,
+            None => 1,
+        };
+        if !(self.sample_interval_ms.is_finite() && self.sample_interval_ms > 0.0) {
+            return Err("sample_interval_ms must be a positive number".to_string());
+        }
+        let mut timestamp = self.timestamp.clone();
+        timestamp::normalize(&mut timestamp, formats)?;
+        let base = ClientTimestamp::parse(&timestamp).ok_or_else(|| {
+            format!("timestamp '{}' must be a date and time to expand sample arrays", self.timestamp)
+        })?;
+
+        Ok((0..count)
+            .map(|i| SensorData {
+                session_id: self.session_id,
+                timestamp: base.plus(Duration::nanoseconds((self.sample_interval_ms * i as f64 * 1e6).round() as i64)),
+                latitude: self.latitude,
+                longitude: self.longitude,
+                altitude: self.altitude,
+                accel_x: self.accel_x.as_ref().map(|samples| samples.get(i)),
+                accel_y: self.accel_y.as_ref().map(|samples| samples.get(i)),
+                accel_z: self.accel_z.as_ref().map(|samples| samples.get(i)),
+                gyro_x: self.gyro_x.as_ref().map(|samples| samples.get(i)),
+                gyro_y: self.gyro_y.as_ref().map(|samples| samples.get(i)),
+                gyro_z: self.gyro_z.as_ref().map(|samples| samples.get(i)),
+                mag_x: self.mag_x.as_ref().map(|samples| samples.get(i)),
+                mag_y: self.mag_y.as_ref().map(|samples| samples.get(i)),
+                mag_z: self.mag_z.as_ref().map(|samples| samples.get(i)),
+                fix_quality: self.fix_quality,
+                num_satellites: self.num_satellites,
+                hdop: self.hdop,
+                temperature_c: self.temperature_c,
+                battery_v: self.battery_v,
+                dac_1: self.dac_1,
+                dac_2: self.dac_2,
+                dac_3: self.dac_3,
+                dac_4: self.dac_4,
+                dac: self.dac.clone(),
+                gps_low_quality: false,
+                outside_fence: false,
+                is_outlier: false,
+                is_interpolated: false,
+                accel_filtered: None,
+                cumulative_angles: None,
+                cumulative_distance_m: None,
+                tilt: None,
+                imu_raw: None,
+                dac_raw: None,
+                position_raw: None,
+                // Each row needs its own ID so a retransmitted block is skipped row by row
+                message_id: self.message_id.as_ref().map(|id| format!("{}#{}", id, i)),
+                extras: self.extras.clone(),
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,346 @@
+use log::{error, info};
+use rusqlite::Connection;
+
+use crate::{DAC_COLUMNS, RAW_DAC_COLUMNS};
+
+// The database's tables and indexes. Every connection that writes runs
+// ensure_schema once it is opened, so none depends on another having created
+// them first.
+
+// One row per record
+pub const SENSOR_DATA_TABLE: &str = "CREATE TABLE IF NOT EXISTS sensor_data (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        sessionID INTEGER,
+        timestamp TEXT,
+        timestamp_ms INTEGER,
+        latitude REAL,
+        longitude REAL,
+        altitude REAL,
+        accel_x REAL,
+        accel_y REAL,
+        accel_z REAL,
+        gyro_x REAL,
+        gyro_y REAL,
+        gyro_z REAL,
+        mag_x REAL,
+        mag_y REAL,
+        mag_z REAL,
+        temperature_c REAL,
+        battery_v REAL,
+        fix_quality INTEGER,
+        num_satellites INTEGER,
+        hdop REAL,
+        gps_low_quality INTEGER NOT NULL DEFAULT 0,
+        outside_fence INTEGER NOT NULL DEFAULT 0,
+        dac_1 REAL,
+        dac_2 REAL,
+        dac_3 REAL,
+        dac_4 REAL,
+        dac_5 REAL,
+        dac_6 REAL,
+        dac_7 REAL,
+        dac_8 REAL,
+        dac_9 REAL,
+        dac_10 REAL,
+        dac_11 REAL,
+        dac_12 REAL,
+        dac_13 REAL,
+        dac_14 REAL,
+        dac_15 REAL,
+        dac_16 REAL,
+        accel_x_raw REAL,
+        accel_y_raw REAL,
+        accel_z_raw REAL,
+        gyro_x_raw REAL,
+        gyro_y_raw REAL,
+        gyro_z_raw REAL,
+        accel_x_filtered REAL,
+        accel_y_filtered REAL,
+        accel_z_filtered REAL,
+        accel_magnitude REAL,
+        cumulative_pitch REAL,
+        cumulative_roll REAL,
+        cumulative_yaw REAL,
+        cumulative_distance_m REAL,
+        pitch_rad REAL,
+        roll_rad REAL,
+        pitch_roll_valid INTEGER,
+        dac_1_raw REAL,
+        dac_2_raw REAL,
+        dac_3_raw REAL,
+        dac_4_raw REAL,
+        dac_5_raw REAL,
+        dac_6_raw REAL,
+        dac_7_raw REAL,
+        dac_8_raw REAL,
+        dac_9_raw REAL,
+        dac_10_raw REAL,
+        dac_11_raw REAL,
+        dac_12_raw REAL,
+        dac_13_raw REAL,
+        dac_14_raw REAL,
+        dac_15_raw REAL,
+        dac_16_raw REAL,
+        latitude_raw REAL,
+        longitude_raw REAL,
+        altitude_raw REAL,
+        is_outlier INTEGER NOT NULL DEFAULT 0,
+        is_interpolated INTEGER NOT NULL DEFAULT 0,
+        device_id TEXT,
+        connection_id INTEGER REFERENCES connections(id),
+        message_id TEXT,
+        extras TEXT,
+        after_session_end INTEGER NOT NULL DEFAULT 0
+    )";
+
+// Runs announced with session_start, with totals filled in when they end
+pub const SESSIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS sessions (
+        sessionID INTEGER PRIMARY KEY AUTOINCREMENT,
+        device_id TEXT,
+        label TEXT,
+        started_at TEXT,
+        ended_at TEXT,
+        status TEXT NOT NULL,
+        record_count INTEGER,
+        first_timestamp TEXT,
+        last_timestamp TEXT,
+        min_battery_v REAL,
+        max_temperature_c REAL,
+        outlier_count INTEGER,
+        interpolated_count INTEGER,
+        max_accel_magnitude REAL,
+        avg_accel_magnitude REAL,
+        final_pitch REAL,
+        final_roll REAL,
+        final_yaw REAL,
+        pitch_roll_valid_fraction REAL,
+        outside_fence_rows_total INTEGER,
+        total_distance_m REAL,
+        expected_count INTEGER,
+        verified_count INTEGER,
+        upload_status TEXT,
+        verified_at TEXT,
+        units_system TEXT,
+        final_stats TEXT
+    )";
+
+// Records that could not be stored normally, kept for inspection
+pub const DEAD_LETTERS_TABLE: &str = "CREATE TABLE IF NOT EXISTS dead_letters (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        received_at TEXT,
+        error_type TEXT,
+        error TEXT,
+        payload TEXT
+    )";
+
+// Values that met an --event-rule, one row per rule and record
+pub const EVENTS_TABLE: &str = "CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        session_id INTEGER,
+        timestamp TEXT,
+        label TEXT NOT NULL,
+        field TEXT NOT NULL,
+        value REAL NOT NULL,
+        threshold REAL NOT NULL
+    )";
+
+// Labelled time ranges of sessions, added over HTTP
+pub const ANNOTATIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS annotations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        session_id INTEGER,
+        start_ts TEXT,
+        end_ts TEXT,
+        label TEXT,
+        notes TEXT
+    )";
+
+// One row per client stream connection, from accept to close
+pub const CONNECTIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS connections (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        client_addr TEXT,
+        start_time TEXT,
+        end_time TEXT,
+        row_count INTEGER DEFAULT 0,
+        status TEXT DEFAULT 'active',
+        notes TEXT,
+        bytes_received INTEGER DEFAULT 0
+    )";
+
+// Lookups by device, session, time within a session and connection, and the
+// index that makes records with a message_id idempotent; rows without one are
+// unaffected
+pub const INDEXES: &str = "
+    CREATE INDEX IF NOT EXISTS idx_sensor_data_device_id ON sensor_data(device_id);
+    CREATE INDEX IF NOT EXISTS idx_sensor_data_session ON sensor_data(sessionID);
+    CREATE INDEX IF NOT EXISTS idx_sensor_data_session_time ON sensor_data(sessionID, timestamp_ms);
+    CREATE INDEX IF NOT EXISTS idx_sensor_data_connection ON sensor_data(connection_id);
+    CREATE UNIQUE INDEX IF NOT EXISTS idx_sensor_data_message_id
+        ON sensor_data(message_id) WHERE message_id IS NOT NULL;
+    CREATE INDEX IF NOT EXISTS idx_events_session ON events(session_id);
+    CREATE INDEX IF NOT EXISTS idx_annotations_session ON annotations(session_id);
+";
+
+// Only with --dedup-timestamps
+pub const SESSION_TIMESTAMP_INDEX: &str =
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_sensor_data_session_timestamp ON sensor_data(sessionID, timestamp)";
+
+// Where the IMU values as received go when smoothing is on
+const RAW_IMU_COLUMNS: [&str; 6] = ["accel_x_raw", "accel_y_raw", "accel_z_raw", "gyro_x_raw", "gyro_y_raw", "gyro_z_raw"];
+
+// Where the position as received goes when it is calibrated
+const RAW_POSITION_COLUMNS: [&str; 3] = ["latitude_raw", "longitude_raw", "altitude_raw"];
+
+// Where the low-pass filtered accelerometer values go
+const FILTERED_ACCEL_COLUMNS: [&str; 3] = ["accel_x_filtered", "accel_y_filtered", "accel_z_filtered"];
+
+// Where the angles integrated from the gyroscope go
+const CUMULATIVE_ANGLE_COLUMNS: [&str; 3] = ["cumulative_pitch", "cumulative_roll", "cumulative_yaw"];
+
+// Where the accelerometer-only tilt estimate goes
+const TILT_COLUMNS: [&str; 3] = ["pitch_rad", "roll_rad", "pitch_roll_valid"];
+
+// Create the tables if they don't exist and bring them up to date. Indexes
+// come after the migrations, since older databases lack columns they cover.
+// SQLite only enforces REFERENCES on connections that turn foreign keys on,
+// so every connection set up here does.
+pub fn ensure_schema(conn: &Connection, dedup_timestamps: bool) -> rusqlite::Result<()> {
+    conn.pragma_update(None, "foreign_keys", true)?;
+    for table in [SENSOR_DATA_TABLE, SESSIONS_TABLE, DEAD_LETTERS_TABLE, EVENTS_TABLE, ANNOTATIONS_TABLE, CONNECTIONS_TABLE] {
+        conn.execute(table, [])?;
+    }
+    // Bring databases created by older versions up to date
+    migrate(conn)?;
+    conn.execute_batch(INDEXES)?;
+    set_timestamp_dedup(conn, dedup_timestamps)
+}
+
+// Add columns introduced after the original schema. Rows written before a
+// column existed keep NULL in it.
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    add_column_if_missing(conn, "sensor_data", "device_id", "TEXT")?;
+    add_column_if_missing(conn, "sensor_data", "after_session_end", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "sensor_data", "message_id", "TEXT")?;
+    add_column_if_missing(conn, "sensor_data", "extras", "TEXT")?;
+    for column in &DAC_COLUMNS[4..] {
+        add_column_if_missing(conn, "sensor_data", column, "REAL")?;
+    }
+    for column in ["mag_x", "mag_y", "mag_z", "temperature_c", "battery_v"] {
+        add_column_if_missing(conn, "sensor_data", column, "REAL")?;
+    }
+    for column in RAW_IMU_COLUMNS.into_iter().chain(FILTERED_ACCEL_COLUMNS) {
+        add_column_if_missing(conn, "sensor_data", column, "REAL")?;
+    }
+    add_column_if_missing(conn, "sensor_data", "is_outlier", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "sensor_data", "fix_quality", "INTEGER")?;
+    add_column_if_missing(conn, "sensor_data", "num_satellites", "INTEGER")?;
+    add_column_if_missing(conn, "sensor_data", "hdop", "REAL")?;
+    add_column_if_missing(conn, "sensor_data", "gps_low_quality", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "sensor_data", "accel_magnitude", "REAL")?;
+    for column in CUMULATIVE_ANGLE_COLUMNS.into_iter().chain(RAW_DAC_COLUMNS) {
+        add_column_if_missing(conn, "sensor_data", column, "REAL")?;
+    }
+    add_column_if_missing(conn, "sensor_data", TILT_COLUMNS[0], "REAL")?;
+    add_column_if_missing(conn, "sensor_data", TILT_COLUMNS[1], "REAL")?;
+    add_column_if_missing(conn, "sensor_data", TILT_COLUMNS[2], "INTEGER")?;
+    add_column_if_missing(conn, "sensor_data", "outside_fence", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "sensor_data", "cumulative_distance_m", "REAL")?;
+    add_column_if_missing(conn, "sensor_data", "is_interpolated", "INTEGER NOT NULL DEFAULT 0")?;
+    for column in RAW_POSITION_COLUMNS {
+        add_column_if_missing(conn, "sensor_data", column, "REAL")?;
+    }
+    add_column_if_missing(conn, "sensor_data", "connection_id", "INTEGER REFERENCES connections(id)")?;
+    if !column_exists(conn, "sensor_data", "timestamp_ms")? {
+        add_column_if_missing(conn, "sensor_data", "timestamp_ms", "INTEGER")?;
+        backfill_timestamp_ms(conn)?;
+    }
+    add_column_if_missing(conn, "sessions", "min_battery_v", "REAL")?;
+    add_column_if_missing(conn, "sessions", "max_temperature_c", "REAL")?;
+    add_column_if_missing(conn, "sessions", "outlier_count", "INTEGER")?;
+    add_column_if_missing(conn, "sessions", "max_accel_magnitude", "REAL")?;
+    add_column_if_missing(conn, "sessions", "avg_accel_magnitude", "REAL")?;
+    for column in ["final_pitch", "final_roll", "final_yaw", "pitch_roll_valid_fraction"] {
+        add_column_if_missing(conn, "sessions", column, "REAL")?;
+    }
+    add_column_if_missing(conn, "sessions", "outside_fence_rows_total", "INTEGER")?;
+    add_column_if_missing(conn, "sessions", "total_distance_m", "REAL")?;
+    add_column_if_missing(conn, "sessions", "interpolated_count", "INTEGER")?;
+    add_column_if_missing(conn, "sessions", "expected_count", "INTEGER")?;
+    add_column_if_missing(conn, "sessions", "verified_count", "INTEGER")?;
+    add_column_if_missing(conn, "sessions", "upload_status", "TEXT")?;
+    add_column_if_missing(conn, "sessions", "verified_at", "TEXT")?;
+    add_column_if_missing(conn, "sessions", "units_system", "TEXT")?;
+    add_column_if_missing(conn, "sessions", "final_stats", "TEXT")?;
+    Ok(())
+}
+
+// With --dedup-timestamps, a unique index keeps a session to one row per
+// timestamp; without it the index is dropped again, so records sharing a
+// timestamp are all stored. Rows without a session are never duplicates,
+// since SQLite takes no two NULLs for equal.
+fn set_timestamp_dedup(conn: &Connection, enabled: bool) -> rusqlite::Result<()> {
+    if !enabled {
+        return conn.execute_batch("DROP INDEX IF EXISTS idx_sensor_data_session_timestamp");
+    }
+    conn.execute(SESSION_TIMESTAMP_INDEX, [])
+    .inspect_err(|e| {
+        if e.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation) {
+            error!("Can't enable --dedup-timestamps: the database already holds sessions with several rows at one timestamp");
+        }
+    })?;
+    Ok(())
+}
+
+// Fill in timestamp_ms for rows stored before the column existed, from their
+// timestamp as SQLite reads it. Rows whose timestamp isn't a date keep NULL.
+fn backfill_timestamp_ms(conn: &Connection) -> rusqlite::Result<()> {
+    let rows = conn.execute(
+        "UPDATE sensor_data
+         SET timestamp_ms = CAST(ROUND((julianday(timestamp) - 2440587.5) * 86400000.0) AS INTEGER)
+         WHERE julianday(timestamp) IS NOT NULL",
+        [],
+    )?;
+    if rows > 0 {
+        info!("Migrated sensor_data: filled in timestamp_ms of {} row(s)", rows);
+    }
+    Ok(())
+}
+
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> rusqlite::Result<()> {
+    if !column_exists(conn, table, column)? {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+        info!("Migrated {}: added {} column", table, column);
+    }
+    Ok(())
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(1)?;
+        if name == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A database from before timestamp_ms gets it filled in for its rows
+    #[test]
+    fn timestamp_ms_is_backfilled() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE sensor_data (id INTEGER PRIMARY KEY AUTOINCREMENT, sessionID INTEGER, timestamp TEXT)", [])
+            .unwrap();
+        for timestamp in ["2024-05-18T10:00:00.123Z", "2024-05-18T12:00:00+02:00", "2024-05-18T10:00:00", "keepalive"] {
+            conn.execute("INSERT INTO sensor_data (sessionID, timestamp) VALUES (1, ?1)", [timestamp]).unwrap();
+        }
+        ensure_schema(&conn, false).unwrap();
+        let mut stmt = conn.prepare("SELECT timestamp_ms FROM sensor_data ORDER BY id").unwrap();
+        let millis: Vec<Option<i64>> = stmt.query_map([], |row| row.get(0)).unwrap().map(Result::unwrap).collect();
+        assert_eq!(millis, [Some(1716026400123), Some(1716026400000), Some(1716026400000), None]);
+    }
+}
@@ -0,0 +1,62 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use log::warn;
+
+// Read a file holding a secret, such as a key or password. On Unix it must
+// not be accessible to group or others: such a file is refused, or with
+// `allow_open` used after a warning. The contents are never logged.
+pub fn load_secret(path: &Path, allow_open: bool) -> io::Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            if !allow_open {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("mode {:o} lets group or others access it; chmod 600 it", mode),
+                ));
+            }
+            warn!("Secret file {} has mode {:o}, which lets group or others access it; chmod 600 it", path.display(), mode);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = allow_open;
+    fs::read_to_string(path)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    // A secret file with `mode`, named after the test writing it
+    fn secret_file(test: &str, mode: u32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("db_receiver-{}-{}", std::process::id(), test));
+        fs::write(&path, "hunter2\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        path
+    }
+
+    #[test]
+    fn private_file_is_read() {
+        let path = secret_file("private", 0o600);
+        assert_eq!(load_secret(&path, false).unwrap(), "hunter2\n");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn open_file_is_refused_unless_allowed() {
+        for mode in [0o640, 0o604, 0o644] {
+            let path = secret_file("open", mode);
+            let error = load_secret(&path, false).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+            assert!(!error.to_string().contains("hunter2"));
+            assert_eq!(load_secret(&path, true).unwrap(), "hunter2\n");
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use clap::Args;
+use log::{debug, error, info, warn};
+
+use crate::batch::SessionTally;
+use crate::peer::Peer;
+use crate::{
+    handle_records, is_read_timeout, parse_message, reject_unparsed, ConnectionState, Message, ParseOptions,
+    ServerState, READ_POLL_INTERVAL,
+};
+
+// Baud rate of a --serial-port given without one
+const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+// Longest wait between attempts to open a port that is missing or failing
+const MAX_REOPEN_DELAY: Duration = Duration::from_secs(60);
+
+// Loggers attached directly to the receiver over a serial line
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "Serial ports")]
+pub struct SerialConfig {
+    /// Also read newline-delimited JSON records from this serial device, e.g. /dev/ttyUSB0:115200; the path is stored as their device_id
+    #[arg(long, value_name = "PATH[:BAUD],...", value_delimiter = ',', value_parser = parse_device)]
+    pub serial_port: Vec<SerialDevice>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialDevice {
+    path: String,
+    baud_rate: u32,
+}
+
+impl fmt::Display for SerialDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.path, self.baud_rate)
+    }
+}
+
+// PATH or PATH:BAUD. Only a number after the last colon is taken for the
+// baud rate, so a path with colons of its own can still be given alone.
+fn parse_device(value: &str) -> Result<SerialDevice, String> {
+    let (path, baud_rate) = match value.rsplit_once(':') {
+        Some((path, baud)) if !baud.is_empty() && baud.bytes().all(|b| b.is_ascii_digit()) => {
+            (path, baud.parse().map_err(|_| format!("bad baud rate '{}'", baud))?)
+        }
+        _ => (value, DEFAULT_BAUD_RATE),
+    };
+    if path.is_empty() {
+        return Err("missing device path".to_string());
+    }
+    if baud_rate == 0 {
+        return Err("baud rate must be greater than 0".to_string());
+    }
+    Ok(SerialDevice { path: path.to_string(), baud_rate })
+}
+
+// The ports as they appear in the effective configuration line
+impl fmt::Display for SerialConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.serial_port.is_empty() {
+            return write!(f, "off");
+        }
+        let ports: Vec<String> = self.serial_port.iter().map(SerialDevice::to_string).collect();
+        write!(f, "{}", ports.join(","))
+    }
+}
+
+// Read each configured port from a thread of its own until the server shuts
+// down or starts draining, storing its lines through the same path as TCP
+// lines. A port that can't be opened, or fails while open, is opened again
+// with growing delays, so a logger can be unplugged and plugged back in.
+pub fn spawn(server: Arc<ServerState>, running: Arc<AtomicBool>, accepting: Arc<AtomicBool>) -> Vec<JoinHandle<()>> {
+    server
+        .config
+        .serial
+        .serial_port
+        .iter()
+        .map(|device| {
+            let (server, running, accepting) = (server.clone(), running.clone(), accepting.clone());
+            // Peers are Copy, so each path is leaked once for the life of the server
+            let path: &'static str = Box::leak(device.path.clone().into_boxed_str());
+            let baud_rate = device.baud_rate;
+            info!("Reading serial port {} at {} baud...", path, baud_rate);
+            thread::spawn(move || {
+                let active = || running.load(Ordering::SeqCst) && accepting.load(Ordering::SeqCst);
+                watch(&server, path, baud_rate, active);
+                info!("Serial port {} closed", path);
+            })
+        })
+        .collect()
+}
+
+fn watch(server: &ServerState, path: &'static str, baud_rate: u32, active: impl Fn() -> bool) {
+    let mut reopen_delay = Duration::from_secs(1);
+    while active() {
+        match serialport::new(path, baud_rate).timeout(READ_POLL_INTERVAL).open() {
+            Ok(port) => {
+                info!("Opened serial port {}", path);
+                reopen_delay = Duration::from_secs(1);
+                match read(server, port, path, &active) {
+                    Ok(()) => continue,
+                    Err(e) => warn!("Serial port {} failed: {}; reopening in {}s", path, e, reopen_delay.as_secs()),
+                }
+            }
+            Err(e) => warn!("Can't open serial port {}: {}; retrying in {}s", path, e, reopen_delay.as_secs()),
+        }
+        let retry_at = Instant::now() + reopen_delay;
+        while active() && Instant::now() < retry_at {
+            thread::sleep(Duration::from_millis(100));
+        }
+        reopen_delay = (reopen_delay * 2).min(MAX_REOPEN_DELAY);
+    }
+}
+
+// Read lines from an open port until the server stops, which returns Ok, or
+// the port fails or reaches its end, as an unplugged device does
+fn read(
+    server: &ServerState,
+    port: Box<dyn serialport::SerialPort>,
+    path: &'static str,
+    active: &impl Fn() -> bool,
+) -> io::Result<()> {
+    let config = &server.config;
+    let addr = Peer::Serial(path);
+    let mut state = ConnectionState::new(config);
+    state.device_id = Some(path.to_string());
+    let tally: Arc<SessionTally> = Arc::default();
+    let mut reader = BufReader::new(port);
+    let mut buffer = Vec::new();
+    // Skipping the rest of a line that grew too long
+    let mut discarding = false;
+
+    while active() {
+        // Read at most one byte past the limit, enough to tell the line is too long
+        let limit = (config.max_line_bytes + 1).saturating_sub(buffer.len()) as u64;
+        match (&mut reader).take(limit).read_until(b'\n', &mut buffer) {
+            Ok(_) if buffer.last() == Some(&b'\n') => {
+                if !std::mem::take(&mut discarding) {
+                    handle_line(server, addr, &mut state, &tally, &buffer);
+                }
+                buffer.clear();
+            }
+            Ok(_) if buffer.len() > config.max_line_bytes => {
+                if !discarding {
+                    warn!("Discarding oversized line from {}: line exceeds {} bytes", addr, config.max_line_bytes);
+                }
+                buffer.clear();
+                discarding = true;
+            }
+            // End of stream, possibly partway through a line
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "device closed")),
+            // Whatever arrived before the timeout stays in the buffer
+            Err(e) if is_read_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Handle a line as a line from a TCP client would be. Nothing is sent back,
+// so control messages are ignored.
+fn handle_line(server: &ServerState, addr: Peer, state: &mut ConnectionState, tally: &Arc<SessionTally>, bytes: &[u8]) {
+    let line = match std::str::from_utf8(bytes) {
+        Ok(line) => line.trim(),
+        Err(e) => {
+            let line = String::from_utf8_lossy(bytes.trim_ascii());
+            reject_unparsed(None, addr, state, server, &line, &format!("line is not valid UTF-8: {}", e));
+            return;
+        }
+    };
+    if line.is_empty() {
+        return;
+    }
+    debug!("Received serial line from {}: {}", addr, line);
+    match parse_message(line, &ParseOptions::from(&server.config)) {
+        Ok(Message::SensorData(rows)) => {
+            if let Err(e) = handle_records(server, None, addr, state, line, rows, tally) {
+                error!("Failed to queue records from {}: {}", addr, e);
+            }
+        }
+        Ok(Message::Keepalive) => {}
+        Ok(message) => debug!("Ignoring control message from serial port {}: {:?}", addr, message),
+        Err(e) => reject_unparsed(None, addr, state, server, line, &e.to_string()),
+    }
+}
@@ -0,0 +1,373 @@
+use dashmap::DashMap;
+use log::{error, info};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::peer::Peer;
+use crate::units::UnitsSystem;
+use crate::writer::Writer;
+
+// Values of sessions.status
+pub const STATUS_OPEN: &str = "open";
+pub const STATUS_ENDED: &str = "ended";
+// Closed by the server when the connection that started it dropped
+pub const STATUS_AUTO_CLOSED: &str = "auto_closed";
+// The handler of the connection that started it panicked
+pub const STATUS_PANIC: &str = "panic";
+
+// Announces the start of a run. The server allocates a sessionID unless the
+// client brings its own, e.g. to continue a session after reconnecting.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionStartMessage {
+    #[serde(rename = "sessionID")]
+    pub session_id: Option<i64>,
+    pub device_id: Option<String>,
+    pub label: Option<String>,
+    // Close the session if the connection drops before session_end
+    #[serde(default)]
+    pub auto_close: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionEndMessage {
+    #[serde(rename = "sessionID")]
+    pub session_id: i64,
+}
+
+// Settings for one session's records on this connection
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SessionConfigMessage {
+    #[serde(rename = "sessionID")]
+    pub session_id: i64,
+    // Low-pass filter factor for the accelerometer, between 0 and 1; null
+    // goes back to the one from --lowpass-cutoff-hz
+    pub lowpass_alpha: Option<f64>,
+}
+
+// Reply to session_start carrying the session's ID
+#[derive(Serialize, Debug)]
+pub struct SessionStarted {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    #[serde(rename = "sessionID")]
+    session_id: i64,
+}
+
+impl SessionStarted {
+    pub fn new(session_id: i64) -> Self {
+        SessionStarted { message_type: "session_started", session_id }
+    }
+}
+
+// Totals computed when a session is closed, stored on its row and sent back
+// in reply to session_end
+#[derive(Serialize, Debug)]
+pub struct SessionSummary {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    #[serde(rename = "sessionID")]
+    pub session_id: i64,
+    pub record_count: i64,
+    pub first_timestamp: Option<String>,
+    pub last_timestamp: Option<String>,
+    // Lowest battery voltage and highest temperature reported, if any were
+    pub min_battery_v: Option<f64>,
+    pub max_temperature_c: Option<f64>,
+    // Records flagged as outliers, and their share of the session's records
+    pub outlier_count: i64,
+    pub outlier_rate: Option<f64>,
+    // Rows made up to fill gaps between records
+    pub interpolated_count: i64,
+    // Largest and mean acceleration magnitude, over records with all three axes
+    pub max_accel_magnitude: Option<f64>,
+    pub avg_accel_magnitude: Option<f64>,
+    // Angles integrated from the gyroscope up to the session's last record
+    pub final_pitch: Option<f64>,
+    pub final_roll: Option<f64>,
+    pub final_yaw: Option<f64>,
+    // Share of records with all three accelerometer axes whose tilt estimate
+    // was taken while still enough to be valid
+    pub pitch_roll_valid_fraction: Option<f64>,
+    // Records whose position lay outside the geo fence
+    pub outside_fence_rows_total: i64,
+    // Metres of GPS track covered over the session
+    pub total_distance_m: Option<f64>,
+}
+
+// Trailer sent after the last record of an upload, so the server can confirm
+// everything arrived
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadCompleteMessage {
+    #[serde(rename = "sessionID")]
+    pub session_id: i64,
+    pub expected_count: i64,
+    // "session" (default) counts every stored row of the session, "connection"
+    // only those stored through this connection
+    #[serde(default)]
+    pub scope: UploadScope,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadScope {
+    #[default]
+    Session,
+    Connection,
+}
+
+// Reply to upload_complete
+#[derive(Serialize, Debug, Clone)]
+pub struct UploadStatus {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    #[serde(rename = "sessionID")]
+    pub session_id: i64,
+    pub status: &'static str,
+    pub expected: i64,
+    pub stored: i64,
+}
+
+impl UploadStatus {
+    pub fn new(session_id: i64, expected: i64, stored: i64) -> Self {
+        UploadStatus {
+            message_type: "upload_status",
+            session_id,
+            status: if stored == expected { "ok" } else { "mismatch" },
+            expected,
+            stored,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.stored == self.expected
+    }
+}
+
+// Asks where a session's stored records end, so a client that lost its
+// connection can carry on from there
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResumeInfoMessage {
+    #[serde(rename = "sessionID", alias = "session_id")]
+    pub session_id: i64,
+}
+
+// Reply to resume_info. Both fields are null for a session with no stored
+// records.
+#[derive(Serialize, Debug)]
+pub struct ResumePosition {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    #[serde(rename = "sessionID")]
+    pub session_id: i64,
+    pub max_id: Option<i64>,
+    // Timestamp of the row with max_id, as the client sent it
+    pub last_timestamp: Option<String>,
+}
+
+// A session started on a connection that is still live
+#[derive(Debug, Clone, Copy)]
+pub struct OpenSession {
+    pub addr: Peer,
+    pub auto_close: bool,
+}
+
+// Sessions started by live connections, so they can be closed or marked when
+// their connection goes away. The map is sharded, so client threads touching
+// different sessions don't wait on each other.
+pub type OpenSessions = DashMap<i64, OpenSession>;
+
+fn now() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+// Create the session row, or reopen it if the client supplied an ID that
+// already exists. Returns the session's ID. `units` is the system its records
+// are stored in.
+pub fn start_session(
+    conn: &Connection,
+    start: &SessionStartMessage,
+    device_id: Option<&str>,
+    units: UnitsSystem,
+) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT INTO sessions (sessionID, device_id, label, started_at, status, units_system)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(sessionID) DO UPDATE SET status = excluded.status, ended_at = NULL, units_system = excluded.units_system",
+        params![start.session_id, device_id, start.label, now(), STATUS_OPEN, units.name()],
+    )?;
+    match start.session_id {
+        Some(id) => Ok(id),
+        None => Ok(conn.last_insert_rowid()),
+    }
+}
+
+// Record the end of a session with the given status and compute its summary.
+// Returns None if there is no such session.
+pub fn end_session(
+    conn: &Connection,
+    session_id: i64,
+    status: &str,
+) -> rusqlite::Result<Option<SessionSummary>> {
+    let updated = conn.execute(
+        "UPDATE sessions SET
+            ended_at = ?2,
+            status = ?3,
+            record_count = (SELECT COUNT(*) FROM sensor_data WHERE sessionID = ?1),
+            first_timestamp = (SELECT MIN(timestamp) FROM sensor_data WHERE sessionID = ?1),
+            last_timestamp = (SELECT MAX(timestamp) FROM sensor_data WHERE sessionID = ?1),
+            min_battery_v = (SELECT MIN(battery_v) FROM sensor_data WHERE sessionID = ?1),
+            max_temperature_c = (SELECT MAX(temperature_c) FROM sensor_data WHERE sessionID = ?1),
+            outlier_count = (SELECT COUNT(*) FROM sensor_data WHERE sessionID = ?1 AND is_outlier),
+            interpolated_count = (SELECT COUNT(*) FROM sensor_data WHERE sessionID = ?1 AND is_interpolated),
+            max_accel_magnitude = (SELECT MAX(accel_magnitude) FROM sensor_data WHERE sessionID = ?1),
+            avg_accel_magnitude = (SELECT AVG(accel_magnitude) FROM sensor_data WHERE sessionID = ?1),
+            (final_pitch, final_roll, final_yaw) = (
+                SELECT cumulative_pitch, cumulative_roll, cumulative_yaw FROM sensor_data
+                WHERE sessionID = ?1 AND cumulative_pitch IS NOT NULL ORDER BY id DESC LIMIT 1
+            ),
+            pitch_roll_valid_fraction = (SELECT AVG(pitch_roll_valid) FROM sensor_data WHERE sessionID = ?1),
+            outside_fence_rows_total = (SELECT COUNT(*) FROM sensor_data WHERE sessionID = ?1 AND outside_fence),
+            total_distance_m = (
+                SELECT cumulative_distance_m FROM sensor_data
+                WHERE sessionID = ?1 AND cumulative_distance_m IS NOT NULL ORDER BY id DESC LIMIT 1
+            )
+         WHERE sessionID = ?1",
+        params![session_id, now(), status],
+    )?;
+    if updated == 0 {
+        return Ok(None);
+    }
+    conn.query_row(
+        "SELECT record_count, first_timestamp, last_timestamp, min_battery_v, max_temperature_c, outlier_count,
+                max_accel_magnitude, avg_accel_magnitude, final_pitch, final_roll, final_yaw, pitch_roll_valid_fraction,
+                outside_fence_rows_total, total_distance_m, interpolated_count
+         FROM sessions WHERE sessionID = ?",
+        [session_id],
+        |row| {
+            let record_count: i64 = row.get(0)?;
+            let outlier_count: i64 = row.get(5)?;
+            Ok(SessionSummary {
+                message_type: "session_ended",
+                session_id,
+                record_count,
+                first_timestamp: row.get(1)?,
+                last_timestamp: row.get(2)?,
+                min_battery_v: row.get(3)?,
+                max_temperature_c: row.get(4)?,
+                outlier_count,
+                outlier_rate: (record_count > 0).then(|| outlier_count as f64 / record_count as f64),
+                max_accel_magnitude: row.get(6)?,
+                avg_accel_magnitude: row.get(7)?,
+                final_pitch: row.get(8)?,
+                final_roll: row.get(9)?,
+                final_yaw: row.get(10)?,
+                pitch_roll_valid_fraction: row.get(11)?,
+                outside_fence_rows_total: row.get(12)?,
+                total_distance_m: row.get(13)?,
+                interpolated_count: row.get(14)?,
+            })
+        },
+    )
+    .optional()
+}
+
+// Rows stored for a session, counting identical rows once so a record the
+// client sent again (e.g. after a reconnect) doesn't show up as a surplus.
+// Interpolated rows were never sent, so they don't count.
+// Smoothed IMU values depend on the records before them, so rows are compared
+// by the values as received where those were kept. Encrypted coordinates
+// differ every time they are stored, so they aren't compared.
+pub fn count_stored(conn: &Connection, session_id: i64, gps_encrypted: bool) -> rusqlite::Result<i64> {
+    let position = if gps_encrypted { "altitude" } else { "latitude, longitude, altitude" };
+    let sql = format!(
+        "SELECT COUNT(*) FROM (
+            SELECT DISTINCT timestamp, {},
+                COALESCE(accel_x_raw, accel_x), COALESCE(accel_y_raw, accel_y), COALESCE(accel_z_raw, accel_z),
+                COALESCE(gyro_x_raw, gyro_x), COALESCE(gyro_y_raw, gyro_y), COALESCE(gyro_z_raw, gyro_z),
+                mag_x, mag_y, mag_z, temperature_c, battery_v, fix_quality, num_satellites, hdop,
+                dac_1, dac_2, dac_3, dac_4, dac_5, dac_6, dac_7, dac_8,
+                dac_9, dac_10, dac_11, dac_12, dac_13, dac_14, dac_15, dac_16, device_id
+            FROM sensor_data WHERE sessionID = ? AND NOT is_interpolated
+        )",
+        position
+    );
+    conn.query_row(&sql, [session_id], |row| row.get(0))
+}
+
+// The highest row id stored for a session and that row's timestamp. The
+// aggregate always yields one row, all NULL when nothing matches; SQLite takes
+// the bare timestamp from the row that holds the maximum.
+pub fn resume_position(conn: &Connection, session_id: i64) -> rusqlite::Result<ResumePosition> {
+    conn.query_row(
+        "SELECT MAX(id), timestamp FROM sensor_data WHERE sessionID = ?1",
+        [session_id],
+        |row| {
+            Ok(ResumePosition {
+                message_type: "resume_position",
+                session_id,
+                max_id: row.get(0)?,
+                last_timestamp: row.get(1)?,
+            })
+        },
+    )
+}
+
+// Keep the outcome of an upload check on the session's row for later audits,
+// creating the row if the client never sent session_start
+pub fn record_upload_status(conn: &Connection, status: &UploadStatus) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO sessions (sessionID, status, expected_count, verified_count, upload_status, verified_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(sessionID) DO UPDATE SET
+            expected_count = excluded.expected_count,
+            verified_count = excluded.verified_count,
+            upload_status = excluded.upload_status,
+            verified_at = excluded.verified_at",
+        params![status.session_id, STATUS_OPEN, status.expected, status.stored, status.status, now()],
+    )?;
+    Ok(())
+}
+
+// Deal with the sessions a connection started when it goes away. Sessions
+// that asked for it are closed, the rest stay open for a later connection to
+// end. After a panic every one of them is marked instead, since the client
+// never got to end them.
+pub fn release_sessions(
+    writer: &Writer,
+    sessions: &OpenSessions,
+    addr: Peer,
+    panicked: bool,
+) {
+    let mut released = Vec::new();
+    sessions.retain(|&id, session| {
+        if session.addr == addr {
+            released.push((id, *session));
+            false
+        } else {
+            true
+        }
+    });
+
+    for (session_id, session) in released {
+        let status = match (panicked, session.auto_close) {
+            (true, _) => STATUS_PANIC,
+            (false, true) => STATUS_AUTO_CLOSED,
+            (false, false) => continue,
+        };
+        let result = writer.call(move |conn| {
+            if panicked {
+                conn.execute(
+                    "UPDATE sessions SET status = ? WHERE sessionID = ?",
+                    params![status, session_id],
+                )
+                .map(|_| ())
+            } else {
+                end_session(conn, session_id, status).map(|_| ())
+            }
+        });
+        match result {
+            Ok(()) => info!("Session {} marked {} after {} disconnected", session_id, status, addr),
+            Err(e) => error!("Failed to mark session {} as {}: {}", session_id, status, e),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use serde::{de, Deserialize, Deserializer};
+
+// sessionID as firmware sends it: a JSON number of any integer size, or the
+// same number as a string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawSessionId {
+    Number(i64),
+    Text(String),
+}
+
+// Read a record's sessionID, widening it to i64. Null and a missing field
+// (with `#[serde(default)]`) give None; anything that isn't an integer fails.
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<i64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<RawSessionId>::deserialize(deserializer) {
+        Ok(None) => Ok(None),
+        Ok(Some(RawSessionId::Number(id))) => Ok(Some(id)),
+        Ok(Some(RawSessionId::Text(text))) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| de::Error::custom(format!("sessionID '{}' is not an integer", text))),
+        Err(_) => Err(de::Error::custom("sessionID must be an integer or a string holding one")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Record {
+        #[serde(rename = "sessionID", default, deserialize_with = "super::deserialize")]
+        session_id: Option<i64>,
+    }
+
+    fn session_id(json: &str) -> Result<Option<i64>, serde_json::Error> {
+        serde_json::from_str::<Record>(json).map(|record| record.session_id)
+    }
+
+    #[test]
+    fn string_holding_an_integer() {
+        assert_eq!(session_id(r#"{"sessionID":"42"}"#).unwrap(), Some(42));
+    }
+
+    #[test]
+    fn number_beyond_i32() {
+        assert_eq!(session_id(r#"{"sessionID":5000000000}"#).unwrap(), Some(5_000_000_000));
+    }
+
+    #[test]
+    fn missing_or_null() {
+        assert_eq!(session_id("{}").unwrap(), None);
+        assert_eq!(session_id(r#"{"sessionID":null}"#).unwrap(), None);
+    }
+
+    #[test]
+    fn not_an_integer() {
+        assert!(session_id(r#"{"sessionID":"abc"}"#).is_err());
+        assert!(session_id(r#"{"sessionID":1.5}"#).is_err());
+    }
+}
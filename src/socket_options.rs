@@ -0,0 +1,84 @@
+use std::fmt;
+use std::net::TcpStream;
+use std::time::Duration;
+use clap::{ArgAction, Args};
+use log::{info, warn};
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::peer::Peer;
+
+// Socket options set on every TCP connection the listeners and the WebSocket
+// port accept
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "TCP connections")]
+pub struct TcpOptions {
+    /// Send small replies at once instead of letting Nagle's algorithm hold them back (true or false)
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = ArgAction::Set)]
+    pub tcp_nodelay: bool,
+
+    /// Have the kernel probe a connection idle this long, closing it once a peer stops answering
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub tcp_keepalive_idle_secs: Option<u64>,
+
+    /// Time between keepalive probes
+    #[arg(long, value_name = "SECS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub tcp_keepalive_interval_secs: u64,
+
+    /// Unanswered keepalive probes after which the connection is dropped
+    #[arg(long, value_name = "N", default_value_t = 6, value_parser = clap::value_parser!(u32).range(1..))]
+    pub tcp_keepalive_count: u32,
+}
+
+// The options as they appear in the effective configuration line
+impl fmt::Display for TcpOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nodelay={}", self.tcp_nodelay)?;
+        match self.tcp_keepalive_idle_secs {
+            Some(idle) => write!(
+                f,
+                ",keepalive={}s/{}s/{}",
+                idle, self.tcp_keepalive_interval_secs, self.tcp_keepalive_count
+            ),
+            None => write!(f, ",keepalive=off"),
+        }
+    }
+}
+
+impl TcpOptions {
+    // Say once what accepted connections will get
+    pub fn log(&self) {
+        match self.tcp_keepalive_idle_secs {
+            Some(idle) => info!(
+                "TCP connections: TCP_NODELAY {}, keepalive probes after {}s idle, every {}s, dropped after {} unanswered",
+                if self.tcp_nodelay { "on" } else { "off" },
+                idle,
+                self.tcp_keepalive_interval_secs,
+                self.tcp_keepalive_count
+            ),
+            None => info!(
+                "TCP connections: TCP_NODELAY {}, no keepalive probes",
+                if self.tcp_nodelay { "on" } else { "off" }
+            ),
+        }
+    }
+
+    // Set the options on an accepted connection. One the platform refuses is
+    // logged and left at its default; the connection is served either way.
+    pub fn apply(&self, stream: &TcpStream, addr: Peer) {
+        let socket = SockRef::from(stream);
+        if let Err(e) = socket.set_tcp_nodelay(self.tcp_nodelay) {
+            warn!("Can't set TCP_NODELAY on the connection from {}: {}", addr, e);
+        }
+        let Some(idle) = self.tcp_keepalive_idle_secs else {
+            return;
+        };
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(idle));
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd", target_os = "netbsd", target_os = "windows"))]
+        let keepalive = keepalive
+            .with_interval(Duration::from_secs(self.tcp_keepalive_interval_secs))
+            .with_retries(self.tcp_keepalive_count);
+        if let Err(e) = socket.set_tcp_keepalive(&keepalive) {
+            warn!("Can't set TCP keepalive on the connection from {}: {}", addr, e);
+        }
+    }
+}
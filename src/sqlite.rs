@@ -0,0 +1,132 @@
+use std::fmt;
+use std::path::Path;
+use clap::{Args, ValueEnum};
+use log::{debug, warn};
+use rusqlite::Connection;
+
+// Rows inserted across the server between runs of PRAGMA optimize
+pub const OPTIMIZE_INTERVAL_ROWS: u64 = 10_000;
+
+// PRAGMA tuning applied to every database connection the server opens
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "SQLite")]
+pub struct SqliteConfig {
+    /// How hard SQLite works to keep committed records through a crash or power loss
+    #[arg(long, value_enum, default_value_t = Durability::Balanced, global = true)]
+    pub durability: Durability,
+
+    /// Page size for a new database file, a power of two from 512 to 65536; existing files keep theirs
+    #[arg(long = "sqlite-page-size-bytes", value_name = "BYTES", value_parser = parse_page_size, global = true)]
+    pub page_size_bytes: Option<u32>,
+
+    /// Page cache per connection, passed to PRAGMA cache_size: negative is KiB (-65536 = 64 MiB), positive is pages
+    #[arg(long = "sqlite-cache-size-kb", value_name = "N", default_value_t = -65536, allow_negative_numbers = true, global = true)]
+    pub cache_size_kb: i64,
+
+    /// Memory-mapped I/O per connection; 0 turns it off
+    #[arg(long = "sqlite-mmap-size-mb", value_name = "MB", default_value_t = 0, global = true)]
+    pub mmap_size_mb: u64,
+
+    /// Pages the journal may reach in WAL mode before SQLite checkpoints it; 0 turns that off
+    #[arg(long = "sqlite-wal-autocheckpoint-pages", value_name = "PAGES", default_value_t = 1000, global = true)]
+    pub wal_autocheckpoint_pages: u32,
+
+    /// Where SQLite keeps temporary tables and indices
+    #[arg(long = "sqlite-temp-store", value_enum, default_value_t = TempStore::Memory, global = true)]
+    pub temp_store: TempStore,
+}
+
+// Journal and sync settings, from safest to fastest
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// synchronous=FULL: every commit reaches the disk before it returns
+    Safe,
+    /// journal_mode=WAL with synchronous=NORMAL: a power loss can undo the last commits but never corrupts the database
+    Balanced,
+    /// synchronous=OFF: commits don't wait for the disk; a crash of the machine can lose data or corrupt the database
+    Fast,
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::Safe => write!(f, "safe"),
+            Durability::Balanced => write!(f, "balanced"),
+            Durability::Fast => write!(f, "fast"),
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[value(rename_all = "UPPER")]
+pub enum TempStore {
+    Default,
+    File,
+    Memory,
+}
+
+impl fmt::Display for TempStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TempStore::Default => write!(f, "DEFAULT"),
+            TempStore::File => write!(f, "FILE"),
+            TempStore::Memory => write!(f, "MEMORY"),
+        }
+    }
+}
+
+fn parse_page_size(value: &str) -> Result<u32, String> {
+    let size: u32 = value.parse().map_err(|e: std::num::ParseIntError| e.to_string())?;
+    if size.is_power_of_two() && (512..=65536).contains(&size) {
+        Ok(size)
+    } else {
+        Err("must be a power of two from 512 to 65536".to_string())
+    }
+}
+
+// Open the database with the configured PRAGMAs applied. Applying them reads
+// the schema, which fails while another process holds an exclusive lock; the
+// connection is still usable then, just untuned, so that is only logged.
+pub fn open(path: impl AsRef<Path>, config: &SqliteConfig) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    if let Err(e) = configure_connection(&conn, config) {
+        warn!("Could not apply SQLite settings, using SQLite's defaults on this connection: {}", e);
+    }
+    Ok(conn)
+}
+
+pub fn configure_connection(conn: &Connection, config: &SqliteConfig) -> rusqlite::Result<()> {
+    // page_size has to come before WAL mode, which fixes it for the file
+    if let Some(page_size) = config.page_size_bytes {
+        conn.pragma_update(None, "page_size", page_size)?;
+    }
+    match config.durability {
+        Durability::Safe => conn.pragma_update(None, "synchronous", "FULL")?,
+        Durability::Balanced => {
+            // SQLite reports the journal mode it switched to as a row
+            conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+            conn.pragma_update(None, "synchronous", "NORMAL")?;
+        }
+        Durability::Fast => conn.pragma_update(None, "synchronous", "OFF")?,
+    }
+    conn.pragma_update(None, "cache_size", config.cache_size_kb)?;
+    // SQLite reports the resulting size as a row, which pragma_update rejects
+    let mmap_bytes = config.mmap_size_mb.saturating_mul(1024 * 1024);
+    conn.query_row(&format!("PRAGMA mmap_size = {}", mmap_bytes), [], |_| Ok(()))?;
+    conn.query_row(
+        &format!("PRAGMA wal_autocheckpoint = {}", config.wal_autocheckpoint_pages),
+        [],
+        |_| Ok(()),
+    )?;
+    conn.pragma_update(None, "temp_store", config.temp_store.to_string())?;
+    Ok(())
+}
+
+// Let SQLite refresh the statistics its query planner relies on. Only worth
+// doing now and then, and harmless to skip if it fails.
+pub fn optimize(conn: &Connection) {
+    match conn.execute_batch("PRAGMA optimize") {
+        Ok(()) => debug!("Ran PRAGMA optimize"),
+        Err(e) => warn!("PRAGMA optimize failed: {}", e),
+    }
+}
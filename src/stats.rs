@@ -0,0 +1,207 @@
+use std::collections::BTreeMap;
+use dashmap::DashMap;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::batch::PendingRecord;
+use crate::encryption::ENCRYPTED_COLUMNS;
+use crate::SensorData;
+
+// Running mean and variance of one field by Welford's online algorithm,
+// which stays accurate without keeping the values
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RunningStats {
+    n: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl RunningStats {
+    pub fn count(&self) -> u64 {
+        self.n
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.n > 0).then_some(self.mean)
+    }
+
+    // Sample standard deviation of the values so far
+    pub fn std_dev(&self) -> Option<f64> {
+        (self.n >= 2).then(|| (self.m2 / (self.n - 1) as f64).sqrt())
+    }
+
+    pub fn push(&mut self, value: f64) {
+        self.n += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+}
+
+// What a snapshot holds for one field
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct FieldStats {
+    pub count: u64,
+    pub mean: Option<f64>,
+    pub stddev: Option<f64>,
+}
+
+impl From<&RunningStats> for FieldStats {
+    fn from(stats: &RunningStats) -> Self {
+        FieldStats { count: stats.n, mean: stats.mean(), stddev: stats.std_dev() }
+    }
+}
+
+// The same statistics picked up again, e.g. when more records of a session
+// arrive after its snapshot was saved. The sum of squares comes back from the
+// standard deviation.
+impl From<&FieldStats> for RunningStats {
+    fn from(stats: &FieldStats) -> Self {
+        let n = stats.count;
+        let m2 = stats.stddev.unwrap_or(0.0).powi(2) * n.saturating_sub(1) as f64;
+        RunningStats { n, mean: stats.mean.unwrap_or(0.0), m2 }
+    }
+}
+
+// Statistics of each sensor field over a session's stored rows, keyed by
+// column name. A field only shows up once a row carried it.
+pub type Snapshot = BTreeMap<String, FieldStats>;
+
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    fields: BTreeMap<String, RunningStats>,
+}
+
+impl SessionStats {
+    // Add a stored row. Coordinates stored encrypted are left out, since
+    // their mean would give the track away.
+    pub fn push(&mut self, data: &SensorData, gps_encrypted: bool) {
+        for (name, value) in data.fields() {
+            let Some(value) = value else {
+                continue;
+            };
+            if gps_encrypted && ENCRYPTED_COLUMNS.contains(&name) {
+                continue;
+            }
+            match self.fields.get_mut(name) {
+                Some(stats) => stats.push(value),
+                None => {
+                    let mut stats = RunningStats::default();
+                    stats.push(value);
+                    self.fields.insert(name.to_string(), stats);
+                }
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.fields.iter().map(|(name, stats)| (name.clone(), FieldStats::from(stats))).collect()
+    }
+
+    fn restore(snapshot: &Snapshot) -> Self {
+        SessionStats { fields: snapshot.iter().map(|(name, stats)| (name.clone(), RunningStats::from(stats))).collect() }
+    }
+}
+
+// Statistics of the sessions whose records are being stored, for
+// GET /sessions/<id>/stats/live. Only the writer thread changes them.
+pub type LiveStats = DashMap<i64, SessionStats>;
+
+// Add the rows of a commit to their sessions' statistics. Duplicates that
+// weren't stored and interpolated rows, which were never measured, are left
+// out. A session missing from `live` starts from its saved snapshot, so one
+// whose connection came back carries on where it left off.
+pub fn update(
+    conn: &Connection,
+    live: &LiveStats,
+    records: &[PendingRecord],
+    row_ids: &[Option<i64>],
+    gps_encrypted: bool,
+) {
+    for (record, row_id) in records.iter().zip(row_ids) {
+        let (Some(session_id), Some(_)) = (record.data.session_id, row_id) else {
+            continue;
+        };
+        if record.data.is_interpolated {
+            continue;
+        }
+        live.entry(session_id)
+            .or_insert_with(|| match final_snapshot(conn, session_id) {
+                Ok(Some(snapshot)) => SessionStats::restore(&snapshot),
+                _ => SessionStats::default(),
+            })
+            .push(&record.data, gps_encrypted);
+    }
+}
+
+// Store a session's statistics on its row, creating the row if the client
+// never sent session_start, and stop keeping them live. Nothing is written
+// for a session without live statistics, so an earlier snapshot stays.
+pub fn save_final(conn: &Connection, live: &LiveStats, session_id: i64) -> rusqlite::Result<()> {
+    let Some((_, stats)) = live.remove(&session_id) else {
+        return Ok(());
+    };
+    let snapshot = serde_json::to_string(&stats.snapshot()).expect("statistics serialize to JSON");
+    conn.execute(
+        "INSERT INTO sessions (sessionID, status, final_stats) VALUES (?1, ?2, ?3)
+         ON CONFLICT(sessionID) DO UPDATE SET final_stats = excluded.final_stats",
+        params![session_id, crate::session::STATUS_OPEN, snapshot],
+    )?;
+    Ok(())
+}
+
+// The statistics last saved for a session, or None if it has none
+pub fn final_snapshot(conn: &Connection, session_id: i64) -> rusqlite::Result<Option<Snapshot>> {
+    let stored: Option<String> = conn
+        .query_row("SELECT final_stats FROM sessions WHERE sessionID = ?", [session_id], |row| row.get(0))
+        .optional()?
+        .flatten();
+    Ok(stored.and_then(|json| serde_json::from_str(&json).ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_of(values: &[f64]) -> RunningStats {
+        let mut stats = RunningStats::default();
+        values.iter().for_each(|&value| stats.push(value));
+        stats
+    }
+
+    #[test]
+    fn running_mean_and_sample_std_dev() {
+        let stats = stats_of(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+        assert_eq!(stats.count(), 8);
+        assert_eq!(stats.mean(), Some(5.0));
+        assert!((stats.std_dev().unwrap() - (32.0f64 / 7.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn too_few_values() {
+        assert_eq!(stats_of(&[]).mean(), None);
+        assert_eq!(stats_of(&[3.0]).mean(), Some(3.0));
+        assert_eq!(stats_of(&[3.0]).std_dev(), None);
+    }
+
+    // Welford's update doesn't lose the spread to a large offset the way
+    // the sum of squares would
+    #[test]
+    fn large_offset_keeps_precision() {
+        let stats = stats_of(&[1e9 + 4.0, 1e9 + 7.0, 1e9 + 13.0, 1e9 + 16.0]);
+        assert_eq!(stats.mean(), Some(1e9 + 10.0));
+        assert!((stats.std_dev().unwrap() - 30.0f64.sqrt()).abs() < 1e-6);
+    }
+
+    // Picked up again from a snapshot, the statistics carry on as if never
+    // interrupted
+    #[test]
+    fn restored_from_snapshot() {
+        let mut restored = RunningStats::from(&FieldStats::from(&stats_of(&[1.0, 2.0, 3.0])));
+        restored.push(10.0);
+        let whole = stats_of(&[1.0, 2.0, 3.0, 10.0]);
+        assert_eq!(restored.count(), whole.count());
+        assert!((restored.mean().unwrap() - whole.mean().unwrap()).abs() < 1e-12);
+        assert!((restored.std_dev().unwrap() - whole.std_dev().unwrap()).abs() < 1e-12);
+    }
+}
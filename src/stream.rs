@@ -0,0 +1,86 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::websocket::WsStream;
+
+// A client connection, over TCP, a Unix domain socket or WebSocket. All of
+// them carry the same protocol, so the handlers don't need to know which it is.
+#[derive(Debug)]
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    WebSocket(WsStream),
+}
+
+impl ClientStream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            ClientStream::Tcp(stream) => stream.try_clone().map(ClientStream::Tcp),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.try_clone().map(ClientStream::Unix),
+            ClientStream::WebSocket(stream) => stream.try_clone().map(ClientStream::WebSocket),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            ClientStream::WebSocket(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.set_read_timeout(timeout),
+            ClientStream::WebSocket(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.shutdown(how),
+            ClientStream::WebSocket(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.read(buf),
+            ClientStream::WebSocket(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.write(buf),
+            ClientStream::WebSocket(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.flush(),
+            ClientStream::WebSocket(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::io::{self, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::batch::PendingRecord;
+use crate::metrics::Metrics;
+use crate::peer::Peer;
+use crate::stream::ClientStream;
+
+// Records a subscriber may fall behind by before further ones are dropped
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+// Shortest gap between two lag warnings about one subscriber
+const LAG_WARNING_INTERVAL: Duration = Duration::from_secs(5);
+
+// Asks for every record stored from now on, or only those of one session
+#[derive(Deserialize, Debug)]
+pub struct SubscribeMessage {
+    #[serde(rename = "session_id", alias = "sessionID")]
+    pub session_id: Option<i64>,
+}
+
+// Reply to subscribe
+#[derive(Serialize, Debug)]
+pub struct Subscribed {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    session_id: Option<i64>,
+}
+
+impl Subscribed {
+    pub fn new(session_id: Option<i64>) -> Self {
+        Subscribed { message_type: "subscribed", session_id }
+    }
+}
+
+// Sent ahead of the next record when records were dropped because the
+// subscriber fell behind
+#[derive(Serialize, Debug)]
+struct SubscriptionLag {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    dropped: u64,
+}
+
+// The write half of a client connection. The connection's handler and its
+// subscription both write to it, a whole line at a time, so their lines
+// never interleave.
+#[derive(Clone)]
+pub struct ClientWriter {
+    stream: Arc<Mutex<ClientStream>>,
+    // For shutting the socket down without waiting for a write in progress
+    socket: Arc<ClientStream>,
+}
+
+impl ClientWriter {
+    pub fn new(stream: ClientStream) -> io::Result<Self> {
+        let socket = Arc::new(stream.try_clone()?);
+        Ok(ClientWriter { stream: Arc::new(Mutex::new(stream)), socket })
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.shutdown(how)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ClientStream> {
+        self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Write for ClientWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.lock().write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().flush()
+    }
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    id: u64,
+    addr: Peer,
+    session_id: Option<i64>,
+    sender: SyncSender<Arc<str>>,
+    // Records dropped since the subscriber was last told
+    dropped: Arc<AtomicU64>,
+    last_warning: Option<Instant>,
+}
+
+// Live consumers of newly stored records. The writer thread publishes each
+// commit; every subscriber has a bounded queue drained by a thread of its
+// own, so a slow one loses records instead of holding up the writer.
+#[derive(Debug, Default)]
+pub struct Subscribers {
+    next_id: AtomicU64,
+    list: Mutex<Vec<Subscriber>>,
+}
+
+// A connection's subscription, ended when dropped
+#[derive(Debug)]
+pub struct Subscription {
+    id: u64,
+    subscribers: Arc<Subscribers>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut list = self.subscribers.list.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(i) = list.iter().position(|subscriber| subscriber.id == self.id) {
+            let subscriber = list.remove(i);
+            info!("Client {} unsubscribed from {}", subscriber.addr, describe(subscriber.session_id));
+        }
+    }
+}
+
+impl Subscribers {
+    // Start pushing stored records to `writer` from a thread of their own
+    pub fn subscribe(
+        self: &Arc<Self>,
+        addr: Peer,
+        session_id: Option<i64>,
+        mut writer: ClientWriter,
+    ) -> Subscription {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let lag = dropped.clone();
+        thread::spawn(move || forward(&receiver, &lag, &mut writer, addr));
+        self.list.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(Subscriber {
+            id,
+            addr,
+            session_id,
+            sender,
+            dropped,
+            last_warning: None,
+        });
+        info!("Client {} subscribed to {}", addr, describe(session_id));
+        Subscription { id, subscribers: self.clone() }
+    }
+
+    // Hand freshly committed records to the subscribers they match. Records
+    // skipped as duplicates (no row ID) were stored before and aren't sent.
+    pub fn publish(&self, records: &[PendingRecord], row_ids: &[Option<i64>], metrics: &Metrics) {
+        let mut list = self.list.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if list.is_empty() {
+            return;
+        }
+        for (record, row_id) in records.iter().zip(row_ids) {
+            let Some(row_id) = row_id else {
+                continue;
+            };
+            if !list.iter().any(|subscriber| subscriber.wants(record)) {
+                continue;
+            }
+            let Some(line) = record_line(record, *row_id) else {
+                continue;
+            };
+            list.retain_mut(|subscriber| {
+                if !subscriber.wants(record) {
+                    return true;
+                }
+                match subscriber.sender.try_send(line.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        subscriber.lagged();
+                        metrics.subscriber_dropped_records.fetch_add(1, Ordering::Relaxed);
+                        true
+                    }
+                    // Its thread stopped after the client went away
+                    Err(TrySendError::Disconnected(_)) => false,
+                }
+            });
+        }
+    }
+}
+
+impl Subscriber {
+    fn wants(&self, record: &PendingRecord) -> bool {
+        self.session_id.is_none() || self.session_id == record.data.session_id
+    }
+
+    fn lagged(&mut self) {
+        let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.last_warning.is_none_or(|at| at.elapsed() >= LAG_WARNING_INTERVAL) {
+            warn!(
+                "Subscriber {} is more than {} records behind; {} record(s) dropped since its last lag notice",
+                self.addr, SUBSCRIBER_BUFFER, dropped
+            );
+            self.last_warning = Some(Instant::now());
+        }
+    }
+}
+
+fn describe(session_id: Option<i64>) -> String {
+    match session_id {
+        Some(id) => format!("session {}", id),
+        None => "all sessions".to_string(),
+    }
+}
+
+// A stored record as pushed to subscribers: its row ID and the record as
+// archived, with a type so it can't be mistaken for a reply
+fn record_line(record: &PendingRecord, row_id: i64) -> Option<Arc<str>> {
+    let mut value = serde_json::to_value(record).ok()?;
+    let object = value.as_object_mut()?;
+    object.insert("type".to_string(), "record".into());
+    object.insert("id".to_string(), row_id.into());
+    let mut line = serde_json::to_string(&value).ok()?;
+    line.push('\n');
+    Some(line.into())
+}
+
+// Write queued records to the subscriber until it goes away or unsubscribes
+fn forward(receiver: &Receiver<Arc<str>>, lag: &AtomicU64, writer: &mut ClientWriter, addr: Peer) {
+    for line in receiver {
+        let dropped = lag.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let notice = SubscriptionLag { message_type: "subscription_lag", dropped };
+            let mut notice = serde_json::to_string(&notice).unwrap_or_default();
+            notice.push('\n');
+            if writer.write_all(notice.as_bytes()).is_err() {
+                break;
+            }
+        }
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            debug!("Stopped pushing records to {}: {}", addr, e);
+            break;
+        }
+    }
+}
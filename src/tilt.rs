@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+use crate::units::accel_ms2_to_g;
+use crate::SensorData;
+
+// How far the acceleration may stray from 1 g before the tilt estimate is
+// marked invalid
+const MAX_DEVIATION_G: f64 = 0.3;
+
+// Pitch and roll from the direction of gravity alone. Only meaningful while
+// the logger is roughly still: any other acceleration tilts the estimate, so
+// `valid` says whether the acceleration was close enough to 1 g to trust it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Tilt {
+    pub pitch_rad: f64,
+    pub roll_rad: f64,
+    pub valid: bool,
+}
+
+// Pitch and roll in radians from the three accelerometer axes
+pub fn compute_pitch_roll(ax: f64, ay: f64, az: f64) -> (f64, f64) {
+    let pitch = ay.atan2(ax.hypot(az));
+    let roll = (-ax).atan2(az);
+    (pitch, roll)
+}
+
+// Fill `tilt` from the accelerometer values about to be stored, in m/s².
+// A record without all three axes gets none.
+pub fn apply(data: &mut SensorData) {
+    data.tilt = match (data.accel_x, data.accel_y, data.accel_z) {
+        (Some(ax), Some(ay), Some(az)) => {
+            let (pitch_rad, roll_rad) = compute_pitch_roll(ax, ay, az);
+            let magnitude_g = accel_ms2_to_g(ax.hypot(ay).hypot(az));
+            Some(Tilt { pitch_rad, roll_rad, valid: (magnitude_g - 1.0).abs() <= MAX_DEVIATION_G })
+        }
+        _ => None,
+    };
+}
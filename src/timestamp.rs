@@ -0,0 +1,279 @@
+use std::fmt;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, SecondsFormat, Utc};
+use serde::{de, Deserialize, Deserializer};
+
+// Epoch values at least this large are milliseconds; as seconds they would
+// be after the year 5000, while as milliseconds anything after 1973 is
+const EPOCH_MILLIS_THRESHOLD: f64 = 1e11;
+
+// A record's timestamp: either a string, stored as sent, or a number of
+// seconds (or milliseconds, see EPOCH_MILLIS_THRESHOLD) since the Unix
+// epoch, stored as RFC 3339 UTC with milliseconds
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTimestamp {
+    Text(String),
+    Epoch(f64),
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match RawTimestamp::deserialize(deserializer) {
+        Ok(RawTimestamp::Text(text)) => Ok(text),
+        Ok(RawTimestamp::Epoch(epoch)) => {
+            from_epoch(epoch).ok_or_else(|| de::Error::custom(format!("epoch timestamp {} is out of range", epoch)))
+        }
+        Err(_) => Err(de::Error::custom("timestamp must be a string or a number of seconds since the epoch")),
+    }
+}
+
+// The stored form of a numeric epoch timestamp
+pub fn from_epoch(epoch: f64) -> Option<String> {
+    let millis = if epoch.abs() >= EPOCH_MILLIS_THRESHOLD { epoch } else { epoch * 1000.0 };
+    let time = DateTime::<Utc>::from_timestamp_millis(millis.round() as i64)?;
+    Some(time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+// A form text timestamps may take, as given to --timestamp-format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimestampFormat {
+    // ISO 8601 with or without an offset, which everything downstream reads
+    // as is
+    Iso8601,
+    // Seconds or milliseconds since the Unix epoch, written as a string
+    Epoch,
+    // A strftime pattern such as %Y%m%d%H%M%S
+    Pattern(String),
+    // Any text at all, stored as sent, as every text timestamp was before
+    // formats could be chosen
+    Any,
+}
+
+pub fn parse_format(value: &str) -> Result<TimestampFormat, String> {
+    match value {
+        "iso8601" => Ok(TimestampFormat::Iso8601),
+        "epoch" => Ok(TimestampFormat::Epoch),
+        "any" => Ok(TimestampFormat::Any),
+        pattern if pattern.contains('%') => {
+            if StrftimeItems::new(pattern).any(|item| item == Item::Error) {
+                return Err(format!("'{}' is not a valid strftime pattern", pattern));
+            }
+            Ok(TimestampFormat::Pattern(pattern.to_string()))
+        }
+        _ => Err(format!("unknown timestamp format '{}'; use iso8601, epoch, any or a strftime pattern such as %Y%m%d%H%M%S", value)),
+    }
+}
+
+impl fmt::Display for TimestampFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimestampFormat::Iso8601 => write!(f, "iso8601"),
+            TimestampFormat::Epoch => write!(f, "epoch"),
+            TimestampFormat::Pattern(pattern) => write!(f, "{}", pattern),
+            TimestampFormat::Any => write!(f, "any"),
+        }
+    }
+}
+
+impl TimestampFormat {
+    // The stored form of `text` if it is in this format. ISO 8601 is kept as
+    // sent, epoch times are stored as numeric ones are, and a pattern's
+    // times as RFC 3339 UTC, taking a time without an offset as UTC.
+    fn normalize(&self, text: &str) -> Option<String> {
+        match self {
+            TimestampFormat::Iso8601 => ClientTimestamp::parse(text).map(|_| text.to_string()),
+            TimestampFormat::Epoch => text.trim().parse::<f64>().ok().filter(|epoch| epoch.is_finite()).and_then(from_epoch),
+            TimestampFormat::Pattern(pattern) => {
+                let time = match DateTime::parse_from_str(text, pattern) {
+                    Ok(time) => time.with_timezone(&Utc),
+                    Err(_) => NaiveDateTime::parse_from_str(text, pattern).ok()?.and_utc(),
+                };
+                Some(time.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+            TimestampFormat::Any => Some(text.to_string()),
+        }
+    }
+}
+
+// Replace a record's timestamp with its stored form, from the first of
+// `formats` it is in. ISO 8601 is tried last when it isn't listed, since
+// numeric and line protocol times reach this point already in that form.
+// One in none of them is an error naming it.
+pub fn normalize(timestamp: &mut String, formats: &[TimestampFormat]) -> Result<(), String> {
+    let implied = (!formats.contains(&TimestampFormat::Iso8601)).then_some(&TimestampFormat::Iso8601);
+    let formats: Vec<&TimestampFormat> = formats.iter().chain(implied).collect();
+    match formats.iter().find_map(|format| format.normalize(timestamp)) {
+        Some(normalized) => {
+            *timestamp = normalized;
+            Ok(())
+        }
+        None => {
+            let formats: Vec<String> = formats.iter().map(ToString::to_string).collect();
+            Err(format!("timestamp '{}' is in none of the accepted formats ({})", timestamp, formats.join(", ")))
+        }
+    }
+}
+
+// A timestamp as a client sent it, so timestamps the server derives from it
+// (sample blocks, delta-encoded batches) are written in the same style
+pub struct ClientTimestamp {
+    time: Kind,
+    // Digits after the decimal point in the original
+    fraction_digits: usize,
+}
+
+enum Kind {
+    Offset(DateTime<FixedOffset>),
+    Naive(NaiveDateTime),
+}
+
+impl ClientTimestamp {
+    // Accepts ISO 8601 with or without an offset
+    pub fn parse(timestamp: &str) -> Option<Self> {
+        let time = match DateTime::parse_from_rfc3339(timestamp) {
+            Ok(t) => Kind::Offset(t),
+            Err(_) => Kind::Naive(NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%.f").ok()?),
+        };
+        let fraction_digits = timestamp
+            .split_once('.')
+            .map_or(0, |(_, rest)| rest.chars().take_while(char::is_ascii_digit).count());
+        Some(ClientTimestamp { time, fraction_digits })
+    }
+
+    // The point in time, taking a timestamp without an offset as UTC
+    pub fn to_utc(&self) -> DateTime<Utc> {
+        match &self.time {
+            Kind::Offset(t) => t.with_timezone(&Utc),
+            Kind::Naive(t) => t.and_utc(),
+        }
+    }
+
+    // Shifted by `offset`, with as many fractional digits as the result needs
+    pub fn plus(&self, offset: Duration) -> String {
+        match &self.time {
+            Kind::Offset(t) => (*t + offset).to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            Kind::Naive(t) => (*t + offset).format("%Y-%m-%dT%H:%M:%S%.f").to_string(),
+        }
+    }
+
+    // Shifted by whole milliseconds, keeping the original's precision (at
+    // least milliseconds) so the result looks like a timestamp the client
+    // could have sent itself
+    pub fn plus_millis(&self, millis: i64) -> String {
+        let offset = Duration::milliseconds(millis);
+        let (seconds_format, naive_format) = match self.fraction_digits {
+            0..=3 => (SecondsFormat::Millis, "%Y-%m-%dT%H:%M:%S%.3f"),
+            4..=6 => (SecondsFormat::Micros, "%Y-%m-%dT%H:%M:%S%.6f"),
+            _ => (SecondsFormat::Nanos, "%Y-%m-%dT%H:%M:%S%.9f"),
+        };
+        match &self.time {
+            Kind::Offset(t) => (*t + offset).to_rfc3339_opts(seconds_format, true),
+            Kind::Naive(t) => (*t + offset).format(naive_format).to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Record {
+        #[serde(deserialize_with = "deserialize")]
+        timestamp: String,
+    }
+
+    fn stored(json: &str) -> String {
+        serde_json::from_str::<Record>(&format!(r#"{{"timestamp":{}}}"#, json)).expect("timestamp parses").timestamp
+    }
+
+    #[test]
+    fn numeric_timestamps_in_seconds_and_milliseconds() {
+        assert_eq!(stored("1716026400"), "2024-05-18T10:00:00.000Z");
+        assert_eq!(stored("1716026400.123"), "2024-05-18T10:00:00.123Z");
+        assert_eq!(stored("1716026400123"), "2024-05-18T10:00:00.123Z");
+    }
+
+    #[test]
+    fn text_timestamps_are_kept_as_sent() {
+        assert_eq!(stored(r#""2024-05-18T10:00:00+02:00""#), "2024-05-18T10:00:00+02:00");
+        assert!(serde_json::from_str::<Record>(r#"{"timestamp":true}"#).is_err());
+    }
+
+    // Just below the threshold is seconds, far in the future; from it on,
+    // milliseconds in the early 1970s
+    #[test]
+    fn millisecond_threshold() {
+        assert_eq!(from_epoch(99_999_999_999.0).unwrap(), "5138-11-16T09:46:39.000Z");
+        assert_eq!(from_epoch(100_000_000_000.0).unwrap(), "1973-03-03T09:46:40.000Z");
+    }
+
+    // The threshold applies to the magnitude, so times before 1970 work too
+    #[test]
+    fn negative_epoch() {
+        assert_eq!(stored("-86400"), "1969-12-31T00:00:00.000Z");
+        assert_eq!(from_epoch(-0.5).unwrap(), "1969-12-31T23:59:59.500Z");
+        assert_eq!(from_epoch(-100_000_000_000.0).unwrap(), "1966-10-31T14:13:20.000Z");
+    }
+
+    #[test]
+    fn out_of_range_epoch_is_rejected() {
+        assert_eq!(from_epoch(1e300), None);
+        assert!(serde_json::from_str::<Record>(r#"{"timestamp":1e300}"#).is_err());
+    }
+
+    fn formats(names: &[&str]) -> Vec<TimestampFormat> {
+        names.iter().map(|name| parse_format(name).unwrap()).collect()
+    }
+
+    fn normalized(text: &str, names: &[&str]) -> Result<String, String> {
+        let mut timestamp = text.to_string();
+        normalize(&mut timestamp, &formats(names)).map(|()| timestamp)
+    }
+
+    // A compact time is a valid epoch too, so the first listed format wins
+    #[test]
+    fn formats_are_tried_in_order() {
+        assert_eq!(normalized("20240518100000", &["%Y%m%d%H%M%S", "epoch"]).unwrap(), "2024-05-18T10:00:00Z");
+        assert_eq!(normalized("20240518100000", &["epoch", "%Y%m%d%H%M%S"]), Ok(from_epoch(20240518100000.0).unwrap()));
+    }
+
+    #[test]
+    fn pattern_match_is_stored_as_rfc3339_utc() {
+        assert_eq!(normalized("2024-05-18 12:00:00.250+0200", &["%Y-%m-%d %H:%M:%S%.f%z"]).unwrap(), "2024-05-18T10:00:00.250Z");
+        // Without an offset in the pattern the time is UTC
+        assert_eq!(normalized("18/05/2024 10:00", &["%d/%m/%Y %H:%M"]).unwrap(), "2024-05-18T10:00:00Z");
+    }
+
+    #[test]
+    fn epoch_string_is_stored_like_a_numeric_epoch() {
+        assert_eq!(normalized("1716026400", &["epoch"]).unwrap(), "2024-05-18T10:00:00.000Z");
+        assert_eq!(normalized(" 1716026400123 ", &["epoch"]).unwrap(), "2024-05-18T10:00:00.123Z");
+    }
+
+    // ISO 8601 is kept as sent, and accepted even when it isn't listed
+    #[test]
+    fn iso8601_is_implied() {
+        assert_eq!(normalized("2024-05-18T10:00:00+02:00", &["iso8601", "epoch"]).unwrap(), "2024-05-18T10:00:00+02:00");
+        assert_eq!(normalized("2024-05-18T10:00:00", &["epoch"]).unwrap(), "2024-05-18T10:00:00");
+    }
+
+    // Listed last, any keeps what no other format took, as sent
+    #[test]
+    fn any_accepts_free_form_text() {
+        assert_eq!(normalized("1716026400", &["epoch", "any"]).unwrap(), "2024-05-18T10:00:00.000Z");
+        assert_eq!(normalized("2024-05-18T10:00:00+02:00", &["epoch", "any"]).unwrap(), "2024-05-18T10:00:00+02:00");
+        assert_eq!(normalized("yesterday", &["iso8601", "epoch", "any"]).unwrap(), "yesterday");
+    }
+
+    #[test]
+    fn unmatched_timestamp_is_rejected() {
+        let error = normalized("yesterday", &["iso8601", "epoch"]).unwrap_err();
+        assert_eq!(error, "timestamp 'yesterday' is in none of the accepted formats (iso8601, epoch)");
+        let error = normalized("18/05/2024", &["epoch"]).unwrap_err();
+        assert_eq!(error, "timestamp '18/05/2024' is in none of the accepted formats (epoch, iso8601)");
+    }
+}
@@ -0,0 +1,531 @@
+use std::error::Error;
+use std::io::Write;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::Serialize;
+
+use crate::annotations;
+use crate::distance::haversine_distance_m;
+use crate::encryption::GpsCipher;
+use crate::export::{self, AltitudeMode, ColorBy, ExportArgs, ExportFormat};
+use crate::timestamp::ClientTimestamp;
+use crate::units::{altitude_ft_to_m, UnitsSystem};
+
+// One position of a session's track, with what a map may show about it
+pub struct TrackPoint {
+    pub session_id: Option<i64>,
+    pub timestamp: String,
+    pub time: Option<DateTime<Utc>>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+    pub accel_magnitude: Option<f64>,
+    // Over the step from the point before, in metres per second
+    pub speed_mps: Option<f64>,
+}
+
+// The positions of the rows `args` selects, session by session and in
+// timestamp order within each. Rows without a position, with one off the
+// globe or without a fix (fix_quality 0) are skipped, and with
+// --collapse-duplicates so is a position equal to the one before it. Also
+// returns how many rows were read.
+pub fn load(conn: &Connection, args: &ExportArgs, gps_cipher: Option<&GpsCipher>) -> Result<(Vec<TrackPoint>, u64), Box<dyn Error>> {
+    let sql = export::select(
+        "sessionID, timestamp, latitude, longitude, altitude, accel_magnitude, fix_quality",
+        "sessionID, julianday(timestamp), id",
+    );
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query(params![args.session, args.device, export::bound(args.from), export::bound(args.to)])?;
+    let mut points: Vec<TrackPoint> = Vec::new();
+    let mut read = 0;
+    while let Some(row) = rows.next()? {
+        read += 1;
+        let (Some(latitude), Some(longitude)) = (coordinate(row, "latitude", gps_cipher)?, coordinate(row, "longitude", gps_cipher)?)
+        else {
+            continue;
+        };
+        if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+            continue;
+        }
+        if row.get::<_, Option<u8>>("fix_quality")? == Some(0) {
+            continue;
+        }
+        let session_id: Option<i64> = row.get("sessionID")?;
+        let previous = points.last().filter(|p| p.session_id == session_id);
+        if args.collapse_duplicates && previous.is_some_and(|p| p.latitude == latitude && p.longitude == longitude) {
+            continue;
+        }
+        let timestamp: String = row.get("timestamp")?;
+        let time = ClientTimestamp::parse(&timestamp).map(|time| time.to_utc());
+        let speed_mps = previous.and_then(|p| {
+            let seconds = (time? - p.time?).num_milliseconds() as f64 / 1000.0;
+            (seconds > 0.0).then(|| haversine_distance_m(p.latitude, p.longitude, latitude, longitude) / seconds)
+        });
+        points.push(TrackPoint {
+            session_id,
+            timestamp,
+            time,
+            latitude,
+            longitude,
+            altitude: row.get("altitude")?,
+            accel_magnitude: row.get("accel_magnitude")?,
+            speed_mps,
+        });
+    }
+    Ok((points, read))
+}
+
+// A latitude or longitude in degrees, decrypted if it was stored encrypted.
+// NaN and infinities count as no position.
+fn coordinate(row: &Row, column: &str, gps_cipher: Option<&GpsCipher>) -> Result<Option<f64>, Box<dyn Error>> {
+    let value = match row.get_ref(column)? {
+        ValueRef::Text(text) => {
+            let cipher =
+                gps_cipher.ok_or_else(|| format!("{} is stored encrypted; give its key with --gps-key-file", column))?;
+            Some(cipher.decrypt(column, &String::from_utf8_lossy(text))?)
+        }
+        value => value.as_f64_or_null()?,
+    };
+    Ok(value.filter(|value| value.is_finite()))
+}
+
+// The time of a point as written to a track: RFC 3339 UTC when it parses,
+// as stored otherwise
+fn time_text(point: &TrackPoint) -> String {
+    match point.time {
+        Some(time) => time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        None => point.timestamp.clone(),
+    }
+}
+
+// Write the tracks `args` selects in the format it asks for, returning how
+// many positions they have. Nothing is written when there is no track to
+// draw.
+pub fn export(conn: &Connection, args: &ExportArgs, gps_cipher: Option<&GpsCipher>) -> Result<u64, Box<dyn Error>> {
+    let session_id = match (args.format, args.session) {
+        (ExportFormat::Geojson, None) => return Err("a GeoJSON track is exported per session; give one with --session".into()),
+        (_, session_id) => session_id,
+    };
+    let (mut points, read) = load(conn, args, gps_cipher)?;
+    if read == 0 {
+        return Err(export::nothing_matched(args).into());
+    }
+    if args.format == ExportFormat::Kml && args.altitude_mode == AltitudeMode::Absolute {
+        points.retain(|p| p.altitude.is_some());
+    }
+    let document = match args.format {
+        ExportFormat::Geojson => {
+            // A LineString needs two positions
+            if points.len() < 2 {
+                return Err(format!(
+                    "nothing to export: {} of the {} matching rows have a valid position, and a track needs two",
+                    points.len(),
+                    read
+                )
+                .into());
+            }
+            serde_json::to_string(&geojson(session_id.unwrap_or_default(), &points, args.points))?
+        }
+        ExportFormat::Gpx => {
+            if points.is_empty() {
+                return Err(format!("nothing to export: none of the {} matching rows has a valid position", read).into());
+            }
+            gpx(conn, &points, args.segment_gap_secs)?
+        }
+        ExportFormat::Kml => {
+            if !sessions(&points).any(|track| track.len() >= 2) {
+                return Err(format!(
+                    "nothing to export: no session has two valid positions{} among the {} matching rows",
+                    if args.altitude_mode == AltitudeMode::Absolute { " with an altitude" } else { "" },
+                    read
+                )
+                .into());
+            }
+            kml(conn, &points, args)?
+        }
+        ExportFormat::Csv | ExportFormat::Jsonl => unreachable!("rows are exported by export::run"),
+    };
+    let mut out = export::open_output(args)?;
+    writeln!(out, "{}", document)?;
+    out.flush()?;
+    Ok(points.len() as u64)
+}
+
+// GeoJSON (RFC 7946) objects as the export writes them. Positions are
+// [longitude, latitude], in that order.
+#[derive(Serialize)]
+struct FeatureCollection {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    features: Vec<Feature>,
+}
+
+#[derive(Serialize)]
+struct Feature {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    geometry: Geometry,
+    properties: Properties,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum Geometry {
+    LineString { coordinates: Vec<[f64; 2]> },
+    Point { coordinates: [f64; 2] },
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Properties {
+    Track {
+        #[serde(rename = "sessionID")]
+        session_id: i64,
+        start_time: String,
+        end_time: String,
+        points: usize,
+    },
+    Point {
+        timestamp: String,
+        altitude: Option<f64>,
+        speed_mps: Option<f64>,
+        accel_magnitude: Option<f64>,
+    },
+}
+
+// The track as a LineString feature, followed by a Point feature per
+// position when `with_points` is set
+fn geojson(session_id: i64, points: &[TrackPoint], with_points: bool) -> FeatureCollection {
+    let feature = |geometry, properties| Feature { kind: "Feature", geometry, properties };
+    let mut features = vec![feature(
+        Geometry::LineString { coordinates: points.iter().map(|p| [p.longitude, p.latitude]).collect() },
+        Properties::Track {
+            session_id,
+            start_time: time_text(&points[0]),
+            end_time: time_text(&points[points.len() - 1]),
+            points: points.len(),
+        },
+    )];
+    if with_points {
+        features.extend(points.iter().map(|p| {
+            feature(
+                Geometry::Point { coordinates: [p.longitude, p.latitude] },
+                Properties::Point {
+                    timestamp: time_text(p),
+                    altitude: p.altitude,
+                    speed_mps: p.speed_mps,
+                    accel_magnitude: p.accel_magnitude,
+                },
+            )
+        }));
+    }
+    FeatureCollection { kind: "FeatureCollection", features }
+}
+
+// The points of each session, in the order `load` returns them
+fn sessions(points: &[TrackPoint]) -> impl Iterator<Item = &[TrackPoint]> {
+    points.chunk_by(|a, b| a.session_id == b.session_id)
+}
+
+// What a track takes from its session's row: the label it is named after,
+// the device and the units its altitude was stored in. Empty for records
+// without a session, or whose session has no row.
+#[derive(Default)]
+struct SessionDetails {
+    label: Option<String>,
+    device_id: Option<String>,
+    units_system: Option<String>,
+}
+
+impl SessionDetails {
+    fn load(conn: &Connection, session_id: Option<i64>) -> rusqlite::Result<Self> {
+        let Some(session_id) = session_id else {
+            return Ok(SessionDetails::default());
+        };
+        Ok(conn
+            .query_row("SELECT label, device_id, units_system FROM sessions WHERE sessionID = ?", [session_id], |row| {
+                Ok(SessionDetails { label: row.get(0)?, device_id: row.get(1)?, units_system: row.get(2)? })
+            })
+            .optional()?
+            .unwrap_or_default())
+    }
+
+    fn name(&self, session_id: Option<i64>) -> String {
+        match (&self.label, session_id) {
+            (Some(label), _) => label.clone(),
+            (None, Some(id)) => format!("Session {}", id),
+            (None, None) => "Records without a session".to_string(),
+        }
+    }
+
+    // An altitude in metres, as GPX and KML have them
+    fn altitude_m(&self, altitude: f64) -> f64 {
+        if self.units_system.as_deref() == Some(UnitsSystem::Imperial.name()) {
+            altitude_ft_to_m(altitude)
+        } else {
+            altitude
+        }
+    }
+}
+
+// A GPX 1.1 document with a track per session, named after its label. A
+// track is split into segments wherever the time between two positions
+// exceeds `segment_gap_secs`, so a dropout isn't drawn as a straight line.
+// Elevations are in metres, as GPX has them, and times in UTC.
+fn gpx(conn: &Connection, points: &[TrackPoint], segment_gap_secs: u64) -> rusqlite::Result<String> {
+    let gap = chrono::Duration::seconds(segment_gap_secs as i64);
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <gpx version=\"1.1\" creator=\"db_receiver\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+    );
+    for track in sessions(points) {
+        let details = SessionDetails::load(conn, track[0].session_id)?;
+        let name = details.name(track[0].session_id);
+        out.push_str(&format!("  <trk>\n    <name>{}</name>\n    <trkseg>\n", xml_text(&name)));
+        for (i, point) in track.iter().enumerate() {
+            let dropout = i > 0 && matches!((track[i - 1].time, point.time), (Some(a), Some(b)) if b - a > gap);
+            if dropout {
+                out.push_str("    </trkseg>\n    <trkseg>\n");
+            }
+            out.push_str(&format!("      <trkpt lat=\"{}\" lon=\"{}\">", point.latitude, point.longitude));
+            if let Some(altitude) = point.altitude {
+                out.push_str(&format!("<ele>{}</ele>", details.altitude_m(altitude)));
+            }
+            if let Some(time) = point.time {
+                out.push_str(&format!("<time>{}</time>", time.to_rfc3339_opts(SecondsFormat::AutoSi, true)));
+            }
+            out.push_str("</trkpt>\n");
+        }
+        out.push_str("    </trkseg>\n  </trk>\n");
+    }
+    out.push_str("</gpx>");
+    Ok(out)
+}
+
+// Line colours of the sessions of a KML document, cycled through, in KML's
+// aabbggrr order: red, blue, green, orange, magenta, cyan, yellow, purple
+const KML_PALETTE: [&str; 8] =
+    ["ff0000ff", "ffff0000", "ff00aa00", "ff00a5ff", "ffff00ff", "ffffff00", "ff00ffff", "ff800080"];
+
+// Colours of the --color-by ranges, lowest first, and of positions without
+// the value
+const KML_RANGE_COLORS: [&str; 4] = ["ff00ff00", "ff00ffff", "ff00a5ff", "ff0000ff"];
+const KML_NO_VALUE_COLOR: &str = "ff808080";
+
+// The span of the --color-by value over every exported position, split into
+// equal ranges, one colour each
+struct ColorRanges {
+    color_by: ColorBy,
+    min: f64,
+    max: f64,
+}
+
+impl ColorRanges {
+    fn new(color_by: ColorBy, points: &[TrackPoint]) -> Self {
+        let (min, max) = points
+            .iter()
+            .filter_map(|p| ColorRanges::value(color_by, p))
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| (min.min(v), max.max(v)));
+        ColorRanges { color_by, min, max }
+    }
+
+    fn value(color_by: ColorBy, point: &TrackPoint) -> Option<f64> {
+        match color_by {
+            ColorBy::AccelMag => point.accel_magnitude,
+            ColorBy::Speed => point.speed_mps,
+        }
+    }
+
+    // The range a position falls in, or None without the value
+    fn range(&self, point: &TrackPoint) -> Option<usize> {
+        let value = ColorRanges::value(self.color_by, point)?;
+        let width = (self.max - self.min) / KML_RANGE_COLORS.len() as f64;
+        if width <= 0.0 {
+            return Some(0);
+        }
+        Some((((value - self.min) / width) as usize).min(KML_RANGE_COLORS.len() - 1))
+    }
+
+    fn describe(&self, range: Option<usize>) -> String {
+        let Some(range) = range else {
+            return "no value".to_string();
+        };
+        let width = (self.max - self.min) / KML_RANGE_COLORS.len() as f64;
+        let low = self.min + width * range as f64;
+        format!("{:.2} to {:.2}", low, low + width)
+    }
+
+    fn style(range: Option<usize>) -> String {
+        range.map_or("no-value".to_string(), |range| format!("range-{}", range))
+    }
+}
+
+// At most `max` of a track's positions, spread evenly over it and always
+// including the first and the last
+fn thin(track: &[TrackPoint], max: usize) -> Vec<&TrackPoint> {
+    if track.len() <= max {
+        return track.iter().collect();
+    }
+    let last = track.len() - 1;
+    (0..max).map(|i| &track[(i * last + (max - 1) / 2) / (max - 1)]).collect()
+}
+
+// A KML 2.2 document for Google Earth with a placemark per session, in a
+// colour of its own. With --color-by, each session is a folder of
+// placemarks instead, one for each stretch whose value stays in one range.
+// Sessions with fewer than two positions can't be drawn as a line and are
+// left out; those with more than --max-points are thinned.
+fn kml(conn: &Connection, points: &[TrackPoint], args: &ExportArgs) -> rusqlite::Result<String> {
+    let ranges = args.color_by.map(|color_by| ColorRanges::new(color_by, points));
+    let mut out = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <kml xmlns=\"http://www.opengis.net/kml/2.2\">\n<Document>\n  <name>db_receiver export</name>\n",
+    );
+    let style = |out: &mut String, id: &str, color: &str| {
+        out.push_str(&format!(
+            "  <Style id=\"{}\"><LineStyle><color>{}</color><width>3</width></LineStyle></Style>\n",
+            id, color
+        ));
+    };
+    match &ranges {
+        Some(ranges) => {
+            let mut legend: Vec<String> = (0..KML_RANGE_COLORS.len())
+                .map(|range| format!("{}: {}", ["Green", "Yellow", "Orange", "Red"][range], ranges.describe(Some(range))))
+                .collect();
+            legend.push("Grey: no value".to_string());
+            out.push_str(&format!("  <description>{}</description>\n", xml_text(&legend.join("<br>"))));
+            for (range, color) in KML_RANGE_COLORS.iter().enumerate() {
+                style(&mut out, &ColorRanges::style(Some(range)), color);
+            }
+            style(&mut out, &ColorRanges::style(None), KML_NO_VALUE_COLOR);
+        }
+        None => {
+            for (i, color) in KML_PALETTE.iter().enumerate() {
+                style(&mut out, &format!("session-{}", i), color);
+            }
+        }
+    }
+    let absolute = args.altitude_mode == AltitudeMode::Absolute;
+    for (i, track) in sessions(points).filter(|track| track.len() >= 2).enumerate() {
+        let session_id = track[0].session_id;
+        let details = SessionDetails::load(conn, session_id)?;
+        let name = details.name(session_id);
+        let thinned = thin(track, args.max_points as usize);
+        let description = kml_description(conn, session_id, &details, track, thinned.len())?;
+        let coordinates = |stretch: &[&TrackPoint]| -> String {
+            let positions: Vec<String> = stretch
+                .iter()
+                .map(|p| match p.altitude.filter(|_| absolute) {
+                    Some(altitude) => format!("{},{},{}", p.longitude, p.latitude, details.altitude_m(altitude)),
+                    None => format!("{},{}", p.longitude, p.latitude),
+                })
+                .collect();
+            positions.join(" ")
+        };
+        let Some(ranges) = &ranges else {
+            let style = format!("session-{}", i % KML_PALETTE.len());
+            kml_placemark(&mut out, "  ", &name, Some(&description), &style, absolute, &coordinates(&thinned));
+            continue;
+        };
+        out.push_str(&format!(
+            "  <Folder>\n    <name>{}</name>\n    <description>{}</description>\n",
+            xml_text(&name),
+            xml_text(&description)
+        ));
+        // Each stretch runs on to the first position of the next, so the
+        // line has no breaks
+        let mut start = 0;
+        while start < thinned.len() - 1 {
+            let range = ranges.range(thinned[start]);
+            let mut end = start + 1;
+            while end < thinned.len() - 1 && ranges.range(thinned[end]) == range {
+                end += 1;
+            }
+            let stretch_name = format!("{} ({})", name, ranges.describe(range));
+            let style = ColorRanges::style(range);
+            kml_placemark(&mut out, "    ", &stretch_name, None, &style, absolute, &coordinates(&thinned[start..=end]));
+            start = end;
+        }
+        out.push_str("  </Folder>\n");
+    }
+    out.push_str("</Document>\n</kml>");
+    Ok(out)
+}
+
+fn kml_placemark(
+    out: &mut String,
+    indent: &str,
+    name: &str,
+    description: Option<&str>,
+    style: &str,
+    absolute: bool,
+    coordinates: &str,
+) {
+    out.push_str(&format!("{}<Placemark>\n{}  <name>{}</name>\n", indent, indent, xml_text(name)));
+    if let Some(description) = description {
+        out.push_str(&format!("{}  <description>{}</description>\n", indent, xml_text(description)));
+    }
+    out.push_str(&format!(
+        "{}  <styleUrl>#{}</styleUrl>\n{}  <LineString><tessellate>1</tessellate><altitudeMode>{}</altitudeMode><coordinates>{}</coordinates></LineString>\n{}</Placemark>\n",
+        indent,
+        style,
+        indent,
+        if absolute { "absolute" } else { "clampToGround" },
+        coordinates,
+        indent
+    ));
+}
+
+// The balloon Google Earth shows for a session: its label, device and time
+// span, how many positions were drawn, and its annotations with their notes.
+// Earth reads it as HTML.
+fn kml_description(
+    conn: &Connection,
+    session_id: Option<i64>,
+    details: &SessionDetails,
+    track: &[TrackPoint],
+    drawn: usize,
+) -> rusqlite::Result<String> {
+    let mut lines = Vec::new();
+    if let (Some(label), Some(id)) = (&details.label, session_id) {
+        lines.push(format!("{} (session {})", label, id));
+    }
+    if let Some(device_id) = &details.device_id {
+        lines.push(format!("Device: {}", device_id));
+    }
+    lines.push(format!("From {} to {}", time_text(&track[0]), time_text(&track[track.len() - 1])));
+    if drawn < track.len() {
+        lines.push(format!("{} of {} positions drawn", drawn, track.len()));
+    } else {
+        lines.push(format!("{} positions", track.len()));
+    }
+    if let Some(id) = session_id {
+        for annotation in annotations::get_annotations(conn, id)? {
+            let mut line = format!("{} ({} to {})", annotation.label, annotation.start_ts, annotation.end_ts);
+            if let Some(notes) = annotation.notes.filter(|notes| !notes.is_empty()) {
+                line.push_str(&format!(": {}", notes));
+            }
+            lines.push(line);
+        }
+    }
+    // Escaped as HTML here, then again as XML when it is written
+    let lines: Vec<String> = lines.iter().map(|line| xml_text(line)).collect();
+    Ok(lines.join("<br>"))
+}
+
+// Text escaped for XML element content and attributes
+fn xml_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
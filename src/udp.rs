@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::{debug, error, info};
+
+use crate::batch::SessionTally;
+use crate::peer::Peer;
+use crate::{
+    handle_records, is_read_timeout, parse_message, reject_unparsed, ConnectionState, Message, ParseOptions,
+    ServerState, BIND_ADDRESS, READ_POLL_INTERVAL,
+};
+
+// How often the statistics of the sources heard from since are logged
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+// A source silent for this long is forgotten, along with its filters and
+// outlier statistics
+const SOURCE_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Datagrams from one source address
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct DatagramStats {
+    received: u64,
+    // Every record in the datagram was accepted
+    parsed: u64,
+    // At least one line of the datagram was rejected or ignored
+    rejected: u64,
+    // Larger than --max-datagram-bytes, dropped unread
+    oversized: u64,
+}
+
+impl fmt::Display for DatagramStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received={} parsed={} rejected={} oversized={}",
+            self.received, self.parsed, self.rejected, self.oversized
+        )
+    }
+}
+
+// What the server keeps about a source address, as it would about a TCP
+// connection
+struct Source {
+    state: ConnectionState,
+    tally: Arc<SessionTally>,
+    stats: DatagramStats,
+    // Statistics when they were last logged
+    logged: DatagramStats,
+    last_seen: Instant,
+}
+
+// Receive datagrams of records on `port` until the server shuts down or
+// starts draining. They are stored through the same path as TCP lines, but
+// nothing is ever sent back.
+pub fn spawn(
+    server: Arc<ServerState>,
+    port: u16,
+    running: Arc<AtomicBool>,
+    accepting: Arc<AtomicBool>,
+) -> io::Result<JoinHandle<()>> {
+    let socket = UdpSocket::bind((BIND_ADDRESS, port))?;
+    socket.set_read_timeout(Some(READ_POLL_INTERVAL))?;
+    info!("Listening for UDP datagrams on port {}...", port);
+    Ok(thread::spawn(move || {
+        let active = || running.load(Ordering::SeqCst) && accepting.load(Ordering::SeqCst);
+        receive(&socket, &server, active);
+        info!("UDP listener on port {} closed", port);
+    }))
+}
+
+fn receive(socket: &UdpSocket, server: &ServerState, active: impl Fn() -> bool) {
+    let max_bytes = server.config.max_datagram_bytes;
+    // One byte more than allowed, so a datagram that fills it is known to be too large
+    let mut buffer = vec![0; max_bytes + 1];
+    let mut sources: HashMap<SocketAddr, Source> = HashMap::new();
+    let mut last_stats = Instant::now();
+
+    while active() {
+        match socket.recv_from(&mut buffer) {
+            Ok((len, addr)) => {
+                let source = sources.entry(addr).or_insert_with(|| {
+                    info!("First UDP datagram from {}", addr);
+                    Source {
+                        state: ConnectionState::new(&server.config),
+                        tally: Arc::default(),
+                        stats: DatagramStats::default(),
+                        logged: DatagramStats::default(),
+                        last_seen: Instant::now(),
+                    }
+                });
+                source.last_seen = Instant::now();
+                source.stats.received += 1;
+                server.metrics.udp_datagrams_received.fetch_add(1, Ordering::Relaxed);
+                if len > max_bytes {
+                    // Counted, not logged one by one: a misconfigured sender
+                    // would flood the log
+                    source.stats.oversized += 1;
+                    server.metrics.udp_datagrams_oversized.fetch_add(1, Ordering::Relaxed);
+                } else if handle_datagram(server, addr, source, &buffer[..len]) {
+                    source.stats.parsed += 1;
+                } else {
+                    source.stats.rejected += 1;
+                    server.metrics.udp_datagrams_rejected.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(e) if is_read_timeout(&e) => {}
+            Err(e) => {
+                // Such as an ICMP error for an earlier packet; the socket stays usable
+                debug!("UDP receive failed: {}", e);
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        if last_stats.elapsed() >= STATS_INTERVAL {
+            log_stats(&mut sources);
+            sources.retain(|addr, source| {
+                let recent = source.last_seen.elapsed() < SOURCE_IDLE_TIMEOUT;
+                if !recent {
+                    info!("Forgetting UDP source {} after {}s of silence", addr, SOURCE_IDLE_TIMEOUT.as_secs());
+                }
+                recent
+            });
+            last_stats = Instant::now();
+        }
+    }
+    log_stats(&mut sources);
+}
+
+// Handle each line of a datagram as a line from a TCP client would be.
+// Returns whether every record in it was accepted.
+fn handle_datagram(server: &ServerState, addr: SocketAddr, source: &mut Source, datagram: &[u8]) -> bool {
+    let config = &server.config;
+    let state = &mut source.state;
+    let addr = Peer::from(addr);
+    let text = match std::str::from_utf8(datagram) {
+        Ok(text) => text,
+        Err(e) => {
+            let text = String::from_utf8_lossy(datagram);
+            reject_unparsed(None, addr, state, server, text.trim(), &format!("datagram is not valid UTF-8: {}", e));
+            return false;
+        }
+    };
+
+    let mut accepted = true;
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        debug!("Received datagram line from {}: {}", addr, line);
+        accepted &= match parse_message(line, &ParseOptions::from(config)) {
+            Ok(Message::SensorData(rows)) => match handle_records(server, None, addr, state, line, rows, &source.tally) {
+                Ok(stored) => stored,
+                Err(e) => {
+                    error!("Failed to queue records from {}: {}", addr, e);
+                    false
+                }
+            },
+            Ok(Message::Keepalive) => true,
+            // Anything that needs an answer or connection state needs TCP
+            Ok(message) => {
+                debug!("Ignoring control message from {} over UDP: {:?}", addr, message);
+                false
+            }
+            Err(e) => {
+                reject_unparsed(None, addr, state, server, line, &e.to_string());
+                false
+            }
+        };
+    }
+    accepted
+}
+
+fn log_stats(sources: &mut HashMap<SocketAddr, Source>) {
+    for (addr, source) in sources.iter_mut().filter(|(_, source)| source.stats != source.logged) {
+        info!("UDP datagrams from {}: {}", addr, source.stats);
+        source.logged = source.stats;
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt;
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::SensorData;
+
+// Standard gravity, m/s² per g
+const STANDARD_GRAVITY: f64 = 9.806_65;
+const FEET_PER_METRE: f64 = 1.0 / 0.3048;
+const KNOTS_PER_METRE_PER_SECOND: f64 = 3600.0 / 1852.0;
+
+// Units the stored sensor values are in. Clients always send SI; with
+// imperial the server converts before storing.
+#[derive(ValueEnum, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnitsSystem {
+    /// Store values as received: m/s², rad/s and metres
+    #[default]
+    Si,
+    /// Store acceleration in g, angular rates in °/s (cumulative angles in degrees) and altitude in feet
+    Imperial,
+}
+
+impl UnitsSystem {
+    pub fn name(self) -> &'static str {
+        match self {
+            UnitsSystem::Si => "si",
+            UnitsSystem::Imperial => "imperial",
+        }
+    }
+
+    // Convert a record's values, including the raw and filtered copies the
+    // server derived from them, to this system. Runs after validation and
+    // the filters, which all work in SI.
+    pub fn apply(self, data: &mut SensorData) {
+        if self == UnitsSystem::Si {
+            return;
+        }
+        for value in [&mut data.accel_x, &mut data.accel_y, &mut data.accel_z] {
+            *value = value.map(accel_ms2_to_g);
+        }
+        for value in [&mut data.gyro_x, &mut data.gyro_y, &mut data.gyro_z] {
+            *value = value.map(gyro_rads_to_degs);
+        }
+        data.altitude = data.altitude.map(altitude_m_to_ft);
+        if let Some(raw) = &mut data.position_raw {
+            raw[2] = raw[2].map(altitude_m_to_ft);
+        }
+        if let Some(raw) = &mut data.imu_raw {
+            for value in &mut raw[..3] {
+                *value = value.map(accel_ms2_to_g);
+            }
+            for value in &mut raw[3..] {
+                *value = value.map(gyro_rads_to_degs);
+            }
+        }
+        if let Some(filtered) = &mut data.accel_filtered {
+            for value in filtered {
+                *value = value.map(accel_ms2_to_g);
+            }
+        }
+        if let Some(angles) = &mut data.cumulative_angles {
+            for angle in angles {
+                *angle = gyro_rads_to_degs(*angle);
+            }
+        }
+    }
+}
+
+impl fmt::Display for UnitsSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub fn accel_ms2_to_g(v: f64) -> f64 {
+    v / STANDARD_GRAVITY
+}
+
+// Also turns radians into degrees, as for the cumulative angles
+pub fn gyro_rads_to_degs(v: f64) -> f64 {
+    v.to_degrees()
+}
+
+pub fn altitude_m_to_ft(v: f64) -> f64 {
+    v * FEET_PER_METRE
+}
+
+// For formats that want metres whatever the data was stored in
+pub fn altitude_ft_to_m(v: f64) -> f64 {
+    v / FEET_PER_METRE
+}
+
+// No record field carries a speed yet
+#[allow(dead_code)]
+pub fn speed_ms_to_knots(v: f64) -> f64 {
+    v * KNOTS_PER_METRE_PER_SECOND
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_close(accel_ms2_to_g(9.806_65), 1.0);
+        assert_close(accel_ms2_to_g(-19.6133), -2.0);
+        assert_close(gyro_rads_to_degs(std::f64::consts::PI), 180.0);
+        assert_close(altitude_m_to_ft(0.3048), 1.0);
+        assert_close(altitude_m_to_ft(1000.0), 3_280.839_895_013_123);
+        assert_close(speed_ms_to_knots(1852.0 / 3600.0), 1.0);
+    }
+
+    #[test]
+    fn altitude_round_trip() {
+        for metres in [-430.5, 0.0, 8848.86] {
+            assert_close(altitude_ft_to_m(altitude_m_to_ft(metres)), metres);
+        }
+    }
+
+    fn record() -> SensorData {
+        serde_json::from_value(serde_json::json!({
+            "sessionID": 1, "timestamp": "2024-05-18T10:00:00Z", "latitude": 45.0, "longitude": -122.0,
+            "altitude": 304.8, "accel_x": 9.806_65, "accel_z": 0.0, "gyro_x": std::f64::consts::FRAC_PI_2,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn imperial_converts_every_copy() {
+        let mut data = record();
+        data.imu_raw = Some([Some(9.806_65), None, None, Some(std::f64::consts::PI), None, None]);
+        data.accel_filtered = Some([Some(19.6133), None, None]);
+        data.cumulative_angles = Some([std::f64::consts::PI; 3]);
+        UnitsSystem::Imperial.apply(&mut data);
+
+        assert_close(data.accel_x.unwrap(), 1.0);
+        assert_eq!(data.accel_y, None);
+        assert_close(data.gyro_x.unwrap(), 90.0);
+        assert_close(data.altitude.unwrap(), 1000.0);
+        // Coordinates are degrees in either system
+        assert_eq!((data.latitude, data.longitude), (Some(45.0), Some(-122.0)));
+        let raw = data.imu_raw.unwrap();
+        assert_close(raw[0].unwrap(), 1.0);
+        assert_close(raw[3].unwrap(), 180.0);
+        assert_close(data.accel_filtered.unwrap()[0].unwrap(), 2.0);
+        assert_close(data.cumulative_angles.unwrap()[2], 180.0);
+    }
+
+    #[test]
+    fn si_leaves_values_alone() {
+        let mut data = record();
+        UnitsSystem::Si.apply(&mut data);
+        assert_eq!(data.accel_x, Some(9.806_65));
+        assert_eq!(data.altitude, Some(304.8));
+    }
+}
@@ -0,0 +1,50 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use clap::Args;
+use rusqlite::Connection;
+
+// Options of the `vacuum` subcommand
+#[derive(Args, Debug, Clone)]
+pub struct VacuumArgs {
+    /// Write a compacted copy here instead of compacting the database in place, which the server can keep using meanwhile
+    #[arg(long, value_name = "PATH")]
+    pub into: Option<PathBuf>,
+}
+
+// Sizes in bytes of the database before and of the result after
+#[derive(Debug)]
+pub struct VacuumReport {
+    pub before: u64,
+    pub after: u64,
+}
+
+// The database file and its write-ahead log, which holds pages not yet
+// checkpointed into it
+pub fn database_size(path: &Path) -> u64 {
+    let wal = PathBuf::from(format!("{}-wal", path.display()));
+    [path, wal.as_path()].iter().filter_map(|path| fs::metadata(path).ok()).map(|metadata| metadata.len()).sum()
+}
+
+// Rebuild the database without its free pages, in place or into a new file.
+// In place needs the database to itself, so it fails while the server is
+// writing; a copy only needs to read it.
+pub fn run(conn: &Connection, path: &Path, args: &VacuumArgs) -> Result<VacuumReport, Box<dyn Error>> {
+    let before = database_size(path);
+    match &args.into {
+        Some(into) => {
+            if into.exists() {
+                return Err(format!("{} already exists", into.display()).into());
+            }
+            conn.execute("VACUUM INTO ?1", [into.to_string_lossy()])?;
+            Ok(VacuumReport { before, after: database_size(into) })
+        }
+        None => {
+            conn.execute_batch("VACUUM")?;
+            // Move what VACUUM wrote to the log into the file, so the size
+            // reported is the one left on disk
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+            Ok(VacuumReport { before, after: database_size(path) })
+        }
+    }
+}
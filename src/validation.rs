@@ -0,0 +1,258 @@
+use std::fmt;
+use clap::{Args, ValueEnum};
+
+use crate::profile;
+use crate::SensorData;
+
+// Altitudes a GPS receiver can plausibly report. Records outside them are
+// stored, but flagged as outliers, since a spike in altitude says little
+// about the rest of the record.
+#[derive(Args, Debug, Clone, Copy)]
+#[command(next_help_heading = "Validation")]
+pub struct AltitudeBounds {
+    /// Lowest plausible altitude in metres; records below it are flagged as outliers
+    #[arg(long, value_name = "METRES", default_value_t = -500.0, allow_negative_numbers = true)]
+    pub altitude_min: f64,
+
+    /// Highest plausible altitude in metres; records above it are flagged as outliers
+    #[arg(long, value_name = "METRES", default_value_t = 50000.0, allow_negative_numbers = true)]
+    pub altitude_max: f64,
+}
+
+impl AltitudeBounds {
+    // The record's altitude if it lies outside the bounds
+    pub fn violation(&self, data: &SensorData) -> Option<f64> {
+        data.altitude.filter(|altitude| !(self.altitude_min..=self.altitude_max).contains(altitude))
+    }
+}
+
+impl fmt::Display for AltitudeBounds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}, {}]", self.altitude_min, self.altitude_max)
+    }
+}
+
+// Why a record's values can't be stored as they are
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationError {
+    LatitudeOutOfRange(f64),
+    LongitudeOutOfRange(f64),
+    BelowMinimum { field: &'static str, value: f64, min: f64 },
+    AboveMaximum { field: &'static str, value: f64, max: f64 },
+}
+
+impl ValidationError {
+    // The error_type of the dead letter a rejected line becomes
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            ValidationError::LatitudeOutOfRange(_) | ValidationError::LongitudeOutOfRange(_) => "gps_range",
+            ValidationError::BelowMinimum { .. } | ValidationError::AboveMaximum { .. } => "field_range",
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::LatitudeOutOfRange(lat) => write!(f, "latitude {} is outside [-90, 90]", lat),
+            ValidationError::LongitudeOutOfRange(lon) => write!(f, "longitude {} is outside [-180, 180]", lon),
+            ValidationError::BelowMinimum { field, value, min } => {
+                write!(f, "{} = {} is below the --field-range minimum {}", field, value, min)
+            }
+            ValidationError::AboveMaximum { field, value, max } => {
+                write!(f, "{} = {} is above the --field-range maximum {}", field, value, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+// The bounds are inclusive: the poles and the antimeridian are real places
+pub fn validate_gps(lat: f64, lon: f64) -> Result<(), ValidationError> {
+    if !(-90.0..=90.0).contains(&lat) {
+        return Err(ValidationError::LatitudeOutOfRange(lat));
+    }
+    if !(-180.0..=180.0).contains(&lon) {
+        return Err(ValidationError::LongitudeOutOfRange(lon));
+    }
+    Ok(())
+}
+
+// A record's position, where a coordinate it doesn't report is in range
+pub fn check_position(data: &SensorData) -> Result<(), ValidationError> {
+    validate_gps(data.latitude.unwrap_or(0.0), data.longitude.unwrap_or(0.0))
+}
+
+// Physical limits of the platform's sensors. A reading beyond them is a
+// sensor fault rather than a measurement. None are set by default.
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "Validation")]
+pub struct FieldLimits {
+    /// Bounds of a sensor field, e.g. accel_x=-160:160 or battery_v=0:; accel, gyro and mag stand for all three axes. Repeatable
+    #[arg(long = "field-range", value_name = "FIELD=MIN:MAX", value_parser = parse_range)]
+    pub field_ranges: Vec<FieldRange>,
+
+    /// What happens to a record with a value outside its --field-range
+    #[arg(long, value_enum, default_value_t = FieldRangeAction::Reject)]
+    pub field_range_action: FieldRangeAction,
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldRange {
+    fields: Vec<&'static str>,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldRangeAction {
+    /// Reject the record and keep it in dead_letters
+    Reject,
+    /// Store the record with is_outlier set
+    Flag,
+}
+
+fn parse_range(value: &str) -> Result<FieldRange, String> {
+    let (name, bounds) = value.split_once('=').ok_or("expected FIELD=MIN:MAX")?;
+    let fields = match name {
+        "accel" => vec!["accel_x", "accel_y", "accel_z"],
+        "gyro" => vec!["gyro_x", "gyro_y", "gyro_z"],
+        "mag" => vec!["mag_x", "mag_y", "mag_z"],
+        _ => vec![profile::parse_field(name)?],
+    };
+    let (min, max) = bounds.split_once(':').ok_or("expected FIELD=MIN:MAX; either bound may be left out")?;
+    let bound = |text: &str| match text.trim() {
+        "" => Ok(None),
+        text => match text.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Some(n)),
+            _ => Err(format!("'{}' is not a finite number", text)),
+        },
+    };
+    let (min, max) = (bound(min)?, bound(max)?);
+    if let (Some(min), Some(max)) = (min, max) {
+        if min > max {
+            return Err(format!("minimum {} is above maximum {}", min, max));
+        }
+    }
+    Ok(FieldRange { fields, min, max })
+}
+
+impl FieldLimits {
+    // The first value of the record beyond its bounds, naming the field and bound
+    pub fn check(&self, data: &SensorData) -> Result<(), ValidationError> {
+        if self.field_ranges.is_empty() {
+            return Ok(());
+        }
+        for (field, value) in data.fields() {
+            let Some(value) = value else { continue };
+            for range in self.field_ranges.iter().filter(|range| range.fields.contains(&field)) {
+                if let Some(min) = range.min.filter(|&min| value < min) {
+                    return Err(ValidationError::BelowMinimum { field, value, min });
+                }
+                if let Some(max) = range.max.filter(|&max| value > max) {
+                    return Err(ValidationError::AboveMaximum { field, value, max });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The limits as they appear in the effective configuration line
+impl fmt::Display for FieldLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.field_ranges.is_empty() {
+            return write!(f, "off");
+        }
+        let action = match self.field_range_action {
+            FieldRangeAction::Reject => "reject",
+            FieldRangeAction::Flag => "flag",
+        };
+        write!(f, "{}(", action)?;
+        for (i, range) in self.field_ranges.iter().enumerate() {
+            let bound = |bound: Option<f64>| bound.map_or(String::new(), |n| n.to_string());
+            let separator = if i == 0 { "" } else { "," };
+            write!(f, "{}{}={}:{}", separator, range.fields.join("+"), bound(range.min), bound(range.max))?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gps_boundaries_are_inclusive() {
+        for lat in [-90.0, 90.0] {
+            for lon in [-180.0, 180.0] {
+                assert!(validate_gps(lat, lon).is_ok(), "({}, {})", lat, lon);
+            }
+        }
+    }
+
+    // One ULP beyond each bound is already out of range
+    #[test]
+    fn gps_one_ulp_outside_is_rejected() {
+        for lat in [f64::next_down(-90.0), f64::next_up(90.0)] {
+            assert!(matches!(validate_gps(lat, 0.0), Err(ValidationError::LatitudeOutOfRange(v)) if v == lat));
+        }
+        for lon in [f64::next_down(-180.0), f64::next_up(180.0)] {
+            assert!(matches!(validate_gps(0.0, lon), Err(ValidationError::LongitudeOutOfRange(v)) if v == lon));
+        }
+    }
+
+    #[test]
+    fn gps_nan_is_rejected() {
+        assert!(validate_gps(f64::NAN, 0.0).is_err());
+        assert!(validate_gps(0.0, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn field_range_parsing() {
+        let range = parse_range("accel_x=-160:160").unwrap();
+        assert_eq!((range.fields, range.min, range.max), (vec!["accel_x"], Some(-160.0), Some(160.0)));
+        let range = parse_range("battery_v=0:").unwrap();
+        assert_eq!((range.min, range.max), (Some(0.0), None));
+        let range = parse_range("temperature_c=: 85").unwrap();
+        assert_eq!((range.min, range.max), (None, Some(85.0)));
+        assert_eq!(parse_range("gyro=-35:35").unwrap().fields, ["gyro_x", "gyro_y", "gyro_z"]);
+        assert_eq!(parse_range("dac_1=0:5").unwrap().fields, ["dac_1"]);
+    }
+
+    #[test]
+    fn bad_field_ranges_are_refused() {
+        for bad in ["accel_x", "accel_x=5", "accel_x=a:1", "accel_x=inf:", "accel_x=NaN:1", "accel_x=2:1", "speed=0:1"] {
+            assert!(parse_range(bad).is_err(), "{} was accepted", bad);
+        }
+        assert_eq!(parse_range("accel_x=2:1").unwrap_err(), "minimum 2 is above maximum 1");
+    }
+
+    fn limits(ranges: &[&str]) -> FieldLimits {
+        FieldLimits {
+            field_ranges: ranges.iter().map(|range| parse_range(range).unwrap()).collect(),
+            field_range_action: FieldRangeAction::Reject,
+        }
+    }
+
+    // The bounds themselves are in range
+    #[test]
+    fn field_range_check() {
+        let data: SensorData =
+            serde_json::from_value(serde_json::json!({"sessionID": 1, "timestamp": "2024-05-18T10:00:00Z", "accel_z": 16.0}))
+                .unwrap();
+        assert_eq!(limits(&[]).check(&data), Ok(()));
+        assert_eq!(limits(&["accel=-16:16"]).check(&data), Ok(()));
+        assert_eq!(
+            limits(&["accel=-8:8"]).check(&data),
+            Err(ValidationError::AboveMaximum { field: "accel_z", value: 16.0, max: 8.0 })
+        );
+        assert_eq!(
+            limits(&["accel_z=20:"]).check(&data),
+            Err(ValidationError::BelowMinimum { field: "accel_z", value: 16.0, min: 20.0 })
+        );
+        // A field the record doesn't carry is never out of range
+        assert_eq!(limits(&["gyro=0:0"]).check(&data), Ok(()));
+    }
+}
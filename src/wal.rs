@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use log::{info, warn};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::batch::PendingRecord;
+use crate::encryption::GpsCipher;
+use crate::error::ReceiverError;
+use crate::insert_sensor_data;
+
+// Marks every line above it as stored
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct CommitSentinel {
+    committed: Option<i64>,
+}
+
+// An open WAL file and how many records logged in it are not yet stored
+struct WalFile {
+    file: File,
+    uncommitted: usize,
+    last_row_id: Option<i64>,
+}
+
+// Write-ahead log of records accepted but not yet committed, one file per
+// session in `<session_id>.jsonl`. Each record is appended before it is
+// buffered for the database, and a commit sentinel follows once every record
+// logged in the file so far has been stored. At that point the file is
+// truncated down to the sentinel, so it only grows while commits lag behind.
+//
+// Records are logged as `PendingRecord`s rather than the raw line, so the
+// device from the handshake survives recovery.
+pub struct Wal {
+    dir: PathBuf,
+    files: Mutex<HashMap<String, WalFile>>,
+}
+
+impl Wal {
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Wal {
+            dir,
+            files: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Log a record before it is buffered. The record counts as uncommitted
+    // even if the write fails, so the sentinel still waits for its commit.
+    pub fn append(&self, record: &PendingRecord) -> io::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        let mut files = self.files.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let session = record.session_key();
+        let wal = match files.get_mut(&session) {
+            Some(wal) => wal,
+            None => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.dir.join(format!("{}.jsonl", session)))?;
+                files.entry(session).or_insert(WalFile { file, uncommitted: 0, last_row_id: None })
+            }
+        };
+        wal.uncommitted += 1;
+        wal.file.write_all(line.as_bytes())
+    }
+
+    // Note that `count` records of a session are no longer at risk, either
+    // committed (with the last row ID) or kept elsewhere (without one)
+    pub fn commit(&self, session: &str, count: usize, row_id: Option<i64>) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(wal) = files.get_mut(session) else {
+            return Ok(());
+        };
+        wal.uncommitted = wal.uncommitted.saturating_sub(count);
+        wal.last_row_id = row_id.or(wal.last_row_id);
+        if wal.uncommitted > 0 {
+            return Ok(());
+        }
+
+        // Everything in the file is stored, so only the sentinel needs keeping
+        let mut line = serde_json::to_string(&CommitSentinel { committed: wal.last_row_id })?;
+        line.push('\n');
+        let result = wal.file.set_len(0).and_then(|()| wal.file.write_all(line.as_bytes()));
+        files.remove(session);
+        result
+    }
+}
+
+// Re-insert records logged after the last commit sentinel of each WAL file,
+// i.e. those accepted but never stored before the server stopped. Each file
+// is replayed in one transaction and then truncated to a sentinel. Returns
+// the number of recovered rows.
+pub fn recover_wal(
+    conn: &Connection,
+    wal_dir: &Path,
+    gps_cipher: Option<&GpsCipher>,
+    dedup_timestamps: bool,
+) -> Result<u64, ReceiverError> {
+    if !wal_dir.exists() {
+        return Ok(0);
+    }
+    let mut files: Vec<PathBuf> = fs::read_dir(wal_dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+        .collect();
+    files.sort();
+
+    let mut recovered = 0;
+    for path in files {
+        let contents = fs::read_to_string(&path)?;
+        let lines: Vec<&str> = contents.lines().filter(|line| !line.trim().is_empty()).collect();
+        let after_sentinel = lines
+            .iter()
+            .rposition(|line| serde_json::from_str::<CommitSentinel>(line).is_ok())
+            .map_or(0, |i| i + 1);
+        if after_sentinel == lines.len() {
+            continue;
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        let mut last_row_id = None;
+        let mut inserted = 0;
+        for line in &lines[after_sentinel..] {
+            // A crash mid-write can leave the last line incomplete
+            match serde_json::from_str::<PendingRecord>(line) {
+                Ok(record) => {
+                    if let Some(row_id) = insert_sensor_data(&tx, &record.data, record.device_id.as_deref(), record.connection_id, gps_cipher, dedup_timestamps)? {
+                        last_row_id = Some(row_id);
+                    }
+                    inserted += 1;
+                }
+                Err(e) => warn!("Skipping unreadable WAL entry in {}: {}", path.display(), e),
+            }
+        }
+        tx.commit()?;
+
+        let mut sentinel = serde_json::to_string(&CommitSentinel { committed: last_row_id })?;
+        sentinel.push('\n');
+        fs::write(&path, sentinel)?;
+        info!("Recovered {} record(s) from {}", inserted, path.display());
+        recovered += inserted;
+    }
+    Ok(recovered)
+}
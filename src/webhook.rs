@@ -0,0 +1,316 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::Args;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::error_reply::truncate_chars;
+use crate::events::Event;
+use crate::peer::Peer;
+
+// Rejections that may wait for the webhook thread; more are not counted
+const QUEUE_CAPACITY: usize = 1024;
+
+// Commits whose events may wait to be posted; events of more are only stored
+const EVENT_QUEUE_CAPACITY: usize = 64;
+
+// Rejected lines quoted in a summary, the most recent ones
+const MAX_SAMPLES: usize = 5;
+
+// Longest part of a rejected line quoted in a summary
+const MAX_SAMPLE_CHARS: usize = 1024;
+
+// How long the webhook may take to accept a connection and to answer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// When a burst of rejected lines is reported to a webhook, and how often
+#[derive(Args, Debug, Clone)]
+#[command(next_help_heading = "Alert webhook")]
+pub struct WebhookConfig {
+    /// POST a JSON summary to this http:// URL while lines keep being rejected
+    #[arg(long, value_name = "URL", value_parser = parse_url)]
+    pub alert_webhook: Option<WebhookUrl>,
+
+    /// Rejected lines within the window that make the webhook fire
+    #[arg(long, value_name = "N", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub alert_webhook_threshold: u64,
+
+    /// Window rejected lines are counted over
+    #[arg(long, value_name = "SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub alert_webhook_window_secs: u64,
+
+    /// Least time between two posts to the webhook
+    #[arg(long, value_name = "SECS", default_value_t = 300)]
+    pub alert_webhook_interval_secs: u64,
+}
+
+// The settings as they appear in the effective configuration line
+impl fmt::Display for WebhookConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.alert_webhook {
+            Some(url) => write!(
+                f,
+                "{}(threshold={}/{}s,interval={}s)",
+                url, self.alert_webhook_threshold, self.alert_webhook_window_secs, self.alert_webhook_interval_secs
+            ),
+            None => write!(f, "off"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl fmt::Display for WebhookUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+// http://host[:port][/path]. The client is minimal, so there is no TLS.
+fn parse_url(value: &str) -> Result<WebhookUrl, String> {
+    let rest = match value.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+        Some((scheme, _)) => return Err(format!("unsupported scheme '{}'; use http://", scheme)),
+        None => return Err("expected an http:// URL".to_string()),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    // An IPv6 address is bracketed, and has colons of its own
+    let (host, port) = match authority.rfind(':').filter(|&i| !authority[i..].contains(']')) {
+        Some(i) => (&authority[..i], authority[i + 1..].parse().map_err(|_| format!("bad port in '{}'", authority))?),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err("missing host".to_string());
+    }
+    Ok(WebhookUrl { host: host.to_string(), port, path })
+}
+
+// A line rejected by parsing or validation
+struct Rejection {
+    at: DateTime<Utc>,
+    received: Instant,
+    client: Peer,
+    error_type: &'static str,
+    error: String,
+    line: String,
+}
+
+// Body posted to the webhook
+#[derive(Serialize, Debug)]
+struct Summary {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    window_secs: u64,
+    rejected: usize,
+    // Rejected lines per error type (parse_error, validation, ...) and client
+    by_type: BTreeMap<&'static str, usize>,
+    by_client: BTreeMap<String, usize>,
+    samples: Vec<Sample>,
+    first_rejected_at: String,
+    last_rejected_at: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Sample {
+    client: String,
+    error_type: &'static str,
+    error: String,
+    payload: String,
+}
+
+// Hands rejected lines to the thread that watches their rate and posts to the
+// webhook. Ingest never waits for it: when it falls behind, rejections are
+// left out of the count.
+#[derive(Debug)]
+pub struct RejectionAlerts {
+    sender: SyncSender<Rejection>,
+}
+
+impl RejectionAlerts {
+    pub fn start(config: &WebhookConfig) -> Option<Self> {
+        let url = config.alert_webhook.clone()?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let config = config.clone();
+        info!("Posting rejection alerts to {}", url);
+        thread::spawn(move || watch(&config, &url, receiver));
+        Some(RejectionAlerts { sender })
+    }
+
+    pub fn record(&self, client: Peer, error_type: &'static str, error: &str, line: &str) {
+        let _ = self.sender.try_send(Rejection {
+            at: Utc::now(),
+            received: Instant::now(),
+            client,
+            error_type,
+            error: error.to_string(),
+            line: truncate_chars(line, MAX_SAMPLE_CHARS),
+        });
+    }
+}
+
+// Body posted to the webhook for the events of one commit
+#[derive(Serialize, Debug)]
+struct EventNotice<'a> {
+    #[serde(rename = "type")]
+    message_type: &'static str,
+    events: &'a [Event],
+}
+
+// Hands the events of each commit to a thread that posts them to the webhook
+// as they come, unlike rejections, which are only summarized. The writer
+// never waits for it: when it falls behind, events are stored but not posted.
+#[derive(Debug)]
+pub struct EventAlerts {
+    sender: SyncSender<Vec<Event>>,
+}
+
+impl EventAlerts {
+    pub fn start(config: &WebhookConfig) -> Option<Self> {
+        let url = config.alert_webhook.clone()?;
+        let (sender, receiver) = mpsc::sync_channel::<Vec<Event>>(EVENT_QUEUE_CAPACITY);
+        info!("Posting events to {}", url);
+        thread::spawn(move || {
+            for events in receiver {
+                let notice = EventNotice { message_type: "events", events: &events };
+                match post(&url, &serde_json::to_string(&notice).unwrap_or_default()) {
+                    Ok(()) => info!("Posted {} event(s) to {}", events.len(), url),
+                    Err(e) => warn!("Failed to post {} event(s) to {}: {}", events.len(), url, e),
+                }
+            }
+        });
+        Some(EventAlerts { sender })
+    }
+
+    pub fn record(&self, events: Vec<Event>) {
+        let _ = self.sender.try_send(events);
+    }
+}
+
+// What is kept of each rejection in the window; only the samples keep the
+// lines themselves, so a flood of rejections doesn't hold on to them all
+struct Seen {
+    received: Instant,
+    at: DateTime<Utc>,
+    client: Peer,
+    error_type: &'static str,
+}
+
+// Keep the rejections of the last window and post a summary of them whenever
+// they reach the threshold, at most once per interval
+fn watch(config: &WebhookConfig, url: &WebhookUrl, receiver: Receiver<Rejection>) {
+    let window = Duration::from_secs(config.alert_webhook_window_secs);
+    let interval = Duration::from_secs(config.alert_webhook_interval_secs);
+    let mut recent: VecDeque<Seen> = VecDeque::new();
+    let mut samples: VecDeque<Rejection> = VecDeque::new();
+    let mut last_posted: Option<Instant> = None;
+    loop {
+        match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(rejection) => {
+                recent.push_back(Seen {
+                    received: rejection.received,
+                    at: rejection.at,
+                    client: rejection.client,
+                    error_type: rejection.error_type,
+                });
+                if samples.len() == MAX_SAMPLES {
+                    samples.pop_front();
+                }
+                samples.push_back(rejection);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        while recent.front().is_some_and(|seen| seen.received.elapsed() > window) {
+            recent.pop_front();
+        }
+        samples.retain(|sample| sample.received.elapsed() <= window);
+        let due = last_posted.is_none_or(|posted| posted.elapsed() >= interval);
+        if recent.len() as u64 >= config.alert_webhook_threshold && due {
+            // Counted as posted even if it fails, so a dead webhook isn't
+            // retried on every rejection
+            last_posted = Some(Instant::now());
+            let summary = summarize(&recent, &samples, config.alert_webhook_window_secs);
+            match post(url, &serde_json::to_string(&summary).unwrap_or_default()) {
+                Ok(()) => info!("Posted rejection alert for {} line(s) to {}", summary.rejected, url),
+                Err(e) => warn!("Failed to post rejection alert to {}: {}", url, e),
+            }
+        }
+    }
+}
+
+fn summarize(recent: &VecDeque<Seen>, samples: &VecDeque<Rejection>, window_secs: u64) -> Summary {
+    let mut by_type = BTreeMap::new();
+    let mut by_client = BTreeMap::new();
+    for seen in recent {
+        *by_type.entry(seen.error_type).or_default() += 1;
+        *by_client.entry(seen.client.to_string()).or_default() += 1;
+    }
+    let timestamp = |seen: Option<&Seen>| seen.map(|seen| seen.at.to_rfc3339_opts(SecondsFormat::Millis, true)).unwrap_or_default();
+    Summary {
+        message_type: "rejection_alert",
+        window_secs,
+        rejected: recent.len(),
+        by_type,
+        by_client,
+        samples: samples
+            .iter()
+            .rev()
+            .map(|rejection| Sample {
+                client: rejection.client.to_string(),
+                error_type: rejection.error_type,
+                error: rejection.error.clone(),
+                payload: rejection.line.clone(),
+            })
+            .collect(),
+        first_rejected_at: timestamp(recent.front()),
+        last_rejected_at: timestamp(recent.back()),
+    }
+}
+
+// POST `body` as JSON and check for a 2xx status; the rest of the answer is
+// ignored
+fn post(url: &WebhookUrl, body: &str) -> io::Result<()> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} resolves to no address", url.host));
+    let host = url.host.trim_start_matches('[').trim_end_matches(']');
+    for addr in (host, url.port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(mut stream) => {
+                stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+                stream.set_write_timeout(Some(RESPONSE_TIMEOUT))?;
+                write!(
+                    stream,
+                    "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    url.path,
+                    url.host,
+                    url.port,
+                    body.len(),
+                    body
+                )?;
+                let mut status_line = String::new();
+                BufReader::new(stream).read_line(&mut status_line)?;
+                let status = status_line.split_whitespace().nth(1).unwrap_or("");
+                if !status.starts_with('2') {
+                    return Err(io::Error::other(format!("webhook answered '{}'", status_line.trim())));
+                }
+                return Ok(());
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
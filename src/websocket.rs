@@ -0,0 +1,170 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use log::{info, warn};
+use tungstenite::protocol::WebSocketConfig;
+use tungstenite::{Error, Message, WebSocket};
+
+use crate::error_reply::{ErrorCode, ErrorReply};
+
+// How long a client may take over the opening handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A WebSocket connection seen as the line-based stream TCP clients use. Each
+// text frame reads as one line, and each line written goes out as a text
+// frame, so WebSocket clients are handled exactly like TCP clients.
+//
+// A WebSocket can't be split into independent read and write halves, so the
+// clones of a stream share it. Reading holds it while waiting for a frame,
+// which is fine for the connection's own thread but would stall a writer on
+// another thread; subscriptions aren't offered over WebSocket for that reason.
+#[derive(Debug)]
+pub struct WsStream {
+    ws: Arc<Mutex<WebSocket<TcpStream>>>,
+    // For timeouts and shutdown without taking the lock
+    socket: TcpStream,
+    // The frame being read, with its newline, and how much of it was returned
+    incoming: Vec<u8>,
+    consumed: usize,
+    // The start of a line not yet complete enough to send
+    outgoing: Vec<u8>,
+}
+
+// Complete the opening handshake on a newly accepted connection. Frames may
+// be as large as the lines TCP clients may send; a larger one closes the
+// connection.
+pub fn accept(stream: TcpStream, max_message_bytes: usize) -> io::Result<WsStream> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let socket = stream.try_clone()?;
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(max_message_bytes))
+        .max_frame_size(Some(max_message_bytes));
+    let ws = tungstenite::accept_with_config(stream, Some(config))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("WebSocket handshake with {} failed: {}", peer, e)))?;
+    info!("WebSocket handshake with {} complete", peer);
+    Ok(WsStream { ws: Arc::new(Mutex::new(ws)), socket, incoming: Vec::new(), consumed: 0, outgoing: Vec::new() })
+}
+
+impl WsStream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(WsStream {
+            ws: self.ws.clone(),
+            socket: self.socket.try_clone()?,
+            incoming: Vec::new(),
+            consumed: 0,
+            outgoing: Vec::new(),
+        })
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.socket.shutdown(how)
+    }
+
+    // Wait for the next frame that carries a line. Ok(false) means the client
+    // closed the connection.
+    fn next_frame(&mut self) -> io::Result<bool> {
+        let ws = self.ws.clone();
+        let mut ws = lock(&ws);
+        loop {
+            match ws.read() {
+                Ok(Message::Text(text)) => {
+                    self.incoming.extend_from_slice(text.as_bytes());
+                    self.incoming.push(b'\n');
+                    return Ok(true);
+                }
+                // tungstenite queues the pong. The empty line counts as
+                // activity, like a keepalive message.
+                Ok(Message::Ping(_)) => {
+                    self.incoming.push(b'\n');
+                    return Ok(true);
+                }
+                Ok(Message::Binary(data)) => {
+                    warn!("Rejected binary frame of {} byte(s) from {}", data.len(), peer(&self.socket));
+                    let reply = ErrorReply::new(
+                        ErrorCode::Unsupported,
+                        "binary frames are not accepted; send JSON as text frames",
+                        "",
+                    );
+                    let reply = serde_json::to_string(&reply).map_err(io::Error::other)?;
+                    ws.send(Message::text(reply)).map_err(into_io)?;
+                }
+                Ok(Message::Pong(_) | Message::Frame(_)) => {}
+                // The reply to the close frame goes out with the next flush
+                Ok(Message::Close(_)) => {
+                    match ws.flush() {
+                        Ok(()) | Err(Error::ConnectionClosed) => {}
+                        Err(e) => warn!("Failed to complete WebSocket close with {}: {}", peer(&self.socket), e),
+                    }
+                    return Ok(false);
+                }
+                Err(Error::ConnectionClosed | Error::AlreadyClosed) => return Ok(false),
+                Err(e) => return Err(into_io(e)),
+            }
+        }
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.consumed == self.incoming.len() {
+            self.incoming.clear();
+            self.consumed = 0;
+            if !self.next_frame()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.incoming.len() - self.consumed);
+        buf[..n].copy_from_slice(&self.incoming[self.consumed..self.consumed + n]);
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        let Some(end) = self.outgoing.iter().rposition(|&b| b == b'\n') else {
+            return Ok(buf.len());
+        };
+        let lines: Vec<u8> = self.outgoing.drain(..=end).collect();
+        let mut ws = lock(&self.ws);
+        for line in lines[..end].split(|&b| b == b'\n') {
+            let text = String::from_utf8_lossy(line).into_owned();
+            ws.write(Message::text(text)).map_err(into_io)?;
+        }
+        ws.flush().map_err(into_io)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        lock(&self.ws).flush().map_err(into_io)
+    }
+}
+
+fn lock(ws: &Mutex<WebSocket<TcpStream>>) -> MutexGuard<'_, WebSocket<TcpStream>> {
+    ws.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn peer(socket: &TcpStream) -> String {
+    socket.peer_addr().map_or("unknown peer".to_string(), |addr| addr.to_string())
+}
+
+// Timeouts and resets keep their kind, so the read loop tells them apart as
+// it does for TCP
+fn into_io(e: Error) -> io::Error {
+    match e {
+        Error::Io(e) => e,
+        Error::ConnectionClosed | Error::AlreadyClosed => io::Error::from(ErrorKind::NotConnected),
+        e => io::Error::new(ErrorKind::InvalidData, e),
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+use std::io::{self, ErrorKind, Read};
+use log::{info, warn};
+
+use crate::peer::Peer;
+
+// Bytes of the big-endian length in front of each MessagePack frame
+const LENGTH_PREFIX_BYTES: usize = 4;
+
+// What a connection sends. Clients never say; the first bytes tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    // Newline-delimited lines: JSON, or InfluxDB line protocol
+    Lines,
+    // MessagePack values, each behind a 4-byte big-endian length
+    Msgpack,
+}
+
+impl fmt::Display for WireFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireFormat::Lines => write!(f, "JSON lines"),
+            WireFormat::Msgpack => write!(f, "length-prefixed MessagePack"),
+        }
+    }
+}
+
+// Decide the format from the first bytes of a connection, or None while more
+// are needed. A leading `{` or `[` is JSON. MessagePack takes a length that
+// fits `max_frame_bytes` followed by a map, the record or batch's first
+// byte. Anything else, a line ending first or the connection closing
+// (`complete`) is treated as lines, the format older clients speak.
+pub fn detect(prefix: &[u8], complete: bool, max_frame_bytes: usize) -> Option<WireFormat> {
+    let first = prefix.first()?;
+    if matches!(first, b'{' | b'[') || prefix.contains(&b'\n') || complete {
+        return Some(WireFormat::Lines);
+    }
+    let &marker = prefix.get(LENGTH_PREFIX_BYTES)?;
+    let length = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+    // fixmap, map 16 and map 32
+    let is_map = matches!(marker, 0x80..=0x8f | 0xde | 0xdf);
+    if is_map && (1..=max_frame_bytes).contains(&length) {
+        Some(WireFormat::Msgpack)
+    } else {
+        Some(WireFormat::Lines)
+    }
+}
+
+// Reads a client's stream for the line reader, whatever format it turns out
+// to speak. Lines pass through untouched; each MessagePack frame comes out as
+// one compact JSON line, so both formats share the rest of the pipeline.
+// Timeouts leave what has arrived so far buffered for the next read.
+pub struct WireReader<R> {
+    inner: R,
+    addr: Peer,
+    max_frame_bytes: usize,
+    format: Option<WireFormat>,
+    // Bytes read but not yet passed on or decoded
+    pending: Vec<u8>,
+    // The JSON line of the last frame, and how much of it was returned
+    line: Vec<u8>,
+    consumed: usize,
+}
+
+impl<R: Read> WireReader<R> {
+    // A reader that works out the format from the first bytes, or that takes
+    // `format` as given
+    pub fn new(inner: R, addr: Peer, max_frame_bytes: usize, format: Option<WireFormat>) -> Self {
+        WireReader { inner, addr, max_frame_bytes, format, pending: Vec::new(), line: Vec::new(), consumed: 0 }
+    }
+
+    // Read more into `pending`; false once the client has closed the connection
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 8192];
+        let n = self.inner.read(&mut chunk)?;
+        self.pending.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    fn sniff(&mut self) -> io::Result<WireFormat> {
+        let mut complete = false;
+        loop {
+            if let Some(format) = detect(&self.pending, complete, self.max_frame_bytes) {
+                info!("Client {} speaks {}", self.addr, format);
+                self.format = Some(format);
+                return Ok(format);
+            }
+            complete = !self.fill()?;
+        }
+    }
+
+    // Decode the next frame into `line`; false once the client has closed the
+    // connection between frames. A frame that is too large or isn't valid
+    // MessagePack leaves no way to trust what follows, so it ends the
+    // connection.
+    fn next_frame(&mut self) -> io::Result<bool> {
+        loop {
+            if let Some(prefix) = self.pending.first_chunk::<LENGTH_PREFIX_BYTES>() {
+                let length = u32::from_be_bytes(*prefix) as usize;
+                if length > self.max_frame_bytes {
+                    let error = format!("MessagePack frame of {} bytes exceeds {} bytes", length, self.max_frame_bytes);
+                    warn!("Closing connection from {}: {}", self.addr, error);
+                    return Err(io::Error::new(ErrorKind::InvalidData, error));
+                }
+                if self.pending.len() >= LENGTH_PREFIX_BYTES + length {
+                    let frame: Vec<u8> = self.pending.drain(..LENGTH_PREFIX_BYTES + length).collect();
+                    let value: serde_json::Value = rmp_serde::from_slice(&frame[LENGTH_PREFIX_BYTES..]).map_err(|e| {
+                        warn!("Closing connection from {}: invalid MessagePack frame: {}", self.addr, e);
+                        io::Error::new(ErrorKind::InvalidData, e)
+                    })?;
+                    self.line = serde_json::to_vec(&value)?;
+                    self.line.push(b'\n');
+                    self.consumed = 0;
+                    return Ok(true);
+                }
+            }
+            if !self.fill()? {
+                if self.pending.is_empty() {
+                    return Ok(false);
+                }
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed part-way through a frame"));
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for WireReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let format = match self.format {
+            Some(format) => format,
+            None => self.sniff()?,
+        };
+        match format {
+            WireFormat::Lines if self.pending.is_empty() => self.inner.read(buf),
+            // What sniffing read goes out first
+            WireFormat::Lines => {
+                let n = buf.len().min(self.pending.len());
+                buf[..n].copy_from_slice(&self.pending[..n]);
+                self.pending.drain(..n);
+                Ok(n)
+            }
+            WireFormat::Msgpack => {
+                if self.consumed == self.line.len() && !self.next_frame()? {
+                    return Ok(0);
+                }
+                let n = buf.len().min(self.line.len() - self.consumed);
+                buf[..n].copy_from_slice(&self.line[self.consumed..self.consumed + n]);
+                self.consumed += n;
+                Ok(n)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufRead;
+    use serde_json::json;
+
+    use super::*;
+
+    const MAX: usize = 1024;
+
+    fn frame(value: &serde_json::Value) -> Vec<u8> {
+        let body = rmp_serde::to_vec_named(value).unwrap();
+        let mut bytes = (body.len() as u32).to_be_bytes().to_vec();
+        bytes.extend(body);
+        bytes
+    }
+
+    #[test]
+    fn json_lines() {
+        assert_eq!(detect(b"{\"sessionID\":1}", false, MAX), Some(WireFormat::Lines));
+        assert_eq!(detect(b"[{\"sessionID\":1}]", false, MAX), Some(WireFormat::Lines));
+    }
+
+    #[test]
+    fn length_prefixed_msgpack() {
+        let bytes = frame(&json!({"sessionID": 1, "timestamp": "2024-01-01T00:00:00Z"}));
+        assert_eq!(detect(&bytes, false, MAX), Some(WireFormat::Msgpack));
+
+        let mut reader = io::BufReader::new(WireReader::new(&bytes[..], Peer::Mqtt, MAX, None)).lines();
+        let line = reader.next().unwrap().unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&line).unwrap()["sessionID"], 1);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn line_protocol() {
+        let line = b"imu,session=1 accel_x=0.5 1704067200000000000\n";
+        assert_eq!(detect(line, false, MAX), Some(WireFormat::Lines));
+        // Still lines when the connection closes before a line ending
+        assert_eq!(detect(&line[..8], true, MAX), Some(WireFormat::Lines));
+    }
+
+    // Until the length and the byte after it are in, there is no telling
+    #[test]
+    fn incomplete_prefix() {
+        let bytes = frame(&json!({"sessionID": 1}));
+        assert_eq!(detect(b"", false, MAX), None);
+        for end in 1..=LENGTH_PREFIX_BYTES {
+            assert_eq!(detect(&bytes[..end], false, MAX), None, "{} bytes", end);
+        }
+        assert_eq!(detect(&bytes[..=LENGTH_PREFIX_BYTES], false, MAX), Some(WireFormat::Msgpack));
+    }
+
+    // A length beyond the frame limit isn't taken for MessagePack, and a
+    // connection known to speak it is closed on such a frame
+    #[test]
+    fn oversized_frame() {
+        let bytes = frame(&json!({"notes": "x".repeat(2 * MAX)}));
+        assert_eq!(detect(&bytes, false, MAX), Some(WireFormat::Lines));
+
+        let mut reader = WireReader::new(&bytes[..], Peer::Mqtt, MAX, Some(WireFormat::Msgpack));
+        let error = reader.read(&mut [0; 64]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
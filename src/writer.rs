@@ -0,0 +1,230 @@
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
+use rusqlite::{ffi, Connection};
+
+use crate::batch::{BatchWriter, PendingRecord, SessionTally};
+use crate::breaker;
+use crate::database::Database;
+use crate::metrics::Metrics;
+use crate::stats;
+use crate::{ServerState, DATABASE_PATH};
+
+// Pause between commit attempts while the writer's buffer is full
+const BACKPRESSURE_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// Pause between attempts to queue records while the queue is full
+const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// Work queued for the writer thread, handled in the order it was sent
+enum Request {
+    // Rows from one line, with the tally of the connection that sent them
+    Records(Vec<PendingRecord>, Arc<SessionTally>),
+    // Database work of a client thread, run once everything queued before it
+    // has been committed
+    Call(Box<dyn FnOnce(&Connection) + Send>),
+    // Commit everything buffered now rather than when the batch is due, and
+    // report how many records that committed
+    Flush(mpsc::Sender<rusqlite::Result<usize>>),
+    Shutdown,
+}
+
+// What became of records handed to `Writer::send`
+pub enum Sent {
+    Queued,
+    // The queue stayed full for the whole timeout; the records are returned
+    Dropped(Vec<PendingRecord>),
+}
+
+// Handle to the single thread that owns the database connection. Client
+// threads queue their records here instead of each writing through a
+// connection of its own, so commits don't contend for SQLite's write lock
+// and every record is batched with those of other clients. The queue is
+// bounded: when it is full, sending waits, the client's socket is no longer
+// read, and TCP pushes back on the sender. Records that still find no room
+// after the backpressure timeout are dropped.
+pub struct Writer {
+    sender: SyncSender<Request>,
+    receiver: Mutex<Option<Receiver<Request>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    send_timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl Writer {
+    // The queue holds up to `capacity` lines of records; the thread is
+    // started separately by `start`, once the server state it needs exists
+    pub fn new(capacity: usize, send_timeout: Duration, metrics: Arc<Metrics>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(capacity.max(1));
+        Writer {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            thread: Mutex::new(None),
+            send_timeout,
+            metrics,
+        }
+    }
+
+    // Run the writer on `conn`, which must already have the schema
+    pub fn start(server: &Arc<ServerState>, conn: Connection) {
+        let Some(receiver) = server.writer.receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take() else {
+            return;
+        };
+        let state = server.clone();
+        let handle = thread::spawn(move || run(&state, conn, &receiver));
+        *server.writer.thread.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(handle);
+    }
+
+    // Queue the rows of one line, retrying while the queue is full until
+    // the backpressure timeout runs out
+    pub fn send(&self, records: Vec<PendingRecord>, tally: &Arc<SessionTally>) -> rusqlite::Result<Sent> {
+        let count = records.len() as u64;
+        let mut request = Request::Records(records, tally.clone());
+        let mut stalled_since: Option<Instant> = None;
+        loop {
+            request = match self.sender.try_send(request) {
+                Ok(()) => {
+                    self.metrics.records_queued.fetch_add(count, Ordering::Relaxed);
+                    return Ok(Sent::Queued);
+                }
+                Err(TrySendError::Full(request)) => request,
+                Err(TrySendError::Disconnected(_)) => return Err(writer_stopped()),
+            };
+            let since = *stalled_since.get_or_insert_with(|| {
+                self.metrics.backpressure_stalls.fetch_add(1, Ordering::Relaxed);
+                debug!("Writer queue is full; pausing reads until it catches up");
+                Instant::now()
+            });
+            if since.elapsed() >= self.send_timeout {
+                self.metrics.dropped_records.fetch_add(count, Ordering::Relaxed);
+                let Request::Records(records, _) = request else {
+                    unreachable!("only records are retried");
+                };
+                return Ok(Sent::Dropped(records));
+            }
+            thread::sleep(SEND_RETRY_INTERVAL);
+        }
+    }
+
+    // Run `f` on the writer's connection after everything queued so far has
+    // been committed, and wait for its result
+    pub fn call<T, F>(&self, f: F) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let (reply, result) = mpsc::channel();
+        let request = Request::Call(Box::new(move |conn| {
+            let _ = reply.send(f(conn));
+        }));
+        self.sender.send(request).map_err(|_| writer_stopped())?;
+        result.recv().map_err(|_| writer_stopped())?
+    }
+
+    // Commit everything queued so far, including other clients' records, and
+    // wait for the commit. Unlike `call`, a failed commit is an error.
+    pub fn flush(&self) -> rusqlite::Result<usize> {
+        let (reply, result) = mpsc::channel();
+        self.sender.send(Request::Flush(reply)).map_err(|_| writer_stopped())?;
+        result.recv().map_err(|_| writer_stopped())?
+    }
+
+    // Commit everything queued and stop the thread
+    pub fn shutdown(&self) {
+        let _ = self.sender.send(Request::Shutdown);
+        let handle = self.thread.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        if let Some(handle) = handle {
+            if handle.join().is_err() {
+                error!("Database writer panicked during shutdown");
+            }
+        }
+    }
+}
+
+// Reported to clients once the writer thread is gone
+fn writer_stopped() -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(
+        ffi::Error::new(ffi::SQLITE_MISUSE),
+        Some("database writer has stopped".to_string()),
+    )
+}
+
+fn run(server: &ServerState, conn: Connection, receiver: &Receiver<Request>) {
+    info!("Database writer started");
+    let db = Database::new(
+        conn,
+        DATABASE_PATH,
+        server.config.sqlite.clone(),
+        server.config.dedup_timestamps,
+        server.metrics.clone(),
+    );
+    // Whatever is still buffered when this returns is committed or
+    // dead-lettered as the batch is dropped
+    let mut batch = BatchWriter::new(db, server);
+
+    loop {
+        // While the circuit breaker is open nothing but its probe writes
+        // records; they stay buffered until it closes
+        let until_probe = server.breaker.until_probe();
+        if until_probe.is_some_and(|wait| wait.is_zero()) {
+            batch.probe();
+            continue;
+        }
+        let paused = until_probe.is_some();
+
+        // While the database can't take the buffered records, stop taking
+        // more, so the queue fills up and clients are no longer read
+        if batch.is_full() {
+            match until_probe {
+                Some(wait) => thread::sleep(wait),
+                None => {
+                    if let Err(e) = batch.flush_all() {
+                        debug!("Commit while the buffer is full failed: {}", e);
+                        thread::sleep(BACKPRESSURE_RETRY_INTERVAL);
+                    }
+                }
+            }
+            continue;
+        }
+
+        match receiver.recv_timeout(until_probe.unwrap_or_else(|| batch.until_due())) {
+            Ok(Request::Records(records, tally)) => {
+                if let Err(e) = batch.push_all(records, tally) {
+                    error!("Database error: {}", e);
+                }
+            }
+            Ok(Request::Call(f)) => {
+                if !paused {
+                    if let Err(e) = batch.flush_all() {
+                        warn!("Database error committing ahead of a client request: {}", e);
+                    }
+                }
+                f(batch.conn());
+            }
+            Ok(Request::Flush(reply)) => {
+                let _ = reply.send(if paused { Err(breaker::paused()) } else { batch.flush_all() });
+            }
+            Ok(Request::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) if paused => {}
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(e) = batch.flush_if_due() {
+                    error!("Database error: {}", e);
+                }
+            }
+        }
+    }
+    // Sessions still being sent keep the statistics they got to
+    if let Err(e) = batch.flush_all() {
+        error!("Database error: {}", e);
+    }
+    let sessions: Vec<i64> = server.live_stats.iter().map(|entry| *entry.key()).collect();
+    for session_id in sessions {
+        if let Err(e) = stats::save_final(batch.conn(), &server.live_stats, session_id) {
+            error!("Failed to save the statistics of session {}: {}", session_id, e);
+        }
+    }
+    info!("Database writer stopped");
+}